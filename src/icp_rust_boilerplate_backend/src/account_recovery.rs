@@ -0,0 +1,279 @@
+use crate::admin::{self, require_admin};
+use crate::events::{record_event, EventKind};
+use crate::{
+    blacklist, certification, counter_offers, order_limits, owner_order_ids, pending_withdrawals, rate_limit,
+    store_order, BalanceChanges, Error, Memory, StorablePrincipal, SwapStatus, MEMORY_MANAGER, ORDERS_BY_OWNER,
+    SWAP_ORDERS, USER_ACCOUNTS,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const DEFAULT_RECOVERY_DELAY_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Bounds on the delay the admin can set, so the owner always has time to veto
+const MIN_RECOVERY_DELAY_SECS: u64 = 24 * 60 * 60;
+const MAX_RECOVERY_DELAY_SECS: u64 = 90 * 24 * 60 * 60;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecoverySetup {
+    recovery_principal: Principal,
+    set_at: u64,
+    claimable_at: Option<u64>, // set once the recovery principal asks to claim, cleared by a veto
+}
+
+impl Storable for RecoverySetup {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode RecoverySetup"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode RecoverySetup")
+    }
+}
+
+impl BoundedStorable for RecoverySetup {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Owner -> the principal that may take over the account
+    static RECOVERY_SETUPS: RefCell<StableBTreeMap<StorablePrincipal, RecoverySetup, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    // How long the owner has to veto a recovery request
+    static RECOVERY_DELAY_NANOS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))), DEFAULT_RECOVERY_DELAY_NANOS)
+            .expect("Cannot create the recovery delay")
+    );
+}
+
+#[ic_cdk::query]
+fn get_account_recovery_delay_secs() -> u64 {
+    RECOVERY_DELAY_NANOS.with(|cell| *cell.borrow().get()) / 1_000_000_000
+}
+
+// Applies to requests made from now on; pending ones keep their claimable_at
+#[ic_cdk::update]
+fn set_account_recovery_delay_secs(delay_secs: u64) -> Result<(), Error> {
+    require_admin()?;
+    if !(MIN_RECOVERY_DELAY_SECS..=MAX_RECOVERY_DELAY_SECS).contains(&delay_secs) {
+        return Err(Error::InvalidRecoveryDelay);
+    }
+    RECOVERY_DELAY_NANOS.with(|cell| cell.borrow_mut().set(delay_secs * 1_000_000_000))
+        .expect("Failed to store the recovery delay");
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_recovery_setup() -> Option<RecoverySetup> {
+    RECOVERY_SETUPS.with(|setups| setups.borrow().get(&StorablePrincipal::from(caller())))
+}
+
+// Names the principal that may take over the caller's account if they lose
+// access to it. Replacing it drops any pending request by the previous one.
+#[ic_cdk::update]
+fn set_recovery_principal(recovery_principal: Principal) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let owner = caller();
+    if recovery_principal == Principal::anonymous() || recovery_principal == owner {
+        return Err(Error::InvalidRecoveryPrincipal);
+    }
+
+    let setup = RecoverySetup {
+        recovery_principal,
+        set_at: time(),
+        claimable_at: None,
+    };
+    RECOVERY_SETUPS.with(|setups| setups.borrow_mut().insert(StorablePrincipal::from(owner), setup));
+    record_event(EventKind::RecoveryPrincipalSet {
+        owner,
+        recovery_principal: Some(recovery_principal),
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_recovery_principal() -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let owner = caller();
+    RECOVERY_SETUPS
+        .with(|setups| setups.borrow_mut().remove(&StorablePrincipal::from(owner)))
+        .ok_or(Error::RecoveryNotConfigured)?;
+    record_event(EventKind::RecoveryPrincipalSet {
+        owner,
+        recovery_principal: None,
+    });
+    Ok(())
+}
+
+// Called by the recovery principal to start the delay, after which
+// claim_account can take over `owner`'s account. Returns when it can.
+#[ic_cdk::update]
+fn request_account_recovery(owner: Principal) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let mut setup = setup_for_recovery_principal(owner)?;
+    if setup.claimable_at.is_some() {
+        return Err(Error::RecoveryAlreadyRequested);
+    }
+
+    let claimable_at = time().saturating_add(RECOVERY_DELAY_NANOS.with(|cell| *cell.borrow().get()));
+    setup.claimable_at = Some(claimable_at);
+    RECOVERY_SETUPS.with(|setups| setups.borrow_mut().insert(StorablePrincipal::from(owner), setup.clone()));
+    record_event(EventKind::RecoveryRequested {
+        owner,
+        recovery_principal: setup.recovery_principal,
+        claimable_at,
+    });
+    Ok(claimable_at)
+}
+
+// The owner turning down a pending request; the recovery principal stays
+// registered
+#[ic_cdk::update]
+fn veto_account_recovery() -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let owner = caller();
+    let key = StorablePrincipal::from(owner);
+    let mut setup = RECOVERY_SETUPS.with(|setups| setups.borrow().get(&key)).ok_or(Error::RecoveryNotConfigured)?;
+    if setup.claimable_at.take().is_none() {
+        return Err(Error::RecoveryNotRequested);
+    }
+
+    RECOVERY_SETUPS.with(|setups| setups.borrow_mut().insert(key, setup.clone()));
+    record_event(EventKind::RecoveryVetoed {
+        owner,
+        recovery_principal: setup.recovery_principal,
+    });
+    Ok(())
+}
+
+// Once the delay has passed without a veto, moves every balance of `owner`,
+// locked ones included, to the caller and hands them the escrow behind it:
+// the owner's open orders, orders they hold accepted and their open
+// counter-offers. Finished orders stay in the owner's history. Returns how
+// many open orders changed hands.
+#[ic_cdk::update]
+fn claim_account(owner: Principal) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let setup = setup_for_recovery_principal(owner)?;
+    let claimable_at = setup.claimable_at.ok_or(Error::RecoveryNotRequested)?;
+    if time() < claimable_at {
+        return Err(Error::RecoveryDelayNotElapsed { claimable_at });
+    }
+    // A recovery doesn't move funds out of a frozen account, or into one
+    if blacklist::is_blacklisted(owner) || blacklist::is_blacklisted(caller()) {
+        return Err(Error::Blacklisted);
+    }
+
+    let recovery_principal = caller();
+    let from = StorablePrincipal::from(owner);
+    let to = StorablePrincipal::from(recovery_principal);
+    pending_withdrawals::cancel_all_for(&from);
+    move_balances(&from, &to)?;
+    let orders_reassigned = reassign_orders(&from, recovery_principal);
+    counter_offers::reassign_proposer(owner, recovery_principal);
+
+    RECOVERY_SETUPS.with(|setups| setups.borrow_mut().remove(&from));
+    record_event(EventKind::AccountRecovered {
+        owner,
+        recovery_principal,
+        orders_reassigned,
+    });
+    Ok(orders_reassigned)
+}
+
+// The owner's setup, if the caller is its recovery principal
+fn setup_for_recovery_principal(owner: Principal) -> Result<RecoverySetup, Error> {
+    RECOVERY_SETUPS
+        .with(|setups| setups.borrow().get(&StorablePrincipal::from(owner)))
+        .filter(|setup| setup.recovery_principal == caller())
+        .ok_or(Error::RecoveryNotConfigured)
+}
+
+fn move_balances(from: &StorablePrincipal, to: &StorablePrincipal) -> Result<(), Error> {
+    let Some(account) = USER_ACCOUNTS.with(|accounts| accounts.borrow().get(from)) else {
+        return Ok(());
+    };
+    let mut changes = BalanceChanges::new();
+    for (currency, amount) in &account.balances {
+        changes.debit(from, currency, *amount)?;
+        changes.credit(to, currency, *amount)?;
+    }
+    for (currency, amount) in account.locked.iter().flatten() {
+        changes.release(from, currency, *amount)?;
+        changes.credit(to, currency, *amount)?;
+        changes.lock(to, currency, *amount)?;
+    }
+    changes.commit();
+    Ok(())
+}
+
+// Hands the escrow holding orders of `from` to `to`: the ones they own and
+// the ones they accepted as taker
+fn reassign_orders(from: &StorablePrincipal, to: Principal) -> u64 {
+    let to_principal = StorablePrincipal::from(to);
+    let mut reassigned = 0;
+    for order_id in owner_order_ids(from) {
+        let Some(mut swap_order) = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id)) else {
+            continue;
+        };
+        if !swap_order.holds_escrow() {
+            continue;
+        }
+        ORDERS_BY_OWNER.with(|index| {
+            let mut index_borrowed = index.borrow_mut();
+            index_borrowed.remove(&(from.clone(), order_id));
+            index_borrowed.insert((to_principal.clone(), order_id), ());
+        });
+        order_limits::record_open_change(from, true, false);
+        order_limits::record_open_change(&to_principal, false, true);
+        swap_order.owner = to;
+        certification::certify_order(&swap_order);
+        store_order(swap_order);
+        reassigned += 1;
+    }
+
+    // Accepted orders aren't indexed by taker, and only live ones can be
+    // accepted
+    let from_principal = Principal::from(from.clone());
+    let accepted: Vec<_> = SWAP_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .filter(|(_, order)| order.status == SwapStatus::Accepted && order.accepted_by == Some(from_principal))
+            .map(|(_, order)| order)
+            .collect()
+    });
+    for mut swap_order in accepted {
+        swap_order.accepted_by = Some(to);
+        store_order(swap_order);
+    }
+    reassigned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_recovery_calls_are_rejected() {
+        let other = Principal::from_slice(&[84]);
+        assert_eq!(set_recovery_principal(other), Err(Error::AnonymousNotAllowed));
+        assert_eq!(remove_recovery_principal(), Err(Error::AnonymousNotAllowed));
+        assert_eq!(request_account_recovery(other), Err(Error::AnonymousNotAllowed));
+        assert_eq!(veto_account_recovery(), Err(Error::AnonymousNotAllowed));
+        assert_eq!(claim_account(other), Err(Error::AnonymousNotAllowed));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::admin::require_admin;
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::events::{record_event, EventKind};
+use crate::proposals::{self, ProposedAction};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{BalanceChanges, Error, Memory, StorablePrincipal, MEMORY_MANAGER, USER_ACCOUNTS};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Kept short enough that the reason fits in the adjustment and its audit event
+const MAX_ADJUSTMENT_REASON_BYTES: usize = 128;
+
+// Upper bound on adjustments returned by a single page of list_adjustments
+const MAX_ADJUSTMENTS_PAGE_SIZE: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Adjustment {
+    id: u64,
+    principal: Principal,
+    currency: String,
+    delta: i128, // added to the available balance, negative for a debit
+    balance_after: u128,
+    reason: String,
+    admin: Principal,
+    transaction_id: u64,
+    timestamp: u64,
+}
+
+impl Storable for Adjustment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Adjustment"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Adjustment")
+    }
+}
+
+impl BoundedStorable for Adjustment {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Append-only, keyed by id starting at 1
+    static ADJUSTMENTS: RefCell<StableBTreeMap<u64, Adjustment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AdjustmentsPage {
+    adjustments: Vec<Adjustment>,
+    next_start_id: Option<u64>, // pass as start_id to fetch the next page, None once exhausted
+}
+
+// Support's way to compensate a user or take back a mistaken credit. Moves
+// the available balance by `delta`, never below zero, and leaves an
+// Adjustment in the user's transaction history and an audit event naming the
+// admin and the reason. Needs the admins' approval.
+#[ic_cdk::update]
+fn admin_adjust_balance(principal: Principal, currency: String, delta: i128, reason: String) -> Result<u64, Error> {
+    require_admin()?;
+    let currency = check_adjustment(principal, &currency, delta, &reason)?;
+    proposals::require_approval(ProposedAction::AdjustBalance {
+        principal,
+        currency: currency.clone(),
+        delta,
+        reason: reason.clone(),
+    })?;
+    apply_adjustment(principal, currency, delta, reason)
+}
+
+// Makes the adjustment, with the caller recorded as the admin behind it.
+// Returns its id.
+pub(crate) fn apply_adjustment(
+    principal: Principal,
+    currency: String,
+    delta: i128,
+    reason: String,
+) -> Result<u64, Error> {
+    let currency = check_adjustment(principal, &currency, delta, &reason)?;
+    let account = StorablePrincipal::from(principal);
+    let amount = delta.unsigned_abs();
+    let mut changes = BalanceChanges::new();
+    if delta > 0 {
+        changes.credit(&account, &currency, amount)?;
+    } else {
+        changes.debit(&account, &currency, amount)?;
+    }
+    changes.commit();
+
+    let transaction_id = if delta > 0 {
+        record_transaction(TransactionKind::Adjustment, None, Some(principal), &currency, amount, None)
+    } else {
+        record_transaction(TransactionKind::Adjustment, Some(principal), None, &currency, amount, None)
+    };
+    let id = ADJUSTMENTS.with(|adjustments| adjustments.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let adjustment = Adjustment {
+        id,
+        principal,
+        balance_after: USER_ACCOUNTS
+            .with(|accounts| accounts.borrow().get(&account))
+            .map_or(0, |user_account| user_account.balance(&currency)),
+        currency: currency.clone(),
+        delta,
+        reason: reason.clone(),
+        admin: caller(),
+        transaction_id,
+        timestamp: time(),
+    };
+    ADJUSTMENTS.with(|adjustments| adjustments.borrow_mut().insert(id, adjustment));
+    record_event(EventKind::BalanceAdjusted {
+        adjustment_id: id,
+        principal,
+        admin: caller(),
+        currency,
+        delta,
+        reason,
+    });
+
+    Ok(id)
+}
+
+// Returns the currency normalized
+fn check_adjustment(principal: Principal, currency: &str, delta: i128, reason: &str) -> Result<String, Error> {
+    if principal == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+    let currency = normalize_currency(currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+    if delta == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_ADJUSTMENT_REASON_BYTES {
+        return Err(Error::InvalidReason);
+    }
+    Ok(currency)
+}
+
+// Pages through every adjustment in id order, like list_orders does orders
+#[ic_cdk::query]
+fn list_adjustments(start_id: u64, limit: u16) -> Result<AdjustmentsPage, Error> {
+    require_admin()?;
+    let limit = (limit as usize).min(MAX_ADJUSTMENTS_PAGE_SIZE);
+    let mut adjustments: Vec<Adjustment> = ADJUSTMENTS.with(|adjustments| {
+        adjustments
+            .borrow()
+            .range(start_id..)
+            .take(limit + 1)
+            .map(|(_, adjustment)| adjustment)
+            .collect()
+    });
+
+    // The extra entry only tells us where the next page starts
+    let next_start_id = if adjustments.len() > limit {
+        adjustments.pop().map(|adjustment| adjustment.id)
+    } else {
+        None
+    };
+
+    Ok(AdjustmentsPage {
+        adjustments,
+        next_start_id,
+    })
+}
@@ -0,0 +1,317 @@
+use crate::proposals::{self, ProposedAction};
+use crate::snapshot::import_in_progress;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_cdk::api::{is_controller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Admins besides the one set at init or by transfer_admin
+const MAX_CO_ADMINS: u64 = 9;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AdminSet {
+    admin: Option<Principal>, // the one set at init or by transfer_admin
+    co_admins: Vec<Principal>,
+    threshold: u8, // admins who must approve a sensitive action, see approve_proposal
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub(crate) struct TradingStatus {
+    paused: bool,
+    updated_by: Option<Principal>, // admin who last paused or unpaused trading
+    updated_at: Option<u64>,
+}
+
+impl Storable for TradingStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode TradingStatus"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode TradingStatus")
+    }
+}
+
+thread_local! {
+    // Anonymous until init stores the admin. Canisters upgraded from a version
+    // without an admin keep it unset until a controller calls transfer_admin.
+    static ADMIN: RefCell<Cell<StorablePrincipal, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            StorablePrincipal::from(Principal::anonymous()),
+        )
+        .expect("Cannot create the admin cell")
+    );
+
+    static TRADING_STATUS: RefCell<Cell<TradingStatus, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), TradingStatus::default())
+            .expect("Cannot create the trading status")
+    );
+
+    // Principals with the same rights as the admin
+    static CO_ADMINS: RefCell<StableBTreeMap<StorablePrincipal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+
+    // At 1 sensitive actions take effect right away, as before co-admins
+    static APPROVAL_THRESHOLD: RefCell<Cell<u8, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98))), 1)
+            .expect("Cannot create the approval threshold")
+    );
+}
+
+fn stored_admin() -> Option<Principal> {
+    let admin: Principal = ADMIN.with(|admin| admin.borrow().get().clone()).into();
+    (admin != Principal::anonymous()).then_some(admin)
+}
+
+pub(crate) fn set_admin(admin: Principal) -> Result<(), Error> {
+    if admin == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+
+    ADMIN.with(|cell| cell.borrow_mut().set(StorablePrincipal::from(admin)))
+        .expect("Failed to store the admin");
+    // Counted once, as the admin
+    CO_ADMINS.with(|co_admins| co_admins.borrow_mut().remove(&StorablePrincipal::from(admin)));
+
+    Ok(())
+}
+
+// Controllers stand in for the admin only while none has been stored
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    if CO_ADMINS.with(|co_admins| co_admins.borrow().contains_key(&StorablePrincipal::from(*principal))) {
+        return true;
+    }
+    match stored_admin() {
+        Some(admin) => admin == *principal,
+        None => is_controller(principal),
+    }
+}
+
+pub(crate) fn approval_threshold() -> u8 {
+    APPROVAL_THRESHOLD.with(|cell| *cell.borrow().get())
+}
+
+// Funds credited to the anonymous principal could never be moved again, so
+// every update that touches balances or orders refuses it
+pub(crate) fn require_authenticated() -> Result<(), Error> {
+    if caller() == Principal::anonymous() {
+        Err(Error::AnonymousNotAllowed)
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn require_admin() -> Result<(), Error> {
+    if is_admin(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+#[ic_cdk::update]
+fn transfer_admin(new_admin: Principal) -> Result<(), Error> {
+    require_admin()?;
+    if new_admin == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+    proposals::require_approval(ProposedAction::TransferAdmin { new_admin })?;
+    set_admin(new_admin)
+}
+
+#[ic_cdk::query]
+fn get_admin() -> Option<Principal> {
+    stored_admin()
+}
+
+#[ic_cdk::query]
+fn get_admins() -> AdminSet {
+    AdminSet {
+        admin: stored_admin(),
+        co_admins: CO_ADMINS
+            .with(|co_admins| co_admins.borrow().iter().map(|(principal, _)| principal.into()).collect()),
+        threshold: approval_threshold(),
+    }
+}
+
+// Like every change to the admin set, needs the threshold's approval
+#[ic_cdk::update]
+fn add_admin(principal: Principal) -> Result<(), Error> {
+    require_admin()?;
+    if principal == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+    if is_admin(&principal) {
+        return Err(Error::AlreadyAdmin);
+    }
+    if CO_ADMINS.with(|co_admins| co_admins.borrow().len()) >= MAX_CO_ADMINS {
+        return Err(Error::TooManyAdmins { limit: MAX_CO_ADMINS });
+    }
+    proposals::require_approval(ProposedAction::AddAdmin { principal })?;
+    apply_add_admin(principal)
+}
+
+// Only co-admins can be removed; the admin is replaced with transfer_admin
+#[ic_cdk::update]
+fn remove_admin(principal: Principal) -> Result<(), Error> {
+    require_admin()?;
+    check_remove_admin(&principal)?;
+    proposals::require_approval(ProposedAction::RemoveAdmin { principal })?;
+    apply_remove_admin(principal)
+}
+
+#[ic_cdk::update]
+fn set_approval_threshold(threshold: u8) -> Result<(), Error> {
+    require_admin()?;
+    check_threshold(threshold, admin_count())?;
+    proposals::require_approval(ProposedAction::SetApprovalThreshold { threshold })?;
+    apply_approval_threshold(threshold)
+}
+
+// The apply_ functions run the action once it is approved, re-checking what
+// may have changed since it was proposed
+pub(crate) fn apply_add_admin(principal: Principal) -> Result<(), Error> {
+    if is_admin(&principal) {
+        return Err(Error::AlreadyAdmin);
+    }
+    if CO_ADMINS.with(|co_admins| co_admins.borrow().len()) >= MAX_CO_ADMINS {
+        return Err(Error::TooManyAdmins { limit: MAX_CO_ADMINS });
+    }
+    CO_ADMINS.with(|co_admins| co_admins.borrow_mut().insert(StorablePrincipal::from(principal), ()));
+    Ok(())
+}
+
+pub(crate) fn apply_remove_admin(principal: Principal) -> Result<(), Error> {
+    check_remove_admin(&principal)?;
+    CO_ADMINS.with(|co_admins| co_admins.borrow_mut().remove(&StorablePrincipal::from(principal)));
+    Ok(())
+}
+
+pub(crate) fn apply_approval_threshold(threshold: u8) -> Result<(), Error> {
+    check_threshold(threshold, admin_count())?;
+    APPROVAL_THRESHOLD.with(|cell| cell.borrow_mut().set(threshold)).expect("Failed to store the approval threshold");
+    Ok(())
+}
+
+// Never leaves fewer admins than approvals needed
+fn check_remove_admin(principal: &Principal) -> Result<(), Error> {
+    if !CO_ADMINS.with(|co_admins| co_admins.borrow().contains_key(&StorablePrincipal::from(*principal))) {
+        return Err(Error::AdminNotFound);
+    }
+    check_threshold(approval_threshold(), admin_count() - 1)
+}
+
+fn check_threshold(threshold: u8, admins: u64) -> Result<(), Error> {
+    if threshold == 0 || u64::from(threshold) > admins {
+        return Err(Error::InvalidApprovalThreshold);
+    }
+    Ok(())
+}
+
+// The admin, or the controllers standing in for it, count as one
+fn admin_count() -> u64 {
+    CO_ADMINS.with(|co_admins| co_admins.borrow().len()) + 1
+}
+
+#[ic_cdk::update]
+fn pause() -> Result<(), Error> {
+    require_admin()?;
+    set_paused(true);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unpause() -> Result<(), Error> {
+    require_admin()?;
+    if import_in_progress() {
+        return Err(Error::ImportInProgress);
+    }
+    set_paused(false);
+    Ok(())
+}
+
+pub(crate) fn set_paused(paused: bool) {
+    let status = TradingStatus {
+        paused,
+        updated_by: Some(caller()),
+        updated_at: Some(time()),
+    };
+    TRADING_STATUS.with(|cell| cell.borrow_mut().set(status))
+        .expect("Failed to store the trading status");
+}
+
+#[ic_cdk::query]
+fn get_trading_status() -> TradingStatus {
+    TRADING_STATUS.with(|cell| cell.borrow().get().clone())
+}
+
+pub(crate) fn trading_paused() -> bool {
+    TRADING_STATUS.with(|cell| cell.borrow().get().paused)
+}
+
+// Guards endpoints that open new positions. Cancelling and withdrawing stay
+// available while paused so users can always get their funds out.
+pub(crate) fn require_trading_active() -> Result<(), Error> {
+    if trading_paused() {
+        Err(Error::TradingPaused)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    thread_local! {
+        // Unit tests run outside a canister, where ic_cdk's caller() traps
+        static CALLER: std::cell::Cell<Principal> = const { std::cell::Cell::new(Principal::anonymous()) };
+    }
+
+    // Anonymous until a test says otherwise
+    pub(crate) fn caller() -> Principal {
+        CALLER.with(|caller| caller.get())
+    }
+
+    pub(crate) fn set_caller(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal));
+    }
+
+    thread_local! {
+        // ic_cdk's time() traps outside a canister too
+        static NOW: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+
+    // Zero until a test says otherwise
+    pub(crate) fn time() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    pub(crate) fn set_time(now: u64) {
+        NOW.with(|cell| cell.set(now));
+    }
+
+    // There is no certified data to set outside a canister
+    pub(crate) fn set_certified_data(_data: &[u8]) {}
+
+    #[test]
+    fn anonymous_callers_are_not_authenticated() {
+        assert_eq!(require_authenticated(), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn other_callers_are_authenticated() {
+        set_caller(Principal::from_slice(&[1]));
+        assert_eq!(require_authenticated(), Ok(()));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Upper bound on principals returned by a single page
+const MAX_ALLOWLIST_PAGE_SIZE: u64 = 100;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub(crate) enum AccessMode {
+    #[default]
+    Open,      // anyone authenticated may trade
+    Allowlist, // only approved principals may deposit, trade or transfer
+}
+
+impl Storable for AccessMode {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode AccessMode"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode AccessMode")
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AllowlistPage {
+    principals: Vec<(Principal, u64)>, // with when each was approved
+    total: u64,
+}
+
+thread_local! {
+    static ACCESS_MODE: RefCell<Cell<AccessMode, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))), AccessMode::default())
+            .expect("Cannot create the access mode")
+    );
+
+    // Approved principal -> when it was approved
+    static ALLOWLIST: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_access_mode() -> AccessMode {
+    ACCESS_MODE.with(|cell| *cell.borrow().get())
+}
+
+// The list is kept while the mode is Open, so approvals made beforehand take
+// effect as soon as Allowlist is switched on
+#[ic_cdk::update]
+fn set_access_mode(mode: AccessMode) -> Result<(), Error> {
+    require_admin()?;
+    ACCESS_MODE.with(|cell| cell.borrow_mut().set(mode))
+        .expect("Failed to store the access mode");
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn add_to_allowlist(principal: Principal) -> Result<(), Error> {
+    require_admin()?;
+    if principal == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+
+    ALLOWLIST.with(|allowlist| allowlist.borrow_mut().insert(StorablePrincipal::from(principal), time()));
+    Ok(())
+}
+
+// The principal keeps its balances and can still withdraw and cancel, but
+// can't trade them while the mode is Allowlist
+#[ic_cdk::update]
+fn remove_from_allowlist(principal: Principal) -> Result<(), Error> {
+    require_admin()?;
+    ALLOWLIST.with(|allowlist| allowlist.borrow_mut().remove(&StorablePrincipal::from(principal)));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_allowlisted(offset: u64, limit: u64) -> Result<AllowlistPage, Error> {
+    require_admin()?;
+    let limit = limit.min(MAX_ALLOWLIST_PAGE_SIZE) as usize;
+    ALLOWLIST.with(|allowlist| {
+        let allowlist_borrowed = allowlist.borrow();
+        Ok(AllowlistPage {
+            principals: allowlist_borrowed
+                .iter()
+                .skip(offset as usize)
+                .take(limit)
+                .map(|(principal, approved_at)| (principal.into(), approved_at))
+                .collect(),
+            total: allowlist_borrowed.len(),
+        })
+    })
+}
+
+// Whether the principal may trade under the current mode
+pub(crate) fn is_allowed(principal: Principal) -> bool {
+    get_access_mode() == AccessMode::Open
+        || ALLOWLIST.with(|allowlist| allowlist.borrow().contains_key(&StorablePrincipal::from(principal)))
+}
+
+// Guards the endpoints that add funds or trade them. Withdrawals and
+// cancellations stay open so removed users can still get their funds out.
+pub(crate) fn require_allowlisted() -> Result<(), Error> {
+    if is_allowed(caller()) {
+        Ok(())
+    } else {
+        Err(Error::NotAllowlisted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::tests::set_caller;
+
+    #[test]
+    fn allowlist_mode_admits_only_approved_callers() {
+        let (approved, other) = (Principal::from_slice(&[63]), Principal::from_slice(&[64]));
+        ALLOWLIST.with(|allowlist| allowlist.borrow_mut().insert(StorablePrincipal::from(approved), 0));
+
+        set_caller(other);
+        assert_eq!(require_allowlisted(), Ok(()));
+
+        ACCESS_MODE.with(|cell| cell.borrow_mut().set(AccessMode::Allowlist)).unwrap();
+        assert_eq!(require_allowlisted(), Err(Error::NotAllowlisted));
+        set_caller(approved);
+        assert_eq!(require_allowlisted(), Ok(()));
+    }
+}
@@ -0,0 +1,106 @@
+use candid::Nat;
+use std::cmp::Ordering;
+
+// Amounts are u128, so the product of two of them can take 256 bits. Prices,
+// fills and fees are worked out on Nats and only narrowed back to u128 once
+// divided down.
+
+// Compares a * b with c * d exactly
+pub(crate) fn cmp_products(a: u128, b: u128, c: u128, d: u128) -> Ordering {
+    (Nat::from(a) * Nat::from(b)).cmp(&(Nat::from(c) * Nat::from(d)))
+}
+
+// a * b / c rounded down, None when c is 0 or the quotient needs more than
+// 128 bits
+pub(crate) fn mul_div(a: u128, b: u128, c: u128) -> Option<u128> {
+    if c == 0 {
+        return None;
+    }
+    narrow(Nat::from(a) * Nat::from(b) / Nat::from(c))
+}
+
+// a * b / c rounded up, None like mul_div
+pub(crate) fn mul_div_ceil(a: u128, b: u128, c: u128) -> Option<u128> {
+    if c == 0 {
+        return None;
+    }
+    let product = Nat::from(a) * Nat::from(b);
+    let divisor = Nat::from(c);
+    let rounds_up = product.clone() % divisor.clone() != 0u8;
+    let quotient = narrow(product / divisor)?;
+    if rounds_up {
+        quotient.checked_add(1)
+    } else {
+        Some(quotient)
+    }
+}
+
+// Bytes of a price key, enough for any numerator shifted left by 256 bits
+pub(crate) const PRICE_KEY_BYTES: usize = 48;
+
+// numerator / denominator as big-endian bytes that sort like the fractions
+// do: floor(numerator * 2^256 / denominator). Two different fractions of
+// u128s differ by more than 2^-256, so they never share a key. The
+// denominator must not be 0.
+pub(crate) fn price_key(numerator: u128, denominator: u128) -> [u8; PRICE_KEY_BYTES] {
+    let scaled = (Nat::from(numerator).0 << 256u32) / Nat::from(denominator).0;
+    let digits = scaled.to_bytes_be();
+    let mut key = [0u8; PRICE_KEY_BYTES];
+    key[PRICE_KEY_BYTES - digits.len()..].copy_from_slice(&digits);
+    key
+}
+
+fn narrow(value: Nat) -> Option<u128> {
+    u128::try_from(value.0).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn products_past_128_bits_are_compared_exactly() {
+        // (2^128 - 1)^2 needs all 256 bits
+        let max = u128::MAX;
+        assert_eq!(cmp_products(max, max, max, max), Ordering::Equal);
+        assert_eq!(cmp_products(max, max - 1, max, max), Ordering::Less);
+        assert_eq!(cmp_products(max, max, max - 1, max), Ordering::Greater);
+    }
+
+    #[test]
+    fn quotients_of_products_past_128_bits_fit_again() {
+        let big = 10u128.pow(30);
+        assert_eq!(mul_div(big, big, big), Some(big));
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), Some(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 2, 3), Some(u128::MAX / 3 * 2));
+    }
+
+    #[test]
+    fn quotients_past_128_bits_or_by_zero_are_none() {
+        assert_eq!(mul_div(u128::MAX, 2, 1), None);
+        assert_eq!(mul_div(1, 1, 0), None);
+        assert_eq!(mul_div_ceil(u128::MAX, 2, 1), None);
+        assert_eq!(mul_div_ceil(1, 1, 0), None);
+    }
+
+    #[test]
+    fn ceil_division_rounds_up_only_with_a_remainder() {
+        let big = u64::MAX as u128 * 4;
+        assert_eq!(mul_div_ceil(big, 3, 4), Some(u64::MAX as u128 * 3));
+        assert_eq!(mul_div_ceil(big + 1, 3, 4), Some(u64::MAX as u128 * 3 + 1));
+        assert_eq!(mul_div(big + 1, 3, 4), Some(u64::MAX as u128 * 3));
+        assert_eq!(mul_div_ceil(u128::MAX, 2, 2), Some(u128::MAX));
+        // (2^129 - 1) / 2 rounds down to u128::MAX, rounding it up doesn't fit
+        let seventh = 97_223_533_405_982_418_132_392_744_980_505_203_273;
+        assert_eq!(mul_div(seventh, 7, 2), Some(u128::MAX));
+        assert_eq!(mul_div_ceil(seventh, 7, 2), None);
+    }
+
+    #[test]
+    fn price_keys_sort_like_the_fractions_past_u64() {
+        let big = u64::MAX as u128 + 1;
+        assert!(price_key(big, big + 1) < price_key(big + 1, big + 2));
+        assert!(price_key(big * 3, big) > price_key(big * 3 - 1, big));
+        assert_eq!(price_key(big * 2, big * 4), price_key(1, 2));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::admin::require_admin;
+use crate::events::{record_event, EventKind};
+use crate::{
+    certification, fill_callbacks, order_labels, order_queries, self_trade, stats, Error, Memory, OrdersCursorPage,
+    StorablePrincipal, SwapOrder, SwapStatus, MAX_ORDERS_PAGE_SIZE, MEMORY_MANAGER, ORDERS_BY_COUNTERPARTY,
+    ORDERS_BY_OWNER, SWAP_ORDERS,
+};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+const DEFAULT_ARCHIVE_MIN_AGE_NANOS: u64 = 30 * NANOS_PER_DAY;
+
+const DEFAULT_ARCHIVE_RETENTION_NANOS: u64 = 2 * 365 * NANOS_PER_DAY;
+
+// Upper bound on orders moved by one call
+const MAX_ARCHIVE_BATCH: u32 = 500;
+
+// Live orders inspected per call, keeps a call inside the instruction limit
+// even when few of the inspected orders qualify
+const ARCHIVE_SCAN_LIMIT: usize = 2000;
+
+thread_local! {
+    // Finished orders moved out of SWAP_ORDERS, same key and value
+    pub(crate) static ARCHIVED_ORDERS: RefCell<StableBTreeMap<u64, SwapOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // How long an order stays in SWAP_ORDERS after it finished
+    static ARCHIVE_MIN_AGE_NANOS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))), DEFAULT_ARCHIVE_MIN_AGE_NANOS)
+            .expect("Cannot create the archive age")
+    );
+
+    // How long an archived order is kept after it finished before
+    // prune_archive may delete it
+    static ARCHIVE_RETENTION_NANOS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))), DEFAULT_ARCHIVE_RETENTION_NANOS)
+            .expect("Cannot create the archive retention")
+    );
+
+    // Next order id the archiving scan resumes from; heap only like the
+    // expiry sweep cursor
+    static ARCHIVE_CURSOR: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+
+    // Next archived order id prune_archive resumes from, the same way
+    static PRUNE_CURSOR: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+#[ic_cdk::query]
+fn get_archive_min_age_secs() -> u64 {
+    ARCHIVE_MIN_AGE_NANOS.with(|cell| *cell.borrow().get()) / 1_000_000_000
+}
+
+#[ic_cdk::update]
+fn set_archive_min_age_secs(min_age_secs: u64) -> Result<(), Error> {
+    require_admin()?;
+    let min_age_nanos = min_age_secs.checked_mul(1_000_000_000).ok_or(Error::Overflow)?;
+    ARCHIVE_MIN_AGE_NANOS.with(|cell| cell.borrow_mut().set(min_age_nanos))
+        .expect("Failed to store the archive age");
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_archive_retention_secs() -> u64 {
+    ARCHIVE_RETENTION_NANOS.with(|cell| *cell.borrow().get()) / 1_000_000_000
+}
+
+#[ic_cdk::update]
+fn set_archive_retention_secs(retention_secs: u64) -> Result<(), Error> {
+    require_admin()?;
+    let retention_nanos = retention_secs.checked_mul(1_000_000_000).ok_or(Error::Overflow)?;
+    ARCHIVE_RETENTION_NANOS.with(|cell| cell.borrow_mut().set(retention_nanos))
+        .expect("Failed to store the archive retention");
+    Ok(())
+}
+
+// Moves up to `max_items` orders that finished longer than the minimum age
+// ago into the archive and returns how many were moved. Each call resumes
+// where the last one stopped and wraps around at the end of the map, so
+// repeated calls cover every order.
+#[ic_cdk::update]
+fn archive_finished_orders(max_items: u32) -> Result<u64, Error> {
+    require_admin()?;
+    let max_items = max_items.min(MAX_ARCHIVE_BATCH) as usize;
+    let cutoff = time().saturating_sub(ARCHIVE_MIN_AGE_NANOS.with(|cell| *cell.borrow().get()));
+
+    let cursor = ARCHIVE_CURSOR.with(|cursor| cursor.get());
+    let batch: Vec<(u64, SwapOrder)> =
+        SWAP_ORDERS.with(|orders| orders.borrow().range(cursor..).take(ARCHIVE_SCAN_LIMIT).collect());
+
+    let mut next_cursor = match batch.last() {
+        Some((last_id, _)) if batch.len() == ARCHIVE_SCAN_LIMIT => last_id + 1,
+        _ => 0,
+    };
+    let mut archived = 0;
+    for (order_id, swap_order) in batch {
+        if archived == max_items {
+            next_cursor = order_id;
+            break;
+        }
+        if finished_at(&swap_order).is_some_and(|finished_at| finished_at <= cutoff) {
+            SWAP_ORDERS.with(|orders| orders.borrow_mut().remove(&order_id));
+            ARCHIVED_ORDERS.with(|archive| archive.borrow_mut().insert(order_id, swap_order));
+            archived += 1;
+        }
+    }
+    ARCHIVE_CURSOR.with(|cursor| cursor.set(next_cursor));
+
+    Ok(archived as u64)
+}
+
+// Deletes up to `max_items` archived orders that finished before
+// `before_timestamp` and are past the retention period, and returns how many
+// went. One ArchivePruned event summarizes them, so the event log still
+// accounts for every order ever placed. Like archive_finished_orders, each
+// call resumes where the last one stopped and wraps around at the end.
+#[ic_cdk::update]
+fn prune_archive(before_timestamp: u64, max_items: u32) -> Result<u64, Error> {
+    require_admin()?;
+    let max_items = max_items.min(MAX_ARCHIVE_BATCH) as usize;
+    let retention = ARCHIVE_RETENTION_NANOS.with(|cell| *cell.borrow().get());
+    let cutoff = before_timestamp.min(time().saturating_sub(retention));
+
+    let cursor = PRUNE_CURSOR.with(|cursor| cursor.get());
+    let batch: Vec<SwapOrder> = ARCHIVED_ORDERS
+        .with(|archive| archive.borrow().range(cursor..).take(ARCHIVE_SCAN_LIMIT).map(|(_, order)| order).collect());
+
+    let mut next_cursor = match batch.last() {
+        Some(last) if batch.len() == ARCHIVE_SCAN_LIMIT => last.id + 1,
+        _ => 0,
+    };
+    let mut prunable = Vec::new();
+    for swap_order in batch {
+        if prunable.len() == max_items {
+            next_cursor = swap_order.id;
+            break;
+        }
+        // Open orders never reach the archive, but an order that could still
+        // change must not be deleted whatever its age
+        if swap_order.status != SwapStatus::Created
+            && finished_at(&swap_order).is_some_and(|finished_at| finished_at < cutoff)
+        {
+            prunable.push(swap_order);
+        }
+    }
+    PRUNE_CURSOR.with(|cursor| cursor.set(next_cursor));
+    if prunable.is_empty() {
+        return Ok(0);
+    }
+
+    let mut ids_hasher = Sha256::new();
+    let mut total_volume: u128 = 0;
+    for swap_order in &prunable {
+        ids_hasher.update(swap_order.id.to_be_bytes());
+        total_volume = total_volume.saturating_add(swap_order.filled());
+
+        ARCHIVED_ORDERS.with(|archive| archive.borrow_mut().remove(&swap_order.id));
+        ORDERS_BY_OWNER
+            .with(|index| index.borrow_mut().remove(&(StorablePrincipal::from(swap_order.owner), swap_order.id)));
+        if let Some(counterparty) = swap_order.counterparty {
+            ORDERS_BY_COUNTERPARTY
+                .with(|index| index.borrow_mut().remove(&(StorablePrincipal::from(counterparty), swap_order.id)));
+        }
+        order_queries::forget_order(swap_order);
+        self_trade::forget_self_trades(swap_order.id);
+        order_labels::forget_labels(swap_order.id);
+        fill_callbacks::forget_callback(swap_order.id);
+        stats::record_order_removed(swap_order);
+    }
+    certification::forget_orders(prunable.iter().map(|order| order.id));
+    let count = prunable.len() as u64;
+    record_event(EventKind::ArchivePruned {
+        admin: caller(),
+        count,
+        total_volume,
+        ids_hash: ids_hasher.finalize().to_vec(),
+    });
+
+    Ok(count)
+}
+
+// Pages through the archive in id order, like list_orders does the live map
+#[ic_cdk::query]
+fn list_archived_orders(start_id: u64, limit: u16) -> OrdersCursorPage {
+    let limit = (limit as u64).min(MAX_ORDERS_PAGE_SIZE) as usize;
+    let viewer = caller();
+    let mut orders: Vec<SwapOrder> = ARCHIVED_ORDERS.with(|archive| {
+        archive
+            .borrow()
+            .range(start_id..)
+            .take(limit + 1)
+            .map(|(_, order)| order.viewed_by(&viewer))
+            .collect()
+    });
+
+    let next_start_id = if orders.len() > limit {
+        orders.pop().map(|order| order.id)
+    } else {
+        None
+    };
+
+    OrdersCursorPage { orders, next_start_id }
+}
+
+// Looks an order up in the live map first, then in the archive
+pub(crate) fn find_order(order_id: u64) -> Option<SwapOrder> {
+    SWAP_ORDERS
+        .with(|orders| orders.borrow().get(&order_id))
+        .or_else(|| ARCHIVED_ORDERS.with(|archive| archive.borrow().get(&order_id)))
+}
+
+pub(crate) fn archived_order_count() -> u64 {
+    ARCHIVED_ORDERS.with(|archive| archive.borrow().len())
+}
+
+// When a finished order reached its final status, None while it can still
+// change. Killed orders finish as they are placed.
+fn finished_at(swap_order: &SwapOrder) -> Option<u64> {
+    match swap_order.status {
+        SwapStatus::Executed => Some(swap_order.executed_at.unwrap_or(swap_order.created_at)),
+        SwapStatus::Cancelled => Some(swap_order.cancelled_at.unwrap_or(swap_order.created_at)),
+        SwapStatus::Expired => Some(swap_order.expires_at.unwrap_or(swap_order.created_at)),
+        SwapStatus::Killed => Some(swap_order.created_at),
+        SwapStatus::Created | SwapStatus::PartiallyFilled | SwapStatus::Accepted => None,
+    }
+}
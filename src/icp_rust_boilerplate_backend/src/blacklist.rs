@@ -0,0 +1,104 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const MAX_BLACKLIST_REASON_BYTES: usize = 128;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct BlacklistEntry {
+    reason: String,
+    blacklisted_by: Principal,
+    blacklisted_at: u64,
+}
+
+impl Storable for BlacklistEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode BlacklistEntry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode BlacklistEntry")
+    }
+}
+
+impl BoundedStorable for BlacklistEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static BLACKLIST: RefCell<StableBTreeMap<StorablePrincipal, BlacklistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+}
+
+// Blocks the principal from depositing, trading, transferring and
+// withdrawing. Cancelling stays open so its orders can still be unwound.
+// Blacklisting again replaces the reason.
+#[ic_cdk::update]
+fn blacklist(principal: Principal, reason: String) -> Result<(), Error> {
+    require_admin()?;
+    if reason.trim().is_empty() || reason.len() > MAX_BLACKLIST_REASON_BYTES {
+        return Err(Error::InvalidReason);
+    }
+
+    let entry = BlacklistEntry {
+        reason,
+        blacklisted_by: caller(),
+        blacklisted_at: time(),
+    };
+    BLACKLIST.with(|blacklist| blacklist.borrow_mut().insert(StorablePrincipal::from(principal), entry));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unblacklist(principal: Principal) -> Result<(), Error> {
+    require_admin()?;
+    BLACKLIST.with(|blacklist| blacklist.borrow_mut().remove(&StorablePrincipal::from(principal)));
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub(crate) fn is_blacklisted(principal: Principal) -> bool {
+    BLACKLIST.with(|blacklist| blacklist.borrow().contains_key(&StorablePrincipal::from(principal)))
+}
+
+pub(crate) fn require_not_blacklisted() -> Result<(), Error> {
+    if is_blacklisted(caller()) {
+        Err(Error::Blacklisted)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::tests::set_caller;
+
+    #[test]
+    fn only_blacklisted_callers_are_refused() {
+        let (blocked, other) = (Principal::from_slice(&[60]), Principal::from_slice(&[61]));
+        let entry = BlacklistEntry {
+            reason: "fraud".to_string(),
+            blacklisted_by: Principal::from_slice(&[62]),
+            blacklisted_at: 0,
+        };
+        BLACKLIST.with(|blacklist| blacklist.borrow_mut().insert(StorablePrincipal::from(blocked), entry));
+
+        set_caller(blocked);
+        assert_eq!(require_not_blacklisted(), Err(Error::Blacklisted));
+        set_caller(other);
+        assert_eq!(require_not_blacklisted(), Ok(()));
+    }
+}
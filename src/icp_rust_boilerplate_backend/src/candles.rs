@@ -0,0 +1,239 @@
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::{CurrencyPair, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+// Upper bound on candles returned by a single get_candles call
+const MAX_CANDLES: usize = 500;
+
+// Buckets kept per pair at the finer resolutions. Every trade is also added to
+// the coarser buckets, so dropping an old minute or hour bucket loses nothing
+// the hour and day candles don't already cover.
+const MINUTE_CANDLES_KEPT: u64 = 24 * 60;
+const HOUR_CANDLES_KEPT: u64 = 90 * 24;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+pub(crate) enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Resolution::Minute, Resolution::Hour, Resolution::Day];
+
+    fn nanos(&self) -> u64 {
+        match self {
+            Resolution::Minute => NANOS_PER_MINUTE,
+            Resolution::Hour => 60 * NANOS_PER_MINUTE,
+            Resolution::Day => 24 * 60 * NANOS_PER_MINUTE,
+        }
+    }
+
+    // None keeps every bucket
+    fn buckets_kept(&self) -> Option<u64> {
+        match self {
+            Resolution::Minute => Some(MINUTE_CANDLES_KEPT),
+            Resolution::Hour => Some(HOUR_CANDLES_KEPT),
+            Resolution::Day => None,
+        }
+    }
+
+    fn with_candles<R>(&self, f: impl FnOnce(&mut StableBTreeMap<(CurrencyPair, u64), Candle, Memory>) -> R) -> R {
+        let candles = match self {
+            Resolution::Minute => &MINUTE_CANDLES,
+            Resolution::Hour => &HOUR_CANDLES,
+            Resolution::Day => &DAY_CANDLES,
+        };
+        candles.with(|candles| f(&mut candles.borrow_mut()))
+    }
+}
+
+// Trades of one bucket. Candles are kept per pair in one direction only, the
+// currencies in alphabetical order, with prices in units of the second per
+// unit of the first; trades the other way round are inverted on the way in.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Candle {
+    open: f64,
+    high: f64,
+    low: f64,
+    close: f64,
+    base_volume: u128,  // first currency of the stored pair
+    quote_volume: u128, // second currency of the stored pair
+}
+
+impl Candle {
+    fn add(&mut self, price: f64, base_volume: u128, quote_volume: u128) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.base_volume = self.base_volume.saturating_add(base_volume);
+        self.quote_volume = self.quote_volume.saturating_add(quote_volume);
+    }
+
+    // The same trades seen from the other currency
+    fn inverted(self) -> Candle {
+        Candle {
+            open: 1.0 / self.open,
+            high: 1.0 / self.low,
+            low: 1.0 / self.high,
+            close: 1.0 / self.close,
+            base_volume: self.quote_volume,
+            quote_volume: self.base_volume,
+        }
+    }
+}
+
+impl Storable for Candle {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Candle"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Candle")
+    }
+}
+
+impl BoundedStorable for Candle {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (pair, bucket index since epoch) -> trades in that bucket, one map per
+    // resolution
+    static MINUTE_CANDLES: RefCell<StableBTreeMap<(CurrencyPair, u64), Candle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    static HOUR_CANDLES: RefCell<StableBTreeMap<(CurrencyPair, u64), Candle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    static DAY_CANDLES: RefCell<StableBTreeMap<(CurrencyPair, u64), Candle, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+}
+
+// Parallel arrays, one entry per bucket that saw a trade; buckets without
+// trades are left out rather than filled in. Prices are in units of
+// to_currency per unit of from_currency.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct Candles {
+    timestamps: Vec<u64>, // start of each bucket, nanoseconds since epoch
+    open: Vec<f64>,
+    high: Vec<f64>,
+    low: Vec<f64>,
+    close: Vec<f64>,
+    volume: Vec<u128>,       // from_currency traded
+    quote_volume: Vec<u128>, // to_currency traded
+}
+
+// Candles of buckets starting within [start, end], oldest first and at most
+// MAX_CANDLES of them. Minute and hour candles only reach back as far as
+// they are kept.
+#[ic_cdk::query]
+fn get_candles(
+    from_currency: String,
+    to_currency: String,
+    resolution: Resolution,
+    start: u64,
+    end: u64,
+) -> Result<Candles, Error> {
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+
+    let mut candles = Candles::default();
+    if start > end {
+        return Ok(candles);
+    }
+    let (pair, inverted) = stored_pair(&from_currency, &to_currency);
+    let first_bucket = start.div_ceil(resolution.nanos());
+    let last_bucket = end / resolution.nanos();
+    resolution.with_candles(|stored| {
+        for ((_, bucket), candle) in
+            stored.range((pair.clone(), first_bucket)..=(pair.clone(), last_bucket)).take(MAX_CANDLES)
+        {
+            let candle = if inverted { candle.inverted() } else { candle };
+            candles.timestamps.push(bucket * resolution.nanos());
+            candles.open.push(candle.open);
+            candles.high.push(candle.high);
+            candles.low.push(candle.low);
+            candles.close.push(candle.close);
+            candles.volume.push(candle.base_volume);
+            candles.quote_volume.push(candle.quote_volume);
+        }
+    });
+    Ok(candles)
+}
+
+// Called by settle_fill with the amounts of every fill
+pub(crate) fn record_trade(from_currency: &str, to_currency: &str, from_amount: u128, to_amount: u128) {
+    let (pair, inverted) = stored_pair(from_currency, to_currency);
+    let (base_volume, quote_volume) = if inverted {
+        (to_amount, from_amount)
+    } else {
+        (from_amount, to_amount)
+    };
+    let price = quote_volume as f64 / base_volume as f64;
+    let now = time();
+
+    for resolution in Resolution::ALL {
+        let bucket = now / resolution.nanos();
+        resolution.with_candles(|candles| {
+            if let Some(kept) = resolution.buckets_kept() {
+                let expired: Vec<(CurrencyPair, u64)> = candles
+                    .range((pair.clone(), 0)..(pair.clone(), bucket.saturating_sub(kept - 1)))
+                    .map(|(key, _)| key)
+                    .collect();
+                for key in expired {
+                    candles.remove(&key);
+                }
+            }
+
+            let key = (pair.clone(), bucket);
+            let candle = match candles.get(&key) {
+                Some(mut candle) => {
+                    candle.add(price, base_volume, quote_volume);
+                    candle
+                }
+                None => Candle {
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    base_volume,
+                    quote_volume,
+                },
+            };
+            candles.insert(key, candle);
+        });
+    }
+}
+
+// The pair candles of these currencies are stored under, and whether it is
+// the other way round
+pub(crate) fn stored_pair(from_currency: &str, to_currency: &str) -> (CurrencyPair, bool) {
+    if from_currency <= to_currency {
+        (CurrencyPair::new(from_currency, to_currency), false)
+    } else {
+        (CurrencyPair::new(to_currency, from_currency), true)
+    }
+}
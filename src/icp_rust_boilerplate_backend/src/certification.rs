@@ -0,0 +1,411 @@
+use crate::archive::{find_order, ARCHIVED_ORDERS};
+use crate::{
+    get_user_balance, CurrencyBalance, Error, StorablePrincipal, SwapOrder, UserAccount, SWAP_ORDERS, USER_ACCOUNTS,
+};
+#[cfg(test)]
+use crate::admin::tests::set_certified_data;
+#[cfg(not(test))]
+use ic_cdk::api::set_certified_data;
+use ic_cdk::api::{caller, data_certificate};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+// Balances and order statuses are certified in an IC hash tree whose root hash
+// is the canister's certified data:
+//
+//   "balances" / bucket / principal / currency -> available ++ locked
+//   "orders"   / bucket / order id             -> status
+//
+// principal is the raw principal bytes, order id 8 bytes big-endian,
+// available and locked 16 byte big-endian amounts and status the name of the
+// SwapStatus variant, e.g. "PartiallyFilled". bucket is 2 bytes: the first two
+// bytes of the SHA-256 of the label below it, big-endian, shifted right by
+// 16 - BUCKET_BITS. Buckets keep the part of the tree rehashed on each write
+// small. Labels are sorted at every level, so a witness proves an account or
+// order absent as well as present.
+
+const BUCKET_BITS: u32 = 10;
+
+type Hash = [u8; 32];
+
+// The IC hash tree, hashed and CBOR encoded as the interface spec describes
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+impl HashTree {
+    fn fork(left: HashTree, right: HashTree) -> Self {
+        HashTree::Fork(Box::new(left), Box::new(right))
+    }
+
+    fn labeled(label: &[u8], subtree: HashTree) -> Self {
+        HashTree::Labeled(label.to_vec(), Box::new(subtree))
+    }
+
+    fn reconstruct(&self) -> Hash {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => {
+                domain_hash("ic-hashtree-fork", &[&left.reconstruct(), &right.reconstruct()])
+            }
+            HashTree::Labeled(label, subtree) => labeled_hash(label, &subtree.reconstruct()),
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value]),
+            HashTree::Pruned(hash) => *hash,
+        }
+    }
+
+    // CBOR with the self-describe tag, the form agents expect a witness in
+    fn to_cbor(&self) -> Vec<u8> {
+        let mut out = vec![0xd9, 0xd9, 0xf7];
+        self.encode(&mut out);
+        out
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => out.extend_from_slice(&[0x81, 0]),
+            HashTree::Fork(left, right) => {
+                out.extend_from_slice(&[0x83, 1]);
+                left.encode(out);
+                right.encode(out);
+            }
+            HashTree::Labeled(label, subtree) => {
+                out.extend_from_slice(&[0x83, 2]);
+                encode_bytes(out, label);
+                subtree.encode(out);
+            }
+            HashTree::Leaf(value) => {
+                out.extend_from_slice(&[0x82, 3]);
+                encode_bytes(out, value);
+            }
+            HashTree::Pruned(hash) => {
+                out.extend_from_slice(&[0x82, 4]);
+                encode_bytes(out, hash);
+            }
+        }
+    }
+}
+
+// CBOR byte string header and contents
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    const BYTE_STRING: u8 = 2 << 5;
+    match bytes.len() {
+        len @ 0..=23 => out.push(BYTE_STRING | len as u8),
+        len @ 24..=0xff => out.extend_from_slice(&[BYTE_STRING | 24, len as u8]),
+        len @ 0x100..=0xffff => {
+            out.push(BYTE_STRING | 25);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(BYTE_STRING | 26);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(bytes);
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn labeled_hash(label: &[u8], subtree: &Hash) -> Hash {
+    domain_hash("ic-hashtree-labeled", &[label, subtree])
+}
+
+// Hash of the balanced fork tree over labeled subtrees sorted by label, split
+// in the middle at each level like build_witness does
+fn fork_hash(entries: &[(Vec<u8>, Hash)]) -> Hash {
+    match entries {
+        [] => HashTree::Empty.reconstruct(),
+        [(label, subtree)] => labeled_hash(label, subtree),
+        _ => {
+            let (left, right) = entries.split_at(entries.len() / 2);
+            domain_hash("ic-hashtree-fork", &[&fork_hash(left), &fork_hash(right)])
+        }
+    }
+}
+
+// Fork tree over `entries` that reveals `label` with `revealed` under it when
+// present, or the labels either side of where it would sort when absent.
+// Everything else is pruned.
+fn witness(entries: &[(Vec<u8>, Hash)], label: &[u8], revealed: Option<HashTree>) -> HashTree {
+    let position = entries.partition_point(|(entry_label, _)| entry_label.as_slice() < label);
+    let found = entries.get(position).is_some_and(|(entry_label, _)| entry_label == label);
+    let shown = if found {
+        position..position + 1
+    } else {
+        position.saturating_sub(1)..position + 1
+    };
+    let mut revealed = revealed.filter(|_| found);
+    build_witness(entries, 0, &shown, &mut revealed)
+}
+
+fn build_witness(
+    entries: &[(Vec<u8>, Hash)],
+    offset: usize,
+    shown: &Range<usize>,
+    revealed: &mut Option<HashTree>,
+) -> HashTree {
+    if entries.is_empty() {
+        return HashTree::Empty;
+    }
+    if offset + entries.len() <= shown.start || offset >= shown.end {
+        return HashTree::Pruned(fork_hash(entries));
+    }
+    if let [(label, subtree)] = entries {
+        let subtree = revealed.take().unwrap_or(HashTree::Pruned(*subtree));
+        return HashTree::labeled(label, subtree);
+    }
+    let (left, right) = entries.split_at(entries.len() / 2);
+    HashTree::fork(
+        build_witness(left, offset, shown, revealed),
+        build_witness(right, offset + left.len(), shown, revealed),
+    )
+}
+
+fn bucket_of(label: &[u8]) -> u16 {
+    let digest = Sha256::digest(label);
+    u16::from_be_bytes([digest[0], digest[1]]) >> (16 - BUCKET_BITS)
+}
+
+// One "balances" or "orders" subtree: labels grouped into buckets, each
+// bucket's hash cached so a write only rehashes its own bucket
+#[derive(Default)]
+struct CertifiedMap {
+    entries: BTreeMap<(u16, Vec<u8>), Hash>, // (bucket, label) -> hash of the subtree under the label
+    buckets: BTreeMap<u16, Hash>,            // bucket -> hash of its fork tree, non-empty buckets only
+}
+
+impl CertifiedMap {
+    fn from_entries(entries: impl Iterator<Item = (Vec<u8>, Hash)>) -> Self {
+        let mut map = CertifiedMap {
+            entries: entries.map(|(label, subtree)| ((bucket_of(&label), label), subtree)).collect(),
+            buckets: BTreeMap::new(),
+        };
+        let buckets: BTreeSet<u16> = map.entries.keys().map(|(bucket, _)| *bucket).collect();
+        for bucket in buckets {
+            map.rehash_bucket(bucket);
+        }
+        map
+    }
+
+    fn insert(&mut self, label: Vec<u8>, subtree: Hash) {
+        let bucket = bucket_of(&label);
+        self.entries.insert((bucket, label), subtree);
+        self.rehash_bucket(bucket);
+    }
+
+    fn remove(&mut self, label: Vec<u8>) {
+        let bucket = bucket_of(&label);
+        if self.entries.remove(&(bucket, label)).is_some() {
+            self.rehash_bucket(bucket);
+        }
+    }
+
+    fn rehash_bucket(&mut self, bucket: u16) {
+        let entries = self.bucket_entries(bucket);
+        if entries.is_empty() {
+            self.buckets.remove(&bucket);
+        } else {
+            self.buckets.insert(bucket, fork_hash(&entries));
+        }
+    }
+
+    fn bucket_entries(&self, bucket: u16) -> Vec<(Vec<u8>, Hash)> {
+        self.entries
+            .range((bucket, Vec::new())..)
+            .take_while(|((entry_bucket, _), _)| *entry_bucket == bucket)
+            .map(|((_, label), subtree)| (label.clone(), *subtree))
+            .collect()
+    }
+
+    fn bucket_labels(&self) -> Vec<(Vec<u8>, Hash)> {
+        self.buckets.iter().map(|(bucket, hash)| (bucket.to_be_bytes().to_vec(), *hash)).collect()
+    }
+
+    fn root_hash(&self) -> Hash {
+        fork_hash(&self.bucket_labels())
+    }
+
+    fn witness(&self, label: &[u8], revealed: Option<HashTree>) -> HashTree {
+        let bucket = bucket_of(label);
+        let bucket_witness = witness(&self.bucket_entries(bucket), label, revealed);
+        witness(&self.bucket_labels(), &bucket.to_be_bytes(), Some(bucket_witness))
+    }
+}
+
+thread_local! {
+    // Heap only, rebuilt from the stable maps after every upgrade
+    static CERTIFIED_BALANCES: RefCell<CertifiedMap> = RefCell::new(CertifiedMap::default());
+    static CERTIFIED_ORDERS: RefCell<CertifiedMap> = RefCell::new(CertifiedMap::default());
+}
+
+// The caller's balances, as get_user_balance(None) returns them
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedBalances {
+    balances: Vec<CurrencyBalance>,
+    // System certificate, CBOR; its certified_data is the witness's root hash
+    certificate: Vec<u8>,
+    // CBOR hash tree revealing ["balances", bucket, caller] with every
+    // currency under it, or proving the caller has no account
+    witness: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedOrder {
+    order: Option<SwapOrder>,
+    // System certificate, CBOR; its certified_data is the witness's root hash
+    certificate: Vec<u8>,
+    // CBOR hash tree revealing ["orders", bucket, order id] -> status, or
+    // proving no such order exists
+    witness: Vec<u8>,
+}
+
+#[ic_cdk::query]
+fn get_user_balance_certified() -> Result<CertifiedBalances, Error> {
+    // Only query calls made directly by an agent carry a certificate
+    let certificate = data_certificate().ok_or(Error::CertificateUnavailable)?;
+    let principal = StorablePrincipal::from(caller());
+    let revealed = USER_ACCOUNTS
+        .with(|accounts| accounts.borrow().get(&principal))
+        .map(|account| account_tree(&account));
+    let balances = CERTIFIED_BALANCES.with(|map| map.borrow().witness(principal.0.as_slice(), revealed));
+    let orders = CERTIFIED_ORDERS.with(|map| map.borrow().root_hash());
+
+    Ok(CertifiedBalances {
+        balances: get_user_balance(None),
+        certificate,
+        witness: root_tree(balances, HashTree::Pruned(orders)).to_cbor(),
+    })
+}
+
+#[ic_cdk::query]
+fn get_swap_order_certified(order_id: u64) -> Result<CertifiedOrder, Error> {
+    let certificate = data_certificate().ok_or(Error::CertificateUnavailable)?;
+    let order = find_order(order_id);
+    let revealed = order.as_ref().map(status_leaf);
+    let order = order.map(|order| order.viewed_by(&caller()));
+    let balances = CERTIFIED_BALANCES.with(|map| map.borrow().root_hash());
+    let orders = CERTIFIED_ORDERS.with(|map| map.borrow().witness(&order_id.to_be_bytes(), revealed));
+
+    Ok(CertifiedOrder {
+        order,
+        certificate,
+        witness: root_tree(HashTree::Pruned(balances), orders).to_cbor(),
+    })
+}
+
+// Called whenever accounts are written back, with the new contents
+pub(crate) fn certify_accounts<'a>(accounts: impl IntoIterator<Item = (&'a StorablePrincipal, &'a UserAccount)>) {
+    CERTIFIED_BALANCES.with(|map| {
+        let mut map = map.borrow_mut();
+        for (principal, user_account) in accounts {
+            map.insert(principal.0.as_slice().to_vec(), account_tree(user_account).reconstruct());
+        }
+    });
+    publish();
+}
+
+// Called by store_order when an order is placed or changes status
+pub(crate) fn certify_order(swap_order: &SwapOrder) {
+    CERTIFIED_ORDERS.with(|map| {
+        map.borrow_mut().insert(swap_order.id.to_be_bytes().to_vec(), status_leaf(swap_order).reconstruct())
+    });
+    publish();
+}
+
+pub(crate) fn forget_orders(order_ids: impl IntoIterator<Item = u64>) {
+    CERTIFIED_ORDERS.with(|map| {
+        let mut map = map.borrow_mut();
+        for order_id in order_ids {
+            map.remove(order_id.to_be_bytes().to_vec());
+        }
+    });
+    publish();
+}
+
+// Recomputes both subtrees from every account and every live and archived
+// order, for upgrades and imports that write the maps directly
+pub(crate) fn rebuild_certified_state() {
+    let balances = USER_ACCOUNTS.with(|accounts| {
+        CertifiedMap::from_entries(accounts.borrow().iter().map(|(principal, user_account)| {
+            (principal.0.as_slice().to_vec(), account_tree(&user_account).reconstruct())
+        }))
+    });
+    let order_entries = |(order_id, swap_order): (u64, SwapOrder)| {
+        (order_id.to_be_bytes().to_vec(), status_leaf(&swap_order).reconstruct())
+    };
+    let mut order_labels: Vec<(Vec<u8>, Hash)> =
+        SWAP_ORDERS.with(|orders| orders.borrow().iter().map(order_entries).collect());
+    let archived: Vec<(Vec<u8>, Hash)> =
+        ARCHIVED_ORDERS.with(|archive| archive.borrow().iter().map(order_entries).collect());
+    order_labels.extend(archived);
+
+    CERTIFIED_BALANCES.with(|map| *map.borrow_mut() = balances);
+    CERTIFIED_ORDERS.with(|map| *map.borrow_mut() = CertifiedMap::from_entries(order_labels.into_iter()));
+    publish();
+}
+
+// The root hash published as certified data. It changes with any balance or
+// order status.
+pub(crate) fn certified_root() -> [u8; 32] {
+    let balances = CERTIFIED_BALANCES.with(|map| map.borrow().root_hash());
+    let orders = CERTIFIED_ORDERS.with(|map| map.borrow().root_hash());
+    root_tree(HashTree::Pruned(balances), HashTree::Pruned(orders)).reconstruct()
+}
+
+fn publish() {
+    set_certified_data(&certified_root());
+}
+
+fn root_tree(balances: HashTree, orders: HashTree) -> HashTree {
+    HashTree::fork(HashTree::labeled(b"balances", balances), HashTree::labeled(b"orders", orders))
+}
+
+// Every currency the account holds or has locked, sorted by code
+fn account_tree(user_account: &UserAccount) -> HashTree {
+    let mut currencies: BTreeSet<&String> = user_account.balances.keys().collect();
+    if let Some(locked) = &user_account.locked {
+        currencies.extend(locked.keys());
+    }
+    let leaves: Vec<(Vec<u8>, HashTree)> = currencies
+        .into_iter()
+        .map(|currency| {
+            let mut value = user_account.balance(currency).to_be_bytes().to_vec();
+            value.extend_from_slice(&user_account.locked(currency).to_be_bytes());
+            (currency.as_bytes().to_vec(), HashTree::Leaf(value))
+        })
+        .collect();
+    full_tree(leaves)
+}
+
+// Fork tree revealing every leaf, shaped like fork_hash
+fn full_tree(mut leaves: Vec<(Vec<u8>, HashTree)>) -> HashTree {
+    match leaves.len() {
+        0 => HashTree::Empty,
+        1 => {
+            let (label, leaf) = leaves.remove(0);
+            HashTree::Labeled(label, Box::new(leaf))
+        }
+        len => {
+            let right = leaves.split_off(len / 2);
+            HashTree::fork(full_tree(leaves), full_tree(right))
+        }
+    }
+}
+
+fn status_leaf(swap_order: &SwapOrder) -> HashTree {
+    HashTree::Leaf(format!("{:?}", swap_order.status).into_bytes())
+}
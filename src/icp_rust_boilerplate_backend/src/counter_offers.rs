@@ -0,0 +1,475 @@
+use crate::snapshot::{clear_map, entries_after};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{
+    add_to_bucket, admin, allowlist, blacklist, cancel_open_order, currencies, expire_open_order, pairs, rate_limit,
+    require_available, settle_fill_at, store_order, BalanceChanges, CancelReason, Error, ExecutionReceipt, Memory,
+    StorablePrincipal, MEMORY_MANAGER, SWAP_ORDERS,
+};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::{caller, time};
+#[cfg(not(test))]
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub(crate) const COUNTER_OFFER_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Offers expired per sweep tick
+const COUNTER_OFFER_SWEEP_BATCH_SIZE: usize = 200;
+
+// How long an offer waits for the maker before its escrow is released
+const COUNTER_OFFER_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Open offers allowed on one order at a time
+const MAX_OPEN_OFFERS_PER_ORDER: u64 = 10;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum CounterOfferStatus {
+    Open,
+    Accepted,
+    Rejected,    // by the maker
+    Withdrawn,   // by the proposer
+    Expired,     // nobody acted on it within COUNTER_OFFER_TTL_NANOS
+    OrderClosed, // the order executed, was cancelled or expired first
+}
+
+// A bid for an order's visible remainder at a price of the proposer's
+// choosing. The proposer's to_amount stays in escrow while the offer is open.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CounterOffer {
+    id: u64,
+    order_id: u64,
+    proposer: Principal,
+    from_currency: String,
+    to_currency: String,
+    from_amount: u128, // of the order's from_currency the proposer would receive
+    to_amount: u128,   // the proposer pays, instead of what the order asks
+    status: CounterOfferStatus,
+    created_at: u64,
+    expires_at: u64,
+    closed_at: Option<u64>,
+}
+
+impl Storable for CounterOffer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode CounterOffer"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode CounterOffer")
+    }
+}
+
+impl BoundedStorable for CounterOffer {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Every offer ever made, keyed by id starting at 1
+    static COUNTER_OFFERS: RefCell<StableBTreeMap<u64, CounterOffer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    // (order id, offer id) for open offers only
+    static OPEN_OFFERS_BY_ORDER: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    // (expires_at, offer id) for open offers only, walked by the sweep
+    static OPEN_OFFERS_BY_EXPIRY: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+}
+
+// Offers to take the order's visible remainder for `new_to_amount` instead of
+// what the order asks, and escrows that amount until the maker accepts or
+// rejects the offer, the proposer withdraws it or it expires. Nothing tells
+// the maker; they poll list_counter_offers.
+#[ic_cdk::update]
+fn propose_counter_offer(order_id: u64, new_to_amount: u128) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+    let proposer = caller();
+    let now = time();
+
+    if !swap_order.is_open() {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status });
+    }
+    if swap_order.is_expired(now) {
+        return Err(Error::OrderExpired);
+    }
+    if swap_order.owner == proposer {
+        return Err(Error::OwnerCannotExecute);
+    }
+    if !swap_order.can_be_filled_by(&proposer) {
+        return Err(Error::Unauthorized);
+    }
+    if new_to_amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    let from_amount = swap_order.visible_remaining();
+    currencies::check_amount_precision(&swap_order.to_currency, new_to_amount)?;
+    pairs::pair_config(&swap_order.from_currency, &swap_order.to_currency).check_order(
+        from_amount,
+        new_to_amount,
+        None,
+    )?;
+    if open_offer_count(order_id) >= MAX_OPEN_OFFERS_PER_ORDER {
+        return Err(Error::TooManyCounterOffers { limit: MAX_OPEN_OFFERS_PER_ORDER });
+    }
+
+    let proposer_principal = StorablePrincipal::from(proposer);
+    require_available(&proposer_principal, &swap_order.to_currency, new_to_amount)?;
+    let mut changes = BalanceChanges::new();
+    changes.lock(&proposer_principal, &swap_order.to_currency, new_to_amount)?;
+    changes.commit();
+    record_transaction(
+        TransactionKind::Escrow,
+        Some(proposer),
+        None,
+        &swap_order.to_currency,
+        new_to_amount,
+        Some(order_id),
+    );
+
+    let id = COUNTER_OFFERS.with(|offers| offers.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let offer = CounterOffer {
+        id,
+        order_id,
+        proposer,
+        from_currency: swap_order.from_currency,
+        to_currency: swap_order.to_currency,
+        from_amount,
+        to_amount: new_to_amount,
+        status: CounterOfferStatus::Open,
+        created_at: now,
+        expires_at: now.saturating_add(COUNTER_OFFER_TTL_NANOS),
+        closed_at: None,
+    };
+    OPEN_OFFERS_BY_ORDER.with(|index| index.borrow_mut().insert((order_id, id), ()));
+    OPEN_OFFERS_BY_EXPIRY.with(|index| index.borrow_mut().insert((offer.expires_at, id), ()));
+    COUNTER_OFFERS.with(|offers| offers.borrow_mut().insert(id, offer));
+
+    Ok(id)
+}
+
+// The open offers on an order, oldest first. The maker sees all of them,
+// anyone else only their own.
+#[ic_cdk::query]
+fn list_counter_offers(order_id: u64) -> Result<Vec<CounterOffer>, Error> {
+    let swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+    let viewer = caller();
+    Ok(open_offers(order_id)
+        .into_iter()
+        .filter(|offer| viewer == swap_order.owner || viewer == offer.proposer)
+        .collect())
+}
+
+// Settles the offer against the maker's order at the offer's terms, then
+// cancels whatever of the order is left, hidden iceberg size included
+#[ic_cdk::update]
+fn accept_counter_offer(offer_id: u64) -> Result<ExecutionReceipt, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let offer = find_open_offer(offer_id)?;
+    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&offer.order_id))
+        .ok_or(Error::InvalidOrderId)?;
+    let now = time();
+
+    if swap_order.owner != caller() {
+        return Err(Error::Unauthorized);
+    }
+    if !swap_order.is_open() {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status });
+    }
+    // Left to expire, which releases the proposer's escrow
+    if blacklist::is_blacklisted(offer.proposer) {
+        return Err(Error::Blacklisted);
+    }
+    if !allowlist::is_allowed(offer.proposer) {
+        return Err(Error::NotAllowlisted);
+    }
+    // Expiring the order closes its offers too
+    if swap_order.is_expired(now) {
+        let _ = expire_open_order(swap_order);
+        return Err(Error::OrderExpired);
+    }
+    if offer.expires_at <= now {
+        close_offer(offer, CounterOfferStatus::Expired);
+        return Err(Error::CounterOfferNotOpen);
+    }
+    // Fills or a shrink since the offer was made left less than it asks for
+    if swap_order.remaining() < offer.from_amount {
+        return Err(Error::CounterOfferStale);
+    }
+
+    let proposer_principal = StorablePrincipal::from(offer.proposer);
+    let mut changes = BalanceChanges::new();
+    changes.unlock(&proposer_principal, &offer.to_currency, offer.to_amount)?;
+    changes.commit();
+
+    // Both sides were in escrow, so a failure here is a broken invariant.
+    // Trapping rolls the unlock back with it.
+    let receipt = settle_fill_at(
+        proposer_principal,
+        StorablePrincipal::from(swap_order.owner),
+        &swap_order,
+        offer.from_amount,
+        offer.to_amount,
+    )
+    .expect("Escrowed counter-offer failed to settle");
+    let proposer = offer.proposer;
+    finish_offer(offer, CounterOfferStatus::Accepted);
+
+    swap_order.record_fill(proposer, receipt.received_amount, receipt.fee);
+    let rest = (swap_order.remaining() > 0).then(|| swap_order.clone());
+    store_order(swap_order);
+    // A refund that would overflow leaves the rest open, as in the expiry sweep.
+    // The owner cancels it by accepting.
+    if let Some(rest) = rest {
+        let owner = rest.owner;
+        let _ = cancel_open_order(rest, CancelReason::CounterOfferSettled, Some(owner));
+    }
+
+    Ok(receipt)
+}
+
+#[ic_cdk::update]
+fn reject_counter_offer(offer_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let offer = find_open_offer(offer_id)?;
+    let owner = SWAP_ORDERS.with(|orders| orders.borrow().get(&offer.order_id)).map(|order| order.owner);
+    if owner != Some(caller()) {
+        return Err(Error::Unauthorized);
+    }
+    close_offer(offer, CounterOfferStatus::Rejected);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn withdraw_counter_offer(offer_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let offer = find_open_offer(offer_id)?;
+    if offer.proposer != caller() {
+        return Err(Error::Unauthorized);
+    }
+    close_offer(offer, CounterOfferStatus::Withdrawn);
+    Ok(())
+}
+
+// Called by store_order once an order stops holding escrow
+pub(crate) fn close_offers_for_order(order_id: u64) {
+    for offer in open_offers(order_id) {
+        close_offer(offer, CounterOfferStatus::OrderClosed);
+    }
+}
+
+// Releases the escrow of offers past their expiry, earliest first
+pub(crate) fn expire_counter_offers() {
+    let now = time();
+    let due: Vec<u64> = OPEN_OFFERS_BY_EXPIRY.with(|index| {
+        index
+            .borrow()
+            .range(..(now, u64::MAX))
+            .take(COUNTER_OFFER_SWEEP_BATCH_SIZE)
+            .map(|((_, offer_id), _)| offer_id)
+            .collect()
+    });
+    for offer_id in due {
+        if let Ok(offer) = find_open_offer(offer_id) {
+            close_offer(offer, CounterOfferStatus::Expired);
+        }
+    }
+}
+
+// Adds what open offers hold in escrow, for rebuilding locked balances
+pub(crate) fn add_escrow(locked_by_owner: &mut BTreeMap<StorablePrincipal, BTreeMap<String, u128>>) {
+    OPEN_OFFERS_BY_ORDER.with(|index| {
+        for ((_, offer_id), _) in index.borrow().iter() {
+            let Some(offer) = COUNTER_OFFERS.with(|offers| offers.borrow().get(&offer_id)) else {
+                continue;
+            };
+            let locked = locked_by_owner.entry(StorablePrincipal::from(offer.proposer)).or_default();
+            add_to_bucket(locked, &offer.to_currency, offer.to_amount)
+                .expect("Escrowed amounts overflowed while rebuilding locked balances");
+        }
+    });
+}
+
+// Up to `limit` offers after the id `after`, for create_export
+pub(crate) fn export_offers(after: Option<u64>, limit: usize) -> Vec<(u64, CounterOffer)> {
+    COUNTER_OFFERS.with(|offers| entries_after(&offers.borrow(), after, limit))
+}
+
+// Replaces every offer with those of an imported snapshot, listing the open
+// ones again
+pub(crate) fn restore_offers(restored: Vec<CounterOffer>) {
+    OPEN_OFFERS_BY_ORDER.with(|index| clear_map(&mut index.borrow_mut()));
+    OPEN_OFFERS_BY_EXPIRY.with(|index| clear_map(&mut index.borrow_mut()));
+    COUNTER_OFFERS.with(|offers| {
+        let mut offers_borrowed = offers.borrow_mut();
+        clear_map(&mut offers_borrowed);
+        for offer in restored {
+            if offer.status == CounterOfferStatus::Open {
+                OPEN_OFFERS_BY_ORDER.with(|index| index.borrow_mut().insert((offer.order_id, offer.id), ()));
+                OPEN_OFFERS_BY_EXPIRY.with(|index| index.borrow_mut().insert((offer.expires_at, offer.id), ()));
+            }
+            offers_borrowed.insert(offer.id, offer);
+        }
+    });
+}
+
+// Called by claim_account, which moves the proposer's escrow along with the
+// rest of their balances
+pub(crate) fn reassign_proposer(from: Principal, to: Principal) {
+    let offers: Vec<CounterOffer> = OPEN_OFFERS_BY_ORDER.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter_map(|((_, offer_id), _)| COUNTER_OFFERS.with(|offers| offers.borrow().get(&offer_id)))
+            .filter(|offer| offer.proposer == from)
+            .collect()
+    });
+    for mut offer in offers {
+        offer.proposer = to;
+        COUNTER_OFFERS.with(|stored| stored.borrow_mut().insert(offer.id, offer));
+    }
+}
+
+fn open_offer_count(order_id: u64) -> u64 {
+    OPEN_OFFERS_BY_ORDER.with(|index| index.borrow().range((order_id, 0)..=(order_id, u64::MAX)).count() as u64)
+}
+
+fn open_offers(order_id: u64) -> Vec<CounterOffer> {
+    let offer_ids: Vec<u64> = OPEN_OFFERS_BY_ORDER.with(|index| {
+        index
+            .borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|((_, offer_id), _)| offer_id)
+            .collect()
+    });
+    offer_ids
+        .into_iter()
+        .filter_map(|offer_id| COUNTER_OFFERS.with(|offers| offers.borrow().get(&offer_id)))
+        .collect()
+}
+
+fn find_open_offer(offer_id: u64) -> Result<CounterOffer, Error> {
+    let offer = COUNTER_OFFERS.with(|offers| offers.borrow().get(&offer_id))
+        .ok_or(Error::CounterOfferNotFound)?;
+    if offer.status != CounterOfferStatus::Open {
+        return Err(Error::CounterOfferNotOpen);
+    }
+    Ok(offer)
+}
+
+// Returns the proposer's escrow and closes the offer
+fn close_offer(offer: CounterOffer, status: CounterOfferStatus) {
+    let mut changes = BalanceChanges::new();
+    changes
+        .unlock(&StorablePrincipal::from(offer.proposer), &offer.to_currency, offer.to_amount)
+        .expect("Counter-offer escrow was not locked");
+    changes.commit();
+    record_transaction(
+        TransactionKind::Refund,
+        None,
+        Some(offer.proposer),
+        &offer.to_currency,
+        offer.to_amount,
+        Some(offer.order_id),
+    );
+    finish_offer(offer, status);
+}
+
+// Takes the offer out of the open indexes and stores its final status
+fn finish_offer(mut offer: CounterOffer, status: CounterOfferStatus) {
+    OPEN_OFFERS_BY_ORDER.with(|index| index.borrow_mut().remove(&(offer.order_id, offer.id)));
+    OPEN_OFFERS_BY_EXPIRY.with(|index| index.borrow_mut().remove(&(offer.expires_at, offer.id)));
+    offer.status = status;
+    offer.closed_at = Some(time());
+    COUNTER_OFFERS.with(|offers| offers.borrow_mut().insert(offer.id, offer));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events, SwapOrder, UserAccount, USER_ACCOUNTS};
+
+    fn store_locked(principal: &StorablePrincipal, currency: &str, amount: u128) {
+        let user_account = UserAccount {
+            locked: Some([(currency.to_string(), amount)].into_iter().collect()),
+            ..UserAccount::default()
+        };
+        USER_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(principal.clone(), user_account));
+    }
+
+    #[test]
+    fn the_rest_left_by_an_accepted_offer_is_cancelled_by_the_owner() {
+        let owner = StorablePrincipal::from(Principal::from_slice(&[1]));
+        let proposer = StorablePrincipal::from(Principal::from_slice(&[2]));
+        currencies::tests::register("EUR");
+        currencies::tests::register("USD");
+        store_locked(&owner, "EUR", 100);
+        store_locked(&proposer, "USD", 40);
+        let swap_order = SwapOrder {
+            id: 1,
+            owner: owner.0,
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: 100,
+            to_amount: 100,
+            ..SwapOrder::default()
+        };
+        SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(1, swap_order));
+        let offer = CounterOffer {
+            id: 1,
+            order_id: 1,
+            proposer: proposer.0,
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: 50,
+            to_amount: 40,
+            status: CounterOfferStatus::Open,
+            created_at: 0,
+            expires_at: 10_000,
+            closed_at: None,
+        };
+        COUNTER_OFFERS.with(|offers| offers.borrow_mut().insert(1, offer));
+        admin::tests::set_time(1_000);
+        admin::tests::set_caller(owner.0);
+        rate_limit::tests::exempt(owner.0);
+
+        assert!(accept_counter_offer(1).is_ok());
+        let rest = SWAP_ORDERS.with(|orders| orders.borrow().get(&1)).unwrap();
+        assert_eq!(rest.cancel_reason, Some(CancelReason::CounterOfferSettled));
+        assert_eq!(events::tests::cancel_initiators(1), vec![Some(owner.0)]);
+    }
+
+    #[test]
+    fn anonymous_counter_offers_are_rejected() {
+        assert_eq!(propose_counter_offer(1, 100), Err(Error::AnonymousNotAllowed));
+        assert_eq!(accept_counter_offer(1).err(), Some(Error::AnonymousNotAllowed));
+        assert_eq!(reject_counter_offer(1), Err(Error::AnonymousNotAllowed));
+        assert_eq!(withdraw_counter_offer(1), Err(Error::AnonymousNotAllowed));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+lazy_static! {
+    // Letters and digits only, long enough for ledger symbols like "CKBTC".
+    // Matched after normalization, so only upper case needs to be accepted.
+    static ref SYMBOL_REGEX: Regex = Regex::new(r"^[A-Z0-9]{2,10}$").unwrap();
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct CurrencySymbol(pub(crate) String);
+
+impl Storable for CurrencySymbol {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        CurrencySymbol(String::from_utf8(bytes.into_owned()).expect("Failed to decode CurrencySymbol"))
+    }
+}
+
+impl BoundedStorable for CurrencySymbol {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct CurrencyInfo {
+    symbol: String,
+    display_name: String,
+    decimals: u8,  // digits after the point the smallest unit stands for, 2 for cents
+    enabled: bool, // disabled currencies can't be deposited or traded, open orders can still be cancelled
+    // Digits order amounts may use, at most `decimals`; None allows every
+    // smallest unit. With decimals 8 and order_decimals 4 amounts go in steps
+    // of 10^4.
+    order_decimals: Option<u8>,
+}
+
+impl CurrencyInfo {
+    // Smallest unit order amounts have to be a multiple of
+    fn amount_tick(&self) -> u128 {
+        let unused_digits = self.decimals - self.order_decimals.unwrap_or(self.decimals);
+        10u128.pow(unused_digits as u32)
+    }
+}
+
+impl Storable for CurrencyInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode CurrencyInfo"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode CurrencyInfo")
+    }
+}
+
+impl BoundedStorable for CurrencyInfo {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CURRENCIES: RefCell<StableBTreeMap<CurrencySymbol, CurrencyInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AddCurrencyArgs {
+    symbol: String,
+    display_name: String,
+    decimals: u8,
+    order_decimals: Option<u8>,
+}
+
+// A u128 holds 38 full decimal digits
+const MAX_DECIMALS: u8 = 38;
+
+// Registers a currency, or updates and re-enables one that already exists
+#[ic_cdk::update]
+fn add_currency(mut args: AddCurrencyArgs) -> Result<(), Error> {
+    require_admin()?;
+    args.symbol = normalize_currency(&args.symbol);
+    if !is_well_formed_currency(&args.symbol) {
+        return Err(Error::InvalidCurrency { provided: args.symbol });
+    }
+    if args.display_name.is_empty() || args.display_name.len() > 64 {
+        return Err(Error::InvalidCurrency { provided: args.display_name });
+    }
+    if args.decimals > MAX_DECIMALS || args.order_decimals.is_some_and(|digits| digits > args.decimals) {
+        return Err(Error::InvalidDecimals);
+    }
+
+    let currency = CurrencyInfo {
+        symbol: args.symbol.clone(),
+        display_name: args.display_name,
+        decimals: args.decimals,
+        enabled: true,
+        order_decimals: args.order_decimals,
+    };
+    CURRENCIES.with(|currencies| currencies.borrow_mut().insert(CurrencySymbol(args.symbol), currency));
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn disable_currency(symbol: String) -> Result<(), Error> {
+    require_admin()?;
+
+    CURRENCIES.with(|currencies| {
+        let mut currencies_borrowed = currencies.borrow_mut();
+        let key = CurrencySymbol(normalize_currency(&symbol));
+        let mut currency = currencies_borrowed
+            .get(&key)
+            .ok_or_else(|| Error::InvalidCurrency { provided: key.0.clone() })?;
+        currency.enabled = false;
+        currencies_borrowed.insert(key, currency);
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_currency_metadata(symbol: String) -> Result<CurrencyInfo, Error> {
+    lookup_currency(&symbol).ok_or(Error::InvalidCurrency { provided: normalize_currency(&symbol) })
+}
+
+// Smallest units as a decimal string in the currency's precision, e.g.
+// 12345 USD is "123.45"
+#[ic_cdk::query]
+fn format_amount(symbol: String, amount: u128) -> Result<String, Error> {
+    let currency = lookup_currency(&symbol).ok_or(Error::InvalidCurrency { provided: normalize_currency(&symbol) })?;
+    Ok(to_display(amount, currency.decimals))
+}
+
+// The inverse of format_amount, rejecting more digits than the currency has
+#[ic_cdk::query]
+fn parse_amount(symbol: String, display: String) -> Result<u128, Error> {
+    let currency = lookup_currency(&symbol).ok_or(Error::InvalidCurrency { provided: normalize_currency(&symbol) })?;
+    from_display(&display, currency.decimals)
+}
+
+#[ic_cdk::query]
+fn list_currencies() -> Vec<CurrencyInfo> {
+    CURRENCIES.with(|currencies| currencies.borrow().iter().map(|(_, currency)| currency).collect())
+}
+
+fn lookup_currency(symbol: &str) -> Option<CurrencyInfo> {
+    if symbol.len() > CurrencySymbol::MAX_SIZE as usize {
+        return None;
+    }
+    CURRENCIES.with(|currencies| currencies.borrow().get(&CurrencySymbol(normalize_currency(symbol))))
+}
+
+// Symbols are compared case-insensitively, "usd" and "USD" are the same currency
+pub(crate) fn normalize_currency(symbol: &str) -> String {
+    symbol.trim().to_ascii_uppercase()
+}
+
+// Could be a registered symbol, checked without reading the registry
+pub(crate) fn is_well_formed_currency(symbol: &str) -> bool {
+    SYMBOL_REGEX.is_match(&normalize_currency(symbol))
+}
+
+// Registered and enabled, i.e. usable for deposits and new orders
+pub(crate) fn is_valid_currency(symbol: &str) -> bool {
+    lookup_currency(symbol).is_some_and(|currency| currency.enabled)
+}
+
+// Registered at all, disabled or not
+pub(crate) fn is_known_currency(symbol: &str) -> bool {
+    lookup_currency(symbol).is_some()
+}
+
+// Rejects order amounts finer than the currency's order precision
+pub(crate) fn check_amount_precision(symbol: &str, amount: u128) -> Result<(), Error> {
+    let Some(currency) = lookup_currency(symbol) else {
+        return Ok(());
+    };
+    let tick = currency.amount_tick();
+    if !amount.is_multiple_of(tick) {
+        return Err(Error::AmountNotOnTick { currency: currency.symbol, tick });
+    }
+    Ok(())
+}
+
+pub(crate) fn to_display(amount: u128, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    format!("{}.{}", whole, fraction)
+}
+
+// Accepts "123", "123.4" and "123.45" for two decimals, but not "123.456"
+pub(crate) fn from_display(display: &str, decimals: u8) -> Result<u128, Error> {
+    let (whole, fraction) = display.trim().split_once('.').unwrap_or((display.trim(), ""));
+    let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    if whole.is_empty() || !all_digits(whole) || !all_digits(fraction) || fraction.len() > decimals as usize {
+        return Err(Error::InvalidAmount);
+    }
+    let padded = format!("{}{:0<width$}", whole, fraction, width = decimals as usize);
+    padded.parse().map_err(|_| Error::Overflow)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn register(symbol: &str) {
+        let currency = CurrencyInfo {
+            symbol: symbol.to_string(),
+            display_name: symbol.to_string(),
+            decimals: 2,
+            enabled: true,
+            order_decimals: None,
+        };
+        CURRENCIES.with(|currencies| currencies.borrow_mut().insert(CurrencySymbol(symbol.to_string()), currency));
+    }
+
+    #[test]
+    fn normalization_upper_cases_and_trims() {
+        assert_eq!(normalize_currency("usd"), "USD");
+        assert_eq!(normalize_currency("ckBtc"), "CKBTC");
+        assert_eq!(normalize_currency(" Eur "), "EUR");
+    }
+
+    #[test]
+    fn mixed_case_symbols_are_well_formed() {
+        assert!(is_well_formed_currency("usd"));
+        assert!(is_well_formed_currency("ckBTC"));
+        assert!(!is_well_formed_currency("us-d"));
+        assert!(!is_well_formed_currency("u"));
+    }
+
+    #[test]
+    fn mixed_case_lookups_find_the_one_registered_currency() {
+        register("GBP");
+
+        assert!(is_valid_currency("gbp"));
+        assert!(is_valid_currency("Gbp"));
+        assert_eq!(lookup_currency("gBP").unwrap().symbol, "GBP");
+        assert!(CURRENCIES.with(|currencies| currencies.borrow().get(&CurrencySymbol("gbp".to_string()))).is_none());
+    }
+
+    #[test]
+    fn amounts_finer_than_the_order_precision_are_rejected() {
+        let currency = CurrencyInfo {
+            symbol: "BTC".to_string(),
+            display_name: "Bitcoin".to_string(),
+            decimals: 8,
+            enabled: true,
+            order_decimals: Some(4),
+        };
+        CURRENCIES.with(|currencies| currencies.borrow_mut().insert(CurrencySymbol("BTC".to_string()), currency));
+
+        assert_eq!(check_amount_precision("BTC", 120_000), Ok(()));
+        assert_eq!(
+            check_amount_precision("BTC", 120_001),
+            Err(Error::AmountNotOnTick { currency: "BTC".to_string(), tick: 10_000 })
+        );
+    }
+
+    #[test]
+    fn display_amounts_round_trip_through_the_smallest_unit() {
+        assert_eq!(to_display(12_345, 2), "123.45");
+        assert_eq!(to_display(5, 2), "0.05");
+        assert_eq!(to_display(7, 0), "7");
+        assert_eq!(from_display("123.45", 2), Ok(12_345));
+        assert_eq!(from_display("123.4", 2), Ok(12_340));
+        assert_eq!(from_display("123", 2), Ok(12_300));
+        assert_eq!(from_display("123.456", 2), Err(Error::InvalidAmount));
+        assert_eq!(from_display(".5", 2), Err(Error::InvalidAmount));
+    }
+}
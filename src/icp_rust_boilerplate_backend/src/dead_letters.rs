@@ -0,0 +1,353 @@
+use crate::admin::require_admin;
+use crate::archive::ARCHIVED_ORDERS;
+use crate::transactions::{self, Transaction};
+use crate::{
+    certification, limit_scan, order_limits, order_queries, rebuild_locked_balances, rebuild_order_book, solvency,
+    stats, Error, Memory, StorablePrincipal, SwapOrder, UserAccount, MEMORY_MANAGER, ORDERS_BY_OWNER, SWAP_ORDERS,
+    USER_ACCOUNTS,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Records decoded per scan_for_corrupt_records call
+const MAX_SCAN_BATCH_SIZE: usize = 500;
+
+// Upper bound on dead letters returned by a single page of list_dead_letters
+const MAX_DEAD_LETTERS_PAGE_SIZE: usize = 20;
+
+// Kept short enough for the dead letter to stay within its bound
+const MAX_DECODE_ERROR_BYTES: usize = 256;
+
+// Memories of the maps below, as in their thread_local! declarations
+const ACCOUNTS_MEMORY: u8 = 3;
+const ORDERS_MEMORY: u8 = 28;
+const ARCHIVED_ORDERS_MEMORY: u8 = 44;
+const TRANSACTIONS_MEMORY: u8 = 65;
+
+// The maps a corrupt record can be found and quarantined in
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum RecordMap {
+    Accounts,
+    Orders,
+    ArchivedOrders,
+    Transactions,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum RecordKey {
+    Principal(Principal), // Accounts
+    Id(u64),              // orders and transactions
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CorruptRecord {
+    key: RecordKey,
+    size: u64, // stored bytes
+    error: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CorruptRecordScan {
+    corrupt: Vec<CorruptRecord>,
+    next_start: Option<RecordKey>, // pass as start to scan the next batch, None once the map is done
+}
+
+// A corrupt record moved out of its map, kept as it was stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DeadLetter {
+    id: u64,
+    map: RecordMap,
+    key: RecordKey,
+    bytes: Vec<u8>,
+    error: String,
+    quarantined_by: Principal,
+    quarantined_at: u64,
+}
+
+impl Storable for DeadLetter {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode DeadLetter"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode DeadLetter")
+    }
+}
+
+impl BoundedStorable for DeadLetter {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DeadLettersPage {
+    dead_letters: Vec<DeadLetter>,
+    next_start_id: Option<u64>, // pass as start_id to fetch the next page, None once exhausted
+}
+
+// A value as stored, never decoded, so a map of these can read and remove
+// records its typed map would trap on
+struct RawRecord<const MAX_SIZE: u32>(Vec<u8>);
+
+impl<const MAX_SIZE: u32> Storable for RawRecord<MAX_SIZE> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawRecord(bytes.into_owned())
+    }
+}
+
+impl<const MAX_SIZE: u32> BoundedStorable for RawRecord<MAX_SIZE> {
+    const MAX_SIZE: u32 = MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+type RawMap<K, const MAX_SIZE: u32> = StableBTreeMap<K, RawRecord<MAX_SIZE>, Memory>;
+
+thread_local! {
+    // Append-only, keyed by id starting at 1
+    static DEAD_LETTERS: RefCell<StableBTreeMap<u64, DeadLetter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102)))
+    ));
+}
+
+// Decodes up to `limit` records of `map` from `start` on, None for the first,
+// and reports those that fail to. Nothing traps on a bad record, so this is
+// how to find the keys for quarantine_corrupt_record.
+#[ic_cdk::query]
+fn scan_for_corrupt_records(map: RecordMap, start: Option<RecordKey>, limit: u16) -> Result<CorruptRecordScan, Error> {
+    require_admin()?;
+    let limit = (limit as usize).min(MAX_SCAN_BATCH_SIZE);
+    let scan = match (map, start) {
+        (RecordMap::Accounts, None) => scan_accounts(None, limit),
+        (RecordMap::Accounts, Some(RecordKey::Principal(start))) => {
+            scan_accounts(Some(StorablePrincipal::from(start)), limit)
+        }
+        (RecordMap::Orders, start) => {
+            scan_ids::<{ SwapOrder::MAX_SIZE }>(ORDERS_MEMORY, start_id(start)?, limit, order_error)
+        }
+        (RecordMap::ArchivedOrders, start) => {
+            scan_ids::<{ SwapOrder::MAX_SIZE }>(ARCHIVED_ORDERS_MEMORY, start_id(start)?, limit, order_error)
+        }
+        (RecordMap::Transactions, start) => {
+            scan_ids::<{ Transaction::MAX_SIZE }>(TRANSACTIONS_MEMORY, start_id(start)?, limit, transaction_error)
+        }
+        _ => return Err(Error::InvalidRecordKey),
+    };
+    Ok(scan)
+}
+
+// Moves a record that fails to decode out of its map into the dead letters,
+// where list_dead_letters shows its bytes, then rebuilds what was derived
+// from the map. An order's escrow goes with it, and a principal's balances
+// with its account; admin_adjust_balance can credit them back once the
+// bytes have been looked at.
+#[ic_cdk::update]
+fn quarantine_corrupt_record(map: RecordMap, key: RecordKey) -> Result<u64, Error> {
+    require_admin()?;
+    let (bytes, error) = match (map, &key) {
+        (RecordMap::Accounts, RecordKey::Principal(principal)) => {
+            let removed = remove_corrupt::<StorablePrincipal, { UserAccount::MAX_SIZE }>(
+                ACCOUNTS_MEMORY,
+                &StorablePrincipal::from(*principal),
+                account_error,
+            )?;
+            USER_ACCOUNTS.with(|accounts| *accounts.borrow_mut() = StableBTreeMap::init(memory(ACCOUNTS_MEMORY)));
+            rebuild_locked_balances();
+            solvency::rebuild_totals();
+            certification::rebuild_certified_state();
+            removed
+        }
+        (RecordMap::Orders | RecordMap::ArchivedOrders, RecordKey::Id(order_id)) => {
+            let memory_id = if map == RecordMap::Orders { ORDERS_MEMORY } else { ARCHIVED_ORDERS_MEMORY };
+            let removed = remove_corrupt::<u64, { SwapOrder::MAX_SIZE }>(memory_id, order_id, order_error)?;
+            if map == RecordMap::Orders {
+                SWAP_ORDERS.with(|orders| *orders.borrow_mut() = StableBTreeMap::init(memory(memory_id)));
+            } else {
+                ARCHIVED_ORDERS.with(|archive| *archive.borrow_mut() = StableBTreeMap::init(memory(memory_id)));
+            }
+            forget_order(*order_id);
+            removed
+        }
+        (RecordMap::Transactions, RecordKey::Id(transaction_id)) => {
+            let removed = remove_corrupt::<u64, { Transaction::MAX_SIZE }>(
+                TRANSACTIONS_MEMORY,
+                transaction_id,
+                transaction_error,
+            )?;
+            transactions::reload_transactions();
+            removed
+        }
+        _ => return Err(Error::InvalidRecordKey),
+    };
+
+    let id = DEAD_LETTERS.with(|letters| letters.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let mut error = error;
+    error.truncate(MAX_DECODE_ERROR_BYTES);
+    let dead_letter = DeadLetter {
+        id,
+        map,
+        key,
+        bytes,
+        error,
+        quarantined_by: caller(),
+        quarantined_at: time(),
+    };
+    DEAD_LETTERS.with(|letters| letters.borrow_mut().insert(id, dead_letter));
+    Ok(id)
+}
+
+// Pages through every quarantined record in id order
+#[ic_cdk::query]
+fn list_dead_letters(start_id: u64, limit: u16) -> Result<DeadLettersPage, Error> {
+    require_admin()?;
+    let limit = (limit as usize).min(MAX_DEAD_LETTERS_PAGE_SIZE);
+    let mut dead_letters: Vec<DeadLetter> = DEAD_LETTERS.with(|letters| {
+        letters
+            .borrow()
+            .range(start_id..)
+            .take(limit + 1)
+            .map(|(_, dead_letter)| dead_letter)
+            .collect()
+    });
+
+    // The extra entry only tells us where the next page starts
+    let next_start_id = if dead_letters.len() > limit {
+        dead_letters.pop().map(|dead_letter| dead_letter.id)
+    } else {
+        None
+    };
+
+    Ok(DeadLettersPage {
+        dead_letters,
+        next_start_id,
+    })
+}
+
+fn scan_accounts(start: Option<StorablePrincipal>, limit: usize) -> CorruptRecordScan {
+    let (corrupt, next_start) =
+        scan::<StorablePrincipal, { UserAccount::MAX_SIZE }>(ACCOUNTS_MEMORY, start, limit, account_error);
+    CorruptRecordScan {
+        corrupt: corrupt
+            .into_iter()
+            .map(|(principal, size, error)| CorruptRecord {
+                key: RecordKey::Principal(principal.into()),
+                size,
+                error,
+            })
+            .collect(),
+        next_start: next_start.map(|principal| RecordKey::Principal(principal.into())),
+    }
+}
+
+fn scan_ids<const MAX_SIZE: u32>(
+    memory_id: u8,
+    start: Option<u64>,
+    limit: usize,
+    decode: impl Fn(&[u8]) -> Result<(), String>,
+) -> CorruptRecordScan {
+    let (corrupt, next_start) = scan::<u64, MAX_SIZE>(memory_id, start, limit, decode);
+    CorruptRecordScan {
+        corrupt: corrupt
+            .into_iter()
+            .map(|(id, size, error)| CorruptRecord {
+                key: RecordKey::Id(id),
+                size,
+                error,
+            })
+            .collect(),
+        next_start: next_start.map(RecordKey::Id),
+    }
+}
+
+fn start_id(start: Option<RecordKey>) -> Result<Option<u64>, Error> {
+    match start {
+        None => Ok(None),
+        Some(RecordKey::Id(id)) => Ok(Some(id)),
+        Some(RecordKey::Principal(_)) => Err(Error::InvalidRecordKey),
+    }
+}
+
+// Keys, sizes and errors of the records from `start` on, or from the first,
+// that `decode` rejects, plus the key the next batch starts from
+#[allow(clippy::type_complexity)]
+fn scan<K: BoundedStorable + Ord + Clone, const MAX_SIZE: u32>(
+    memory_id: u8,
+    start: Option<K>,
+    limit: usize,
+    decode: impl Fn(&[u8]) -> Result<(), String>,
+) -> (Vec<(K, u64, String)>, Option<K>) {
+    let raw: RawMap<K, MAX_SIZE> = StableBTreeMap::init(memory(memory_id));
+    let mut batch: Vec<(K, RawRecord<MAX_SIZE>)> = match start {
+        Some(start) => raw.range(start..).take(limit + 1).collect(),
+        None => raw.iter().take(limit + 1).collect(),
+    };
+    // The extra entry only tells us where the next batch starts
+    let next_start = if batch.len() > limit { batch.pop().map(|(key, _)| key) } else { None };
+    let corrupt = batch
+        .into_iter()
+        .filter_map(|(key, record)| decode(&record.0).err().map(|error| (key, record.0.len() as u64, error)))
+        .collect();
+    (corrupt, next_start)
+}
+
+// Removes the record through a raw view of its memory and returns its bytes
+// and decode error. The typed map still holds the layout from before the
+// removal, so the caller has to reload it.
+fn remove_corrupt<K: BoundedStorable + Ord + Clone, const MAX_SIZE: u32>(
+    memory_id: u8,
+    key: &K,
+    decode: impl Fn(&[u8]) -> Result<(), String>,
+) -> Result<(Vec<u8>, String), Error> {
+    let mut raw: RawMap<K, MAX_SIZE> = StableBTreeMap::init(memory(memory_id));
+    let record = raw.get(key).ok_or(Error::RecordNotFound)?;
+    let Err(error) = decode(&record.0) else {
+        return Err(Error::RecordNotCorrupt);
+    };
+    raw.remove(key);
+    Ok((record.0, error))
+}
+
+// Drops what the order's indexes and derived state still say about it
+fn forget_order(order_id: u64) {
+    ORDERS_BY_OWNER.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let stale: Vec<(StorablePrincipal, u64)> =
+            index_borrowed.iter().map(|(key, _)| key).filter(|(_, id)| *id == order_id).collect();
+        for key in stale {
+            index_borrowed.remove(&key);
+        }
+    });
+    order_queries::forget_order_id(order_id);
+    rebuild_locked_balances();
+    solvency::rebuild_totals();
+    rebuild_order_book();
+    limit_scan::rebuild_dormant_limit_index();
+    order_limits::rebuild_open_order_counts();
+    stats::recount_orders();
+    certification::rebuild_certified_state();
+}
+
+// Why the bytes don't decode as the map's record, Ok when they do
+fn account_error(bytes: &[u8]) -> Result<(), String> {
+    UserAccount::try_from_bytes(bytes).map(|_| ()).map_err(|error| error.to_string())
+}
+
+fn order_error(bytes: &[u8]) -> Result<(), String> {
+    SwapOrder::try_from_bytes(bytes).map(|_| ())
+}
+
+fn transaction_error(bytes: &[u8]) -> Result<(), String> {
+    Transaction::try_from_bytes(bytes).map(|_| ()).map_err(|error| error.to_string())
+}
+
+fn memory(memory_id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
@@ -0,0 +1,104 @@
+use crate::snapshot::clear_map;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// How long a dedup id is remembered. Agents give up retrying long before this.
+const DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+pub(crate) const DEDUP_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Upper bound on entries dropped by one prune, the rest wait for the next run
+const DEDUP_PRUNE_BATCH_SIZE: usize = 500;
+
+type DedupKey = (StorablePrincipal, u64);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct DedupEntry {
+    result: Result<(), Error>,
+    recorded_at: u64,
+}
+
+impl Storable for DedupEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode DedupEntry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode DedupEntry")
+    }
+}
+
+impl BoundedStorable for DedupEntry {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (principal, dedup id) -> result of the deposit first made with that id
+    static DEPOSIT_RESULTS: RefCell<StableBTreeMap<DedupKey, DedupEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // The same entries keyed by when they were recorded, so pruning only
+    // visits the ones that have aged out
+    static DEPOSIT_RESULTS_BY_TIME: RefCell<StableBTreeMap<(u64, DedupKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+}
+
+// Result of an earlier deposit made with the same id, if it is recent enough
+// to still count as the same request
+pub(crate) fn replayed_deposit(principal: &StorablePrincipal, dedup_id: u64) -> Option<Result<(), Error>> {
+    let entry = DEPOSIT_RESULTS.with(|results| results.borrow().get(&(principal.clone(), dedup_id)))?;
+    (time().saturating_sub(entry.recorded_at) < DEDUP_WINDOW_NANOS).then_some(entry.result)
+}
+
+pub(crate) fn remember_deposit(principal: &StorablePrincipal, dedup_id: u64, result: &Result<(), Error>) {
+    let key = (principal.clone(), dedup_id);
+    let now = time();
+
+    // An id reused after its window replaces the stale entry
+    if let Some(stale) = DEPOSIT_RESULTS.with(|results| results.borrow().get(&key)) {
+        DEPOSIT_RESULTS_BY_TIME.with(|by_time| by_time.borrow_mut().remove(&(stale.recorded_at, key.clone())));
+    }
+    let entry = DedupEntry {
+        result: result.clone(),
+        recorded_at: now,
+    };
+    DEPOSIT_RESULTS.with(|results| results.borrow_mut().insert(key.clone(), entry));
+    DEPOSIT_RESULTS_BY_TIME.with(|by_time| by_time.borrow_mut().insert((now, key), ()));
+}
+
+// Runs on a timer to keep the maps down to the last day of deposits
+pub(crate) fn prune_expired_deposits() {
+    let cutoff = time().saturating_sub(DEDUP_WINDOW_NANOS);
+    let expired: Vec<(u64, DedupKey)> = DEPOSIT_RESULTS_BY_TIME.with(|by_time| {
+        by_time
+            .borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|(recorded_at, _)| *recorded_at < cutoff)
+            .take(DEDUP_PRUNE_BATCH_SIZE)
+            .collect()
+    });
+
+    for (recorded_at, key) in expired {
+        DEPOSIT_RESULTS_BY_TIME.with(|by_time| by_time.borrow_mut().remove(&(recorded_at, key.clone())));
+        DEPOSIT_RESULTS.with(|results| results.borrow_mut().remove(&key));
+    }
+}
+
+// Called by restore_snapshot, so a deposit the imported balances don't hold
+// isn't answered as a replay
+pub(crate) fn clear_deposit_results() {
+    DEPOSIT_RESULTS_BY_TIME.with(|by_time| clear_map(&mut by_time.borrow_mut()));
+    DEPOSIT_RESULTS.with(|results| clear_map(&mut results.borrow_mut()));
+}
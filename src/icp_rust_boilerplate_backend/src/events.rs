@@ -0,0 +1,346 @@
+use crate::receipts::{ExecutionReceipt, NarrowExecutionReceipt};
+use crate::{CancelReason, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Upper bound on events returned by a single page
+const MAX_EVENTS_PAGE_SIZE: u64 = 200;
+
+pub(crate) const EVENT_MIGRATION_INTERVAL: Duration = Duration::from_secs(60);
+
+// Legacy events moved per batch; post_upgrade moves the first, the timer the rest
+pub(crate) const EVENT_MIGRATION_BATCH_SIZE: usize = 1000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) enum EventKind {
+    OrderCreated {
+        order_id: u64,
+        owner: Principal,
+        from_currency: String,
+        to_currency: String,
+        from_amount: u128,
+        to_amount: u128,
+    },
+    OrderExecuted(ExecutionReceipt), // one per fill
+    // reason and initiator are None on events recorded before they were added;
+    // initiator is also None for cancels that follow from a fill, an expiry or
+    // a self-trade rather than anyone asking
+    OrderCancelled {
+        order_id: u64,
+        owner: Principal,
+        reason: Option<CancelReason>,
+        initiator: Option<Principal>,
+    },
+    // Audit record written right after the OrderCancelled it explains
+    OrderCancelledByAdmin {
+        order_id: u64,
+        owner: Principal,
+        admin: Principal,
+        reason: String,
+    },
+    OrderExpired { order_id: u64, owner: Principal },
+    Deposit { principal: Principal, currency: String, amount: u128 },
+    Withdrawal { principal: Principal, currency: String, amount: u128 },
+    // Names the admin behind a FeeWithdrawal transaction, which only has
+    // room for the two accounts
+    FeesWithdrawn {
+        transaction_id: u64,
+        admin: Principal,
+        to: Principal,
+        currency: String,
+        amount: u128,
+    },
+    // Stands in for the archived orders prune_archive deleted
+    ArchivePruned {
+        admin: Principal,
+        count: u64,
+        total_volume: u128, // sum of their filled from_amounts
+        ids_hash: Vec<u8>,  // SHA-256 of their ids as big-endian u64s in ascending order
+    },
+    // Audit record of admin_adjust_balance; delta is negative for a debit
+    BalanceAdjusted {
+        adjustment_id: u64,
+        principal: Principal,
+        admin: Principal,
+        currency: String,
+        delta: i128,
+        reason: String,
+    },
+    // recovery_principal is None once the owner removed it
+    RecoveryPrincipalSet { owner: Principal, recovery_principal: Option<Principal> },
+    RecoveryRequested { owner: Principal, recovery_principal: Principal, claimable_at: u64 },
+    RecoveryVetoed { owner: Principal, recovery_principal: Principal },
+    AccountRecovered { owner: Principal, recovery_principal: Principal, orders_reassigned: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Event {
+    seq: u64,
+    timestamp: u64,
+    kind: EventKind,
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Event"))
+    }
+
+    // Events logged while amounts were u64 are widened on the way out
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), NarrowEvent).map(Event::from))
+            .expect("Failed to decode Event")
+    }
+}
+
+// A balance adjustment with the longest reason comes closest, at 759 bytes
+impl BoundedStorable for Event {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Events as stored under the old bound, which BalanceAdjusted outgrew;
+// drained into EVENTS in batches after an upgrade
+#[derive(Clone)]
+struct LegacyEvent(Event);
+
+impl Storable for LegacyEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        LegacyEvent(Event::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for LegacyEvent {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Event and EventKind as stored while amounts were u64
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NarrowEvent {
+    seq: u64,
+    timestamp: u64,
+    kind: NarrowEventKind,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum NarrowEventKind {
+    OrderCreated {
+        order_id: u64,
+        owner: Principal,
+        from_currency: String,
+        to_currency: String,
+        from_amount: u64,
+        to_amount: u64,
+    },
+    OrderExecuted(NarrowExecutionReceipt),
+    OrderCancelled { order_id: u64, owner: Principal },
+    OrderCancelledByAdmin {
+        order_id: u64,
+        owner: Principal,
+        admin: Principal,
+        reason: String,
+    },
+    OrderExpired { order_id: u64, owner: Principal },
+    Deposit { principal: Principal, currency: String, amount: u64 },
+    Withdrawal { principal: Principal, currency: String, amount: u64 },
+    FeesWithdrawn {
+        transaction_id: u64,
+        admin: Principal,
+        to: Principal,
+        currency: String,
+        amount: u64,
+    },
+    // With unnamed fields, as it was first logged
+    ArchivePruned(Principal, u64, u128, Vec<u8>),
+}
+
+impl From<NarrowEvent> for Event {
+    fn from(narrow: NarrowEvent) -> Self {
+        let kind = match narrow.kind {
+            NarrowEventKind::OrderCreated {
+                order_id,
+                owner,
+                from_currency,
+                to_currency,
+                from_amount,
+                to_amount,
+            } => EventKind::OrderCreated {
+                order_id,
+                owner,
+                from_currency,
+                to_currency,
+                from_amount: from_amount.into(),
+                to_amount: to_amount.into(),
+            },
+            NarrowEventKind::OrderExecuted(receipt) => EventKind::OrderExecuted(receipt.into()),
+            NarrowEventKind::OrderCancelled { order_id, owner } => EventKind::OrderCancelled {
+                order_id,
+                owner,
+                reason: None,
+                initiator: None,
+            },
+            NarrowEventKind::OrderCancelledByAdmin {
+                order_id,
+                owner,
+                admin,
+                reason,
+            } => EventKind::OrderCancelledByAdmin {
+                order_id,
+                owner,
+                admin,
+                reason,
+            },
+            NarrowEventKind::OrderExpired { order_id, owner } => EventKind::OrderExpired { order_id, owner },
+            NarrowEventKind::Deposit { principal, currency, amount } => EventKind::Deposit {
+                principal,
+                currency,
+                amount: amount.into(),
+            },
+            NarrowEventKind::Withdrawal { principal, currency, amount } => EventKind::Withdrawal {
+                principal,
+                currency,
+                amount: amount.into(),
+            },
+            NarrowEventKind::FeesWithdrawn {
+                transaction_id,
+                admin,
+                to,
+                currency,
+                amount,
+            } => EventKind::FeesWithdrawn {
+                transaction_id,
+                admin,
+                to,
+                currency,
+                amount: amount.into(),
+            },
+            NarrowEventKind::ArchivePruned(admin, count, total_volume, ids_hash) => EventKind::ArchivePruned {
+                admin,
+                count,
+                total_volume,
+                ids_hash,
+            },
+        };
+        Event {
+            seq: narrow.seq,
+            timestamp: narrow.timestamp,
+            kind,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct EventsPage {
+    events: Vec<Event>,
+    latest_seq: u64, // 0 while the log is empty
+}
+
+thread_local! {
+    static LEGACY_EVENTS: RefCell<StableBTreeMap<u64, LegacyEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // Append-only, keyed by sequence number starting at 1
+    static EVENTS: RefCell<StableBTreeMap<u64, Event, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+}
+
+// Moves up to `budget` events out of the old memory, oldest first, keeping
+// their sequence numbers. Returns whether the old memory is empty.
+pub(crate) fn migrate_legacy_events(budget: usize) -> bool {
+    let batch: Vec<(u64, LegacyEvent)> = LEGACY_EVENTS.with(|events| events.borrow().iter().take(budget).collect());
+    for (seq, event) in batch {
+        EVENTS.with(|events| events.borrow_mut().insert(seq, event.0));
+        LEGACY_EVENTS.with(|events| events.borrow_mut().remove(&seq));
+    }
+    LEGACY_EVENTS.with(|events| events.borrow().is_empty())
+}
+
+// Called by the update that makes the change, so the log commits or rolls
+// back together with it
+pub(crate) fn record_event(kind: EventKind) {
+    let seq = latest_seq() + 1;
+    EVENTS.with(|events| events.borrow_mut().insert(seq, Event { seq, timestamp: time(), kind }));
+}
+
+// Events from `start_seq` on, without gaps. Indexers pass the last seq they
+// saw plus one to continue. Pages stop short of events still waiting to be
+// migrated, and carry on past them once they are.
+#[ic_cdk::query]
+fn get_events(start_seq: u64, limit: u64) -> EventsPage {
+    let limit = limit.min(MAX_EVENTS_PAGE_SIZE) as usize;
+    let end = migrated_below();
+    EventsPage {
+        events: EVENTS.with(|events| {
+            events.borrow().range(start_seq.min(end)..end).take(limit).map(|(_, event)| event).collect()
+        }),
+        latest_seq: latest_seq(),
+    }
+}
+
+// Counts events still in the old memory, so a seq is never handed out twice
+pub(crate) fn latest_seq() -> u64 {
+    let latest = EVENTS.with(|events| events.borrow().last_key_value().map_or(0, |(last_seq, _)| last_seq));
+    let legacy = LEGACY_EVENTS.with(|events| events.borrow().last_key_value().map_or(0, |(last_seq, _)| last_seq));
+    latest.max(legacy)
+}
+
+// Readers see EVENTS up to the oldest event not yet migrated, which keeps
+// the log they page through free of gaps
+fn migrated_below() -> u64 {
+    LEGACY_EVENTS.with(|events| events.borrow().first_key_value().map_or(u64::MAX, |(seq, _)| seq))
+}
+
+// The fills among the next `scan_limit` events after `after_seq`, with the
+// last seq looked at, or None when there are no newer events
+pub(crate) fn executions_after(after_seq: u64, scan_limit: usize) -> (Vec<(u64, ExecutionReceipt)>, Option<u64>) {
+    EVENTS.with(|events| {
+        let mut executions = Vec::new();
+        let mut last_seq = None;
+        let end = migrated_below();
+        for (seq, event) in events.borrow().range(after_seq.saturating_add(1).min(end)..end).take(scan_limit) {
+            last_seq = Some(seq);
+            if let EventKind::OrderExecuted(receipt) = event.kind {
+                executions.push((seq, receipt));
+            }
+        }
+        (executions, last_seq)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // The initiator of each OrderCancelled event recorded for the order
+    pub(crate) fn cancel_initiators(order_id: u64) -> Vec<Option<Principal>> {
+        EVENTS.with(|events| {
+            events
+                .borrow()
+                .iter()
+                .filter_map(|(_, event)| match event.kind {
+                    EventKind::OrderCancelled { order_id: cancelled, initiator, .. } if cancelled == order_id => {
+                        Some(initiator)
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+    }
+}
@@ -0,0 +1,195 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use ic_cdk::api::caller;
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Daily buckets summed for a trader's volume, the current day included
+const VOLUME_WINDOW_DAYS: u64 = 30;
+
+const MAX_FEE_TIERS: usize = 16;
+
+// A discount of 10_000 waives the fee entirely
+const MAX_DISCOUNT_BPS: u16 = 10_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct FeeTier {
+    min_volume: u128,  // 30-day volume from which the tier applies
+    discount_bps: u16, // share of the maker and taker fee waived, 5000 is half fees
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FeeTierStatus {
+    volume_30d: u128,
+    discount_bps: u16,                 // 0 below the lowest tier
+    next_tier: Option<FeeTier>,        // None at the top tier
+    volume_to_next_tier: Option<u128>,
+}
+
+thread_local! {
+    // Volumes and tiers as stored while amounts were u64, drained into the
+    // maps below by the upgrade migration
+    static LEGACY_TRADER_VOLUME: RefCell<StableBTreeMap<(StorablePrincipal, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    static LEGACY_FEE_TIERS: RefCell<StableBTreeMap<u64, u16, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    // (principal, days since epoch) -> amount the principal delivered on
+    // fills that day, in whichever currency it paid. Buckets older than the
+    // window are pruned when the principal trades again.
+    static TRADER_VOLUME: RefCell<StableBTreeMap<(StorablePrincipal, u64), u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // Minimum 30-day volume -> discount_bps
+    static FEE_TIERS: RefCell<StableBTreeMap<u128, u16, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_fee_tiers() -> Vec<FeeTier> {
+    FEE_TIERS.with(|tiers| {
+        tiers
+            .borrow()
+            .iter()
+            .map(|(min_volume, discount_bps)| FeeTier { min_volume, discount_bps })
+            .collect()
+    })
+}
+
+// Replaces the whole tier table. An empty list turns discounts off.
+#[ic_cdk::update]
+fn set_fee_tiers(fee_tiers: Vec<FeeTier>) -> Result<(), Error> {
+    require_admin()?;
+    if fee_tiers.len() > MAX_FEE_TIERS || fee_tiers.iter().any(|tier| tier.discount_bps > MAX_DISCOUNT_BPS) {
+        return Err(Error::InvalidFee);
+    }
+
+    FEE_TIERS.with(|tiers| {
+        let mut tiers_borrowed = tiers.borrow_mut();
+        let stale: Vec<u128> = tiers_borrowed.iter().map(|(min_volume, _)| min_volume).collect();
+        for min_volume in stale {
+            tiers_borrowed.remove(&min_volume);
+        }
+        for tier in fee_tiers {
+            tiers_borrowed.insert(tier.min_volume, tier.discount_bps);
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_fee_tier() -> FeeTierStatus {
+    let volume_30d = rolling_volume(&StorablePrincipal::from(caller()));
+    let next_tier = FEE_TIERS.with(|tiers| {
+        tiers
+            .borrow()
+            .range(volume_30d.saturating_add(1)..)
+            .next()
+            .map(|(min_volume, discount_bps)| FeeTier { min_volume, discount_bps })
+    });
+
+    FeeTierStatus {
+        volume_30d,
+        discount_bps: discount_for_volume(volume_30d),
+        volume_to_next_tier: next_tier.as_ref().map(|tier| tier.min_volume - volume_30d),
+        next_tier,
+    }
+}
+
+// Discount the principal's fees get on its next fill
+pub(crate) fn discount_bps(principal: &StorablePrincipal) -> u16 {
+    discount_for_volume(rolling_volume(principal))
+}
+
+// Adds a fill to the principal's bucket for today and drops the buckets that
+// have left the window, so each principal keeps at most one per day in it
+pub(crate) fn record_trade_volume(principal: &StorablePrincipal, amount: u128) {
+    let today = time() / NANOS_PER_DAY;
+    TRADER_VOLUME.with(|volumes| {
+        let mut volumes_borrowed = volumes.borrow_mut();
+        let expired: Vec<(StorablePrincipal, u64)> = volumes_borrowed
+            .range((principal.clone(), 0)..(principal.clone(), window_start(today)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            volumes_borrowed.remove(&key);
+        }
+
+        let key = (principal.clone(), today);
+        let bucket = volumes_borrowed.get(&key).unwrap_or(0);
+        volumes_borrowed.insert(key, bucket.saturating_add(amount));
+    });
+}
+
+// Moves the u64 volume buckets and tiers into the u128 maps
+pub(crate) fn migrate_legacy_fee_tiers() {
+    let legacy_volumes: Vec<((StorablePrincipal, u64), u64)> =
+        LEGACY_TRADER_VOLUME.with(|volumes| volumes.borrow().iter().collect());
+    TRADER_VOLUME.with(|volumes| {
+        let mut volumes_borrowed = volumes.borrow_mut();
+        for (key, bucket) in &legacy_volumes {
+            volumes_borrowed.insert(key.clone(), (*bucket).into());
+        }
+    });
+    LEGACY_TRADER_VOLUME.with(|volumes| {
+        let mut volumes_borrowed = volumes.borrow_mut();
+        for (key, _) in &legacy_volumes {
+            volumes_borrowed.remove(key);
+        }
+    });
+
+    let legacy_tiers: Vec<(u64, u16)> = LEGACY_FEE_TIERS.with(|tiers| tiers.borrow().iter().collect());
+    FEE_TIERS.with(|tiers| {
+        let mut tiers_borrowed = tiers.borrow_mut();
+        for (min_volume, discount_bps) in &legacy_tiers {
+            tiers_borrowed.insert((*min_volume).into(), *discount_bps);
+        }
+    });
+    LEGACY_FEE_TIERS.with(|tiers| {
+        let mut tiers_borrowed = tiers.borrow_mut();
+        for (min_volume, _) in &legacy_tiers {
+            tiers_borrowed.remove(min_volume);
+        }
+    });
+}
+
+pub(crate) fn rolling_volume(principal: &StorablePrincipal) -> u128 {
+    let today = time() / NANOS_PER_DAY;
+    TRADER_VOLUME.with(|volumes| {
+        volumes
+            .borrow()
+            .range((principal.clone(), window_start(today))..=(principal.clone(), today))
+            .fold(0u128, |total, (_, bucket)| total.saturating_add(bucket))
+    })
+}
+
+// Highest tier the volume reaches
+fn discount_for_volume(volume: u128) -> u16 {
+    FEE_TIERS.with(|tiers| {
+        tiers
+            .borrow()
+            .range(..=volume)
+            .last()
+            .map_or(0, |(_, discount_bps)| discount_bps)
+    })
+}
+
+fn window_start(today: u64) -> u64 {
+    today.saturating_sub(VOLUME_WINDOW_DAYS - 1)
+}
@@ -0,0 +1,229 @@
+use crate::admin::require_admin;
+use crate::amounts::mul_div;
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::events::{record_event, EventKind};
+use crate::fee_tiers;
+use crate::proposals::{self, ProposedAction};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{BalanceChanges, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::caller;
+#[cfg(not(test))]
+use ic_cdk::id;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Highest maker or taker fee the admin can configure, 10%
+const MAX_FEE_BPS: u16 = 1000;
+
+// Maker or taker fees set above this, 1%, need the admins' approval
+const APPROVAL_FREE_FEE_BPS: u16 = 100;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct FeeConfig {
+    fee_bps: u16,                  // maker fee, withheld from the owner's proceeds on every fill
+    fee_account: Principal,        // account credited with collected fees
+    taker_fee_bps: Option<u16>,    // withheld from what the executor receives, None counts as 0
+    maker_rebate_bps: Option<u16>, // negative maker fee, paid to the owner out of the taker fee
+}
+
+// What one fill owes. maker_fee is in the currency the owner receives,
+// taker_fee and maker_rebate in the currency the executor receives.
+pub(crate) struct FillFees {
+    pub(crate) maker_fee: u128,
+    pub(crate) taker_fee: u128,
+    pub(crate) maker_rebate: u128, // never more than taker_fee
+}
+
+impl Storable for FeeConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode FeeConfig"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode FeeConfig")
+    }
+}
+
+thread_local! {
+    // Fees start disabled and accrue to the canister's own account until the
+    // admin points them elsewhere
+    static FEE_CONFIG: RefCell<Cell<FeeConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+            FeeConfig {
+                fee_bps: 0,
+                fee_account: id(),
+                taker_fee_bps: None,
+                maker_rebate_bps: None,
+            },
+        )
+        .expect("Cannot create the fee config")
+    );
+}
+
+#[ic_cdk::query]
+fn get_fee_config() -> FeeConfig {
+    FEE_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// A negative maker fee is a rebate, funded from the taker fee on the same
+// fill, so it can't be larger than the taker fee
+#[ic_cdk::update]
+fn set_maker_fee_bps(maker_fee_bps: i16) -> Result<(), Error> {
+    require_admin()?;
+    check_maker_fee_bps(maker_fee_bps)?;
+    if maker_fee_bps.unsigned_abs() > APPROVAL_FREE_FEE_BPS {
+        proposals::require_approval(ProposedAction::SetMakerFeeBps { maker_fee_bps })?;
+    }
+    apply_maker_fee_bps(maker_fee_bps)
+}
+
+#[ic_cdk::update]
+fn set_taker_fee_bps(taker_fee_bps: u16) -> Result<(), Error> {
+    require_admin()?;
+    check_taker_fee_bps(taker_fee_bps)?;
+    if taker_fee_bps > APPROVAL_FREE_FEE_BPS {
+        proposals::require_approval(ProposedAction::SetTakerFeeBps { taker_fee_bps })?;
+    }
+    apply_taker_fee_bps(taker_fee_bps)
+}
+
+// Checked again when an approved proposal applies it, as the other fee may
+// have changed in between
+pub(crate) fn apply_maker_fee_bps(maker_fee_bps: i16) -> Result<(), Error> {
+    check_maker_fee_bps(maker_fee_bps)?;
+    let magnitude = maker_fee_bps.unsigned_abs();
+    update_fee_config(|config| {
+        config.fee_bps = if maker_fee_bps > 0 { magnitude } else { 0 };
+        config.maker_rebate_bps = (maker_fee_bps < 0).then_some(magnitude);
+    })
+}
+
+pub(crate) fn apply_taker_fee_bps(taker_fee_bps: u16) -> Result<(), Error> {
+    check_taker_fee_bps(taker_fee_bps)?;
+    update_fee_config(|config| config.taker_fee_bps = Some(taker_fee_bps))
+}
+
+fn check_maker_fee_bps(maker_fee_bps: i16) -> Result<(), Error> {
+    let magnitude = maker_fee_bps.unsigned_abs();
+    if magnitude > MAX_FEE_BPS || (maker_fee_bps < 0 && magnitude > taker_fee_bps(&get_fee_config())) {
+        return Err(Error::InvalidFee);
+    }
+    Ok(())
+}
+
+// Lowering the taker fee below the maker rebate would leave the rebate unfunded
+fn check_taker_fee_bps(taker_fee_bps: u16) -> Result<(), Error> {
+    let maker_rebate_bps = get_fee_config().maker_rebate_bps.unwrap_or(0);
+    if taker_fee_bps > MAX_FEE_BPS || taker_fee_bps < maker_rebate_bps {
+        return Err(Error::InvalidFee);
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_fee_account(fee_account: Principal) -> Result<(), Error> {
+    require_admin()?;
+    if fee_account == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+
+    update_fee_config(|config| config.fee_account = fee_account)
+}
+
+// Moves collected fees from the fee account to `to`'s account inside the
+// canister, from where they can leave through withdraw_to_ledger. Only the
+// fee account's available balance can be taken, all of `amount` or nothing.
+// Returns the transaction id of the withdrawal.
+#[ic_cdk::update]
+fn withdraw_fees(currency: String, amount: u128, to: Principal) -> Result<u64, Error> {
+    require_admin()?;
+    if to == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+    let currency = normalize_currency(&currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+    if amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    let fee_account = fee_account();
+    if fee_account == StorablePrincipal::from(to) {
+        return Err(Error::SelfTransfer);
+    }
+
+    let mut changes = BalanceChanges::new();
+    changes.debit(&fee_account, &currency, amount)?;
+    changes.credit(&StorablePrincipal::from(to), &currency, amount)?;
+    changes.commit();
+
+    let transaction_id =
+        record_transaction(TransactionKind::FeeWithdrawal, Some(fee_account.into()), Some(to), &currency, amount, None);
+    record_event(EventKind::FeesWithdrawn {
+        transaction_id,
+        admin: caller(),
+        to,
+        currency,
+        amount,
+    });
+    Ok(transaction_id)
+}
+
+fn update_fee_config(update: impl FnOnce(&mut FeeConfig)) -> Result<(), Error> {
+    FEE_CONFIG.with(|config| {
+        let mut fee_config = config.borrow().get().clone();
+        update(&mut fee_config);
+        config.borrow_mut().set(fee_config).map_err(|_| Error::InvalidFee)?;
+        Ok(())
+    })
+}
+
+// Tests run off the replica, where there is no canister id to account fees to
+#[cfg(test)]
+fn id() -> Principal {
+    Principal::management_canister()
+}
+
+// Fees owed on a fill where the owner receives `payment` and the executor
+// `fill_amount`, after each side's volume tier discount. Each is rounded down
+// so it never exceeds its rate. The rebate rate is at most the taker rate but
+// the taker's discount can still bring the taker fee below it, so the rebate
+// is capped at the taker fee actually collected.
+pub(crate) fn fees_for(
+    owner: &StorablePrincipal,
+    executor: &StorablePrincipal,
+    payment: u128,
+    fill_amount: u128,
+) -> FillFees {
+    let config = get_fee_config();
+    let maker_fee = discounted(bps_of(payment, config.fee_bps), fee_tiers::discount_bps(owner));
+    let taker_fee = discounted(bps_of(fill_amount, taker_fee_bps(&config)), fee_tiers::discount_bps(executor));
+    FillFees {
+        maker_fee,
+        taker_fee,
+        maker_rebate: bps_of(fill_amount, config.maker_rebate_bps.unwrap_or(0)).min(taker_fee),
+    }
+}
+
+fn discounted(fee: u128, discount_bps: u16) -> u128 {
+    fee - bps_of(fee, discount_bps)
+}
+
+// Rates never exceed 100%, so the share always fits
+fn bps_of(amount: u128, bps: u16) -> u128 {
+    mul_div(amount, bps.into(), BPS_DENOMINATOR).expect("Fee exceeded the amount it was taken from")
+}
+
+fn taker_fee_bps(config: &FeeConfig) -> u16 {
+    config.taker_fee_bps.unwrap_or(0)
+}
+
+pub(crate) fn fee_account() -> StorablePrincipal {
+    StorablePrincipal::from(get_fee_config().fee_account)
+}
@@ -0,0 +1,272 @@
+use crate::receipts::{find_receipt, ExecutionReceipt};
+use crate::snapshot::{clear_map, entries_after};
+use crate::{admin, archive, rate_limit, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub(crate) const NOTIFICATION_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Notifications retried per timer tick
+const NOTIFICATION_RETRY_BATCH_SIZE: usize = 50;
+
+// Sends per notification, the first one included, before it is marked Failed
+const MAX_NOTIFICATION_ATTEMPTS: u32 = 5;
+
+const MAX_METHOD_BYTES: usize = 64;
+
+// Where the owner wants to hear about the order's fills
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FillCallback {
+    canister: Principal,
+    method: String, // called with the ExecutionReceipt as its only argument
+}
+
+impl Storable for FillCallback {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode FillCallback"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode FillCallback")
+    }
+}
+
+impl BoundedStorable for FillCallback {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum NotificationStatus {
+    Pending,   // waiting for a retry
+    Delivered, // the one-way call was sent; its reply, if any, is never seen
+    Failed,    // every attempt was rejected before leaving the canister
+}
+
+// The notification for one fill; see get_execution_receipts for the receipt
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FillNotification {
+    fill_number: u64,
+    status: NotificationStatus,
+    attempts: u32,
+    last_attempt_at: u64,
+    last_error: Option<String>,
+}
+
+impl Storable for FillNotification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode FillNotification"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode FillNotification")
+    }
+}
+
+impl BoundedStorable for FillNotification {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Order id -> callback, only for orders that have one
+    static ORDER_CALLBACKS: RefCell<StableBTreeMap<u64, FillCallback, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+
+    // (order id, fill number) -> notification, keyed like the receipts
+    static FILL_NOTIFICATIONS: RefCell<StableBTreeMap<(u64, u64), FillNotification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    // (order id, fill number) for Pending notifications only
+    static PENDING_NOTIFICATIONS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrderCallback {
+    callback: Option<FillCallback>,
+    notifications: Vec<FillNotification>, // oldest first
+}
+
+// Has every later fill of one of the caller's orders sent to `callback` as a
+// one-way call carrying its receipt; None stops them. Delivery is best
+// effort: a send the system rejects is retried by a timer a few times, and
+// nothing about the notification can undo the fill.
+#[ic_cdk::update]
+fn set_order_callback(order_id: u64, callback: Option<FillCallback>) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let swap_order = archive::find_order(order_id).ok_or(Error::InvalidOrderId)?;
+    if swap_order.owner != caller() {
+        return Err(Error::Unauthorized);
+    }
+
+    match callback {
+        Some(callback) => {
+            let method = callback.method.trim().to_string();
+            if method.is_empty() || method.len() > MAX_METHOD_BYTES {
+                return Err(Error::InvalidCallback);
+            }
+            // Not to the canister itself, the management canister or nobody at all
+            if [ic_cdk::id(), Principal::management_canister(), Principal::anonymous()].contains(&callback.canister) {
+                return Err(Error::InvalidCallback);
+            }
+            let callback = FillCallback {
+                canister: callback.canister,
+                method,
+            };
+            ORDER_CALLBACKS.with(|callbacks| callbacks.borrow_mut().insert(order_id, callback));
+        }
+        None => {
+            ORDER_CALLBACKS.with(|callbacks| callbacks.borrow_mut().remove(&order_id));
+        }
+    };
+    Ok(())
+}
+
+// The order's callback and what became of each notification; owner only
+#[ic_cdk::query]
+fn get_order_callback(order_id: u64) -> Result<OrderCallback, Error> {
+    let swap_order = archive::find_order(order_id).ok_or(Error::InvalidOrderId)?;
+    if swap_order.owner != caller() {
+        return Err(Error::Unauthorized);
+    }
+    let notifications = FILL_NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, notification)| notification)
+            .collect()
+    });
+    Ok(OrderCallback {
+        callback: ORDER_CALLBACKS.with(|callbacks| callbacks.borrow().get(&order_id)),
+        notifications,
+    })
+}
+
+// Called for every fill once its receipt is stored. The first send happens
+// right away, within the fill's own message.
+pub(crate) fn notify_fill(receipt: &ExecutionReceipt, fill_number: u64) {
+    let Some(callback) = ORDER_CALLBACKS.with(|callbacks| callbacks.borrow().get(&receipt.order_id)) else {
+        return;
+    };
+    let notification = FillNotification {
+        fill_number,
+        status: NotificationStatus::Pending,
+        attempts: 0,
+        last_attempt_at: 0,
+        last_error: None,
+    };
+    send(&callback, receipt, notification, receipt.order_id);
+}
+
+// Resends Pending notifications, oldest order first
+pub(crate) fn retry_notifications() {
+    let due: Vec<(u64, u64)> = PENDING_NOTIFICATIONS.with(|pending| {
+        pending
+            .borrow()
+            .iter()
+            .take(NOTIFICATION_RETRY_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for (order_id, fill_number) in due {
+        let key = (order_id, fill_number);
+        let Some(notification) = FILL_NOTIFICATIONS.with(|notifications| notifications.borrow().get(&key)) else {
+            PENDING_NOTIFICATIONS.with(|pending| pending.borrow_mut().remove(&key));
+            continue;
+        };
+        // Removing the callback, or pruning the receipt, drops what is left
+        let callback = ORDER_CALLBACKS.with(|callbacks| callbacks.borrow().get(&order_id));
+        let receipt = find_receipt(order_id, fill_number);
+        match (callback, receipt) {
+            (Some(callback), Some(receipt)) => send(&callback, &receipt, notification, order_id),
+            _ => finish(order_id, notification, NotificationStatus::Failed),
+        }
+    }
+}
+
+// Called by prune_archive for each order it deletes
+pub(crate) fn forget_callback(order_id: u64) {
+    ORDER_CALLBACKS.with(|callbacks| callbacks.borrow_mut().remove(&order_id));
+    let keys: Vec<(u64, u64)> = FILL_NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in keys {
+        FILL_NOTIFICATIONS.with(|notifications| notifications.borrow_mut().remove(&key));
+        PENDING_NOTIFICATIONS.with(|pending| pending.borrow_mut().remove(&key));
+    }
+}
+
+// Up to `limit` callbacks after the order id `after`, for create_export
+pub(crate) fn export_callbacks(after: Option<u64>, limit: usize) -> Vec<(u64, (u64, FillCallback))> {
+    let page = ORDER_CALLBACKS.with(|callbacks| entries_after(&callbacks.borrow(), after, limit));
+    page.into_iter().map(|(order_id, callback)| (order_id, (order_id, callback))).collect()
+}
+
+// Replaces every callback with those of an imported snapshot. Notifications
+// are about fills of the state being replaced, so none are kept.
+pub(crate) fn restore_callbacks(restored: Vec<(u64, FillCallback)>) {
+    FILL_NOTIFICATIONS.with(|notifications| clear_map(&mut notifications.borrow_mut()));
+    PENDING_NOTIFICATIONS.with(|pending| clear_map(&mut pending.borrow_mut()));
+    ORDER_CALLBACKS.with(|callbacks| {
+        let mut callbacks_borrowed = callbacks.borrow_mut();
+        clear_map(&mut callbacks_borrowed);
+        for (order_id, callback) in restored {
+            callbacks_borrowed.insert(order_id, callback);
+        }
+    });
+}
+
+fn send(callback: &FillCallback, receipt: &ExecutionReceipt, mut notification: FillNotification, order_id: u64) {
+    notification.attempts += 1;
+    notification.last_attempt_at = time();
+    match ic_cdk::api::call::notify(callback.canister, &callback.method, (receipt.clone(),)) {
+        Ok(()) => {
+            notification.last_error = None;
+            finish(order_id, notification, NotificationStatus::Delivered);
+        }
+        Err(code) => {
+            notification.last_error = Some(format!("{:?}", code));
+            if notification.attempts >= MAX_NOTIFICATION_ATTEMPTS {
+                finish(order_id, notification, NotificationStatus::Failed);
+            } else {
+                let key = (order_id, notification.fill_number);
+                PENDING_NOTIFICATIONS.with(|pending| pending.borrow_mut().insert(key, ()));
+                FILL_NOTIFICATIONS.with(|notifications| notifications.borrow_mut().insert(key, notification));
+            }
+        }
+    }
+}
+
+fn finish(order_id: u64, mut notification: FillNotification, status: NotificationStatus) {
+    let key = (order_id, notification.fill_number);
+    notification.status = status;
+    PENDING_NOTIFICATIONS.with(|pending| pending.borrow_mut().remove(&key));
+    FILL_NOTIFICATIONS.with(|notifications| notifications.borrow_mut().insert(key, notification));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_callback_changes_are_rejected() {
+        assert_eq!(set_order_callback(1, None), Err(Error::AnonymousNotAllowed));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::admin::{require_admin, trading_paused};
+use crate::{Error, Memory, MEMORY_MANAGER, ORDER_COUNTER};
+use ic_cdk::api::canister_balance128;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Memory as _};
+use std::cell::RefCell;
+
+// Alerts well before the freezing threshold of a canister with default settings
+const DEFAULT_LOW_CYCLES_THRESHOLD: u128 = 1_000_000_000_000;
+
+// Every virtual memory the canister allocates, by the MemoryId it lives in
+const STABLE_MEMORIES: [(&str, u8); 115] = [
+    ("legacy_user_accounts", 0),
+    ("legacy_swap_orders", 1),
+    ("order_counter", 2),
+    ("user_accounts", 3),
+    ("exchange_rates", 4),
+    ("rate_config", 5),
+    ("xrc_rate_cache", 6),
+    ("fee_config", 7),
+    ("admin", 8),
+    ("trading_status", 9),
+    ("currencies", 10),
+    ("legacy_transactions", 11),
+    ("orders_by_owner", 12),
+    ("legacy_order_book", 13),
+    ("receipts", 14),
+    ("pair_configs", 15),
+    ("default_pair_config", 16),
+    ("legacy_withdrawal_limits", 17),
+    ("legacy_recent_withdrawals", 18),
+    ("withdrawal_exemptions", 19),
+    ("order_counts", 20),
+    ("total_volume", 21),
+    ("hourly_volume", 22),
+    ("low_cycles_threshold", 23),
+    ("ledgers", 24),
+    ("ledger_withdrawals", 25),
+    ("deposit_results", 26),
+    ("deposit_results_by_time", 27),
+    ("swap_orders", 28),
+    ("orders_by_counterparty", 29),
+    ("legacy_events", 30),
+    ("open_order_counts", 31),
+    ("max_open_orders", 32),
+    ("open_order_limit_overrides", 33),
+    ("legacy_trader_volume", 34),
+    ("legacy_fee_tiers", 35),
+    ("blacklist", 36),
+    ("access_mode", 37),
+    ("allowlist", 38),
+    ("max_calls_per_window", 39),
+    ("call_limit_overrides", 40),
+    ("call_windows_backup", 41),
+    ("dormant_limit_orders", 42),
+    ("recovery_status", 43),
+    ("archived_orders", 44),
+    ("archive_min_age", 45),
+    ("archive_retention", 46),
+    ("legacy_order_book_2", 47),
+    ("withdrawal_limits", 48),
+    ("recent_withdrawals", 49),
+    ("trader_volume", 50),
+    ("fee_tiers", 51),
+    ("recurring_orders", 52),
+    ("recurring_by_owner", 53),
+    ("recurring_due", 54),
+    ("legacy_order_book_3", 55),
+    ("last_trades", 56),
+    ("minute_candles", 57),
+    ("hour_candles", 58),
+    ("day_candles", 59),
+    ("recent_trades", 60),
+    ("trade_counts", 61),
+    ("daily_trades", 62),
+    ("currency_totals", 63),
+    ("adjustments", 64),
+    ("transactions", 65),
+    ("events", 66),
+    ("order_deposit_config", 67),
+    ("order_labels", 68),
+    ("counter_offers", 69),
+    ("open_offers_by_order", 70),
+    ("open_offers_by_expiry", 71),
+    ("recovery_setups", 72),
+    ("account_recovery_delay", 73),
+    ("session_keys", 74),
+    ("session_keys_by_grantor", 75),
+    ("withdrawal_destinations", 76),
+    ("withdrawal_restriction_lifts", 77),
+    ("withdrawal_confirmation_thresholds", 78),
+    ("pending_withdrawals", 79),
+    ("pending_withdrawals_by_owner", 80),
+    ("pending_withdrawals_by_expiry", 81),
+    ("order_callbacks", 82),
+    ("fill_notifications", 83),
+    ("pending_notifications", 84),
+    ("webhook_config", 85),
+    ("webhooks", 86),
+    ("pools", 87),
+    ("lp_shares", 88),
+    ("referrers", 89),
+    ("referee_counts", 90),
+    ("referral_rewards", 91),
+    ("referral_reward_bps", 92),
+    ("trade_counts", 93),
+    ("leaderboard", 94),
+    ("leaderboard_volumes", 95),
+    ("named_traders", 96),
+    ("co_admins", 97),
+    ("approval_threshold", 98),
+    ("proposals", 99),
+    ("pending_proposals", 100),
+    ("orders_encoded_version", 101),
+    ("dead_letters", 102),
+    ("orders_by_created", 103),
+    ("orders_by_pair", 104),
+    ("order_counts_by_pair", 105),
+    ("order_outcomes", 106),
+    ("cancel_ratio_limit", 107),
+    ("order_creation_blocks", 108),
+    ("self_trade_prevention", 109),
+    ("self_trade_records", 110),
+    ("ledger_deposits", 111),
+    ("order_book", 112),
+    ("derived_state_version", 113),
+    ("otc_book", 114),
+];
+
+thread_local! {
+    static LOW_CYCLES_THRESHOLD: RefCell<Cell<u128, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))), DEFAULT_LOW_CYCLES_THRESHOLD)
+            .expect("Cannot create the low cycles threshold")
+    );
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MemoryUsage {
+    name: String,
+    memory_id: u8,
+    pages: u64, // 64 KiB wasm pages allocated to this memory
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CanisterHealth {
+    stable_memory: Vec<MemoryUsage>,
+    heap_size_bytes: u64, // wasm heap pages in use, 0 outside of wasm
+    order_counter: u64,   // id given to the most recent order
+    cycles: u128,
+    low_cycles_threshold: u128,
+    trading_paused: bool,
+    degraded: bool, // cycles have dropped below low_cycles_threshold
+}
+
+// Reads sizes and cells only, so monitoring can poll it as often as it likes
+#[ic_cdk::query]
+fn get_canister_health() -> CanisterHealth {
+    let stable_memory = MEMORY_MANAGER.with(|m| {
+        let manager = m.borrow();
+        STABLE_MEMORIES
+            .iter()
+            .map(|(name, memory_id)| MemoryUsage {
+                name: name.to_string(),
+                memory_id: *memory_id,
+                pages: manager.get(MemoryId::new(*memory_id)).size(),
+            })
+            .collect()
+    });
+    let cycles = canister_balance128();
+    let low_cycles_threshold = LOW_CYCLES_THRESHOLD.with(|cell| *cell.borrow().get());
+
+    CanisterHealth {
+        stable_memory,
+        heap_size_bytes: heap_size_bytes(),
+        order_counter: ORDER_COUNTER.with(|counter| counter.borrow().last_id()),
+        cycles,
+        low_cycles_threshold,
+        trading_paused: trading_paused(),
+        degraded: cycles < low_cycles_threshold,
+    }
+}
+
+#[ic_cdk::update]
+fn set_low_cycles_threshold(threshold: u128) -> Result<(), Error> {
+    require_admin()?;
+    LOW_CYCLES_THRESHOLD.with(|cell| cell.borrow_mut().set(threshold))
+        .expect("Failed to store the low cycles threshold");
+    Ok(())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_size_bytes() -> u64 {
+    // Memory 0 is the heap, measured in 64 KiB pages
+    core::arch::wasm32::memory_size(0) as u64 * 64 * 1024
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_size_bytes() -> u64 {
+    0
+}
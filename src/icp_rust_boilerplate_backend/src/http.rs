@@ -0,0 +1,153 @@
+use crate::archive;
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::{book_side, OrderType, SwapOrder, SwapStatus, MAX_ORDER_BOOK_DEPTH};
+
+// Kept well under the query response limit so a reply is never rejected by
+// the replica after being built
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+// Order book depth when the request doesn't give one
+const DEFAULT_HTTP_DEPTH: u32 = 20;
+
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// What anyone may see of an order over HTTP. The owner and memo stay out so
+// the endpoint can't be used to follow one account's trading.
+#[derive(Serialize)]
+struct PublicOrder {
+    id: u64,
+    from_currency: String,
+    to_currency: String,
+    from_amount: u128,
+    to_amount: u128,
+    filled_amount: u128,
+    order_type: OrderType,
+    status: SwapStatus,
+    created_at: u64,
+    expires_at: Option<u64>,
+}
+
+// Iceberg orders show their current tranche only
+impl From<SwapOrder> for PublicOrder {
+    fn from(swap_order: SwapOrder) -> Self {
+        let swap_order = swap_order.public_view();
+        PublicOrder {
+            id: swap_order.id,
+            filled_amount: swap_order.filled(),
+            from_currency: swap_order.from_currency,
+            to_currency: swap_order.to_currency,
+            from_amount: swap_order.from_amount,
+            to_amount: swap_order.to_amount,
+            order_type: swap_order.order_type,
+            status: swap_order.status,
+            created_at: swap_order.created_at,
+            expires_at: swap_order.expires_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PublicOrderBook {
+    from_currency: String,
+    to_currency: String,
+    asks: Vec<PublicOrder>, // open orders selling from for to
+    bids: Vec<PublicOrder>, // open orders selling to for from
+}
+
+// Read-only JSON for explorers and bots:
+//   GET /orderbook?from=USD&to=EUR[&depth=N]
+//   GET /order/{id}
+//   GET /stats
+// Nothing tied to an account's balances is served here.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return error_response(405, "only GET is supported");
+    }
+
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    match segments.as_slice() {
+        ["orderbook"] => order_book_response(query),
+        ["order", order_id] => order_response(order_id),
+        ["stats"] => json_response(200, &crate::stats::get_stats()),
+        _ => error_response(404, "not found"),
+    }
+}
+
+fn order_book_response(query: &str) -> HttpResponse {
+    let (from_currency, to_currency) = match (query_param(query, "from"), query_param(query, "to")) {
+        (Some(from), Some(to)) => (normalize_currency(from), normalize_currency(to)),
+        _ => return error_response(400, "from and to are required"),
+    };
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return error_response(404, "unknown currency");
+        }
+    }
+    let depth = match query_param(query, "depth").map(str::parse::<u32>) {
+        None => DEFAULT_HTTP_DEPTH,
+        Some(Ok(depth)) => depth.min(MAX_ORDER_BOOK_DEPTH),
+        Some(Err(_)) => return error_response(400, "depth must be a number"),
+    } as usize;
+
+    let book = PublicOrderBook {
+        asks: book_side(&from_currency, &to_currency).take(depth).map(PublicOrder::from).collect(),
+        bids: book_side(&to_currency, &from_currency).take(depth).map(PublicOrder::from).collect(),
+        from_currency,
+        to_currency,
+    };
+    json_response(200, &book)
+}
+
+fn order_response(order_id: &str) -> HttpResponse {
+    let order = order_id
+        .parse::<u64>()
+        .ok()
+        .and_then(archive::find_order);
+    match order {
+        Some(order) => json_response(200, &PublicOrder::from(order)),
+        None => error_response(404, "order not found"),
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn json_response<T: serde::Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    let body = serde_json::to_vec(value).expect("Failed to encode the response body");
+    if body.len() > MAX_RESPONSE_BYTES {
+        return error_response(413, "response too large, request a smaller depth");
+    }
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+    }
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    json_response(status_code, &serde_json::json!({ "error": message }))
+}
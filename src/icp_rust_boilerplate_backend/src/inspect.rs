@@ -0,0 +1,258 @@
+use crate::currencies::is_well_formed_currency;
+use crate::ledgers::Account;
+use crate::{check_order_args, CreateSwapOrderArgs, DepositArgs, WithdrawArgs, MAX_ORDER_BATCH_SIZE};
+use candid::{Decode, Principal};
+
+// Every method the canister exports, sorted. Queries are listed too, since an
+// agent may call one as an update to get a certified reply.
+const METHODS: &[&str] = &[
+    "__get_candid_interface_tmp_hack",
+    "accept_counter_offer",
+    "accept_swap_order",
+    "add_admin",
+    "add_currency",
+    "add_liquidity",
+    "add_to_allowlist",
+    "add_withdrawal_destination",
+    "admin_adjust_balance",
+    "admin_cancel_order",
+    "amend_swap_order",
+    "approve_proposal",
+    "archive_finished_orders",
+    "authorize_session_key",
+    "blacklist",
+    "cancel_all_my_orders",
+    "cancel_pending_withdrawal",
+    "cancel_recurring_order",
+    "cancel_swap_order",
+    "claim_account",
+    "clear_order_creation_block",
+    "confirm_withdrawal",
+    "count_orders",
+    "count_users",
+    "create_export",
+    "create_oco_orders",
+    "create_pool",
+    "create_recurring_order",
+    "create_swap_order",
+    "create_swap_orders",
+    "deposit",
+    "deposit_from_ledger",
+    "disable_currency",
+    "execute_swap_order",
+    "export_state",
+    "finalize_import",
+    "find_order_by_memo",
+    "format_amount",
+    "get_access_mode",
+    "get_account_recovery_delay_secs",
+    "get_admin",
+    "get_admins",
+    "get_archive_min_age_secs",
+    "get_archive_retention_secs",
+    "get_cancel_ratio_limit",
+    "get_candles",
+    "get_canister_health",
+    "get_currency_ledger",
+    "get_currency_metadata",
+    "get_events",
+    "get_executable_limit_orders",
+    "get_execution_receipts",
+    "get_export_manifest",
+    "get_fee_config",
+    "get_fee_tiers",
+    "get_leaderboard",
+    "get_ledger_withdrawal",
+    "get_my_call_limit",
+    "get_my_fee_tier",
+    "get_my_liquidity",
+    "get_my_open_order_allowance",
+    "get_my_orders",
+    "get_my_pnl",
+    "get_my_recovery_setup",
+    "get_my_referral_stats",
+    "get_my_self_trade_prevention",
+    "get_my_transactions",
+    "get_my_withdrawal_allowance",
+    "get_my_withdrawal_confirmation_thresholds",
+    "get_order_book",
+    "get_order_callback",
+    "get_order_deposit",
+    "get_order_self_trades",
+    "get_orders_for_me",
+    "get_pair_config",
+    "get_pool",
+    "get_portfolio_value",
+    "get_proposal",
+    "get_rate",
+    "get_rate_config",
+    "get_recent_trades",
+    "get_recovery_status",
+    "get_referral_reward_bps",
+    "get_solvency_report",
+    "get_stats",
+    "get_swap_order",
+    "get_swap_order_certified",
+    "get_swap_orders",
+    "get_ticker",
+    "get_timer_status",
+    "get_trading_status",
+    "get_transactions_by_principal",
+    "get_user_balance",
+    "get_user_balance_certified",
+    "get_user_trading_profile",
+    "get_webhook_config",
+    "http_request",
+    "import_state",
+    "is_blacklisted",
+    "list_adjustments",
+    "list_allowlisted",
+    "list_archived_orders",
+    "list_counter_offers",
+    "list_currencies",
+    "list_dead_letters",
+    "list_my_pending_withdrawals",
+    "list_my_recurring_orders",
+    "list_my_session_keys",
+    "list_my_withdrawal_destinations",
+    "list_orders",
+    "list_pending_withdrawals",
+    "list_pools",
+    "list_proposals",
+    "list_uncredited_deposits",
+    "list_webhooks",
+    "parse_amount",
+    "pause",
+    "propose_counter_offer",
+    "prune_archive",
+    "quarantine_corrupt_record",
+    "query_orders",
+    "quote_execution",
+    "quote_swap",
+    "rebuild_book_index",
+    "register_referrer",
+    "register_webhook",
+    "reject_counter_offer",
+    "remove_admin",
+    "remove_from_allowlist",
+    "remove_liquidity",
+    "remove_pair_config",
+    "remove_recovery_principal",
+    "remove_webhook",
+    "remove_withdrawal_destination",
+    "request_account_recovery",
+    "resume_webhook",
+    "retry_deposit_credit",
+    "retry_withdrawal",
+    "revoke_session_key",
+    "scan_for_corrupt_records",
+    "set_access_mode",
+    "set_account_recovery_delay_secs",
+    "set_approval_threshold",
+    "set_archive_min_age_secs",
+    "set_archive_retention_secs",
+    "set_call_limit_override",
+    "set_cancel_ratio_limit",
+    "set_currency_ledger",
+    "set_default_pair_config",
+    "set_fee_account",
+    "set_fee_tiers",
+    "set_leaderboard_visibility",
+    "set_low_cycles_threshold",
+    "set_maker_fee_bps",
+    "set_max_calls_per_window",
+    "set_max_open_orders",
+    "set_open_order_limit_override",
+    "set_order_callback",
+    "set_order_deposit",
+    "set_pair_config",
+    "set_rate",
+    "set_rate_config",
+    "set_recovery_mode",
+    "set_recovery_principal",
+    "set_referral_reward_bps",
+    "set_self_trade_prevention",
+    "set_taker_fee_bps",
+    "set_webhook_config",
+    "set_withdrawal_confirmation_threshold",
+    "set_withdrawal_exemption",
+    "set_withdrawal_limit",
+    "settle_swap_order",
+    "swap_via_pool",
+    "tag_order",
+    "transfer",
+    "transfer_admin",
+    "transform_webhook_response",
+    "unblacklist",
+    "unpause",
+    "validate_swap_order",
+    "verify_invariants",
+    "veto_account_recovery",
+    "withdraw",
+    "withdraw_counter_offer",
+    "withdraw_fees",
+    "withdraw_to_ledger",
+];
+
+// False for calls that can only fail: unknown methods and, for the methods
+// that move funds, arguments that don't decode or fail the checks that need
+// no state. Anything that depends on balances, the registry or the book is
+// left to the endpoint.
+pub(crate) fn is_plausible_call(method: &str, arg: &[u8]) -> bool {
+    if METHODS.binary_search(&method).is_err() {
+        return false;
+    }
+    match method {
+        "deposit" => Decode!(arg, DepositArgs).is_ok_and(|args| is_plausible_amount(&args.currency, args.amount)),
+        "withdraw" => Decode!(arg, WithdrawArgs).is_ok_and(|args| is_plausible_amount(&args.currency, args.amount)),
+        "create_swap_order" => Decode!(arg, CreateSwapOrderArgs).is_ok_and(|args| check_order_args(&args).is_ok()),
+        "create_oco_orders" => Decode!(arg, CreateSwapOrderArgs, CreateSwapOrderArgs)
+            .is_ok_and(|(first, second)| check_order_args(&first).is_ok() && check_order_args(&second).is_ok()),
+        "create_recurring_order" => {
+            Decode!(arg, CreateSwapOrderArgs, u64, u32).is_ok_and(|(args, _, _)| check_order_args(&args).is_ok())
+        }
+        // Items of a batch succeed or fail on their own, so the batch is only
+        // dropped when none of them could succeed
+        "create_swap_orders" => Decode!(arg, Vec<CreateSwapOrderArgs>).is_ok_and(|batch| {
+            batch.len() <= MAX_ORDER_BATCH_SIZE && batch.iter().any(|args| check_order_args(args).is_ok())
+        }),
+        "transfer" => Decode!(arg, Principal, String, u128)
+            .is_ok_and(|(to, currency, amount)| to != Principal::anonymous() && is_plausible_amount(&currency, amount)),
+        "deposit_from_ledger" => {
+            Decode!(arg, String, u128).is_ok_and(|(currency, amount)| is_plausible_amount(&currency, amount))
+        }
+        "withdraw_to_ledger" => Decode!(arg, String, u128, Account)
+            .is_ok_and(|(currency, amount, _)| is_plausible_amount(&currency, amount)),
+        _ => true,
+    }
+}
+
+fn is_plausible_amount(currency: &str, amount: u128) -> bool {
+    amount > 0 && is_well_formed_currency(currency)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Encode;
+
+    #[test]
+    fn methods_are_sorted_for_the_binary_search() {
+        assert!(METHODS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn unknown_methods_are_dropped() {
+        assert!(!is_plausible_call("drain_everything", &Encode!().unwrap()));
+        assert!(is_plausible_call("get_stats", &Encode!().unwrap()));
+    }
+
+    #[test]
+    fn transfers_that_can_only_fail_are_dropped() {
+        let to = Principal::from_slice(&[1]);
+        assert!(is_plausible_call("transfer", &Encode!(&to, &"USD".to_string(), &100u128).unwrap()));
+        assert!(!is_plausible_call("transfer", &Encode!(&to, &"USD".to_string(), &0u128).unwrap()));
+        assert!(!is_plausible_call("transfer", &Encode!(&Principal::anonymous(), &"USD".to_string(), &100u128).unwrap()));
+        assert!(!is_plausible_call("transfer", &Encode!(&"not a transfer".to_string()).unwrap()));
+    }
+}
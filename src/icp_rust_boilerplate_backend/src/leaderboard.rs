@@ -0,0 +1,216 @@
+use crate::{admin, fee_tiers, rate_limit, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::Principal;
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::time::Duration;
+
+// Volumes of the traders on the board are recomputed this often so those who
+// stopped trading drop as their days leave the window
+pub(crate) const LEADERBOARD_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Daily buckets summed for a trader's count, the current day included, as
+// for the volume kept by fee_tiers
+const TRADE_COUNT_WINDOW_DAYS: u64 = 30;
+
+// Traders kept on the board
+const LEADERBOARD_SIZE: u64 = 100;
+
+// Groups of the principal text shown for traders who haven't opted in
+const ANONYMIZED_GROUPS: usize = 2;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LeaderboardEntry {
+    rank: u32,
+    trader: String,               // principal text, truncated unless the trader opted in
+    principal: Option<Principal>, // only for traders who opted in
+    volume_30d: u128,             // as counted for fee tiers, see get_my_fee_tier
+    trades_30d: u64,
+}
+
+thread_local! {
+    // (principal, days since epoch) -> fills the principal took part in that
+    // day. Buckets older than the window are pruned when it trades again.
+    static TRADE_COUNTS: RefCell<StableBTreeMap<(StorablePrincipal, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))
+    ));
+
+    // (u128::MAX - 30-day volume, principal) for at most LEADERBOARD_SIZE
+    // traders, so the map iterates from the top of the ranking down
+    static LEADERBOARD: RefCell<StableBTreeMap<(u128, StorablePrincipal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94)))
+    ));
+
+    // Principal -> the volume it is ranked under in LEADERBOARD
+    static LEADERBOARD_VOLUMES: RefCell<StableBTreeMap<StorablePrincipal, u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+
+    // Traders shown by name
+    static NAMED_TRADERS: RefCell<StableBTreeMap<StorablePrincipal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))
+    ));
+}
+
+// Top traders by 30-day volume, highest first
+#[ic_cdk::query]
+fn get_leaderboard(limit: u64) -> Vec<LeaderboardEntry> {
+    let today = time() / NANOS_PER_DAY;
+    let ranked: Vec<(u128, StorablePrincipal)> = LEADERBOARD.with(|board| {
+        board
+            .borrow()
+            .iter()
+            .take(limit.min(LEADERBOARD_SIZE) as usize)
+            .map(|((inverted_volume, principal), _)| (u128::MAX - inverted_volume, principal))
+            .collect()
+    });
+    ranked
+        .into_iter()
+        .zip(1..)
+        .map(|((volume_30d, principal), rank)| {
+            let named = NAMED_TRADERS.with(|named| named.borrow().contains_key(&principal));
+            let trades_30d = trade_count(&principal, today);
+            let principal = Principal::from(principal);
+            LeaderboardEntry {
+                rank,
+                trader: if named { principal.to_text() } else { anonymized(&principal) },
+                principal: named.then_some(principal),
+                volume_30d,
+                trades_30d,
+            }
+        })
+        .collect()
+}
+
+// Whether the caller is shown by principal on the leaderboard; hidden by default
+#[ic_cdk::update]
+fn set_leaderboard_visibility(visible: bool) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let principal = StorablePrincipal::from(caller());
+    NAMED_TRADERS.with(|named| {
+        if visible {
+            named.borrow_mut().insert(principal, ());
+        } else {
+            named.borrow_mut().remove(&principal);
+        }
+    });
+    Ok(())
+}
+
+// Counts a fill for the principal and re-ranks it. Called after
+// fee_tiers::record_trade_volume so the volume includes the fill.
+pub(crate) fn record_trade(principal: &StorablePrincipal) {
+    let today = time() / NANOS_PER_DAY;
+    TRADE_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let expired: Vec<(StorablePrincipal, u64)> = counts_borrowed
+            .range((principal.clone(), 0)..(principal.clone(), window_start(today)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            counts_borrowed.remove(&key);
+        }
+
+        let key = (principal.clone(), today);
+        let count = counts_borrowed.get(&key).unwrap_or(0);
+        counts_borrowed.insert(key, count + 1);
+    });
+    rank(principal, fee_tiers::rolling_volume(principal));
+}
+
+// Re-ranks the traders on the board under their current volume. A trader
+// below the board whose volume now beats one that decayed gets its place
+// back on its next fill.
+pub(crate) fn refresh_leaderboard() {
+    let ranked: Vec<StorablePrincipal> =
+        LEADERBOARD_VOLUMES.with(|volumes| volumes.borrow().iter().map(|(principal, _)| principal).collect());
+    for principal in ranked {
+        rank(&principal, fee_tiers::rolling_volume(&principal));
+    }
+}
+
+// Places the principal under `volume`, evicting the lowest entry when the
+// board is over its size
+fn rank(principal: &StorablePrincipal, volume: u128) {
+    if let Some(previous) = LEADERBOARD_VOLUMES.with(|volumes| volumes.borrow_mut().remove(principal)) {
+        LEADERBOARD.with(|board| board.borrow_mut().remove(&(u128::MAX - previous, principal.clone())));
+    }
+    if volume == 0 {
+        return;
+    }
+
+    LEADERBOARD.with(|board| {
+        let mut board_borrowed = board.borrow_mut();
+        if board_borrowed.len() >= LEADERBOARD_SIZE {
+            match board_borrowed.last_key_value() {
+                Some(((inverted_volume, lowest_principal), _)) if u128::MAX - inverted_volume < volume => {
+                    board_borrowed.remove(&(inverted_volume, lowest_principal.clone()));
+                    LEADERBOARD_VOLUMES.with(|volumes| volumes.borrow_mut().remove(&lowest_principal));
+                }
+                _ => return,
+            }
+        }
+        board_borrowed.insert((u128::MAX - volume, principal.clone()), ());
+        LEADERBOARD_VOLUMES.with(|volumes| volumes.borrow_mut().insert(principal.clone(), volume));
+    });
+}
+
+fn trade_count(principal: &StorablePrincipal, today: u64) -> u64 {
+    TRADE_COUNTS.with(|counts| {
+        counts
+            .borrow()
+            .range((principal.clone(), window_start(today))..=(principal.clone(), today))
+            .fold(0u64, |total, (_, count)| total.saturating_add(count))
+    })
+}
+
+// "abcde-fghij-…" for the principal
+fn anonymized(principal: &Principal) -> String {
+    let text = principal.to_text();
+    let shown: Vec<&str> = text.split('-').take(ANONYMIZED_GROUPS).collect();
+    format!("{}-…", shown.join("-"))
+}
+
+fn window_start(today: u64) -> u64 {
+    today.saturating_sub(TRADE_COUNT_WINDOW_DAYS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::tests::set_caller;
+
+    #[test]
+    fn anonymous_visibility_changes_are_rejected() {
+        assert_eq!(set_leaderboard_visibility(true), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn traders_can_opt_in_and_back_out_of_being_named() {
+        let trader = Principal::from_slice(&[96]);
+        set_caller(trader);
+        rate_limit::tests::exempt(trader);
+        let is_named = || NAMED_TRADERS.with(|named| named.borrow().contains_key(&StorablePrincipal::from(trader)));
+
+        assert!(!is_named());
+        assert_eq!(set_leaderboard_visibility(true), Ok(()));
+        assert!(is_named());
+        assert_eq!(set_leaderboard_visibility(false), Ok(()));
+        assert!(!is_named());
+    }
+}
@@ -0,0 +1,607 @@
+use crate::admin::{require_admin, require_authenticated, require_trading_active};
+use crate::allowlist::require_allowlisted;
+use crate::blacklist::require_not_blacklisted;
+use crate::rate_limit::check_call_rate;
+use crate::snapshot::{clear_map, entries_after};
+use crate::currencies::{is_known_currency, is_valid_currency, normalize_currency, CurrencySymbol};
+use crate::events::{record_event, EventKind};
+use crate::transactions::{record_ledger_transaction, TransactionKind};
+use crate::withdrawal_destinations;
+use crate::withdrawals::{check_withdrawal_limit, record_withdrawal, release_withdrawal};
+use crate::{BalanceChanges, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_cdk::api::{call::call, caller, id, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Most of a ledger error kept on a failed withdrawal, in bytes
+const MAX_FAILURE_REASON_BYTES: usize = 200;
+
+thread_local! {
+    // Currency -> ICRC-1 ledger canister holding the real tokens behind the
+    // internal balances. Currencies without an entry can't move on chain.
+    static LEDGERS: RefCell<StableBTreeMap<CurrencySymbol, StorablePrincipal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // Every withdrawal_to_ledger, journaled before the ledger is called so a
+    // debit whose transfer never confirmed stays visible and can be retried
+    static LEDGER_WITHDRAWALS: RefCell<StableBTreeMap<u64, LedgerWithdrawal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // Every transfer deposit_from_ledger pulled in, journaled before it is
+    // credited so tokens the canister holds are never left without a record
+    static LEDGER_DEPOSITS: RefCell<StableBTreeMap<u64, LedgerDeposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    // ICRC-1 treats a missing subaccount as the all-zero one
+    pub(crate) fn same_as(&self, other: &Account) -> bool {
+        let default_subaccount = [0u8; 32];
+        let subaccount = |account: &Account| account.subaccount.clone().unwrap_or(default_subaccount.to_vec());
+        self.owner == other.owner && subaccount(self) == subaccount(other)
+    }
+}
+
+// What makes an account one the canister will send tokens to
+pub(crate) fn check_account(account: &Account) -> Result<(), Error> {
+    if account.owner == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+    if account.subaccount.as_ref().is_some_and(|subaccount| subaccount.len() != 32) {
+        return Err(Error::InvalidAccount);
+    }
+    Ok(())
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct TransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum LedgerWithdrawalStatus {
+    Pending, // debited, the ledger transfer isn't confirmed yet
+    Completed { block_index: u64 },
+    Failed { reason: String }, // rejected by the ledger, the balance was restored
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct LedgerWithdrawal {
+    id: u64,
+    owner: Principal,
+    currency: String,
+    amount: u128, // debited from the owner, the ledger fee comes out of it
+    fee: u128,
+    to: Account,
+    created_at: u64, // also sent as created_at_time so the ledger deduplicates retries
+    status: LedgerWithdrawalStatus,
+}
+
+impl Storable for LedgerWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode LedgerWithdrawal"))
+    }
+
+    // Withdrawals journaled while amounts were u64 are widened on the way out
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), NarrowLedgerWithdrawal).map(LedgerWithdrawal::from))
+            .expect("Failed to decode LedgerWithdrawal")
+    }
+}
+
+impl BoundedStorable for LedgerWithdrawal {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// LedgerWithdrawal as stored while amounts were u64
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NarrowLedgerWithdrawal {
+    id: u64,
+    owner: Principal,
+    currency: String,
+    amount: u64,
+    fee: u64,
+    to: Account,
+    created_at: u64,
+    status: LedgerWithdrawalStatus,
+}
+
+impl From<NarrowLedgerWithdrawal> for LedgerWithdrawal {
+    fn from(narrow: NarrowLedgerWithdrawal) -> Self {
+        LedgerWithdrawal {
+            id: narrow.id,
+            owner: narrow.owner,
+            currency: narrow.currency,
+            amount: narrow.amount.into(),
+            fee: narrow.fee.into(),
+            to: narrow.to,
+            created_at: narrow.created_at,
+            status: narrow.status,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum LedgerDepositStatus {
+    Credited,
+    Uncredited { reason: String }, // held by the canister, waiting for retry_deposit_credit
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct LedgerDeposit {
+    id: u64,
+    depositor: Principal,
+    currency: String,
+    amount: u128,
+    block_index: Nat, // kept as the ledger returned it, even past 64 bits
+    created_at: u64,
+    status: LedgerDepositStatus,
+}
+
+impl Storable for LedgerDeposit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode LedgerDeposit"))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode LedgerDeposit")
+    }
+}
+
+impl BoundedStorable for LedgerDeposit {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Points a currency at its ledger; None detaches it
+#[ic_cdk::update]
+fn set_currency_ledger(currency: String, ledger: Option<Principal>) -> Result<(), Error> {
+    require_admin()?;
+    let currency = normalize_currency(&currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+
+    LEDGERS.with(|ledgers| {
+        let mut ledgers_borrowed = ledgers.borrow_mut();
+        match ledger {
+            Some(ledger) => ledgers_borrowed.insert(CurrencySymbol(currency), StorablePrincipal::from(ledger)),
+            None => ledgers_borrowed.remove(&CurrencySymbol(currency)),
+        };
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_currency_ledger(currency: String) -> Option<Principal> {
+    LEDGERS
+        .with(|ledgers| ledgers.borrow().get(&CurrencySymbol(normalize_currency(&currency))))
+        .map(Principal::from)
+}
+
+// Pulls `amount` from the caller's default account on the currency's ledger
+// into the canister and credits it to their balance. The caller must first
+// icrc2_approve this canister for the amount plus the ledger fee. Nothing is
+// credited unless the ledger confirms the transfer; returns its block index.
+// A confirmed transfer is journaled first, so one whose credit fails is kept
+// for retry_deposit_credit.
+#[ic_cdk::update]
+async fn deposit_from_ledger(currency: String, amount: u128) -> Result<u64, Error> {
+    require_authenticated()?;
+    check_call_rate()?;
+    require_not_blacklisted()?;
+    require_allowlisted()?;
+    require_trading_active()?;
+    let currency = normalize_currency(&currency);
+    if amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if !is_valid_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+    let ledger = ledger_for(&currency)?;
+
+    // The same credit is tried before any tokens move, so only a balance
+    // changed while the ledger call is out can make the real one fail
+    let depositor = caller();
+    BalanceChanges::new().credit(&StorablePrincipal::from(depositor), &currency, amount)?;
+
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: depositor, subaccount: None },
+        to: Account { owner: id(), subaccount: None },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) = call(ledger, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, message)| Error::LedgerCallFailed(format!("Ledger call rejected ({:?}): {}", code, message)))?;
+    let block_index = result.map_err(|err| Error::LedgerCallFailed(format!("Ledger returned {:?}", err)))?;
+
+    // The tokens are held by the canister from here on
+    let deposit = LEDGER_DEPOSITS.with(|deposits| {
+        let mut deposits_borrowed = deposits.borrow_mut();
+        let deposit = LedgerDeposit {
+            id: deposits_borrowed.last_key_value().map_or(1, |(last_id, _)| last_id + 1),
+            depositor,
+            currency,
+            amount,
+            block_index,
+            created_at: time(),
+            status: LedgerDepositStatus::Uncredited {
+                reason: "Not credited yet".to_string(),
+            },
+        };
+        deposits_borrowed.insert(deposit.id, deposit.clone());
+        deposit
+    });
+
+    credit_deposit(deposit)
+}
+
+// Credits a journaled deposit whose credit failed, once whatever blocked it
+// is resolved
+#[ic_cdk::update]
+fn retry_deposit_credit(deposit_id: u64) -> Result<u64, Error> {
+    require_admin()?;
+    let deposit = LEDGER_DEPOSITS
+        .with(|deposits| deposits.borrow().get(&deposit_id))
+        .ok_or(Error::DepositNotFound)?;
+    if deposit.status == LedgerDepositStatus::Credited {
+        return Err(Error::DepositAlreadyCredited);
+    }
+
+    credit_deposit(deposit)
+}
+
+#[ic_cdk::query]
+fn list_uncredited_deposits() -> Result<Vec<LedgerDeposit>, Error> {
+    require_admin()?;
+    Ok(LEDGER_DEPOSITS.with(|deposits| {
+        deposits
+            .borrow()
+            .iter()
+            .map(|(_, deposit)| deposit)
+            .filter(|deposit| deposit.status != LedgerDepositStatus::Credited)
+            .collect()
+    }))
+}
+
+// Credits the deposit and marks it credited, or records why it couldn't be
+fn credit_deposit(mut deposit: LedgerDeposit) -> Result<u64, Error> {
+    let credited = nat_to_u64(&deposit.block_index).and_then(|block_index| {
+        let mut changes = BalanceChanges::new();
+        changes.credit(&StorablePrincipal::from(deposit.depositor), &deposit.currency, deposit.amount)?;
+        changes.commit();
+        Ok(block_index)
+    });
+
+    match credited {
+        Ok(block_index) => {
+            record_ledger_transaction(
+                TransactionKind::Deposit,
+                None,
+                Some(deposit.depositor),
+                &deposit.currency,
+                deposit.amount,
+                block_index,
+            );
+            record_event(EventKind::Deposit {
+                principal: deposit.depositor,
+                currency: deposit.currency.clone(),
+                amount: deposit.amount,
+            });
+            deposit.status = LedgerDepositStatus::Credited;
+            store_deposit(deposit);
+            Ok(block_index)
+        }
+        Err(error) => {
+            let mut reason = format!("{:?}", error);
+            while reason.len() > MAX_FAILURE_REASON_BYTES {
+                reason.pop();
+            }
+            deposit.status = LedgerDepositStatus::Uncredited { reason };
+            store_deposit(deposit);
+            Err(error)
+        }
+    }
+}
+
+fn store_deposit(deposit: LedgerDeposit) {
+    LEDGER_DEPOSITS.with(|deposits| deposits.borrow_mut().insert(deposit.id, deposit));
+}
+
+// Sends `amount` less the ledger fee from the canister to `to_account` and
+// returns the ledger block index. Once the caller registers a withdrawal
+// destination, only active destinations are accepted. The balance is debited and the withdrawal
+// journaled before the ledger is called. A transfer the ledger rejects is
+// rolled back; one whose outcome is unknown stays pending for retry_withdrawal.
+#[ic_cdk::update]
+async fn withdraw_to_ledger(currency: String, amount: u128, to_account: Account) -> Result<u64, Error> {
+    require_authenticated()?;
+    check_call_rate()?;
+    require_not_blacklisted()?;
+    let currency = normalize_currency(&currency);
+    if amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+    check_account(&to_account)?;
+    withdrawal_destinations::check_destination(&StorablePrincipal::from(caller()), &currency, &to_account)?;
+    let ledger = ledger_for(&currency)?;
+
+    let (fee,): (Nat,) = call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| Error::LedgerCallFailed(format!("Ledger call rejected ({:?}): {}", code, message)))?;
+    let fee = nat_to_u128(&fee)?;
+    if amount <= fee {
+        return Err(Error::InvalidAmount);
+    }
+
+    // Checked after the fee call, balances may have moved while it was out
+    let owner = StorablePrincipal::from(caller());
+    check_withdrawal_limit(&owner, &currency, amount)?;
+    let mut changes = BalanceChanges::new();
+    changes.debit(&owner, &currency, amount)?;
+    changes.commit();
+    record_withdrawal(&owner, &currency, amount);
+
+    let withdrawal = LEDGER_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals_borrowed = withdrawals.borrow_mut();
+        let withdrawal = LedgerWithdrawal {
+            id: withdrawals_borrowed.last_key_value().map_or(1, |(last_id, _)| last_id + 1),
+            owner: caller(),
+            currency,
+            amount,
+            fee,
+            to: to_account,
+            created_at: time(),
+            status: LedgerWithdrawalStatus::Pending,
+        };
+        withdrawals_borrowed.insert(withdrawal.id, withdrawal.clone());
+        withdrawal
+    });
+
+    send_withdrawal(withdrawal).await
+}
+
+// Sends a pending withdrawal again with its original ledger arguments, so a
+// transfer that did go through is reported as a duplicate, not paid twice
+#[ic_cdk::update]
+async fn retry_withdrawal(withdrawal_id: u64) -> Result<u64, Error> {
+    require_admin()?;
+    let withdrawal = LEDGER_WITHDRAWALS
+        .with(|withdrawals| withdrawals.borrow().get(&withdrawal_id))
+        .ok_or(Error::WithdrawalNotFound)?;
+    if withdrawal.status != LedgerWithdrawalStatus::Pending {
+        return Err(Error::WithdrawalNotPending);
+    }
+
+    send_withdrawal(withdrawal).await
+}
+
+// Visible to the withdrawal's owner and the admin
+#[ic_cdk::query]
+fn get_ledger_withdrawal(withdrawal_id: u64) -> Option<LedgerWithdrawal> {
+    LEDGER_WITHDRAWALS
+        .with(|withdrawals| withdrawals.borrow().get(&withdrawal_id))
+        .filter(|withdrawal| withdrawal.owner == caller() || require_admin().is_ok())
+}
+
+#[ic_cdk::query]
+fn list_pending_withdrawals() -> Result<Vec<LedgerWithdrawal>, Error> {
+    require_admin()?;
+    Ok(LEDGER_WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .iter()
+            .map(|(_, withdrawal)| withdrawal)
+            .filter(|withdrawal| withdrawal.status == LedgerWithdrawalStatus::Pending)
+            .collect()
+    }))
+}
+
+// Up to `limit` journaled withdrawals after the id `after`, for create_export
+pub(crate) fn export_withdrawals(after: Option<u64>, limit: usize) -> Vec<(u64, LedgerWithdrawal)> {
+    LEDGER_WITHDRAWALS.with(|withdrawals| entries_after(&withdrawals.borrow(), after, limit))
+}
+
+// Up to `limit` journaled deposits after the id `after`, for create_export
+pub(crate) fn export_deposits(after: Option<u64>, limit: usize) -> Vec<(u64, LedgerDeposit)> {
+    LEDGER_DEPOSITS.with(|deposits| entries_after(&deposits.borrow(), after, limit))
+}
+
+// Replaces both journals with those of an imported snapshot
+pub(crate) fn restore_journals(withdrawals: Vec<LedgerWithdrawal>, deposits: Vec<LedgerDeposit>) {
+    LEDGER_WITHDRAWALS.with(|journal| {
+        let mut journal_borrowed = journal.borrow_mut();
+        clear_map(&mut journal_borrowed);
+        for withdrawal in withdrawals {
+            journal_borrowed.insert(withdrawal.id, withdrawal);
+        }
+    });
+    LEDGER_DEPOSITS.with(|journal| {
+        let mut journal_borrowed = journal.borrow_mut();
+        clear_map(&mut journal_borrowed);
+        for deposit in deposits {
+            journal_borrowed.insert(deposit.id, deposit);
+        }
+    });
+}
+
+async fn send_withdrawal(withdrawal: LedgerWithdrawal) -> Result<u64, Error> {
+    let ledger = ledger_for(&withdrawal.currency)?;
+    let args = TransferArgs {
+        from_subaccount: None,
+        to: withdrawal.to.clone(),
+        amount: Nat::from(withdrawal.amount - withdrawal.fee),
+        fee: Some(Nat::from(withdrawal.fee)),
+        memo: Some(withdrawal.id.to_be_bytes().to_vec()),
+        created_at_time: Some(withdrawal.created_at),
+    };
+    let (result,): (Result<Nat, TransferError>,) = call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, message)| Error::LedgerCallFailed(format!("Ledger call rejected ({:?}): {}", code, message)))?;
+
+    // A retry may have settled the withdrawal while this call was out
+    let mut withdrawal = LEDGER_WITHDRAWALS
+        .with(|withdrawals| withdrawals.borrow().get(&withdrawal.id))
+        .expect("Journaled withdrawals are never removed");
+    match withdrawal.status {
+        LedgerWithdrawalStatus::Pending => {}
+        LedgerWithdrawalStatus::Completed { block_index } => return Ok(block_index),
+        LedgerWithdrawalStatus::Failed { .. } => return Err(Error::WithdrawalNotPending),
+    }
+
+    match result {
+        Ok(block_index) | Err(TransferError::Duplicate { duplicate_of: block_index }) => {
+            let block_index = nat_to_u64(&block_index)?;
+            withdrawal.status = LedgerWithdrawalStatus::Completed { block_index };
+            record_ledger_transaction(
+                TransactionKind::Withdrawal,
+                Some(withdrawal.owner),
+                None,
+                &withdrawal.currency,
+                withdrawal.amount,
+                block_index,
+            );
+            record_event(EventKind::Withdrawal {
+                principal: withdrawal.owner,
+                currency: withdrawal.currency.clone(),
+                amount: withdrawal.amount,
+            });
+            store_withdrawal(withdrawal);
+            Ok(block_index)
+        }
+        // Past the ledger's deduplication window a resend could pay twice, so
+        // this one has to be reconciled by hand
+        Err(TransferError::TooOld) => Err(Error::LedgerCallFailed(format!(
+            "Withdrawal {} is too old to resend safely",
+            withdrawal.id
+        ))),
+        Err(err) => {
+            let owner = StorablePrincipal::from(withdrawal.owner);
+            let mut changes = BalanceChanges::new();
+            changes.credit(&owner, &withdrawal.currency, withdrawal.amount)?;
+            changes.commit();
+            release_withdrawal(&owner, &withdrawal.currency, withdrawal.created_at, withdrawal.amount);
+
+            let reason = format!("Ledger returned {:?}", err);
+            let mut stored_reason = reason.clone();
+            while stored_reason.len() > MAX_FAILURE_REASON_BYTES {
+                stored_reason.pop();
+            }
+            withdrawal.status = LedgerWithdrawalStatus::Failed { reason: stored_reason };
+            store_withdrawal(withdrawal);
+            Err(Error::LedgerCallFailed(reason))
+        }
+    }
+}
+
+fn store_withdrawal(withdrawal: LedgerWithdrawal) {
+    LEDGER_WITHDRAWALS.with(|withdrawals| withdrawals.borrow_mut().insert(withdrawal.id, withdrawal));
+}
+
+fn ledger_for(currency: &str) -> Result<Principal, Error> {
+    LEDGERS
+        .with(|ledgers| ledgers.borrow().get(&CurrencySymbol(currency.to_string())))
+        .map(Principal::from)
+        .ok_or_else(|| Error::LedgerNotConfigured { currency: currency.to_string() })
+}
+
+fn nat_to_u64(value: &Nat) -> Result<u64, Error> {
+    u64::try_from(&value.0).map_err(|_| Error::LedgerCallFailed(format!("Ledger returned {}, more than 64 bits", value)))
+}
+
+fn nat_to_u128(value: &Nat) -> Result<u128, Error> {
+    u128::try_from(&value.0)
+        .map_err(|_| Error::LedgerCallFailed(format!("Ledger returned {}, more than 128 bits", value)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tests::run_now;
+
+    pub(crate) fn account(id: u8) -> Account {
+        Account {
+            owner: Principal::from_slice(&[id]),
+            subaccount: None,
+        }
+    }
+
+    #[test]
+    fn anonymous_ledger_deposits_are_rejected() {
+        assert_eq!(run_now(deposit_from_ledger("USD".to_string(), 100)), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_ledger_withdrawals_are_rejected() {
+        assert_eq!(
+            run_now(withdraw_to_ledger("USD".to_string(), 100, account(1))),
+            Err(Error::AnonymousNotAllowed)
+        );
+    }
+}
@@ -1,38 +1,392 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+
+mod account_recovery;
+mod adjustments;
+mod admin;
+mod allowlist;
+mod amounts;
+mod archive;
+mod blacklist;
+mod candles;
+mod certification;
+mod counter_offers;
+mod currencies;
+mod dead_letters;
+mod dedup;
+mod events;
+mod fee_tiers;
+mod fees;
+mod fill_callbacks;
+mod health;
+mod http;
+mod inspect;
+mod leaderboard;
+mod ledgers;
+mod limit_scan;
+mod matching;
+mod oco;
+mod order_deposits;
+mod order_labels;
+mod order_limits;
+mod order_queries;
+mod pairs;
+mod pending_withdrawals;
+mod pnl;
+mod pools;
+mod portfolio;
+mod proposals;
+mod quotes;
+mod rate_limit;
+mod rates;
+mod receipts;
+mod recurring;
+mod referrals;
+mod self_trade;
+mod session_keys;
+mod snapshot;
+mod solvency;
+mod stats;
+mod ticker;
+mod trade_feed;
+mod trading_profiles;
+mod transactions;
+mod webhooks;
+mod withdrawal_destinations;
+mod withdrawals;
+
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use admin::tests::caller;
+#[cfg(not(test))]
 use ic_cdk::api::caller;
+#[cfg(test)]
+use admin::tests::time;
+#[cfg(not(test))]
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use lazy_static::lazy_static;
-use regex::Regex;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+use account_recovery::RecoverySetup;
+use adjustments::AdjustmentsPage;
+use admin::{AdminSet, TradingStatus};
+use allowlist::{AccessMode, AllowlistPage};
+use amounts::{cmp_products, mul_div_ceil, price_key, PRICE_KEY_BYTES};
+use candles::{Candles, Resolution};
+use certification::{CertifiedBalances, CertifiedOrder};
+use counter_offers::CounterOffer;
+use currencies::{is_known_currency, is_valid_currency, is_well_formed_currency, normalize_currency, AddCurrencyArgs, CurrencyInfo, CurrencySymbol};
+use dead_letters::{CorruptRecordScan, DeadLettersPage, RecordKey, RecordMap};
+use events::{record_event, EventKind, EventsPage};
+use fee_tiers::{FeeTier, FeeTierStatus};
+use fees::FeeConfig;
+use fill_callbacks::{FillCallback, OrderCallback};
+use health::CanisterHealth;
+use http::{HttpRequest, HttpResponse};
+use leaderboard::LeaderboardEntry;
+use ledgers::{Account, LedgerDeposit, LedgerWithdrawal};
+use limit_scan::TimerStatus;
+use matching::BookFill;
+use order_deposits::OrderDeposit;
+use order_labels::OrderLabels;
+use order_limits::OpenOrderAllowance;
+use order_queries::OrderFilter;
+use pairs::PairConfig;
+use pending_withdrawals::{ConfirmationThreshold, PendingWithdrawal, WithdrawOutcome};
+use pnl::PairPnl;
+use pools::{LiquidityPosition, LiquidityReceipt, Pool, PoolPair, PoolSwapReceipt};
+use portfolio::PortfolioValue;
+use proposals::{Proposal, ProposalsPage};
+use quotes::SwapQuotes;
+use rate_limit::CallLimit;
+use rates::{ExchangeRate, RateConfig};
+use receipts::{store_receipt, ExecutionReceipt};
+use recurring::RecurringOrder;
+use referrals::ReferralStats;
+use self_trade::{SelfTradePrevention, SelfTradeRecord};
+use session_keys::{SessionAction, SessionKey, SessionPermissions};
+use snapshot::{ExportChunk, ExportManifest, ExportProgress, RecoveryStatus};
+#[cfg(debug_assertions)]
+use solvency::InvariantReport;
+use solvency::SolvencyReport;
+use stats::Stats;
+use ticker::Ticker;
+use trade_feed::RecentTrade;
+use trading_profiles::{CancelRatioLimit, TradingProfile};
+use transactions::{record_transaction, TransactionKind, TransactionsPage};
+use withdrawal_destinations::WithdrawalDestination;
+use webhooks::{WebhookConfig, WebhookInfo};
+use withdrawals::WithdrawalAllowance;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
-type BalanceCell = Cell<u64, Memory>;
+
+// Balances held by accounts created before per-currency tracking are credited
+// to this currency when they are migrated on upgrade.
+const LEGACY_CURRENCY: &str = "USD";
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
 struct UserAccount {
-    balance: u64, // balance in smallest denomination
+    balances: BTreeMap<String, u128>,       // currency code -> available balance in smallest denomination
+    locked: Option<BTreeMap<String, u128>>, // currency code -> amount escrowed by open orders
+}
+
+impl UserAccount {
+    fn balance(&self, currency: &str) -> u128 {
+        bucket_amount(&self.balances, currency)
+    }
+
+    fn locked(&self, currency: &str) -> u128 {
+        self.locked.as_ref().map_or(0, |locked| bucket_amount(locked, currency))
+    }
+
+    fn credit(&mut self, currency: &str, amount: u128) -> Result<(), Error> {
+        add_to_bucket(&mut self.balances, currency, amount)
+    }
+
+    fn debit(&mut self, currency: &str, amount: u128) -> Result<(), Error> {
+        subtract_from_bucket(&mut self.balances, currency, amount)
+    }
+
+    // Moves available funds into escrow
+    fn lock(&mut self, currency: &str, amount: u128) -> Result<(), Error> {
+        self.debit(currency, amount)?;
+        add_to_bucket(self.locked.get_or_insert_with(BTreeMap::new), currency, amount)
+    }
+
+    // Takes funds out of escrow without returning them to the available balance
+    fn release(&mut self, currency: &str, amount: u128) -> Result<(), Error> {
+        subtract_from_bucket(self.locked.get_or_insert_with(BTreeMap::new), currency, amount)
+    }
+
+    // Returns escrowed funds to the available balance
+    fn unlock(&mut self, currency: &str, amount: u128) -> Result<(), Error> {
+        self.release(currency, amount)?;
+        self.credit(currency, amount)
+    }
+}
+
+fn bucket_amount(buckets: &BTreeMap<String, u128>, currency: &str) -> u128 {
+    buckets.get(currency).copied().unwrap_or(0)
+}
+
+fn add_to_bucket(buckets: &mut BTreeMap<String, u128>, currency: &str, amount: u128) -> Result<(), Error> {
+    if amount == 0 {
+        return Ok(());
+    }
+    let total = bucket_amount(buckets, currency).checked_add(amount).ok_or(Error::Overflow)?;
+    buckets.insert(currency.to_string(), total);
+    Ok(())
+}
+
+fn subtract_from_bucket(buckets: &mut BTreeMap<String, u128>, currency: &str, amount: u128) -> Result<(), Error> {
+    let held = bucket_amount(buckets, currency);
+    let remaining = held.checked_sub(amount).ok_or_else(|| Error::InsufficientFunds {
+        required: amount,
+        available: held,
+        currency: currency.to_string(),
+    })?;
+    if remaining == 0 {
+        // Drop empty buckets so the encoded account stays small
+        buckets.remove(currency);
+    } else {
+        buckets.insert(currency.to_string(), remaining);
+    }
+    Ok(())
+}
+
+// Fails with what is missing unless the principal has `required` of
+// `currency` available, whether or not it has an account yet. Lets callers
+// report a shortfall before they start building any balance changes.
+fn require_available(principal: &StorablePrincipal, currency: &str, required: u128) -> Result<(), Error> {
+    let available = USER_ACCOUNTS
+        .with(|accounts| accounts.borrow().get(principal))
+        .map_or(0, |user_account| user_account.balance(currency));
+    if available < required {
+        return Err(Error::InsufficientFunds {
+            required,
+            available,
+            currency: currency.to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Balance mutations spanning one or more accounts. Changes are applied to
+// copies of the accounts held here and only written back by `commit`, so an
+// error halfway through a multi-leg operation leaves stable memory untouched.
+struct BalanceChanges {
+    accounts: BTreeMap<StorablePrincipal, UserAccount>,
+}
+
+impl BalanceChanges {
+    fn new() -> Self {
+        BalanceChanges { accounts: BTreeMap::new() }
+    }
+
+    fn load(&mut self, principal: &StorablePrincipal) -> Option<&mut UserAccount> {
+        if !self.accounts.contains_key(principal) {
+            let stored = USER_ACCOUNTS.with(|accounts| accounts.borrow().get(principal))?;
+            self.accounts.insert(principal.clone(), stored);
+        }
+        self.accounts.get_mut(principal)
+    }
+
+    // Credits create the account if the principal has never held funds.
+    // Credits and locks can add a currency to the account, so they check it
+    // still fits.
+    fn credit(&mut self, principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+        let user_account = self
+            .accounts
+            .entry(principal.clone())
+            .or_insert_with(|| USER_ACCOUNTS.with(|accounts| accounts.borrow().get(principal)).unwrap_or_default());
+        user_account.credit(currency, amount)?;
+        validate_encoded_size(user_account)
+    }
+
+    fn debit(&mut self, principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+        self.load(principal).ok_or(Error::UserNotFound)?.debit(currency, amount)
+    }
+
+    fn lock(&mut self, principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+        let user_account = self.load(principal).ok_or(Error::UserNotFound)?;
+        user_account.lock(currency, amount)?;
+        validate_encoded_size(user_account)
+    }
+
+    fn release(&mut self, principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+        self.load(principal).ok_or(Error::UserNotFound)?.release(currency, amount)
+    }
+
+    fn unlock(&mut self, principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+        self.load(principal).ok_or(Error::UserNotFound)?.unlock(currency, amount)
+    }
+
+    fn commit(self) {
+        certification::certify_accounts(&self.accounts);
+        USER_ACCOUNTS.with(|accounts| {
+            let mut accounts_borrowed = accounts.borrow_mut();
+            for (principal, user_account) in self.accounts {
+                debug_assert!(validate_encoded_size(&user_account).is_ok(), "Account outgrew its bound");
+                let previous = accounts_borrowed.get(&principal).unwrap_or_default();
+                solvency::record_account_change(&previous, &user_account);
+                accounts_borrowed.insert(principal, user_account);
+            }
+        });
+    }
 }
 
 impl Storable for UserAccount {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).expect("Failed to encode UserAccount"))
     }
 
+    // Accounts written while amounts were u64 fail to decode as the current
+    // type and are widened; one with no available balance can decode with its
+    // locked amounts dropped, which rebuild_locked_balances restores
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).expect("Failed to decode UserAccount")
+        UserAccount::try_from_bytes(&bytes).expect("Failed to decode UserAccount")
+    }
+}
+
+impl UserAccount {
+    // from_bytes without trapping, for scan_for_corrupt_records
+    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<UserAccount, candid::Error> {
+        Decode!(bytes, Self).or_else(|_| Decode!(bytes, NarrowUserAccount).map(UserAccount::from))
     }
 }
 
 impl BoundedStorable for UserAccount {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A record encoded past its MAX_SIZE traps the stable map insert, so writes
+// that can grow a record check it first and fail with RecordTooLarge instead
+pub(crate) fn validate_encoded_size<T: BoundedStorable>(value: &T) -> Result<(), Error> {
+    let size = value.to_bytes().len();
+    if size > T::MAX_SIZE as usize {
+        return Err(Error::RecordTooLarge {
+            size: size as u64,
+            max_size: T::MAX_SIZE,
+        });
+    }
+    Ok(())
+}
+
+// UserAccount as stored while amounts were u64
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NarrowUserAccount {
+    balances: BTreeMap<String, u64>,
+    locked: Option<BTreeMap<String, u64>>,
+}
+
+impl From<NarrowUserAccount> for UserAccount {
+    fn from(narrow: NarrowUserAccount) -> Self {
+        let widen = |buckets: BTreeMap<String, u64>| -> BTreeMap<String, u128> {
+            buckets.into_iter().map(|(currency, amount)| (currency, amount.into())).collect()
+        };
+        UserAccount {
+            balances: widen(narrow.balances),
+            locked: narrow.locked.map(widen),
+        }
+    }
+}
+
+// Single-balance account layout stored in MemoryId 0 before balances were
+// tracked per currency. Only read by the upgrade migration.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+struct LegacyUserAccount {
+    balance: u64,
+}
+
+impl Storable for LegacyUserAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode LegacyUserAccount"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode LegacyUserAccount")
+    }
+}
+
+impl BoundedStorable for LegacyUserAccount {
     const MAX_SIZE: u32 = 128;
     const IS_FIXED_SIZE: bool = false;
 }
 
+// Last order id handed out, 0 before the first order. Ids start at 1 and are
+// never reused.
+struct OrderIdCounter(Cell<u64, Memory>);
+
+impl OrderIdCounter {
+    fn init(memory: Memory) -> Self {
+        OrderIdCounter(Cell::init(memory, 0).expect("Cannot create the order id counter"))
+    }
+
+    fn last_id(&self) -> u64 {
+        *self.0.get()
+    }
+
+    // Running out of u64 ids fails every new order instead of wrapping back to
+    // ids already in use
+    fn next_id(&mut self) -> Result<u64, Error> {
+        let next_id = self.last_id().checked_add(1).ok_or(Error::OrderIdsExhausted)?;
+        self.set_last_id(next_id)?;
+        Ok(next_id)
+    }
+
+    fn set_last_id(&mut self, last_id: u64) -> Result<(), Error> {
+        self.0.set(last_id).map(|_| ()).map_err(|_| Error::StorageWriteFailed)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 struct StorablePrincipal(candid::Principal);
 
@@ -42,6 +396,14 @@ impl From<candid::Principal> for StorablePrincipal {
     }
 }
 
+// Tuple keys need a default for each element; the anonymous principal never
+// owns anything, so it works as a placeholder
+impl Default for StorablePrincipal {
+    fn default() -> Self {
+        StorablePrincipal(candid::Principal::anonymous())
+    }
+}
+
 impl From<StorablePrincipal> for candid::Principal {
     fn from(storable: StorablePrincipal) -> Self {
         storable.0
@@ -49,7 +411,7 @@ impl From<StorablePrincipal> for candid::Principal {
 }
 
 impl Storable for StorablePrincipal {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(self.0.as_slice().to_vec())
     }
 
@@ -63,333 +425,4049 @@ impl BoundedStorable for StorablePrincipal {
     const IS_FIXED_SIZE: bool = false;
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-enum OrderType {
-    Market,
-    Limit { price: f64 },
-}
-
-impl Default for OrderType {
-    fn default() -> Self {
-        OrderType::Market
-    }
-}
-
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct SwapOrder {
-    id: u64,
-    owner: candid::Principal,
+// Currency pair used as a stable map key, encoded as "FROM/TO"
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct CurrencyPair {
     from_currency: String,
     to_currency: String,
-    from_amount: u64,
-    to_amount: u64,
-    order_type: OrderType,
-    created_at: u64,
-    status: SwapStatus,
 }
 
-impl Default for SwapOrder {
-    fn default() -> Self {
-        SwapOrder {
-            id: 0,
-            owner: candid::Principal::anonymous(),
-            from_currency: String::default(),
-            to_currency: String::default(),
-            from_amount: 0,
-            to_amount: 0,
-            order_type: OrderType::default(),
-            created_at: 0,
-            status: SwapStatus::default(),
+impl CurrencyPair {
+    fn new(from_currency: &str, to_currency: &str) -> Self {
+        CurrencyPair {
+            from_currency: from_currency.to_string(),
+            to_currency: to_currency.to_string(),
         }
     }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
-enum SwapStatus {
-    Created,
-    Executed,
-    Cancelled,
-}
-
-impl Default for SwapStatus {
-    fn default() -> Self {
-        SwapStatus::Created
-    }
-}
-
-impl Storable for SwapOrder {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).expect("Failed to encode SwapOrder"))
+impl Storable for CurrencyPair {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(format!("{}/{}", self.from_currency, self.to_currency).into_bytes())
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).expect("Failed to decode SwapOrder")
+        let encoded = String::from_utf8(bytes.into_owned()).expect("Failed to decode CurrencyPair");
+        let (from_currency, to_currency) = encoded.split_once('/').expect("Failed to decode CurrencyPair");
+        CurrencyPair::new(from_currency, to_currency)
     }
 }
 
-impl BoundedStorable for SwapOrder {
-    const MAX_SIZE: u32 = 512;
+impl BoundedStorable for CurrencyPair {
+    const MAX_SIZE: u32 = 32;
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
-
-    static USER_ACCOUNTS: RefCell<StableBTreeMap<StorablePrincipal, UserAccount, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
-    ));
-
-    static SWAP_ORDERS: RefCell<StableBTreeMap<u64, SwapOrder, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
-
-    static ORDER_COUNTER: RefCell<BalanceCell> = RefCell::new(
-        BalanceCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))), 0)
-            .expect("Cannot create a counter")
-    );
+// Which way an order trades its pair, taking the pair's currencies in
+// alphabetical order as base and quote
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum BookSide {
+    Ask, // sells the base currency for the quote currency
+    Bid, // sells the quote currency for the base currency
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize)]
-struct DepositArgs {
-    amount: u64,
-    currency: String,
+// Order book index key. Open orders are grouped by pair and side, then sorted
+// by price, best for a taker first: asks by ascending and bids by descending
+// quote per base. Book priority and id come last so orders at the same price
+// keep time priority. Keys compare field by field, which is also how their
+// bytes sort.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct BookKey {
+    pair: CurrencyPair, // base and quote, whatever the side
+    side: BookSide,
+    price: [u8; PRICE_KEY_BYTES], // see price_key, inverted for bids
+    priority: u64,
+    order_id: u64,
 }
 
-#[ic_cdk::update]
-fn deposit(args: DepositArgs) -> Result<(), Error> {
-    if args.amount == 0 {
-        return Err(Error::InvalidAmount);
+impl BookKey {
+    fn for_order(swap_order: &SwapOrder) -> Self {
+        let (pair, side) = BookKey::side_of(&swap_order.from_currency, &swap_order.to_currency);
+        // Quote per base, to_amount / from_amount for an ask
+        let price = match side {
+            BookSide::Ask => price_key(swap_order.to_amount, swap_order.from_amount),
+            BookSide::Bid => price_key(swap_order.from_amount, swap_order.to_amount).map(|byte| !byte),
+        };
+        BookKey {
+            pair,
+            side,
+            price,
+            priority: swap_order.book_priority(),
+            order_id: swap_order.id,
+        }
     }
-    if !is_valid_currency(&args.currency) {
-        return Err(Error::InvalidCurrency);
+
+    // The pair and side of orders selling `from_currency` for `to_currency`
+    fn side_of(from_currency: &str, to_currency: &str) -> (CurrencyPair, BookSide) {
+        if from_currency <= to_currency {
+            (CurrencyPair::new(from_currency, to_currency), BookSide::Ask)
+        } else {
+            (CurrencyPair::new(to_currency, from_currency), BookSide::Bid)
+        }
     }
 
-    let caller_principal = StorablePrincipal::from(caller());
-    USER_ACCOUNTS.with(|accounts| {
-        let mut accounts_borrowed = accounts.borrow_mut();
-        let mut user_account = accounts_borrowed.get(&caller_principal).as_ref().cloned().unwrap_or_default();
-        user_account.balance += args.amount;
-        accounts_borrowed.insert(caller_principal, user_account);
-    });
+    // Sorts before every order on the side, for starting a range scan
+    fn first_of_side(from_currency: &str, to_currency: &str) -> Self {
+        let (pair, side) = BookKey::side_of(from_currency, to_currency);
+        BookKey {
+            pair,
+            side,
+            price: [0; PRICE_KEY_BYTES],
+            priority: 0,
+            order_id: 0,
+        }
+    }
 
-    Ok(())
+    fn same_side(&self, other: &BookKey) -> bool {
+        self.pair == other.pair && self.side == other.side
+    }
 }
 
-#[derive(candid::CandidType, Serialize, Deserialize)]
-struct CreateSwapOrderArgs {
-    from_currency: String,
-    to_currency: String,
-    from_amount: u64,
-    to_amount: u64,
-    order_type: OrderType,
+// Stable map tuple keys need a default; no order has this one
+impl Default for BookKey {
+    fn default() -> Self {
+        BookKey::first_of_side("", "")
+    }
 }
 
-#[ic_cdk::update]
-fn create_swap_order(args: CreateSwapOrderArgs) -> Result<u64, Error> {
-    if args.from_amount == 0 || args.to_amount == 0 {
-        return Err(Error::InvalidAmount);
-    }
-    if !is_valid_currency(&args.from_currency) || !is_valid_currency(&args.to_currency) {
-        return Err(Error::InvalidCurrency);
-    }
-    if let OrderType::Limit { price } = &args.order_type {
-        if *price <= 0.0 {
-            return Err(Error::InvalidPrice);
+// Encoded as the base and quote currencies each padded with zeros to
+// CurrencySymbol::MAX_SIZE, the side as one byte, the price key, and priority
+// and order id in big-endian. Currency symbols never contain a zero byte, so
+// the padding sorts a symbol before any longer one it is a prefix of, as
+// String's Ord does.
+impl Storable for BookKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(BookKey::MAX_SIZE as usize);
+        for currency in [&self.pair.from_currency, &self.pair.to_currency] {
+            let mut padded = [0u8; CurrencySymbol::MAX_SIZE as usize];
+            padded[..currency.len()].copy_from_slice(currency.as_bytes());
+            bytes.extend_from_slice(&padded);
         }
+        bytes.push(self.side as u8);
+        bytes.extend_from_slice(&self.price);
+        bytes.extend_from_slice(&self.priority.to_be_bytes());
+        bytes.extend_from_slice(&self.order_id.to_be_bytes());
+        Cow::Owned(bytes)
     }
 
-    let caller_principal = StorablePrincipal::from(caller());
-    let _user_account = USER_ACCOUNTS.with(|accounts| {
-        let mut accounts_borrowed = accounts.borrow_mut();
-        let mut user_account = accounts_borrowed.get(&caller_principal).as_ref().cloned().unwrap_or_else(|| {
-            accounts_borrowed.insert(caller_principal.clone(), UserAccount::default());
-            accounts_borrowed.get(&caller_principal).as_ref().cloned().unwrap()
-        });
-
-        if user_account.balance < args.from_amount {
-            Err(Error::InsufficientFunds)
-        } else {
-            user_account.balance -= args.from_amount;
-            accounts_borrowed.insert(caller_principal, user_account.clone());
-            Ok(user_account)
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        const SYMBOL_BYTES: usize = CurrencySymbol::MAX_SIZE as usize;
+        const PRICE_AT: usize = 2 * SYMBOL_BYTES + 1;
+        let read_currency = |offset: usize| {
+            let padded = &bytes[offset..offset + SYMBOL_BYTES];
+            let len = padded.iter().position(|byte| *byte == 0).unwrap_or(SYMBOL_BYTES);
+            String::from_utf8(padded[..len].to_vec()).expect("Failed to decode BookKey")
+        };
+        let read_u64 = |offset: usize| {
+            u64::from_be_bytes(bytes[offset..offset + 8].try_into().expect("Failed to decode BookKey"))
+        };
+        BookKey {
+            pair: CurrencyPair {
+                from_currency: read_currency(0),
+                to_currency: read_currency(SYMBOL_BYTES),
+            },
+            side: if bytes[2 * SYMBOL_BYTES] == 0 { BookSide::Ask } else { BookSide::Bid },
+            price: bytes[PRICE_AT..PRICE_AT + PRICE_KEY_BYTES].try_into().expect("Failed to decode BookKey"),
+            priority: read_u64(PRICE_AT + PRICE_KEY_BYTES),
+            order_id: read_u64(PRICE_AT + PRICE_KEY_BYTES + 8),
         }
-    })?;
-
-    let order_id = ORDER_COUNTER.with(|counter| -> Result<u64, Error> {
-        let binding = counter.borrow();
-        let current_value = binding.get();
-        let new_value = current_value + 1;
-        counter.borrow_mut().set(new_value).map_err(|_| Error::InvalidAmount)?;
-        Ok(new_value)
-    })?;
-       
-    let swap_order = SwapOrder {
-        id: order_id,
-        owner: caller(),
-        from_currency: args.from_currency,
-        to_currency: args.to_currency,
-        from_amount: args.from_amount,
-        to_amount: args.to_amount,
-        order_type: args.order_type,
-        created_at: time(),
-        status: SwapStatus::Created,
-    };
+    }
+}
 
-    SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(order_id, swap_order));
+impl BoundedStorable for BookKey {
+    const MAX_SIZE: u32 = 2 * CurrencySymbol::MAX_SIZE + 1 + PRICE_KEY_BYTES as u32 + 16;
+    const IS_FIXED_SIZE: bool = true;
+}
 
-    Ok(order_id)
+// Units of to_currency per unit of from_currency as an exact fraction, so
+// prices compare the same way on every replica
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+struct Price {
+    numerator: u64,
+    denominator: u64,
 }
 
-#[ic_cdk::update]
-fn execute_swap_order(order_id: u64) -> Result<(), Error> {
-    let executor_principal = StorablePrincipal::from(caller());
-    if executor_principal == StorablePrincipal::from(candid::Principal::anonymous()) {
-        return Err(Error::AnonymousNotAllowed);
+// Orders stored with an f64 price are converted at this scale, which keeps
+// the 8 decimals XRC quotes with
+const LEGACY_PRICE_SCALE: u64 = 100_000_000;
+
+impl Price {
+    fn is_valid(&self) -> bool {
+        self.numerator > 0 && self.denominator > 0
     }
 
-    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id).as_ref().cloned())
-        .ok_or(Error::InvalidOrderId)?;
+    // Whether getting `received` units of to_currency for `paid` units of
+    // from_currency is at least this price, cross-multiplied exactly
+    fn is_met_by(&self, received: u128, paid: u128) -> bool {
+        cmp_products(received, self.denominator.into(), self.numerator.into(), paid) != Ordering::Less
+    }
 
-    if swap_order.status != SwapStatus::Created {
-        return Err(Error::InvalidOrderStatus);
+    // Oracle rates are floating point; the comparison scales the rate by the
+    // denominator instead of dividing the price
+    fn is_at_most(&self, rate: f64) -> bool {
+        self.numerator as f64 <= rate * self.denominator as f64
     }
 
-    let owner_principal = StorablePrincipal::from(swap_order.owner);
+    // Whether the price is a whole number of ticks: p / t is an integer when
+    // p.numerator * t.denominator divides evenly by p.denominator * t.numerator
+    fn is_multiple_of(&self, tick: Price) -> bool {
+        let scaled_price = self.numerator as u128 * tick.denominator as u128;
+        let scaled_tick = self.denominator as u128 * tick.numerator as u128;
+        scaled_price.is_multiple_of(scaled_tick)
+    }
 
-    if owner_principal == executor_principal {
-        return Err(Error::OwnerCannotExecute);
+    fn is_at_least(&self, rate: f64) -> bool {
+        self.numerator as f64 >= rate * self.denominator as f64
     }
 
-    let transfer_result = match swap_order.order_type {
-        OrderType::Market => {
-            // For market orders, execute immediately
-            transfer_funds(executor_principal, owner_principal, swap_order.to_amount)
-        }
-        OrderType::Limit { price } => {
-            // For limit orders, check if the price condition is met
-            if is_price_condition_met(price) {
-                transfer_funds(executor_principal, owner_principal, swap_order.to_amount)
-            } else {
-                Err(Error::PriceConditionNotMet)
-            }
+    // Rounded to the nearest representable price; the f64 to u64 cast saturates
+    fn from_legacy(price: f64) -> Self {
+        let scaled = (price * LEGACY_PRICE_SCALE as f64).round().max(1.0);
+        Price {
+            numerator: scaled as u64,
+            denominator: LEGACY_PRICE_SCALE,
         }
-    };
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+enum OrderType {
+    #[default]
+    Market,
+    Limit { price: Price },
+    // Immediate orders match against the book at creation and never rest on it
+    FillOrKill { price: Price },        // fills completely or is rejected
+    ImmediateOrCancel { price: Price }, // fills what it can, the rest is cancelled
+    // Dormant until the pair's rate crosses trigger_price, then executes like
+    // a market order
+    StopMarket { trigger_price: Price, direction: StopDirection },
+}
 
-    match transfer_result {
-        Ok(()) => {
-            swap_order.status = SwapStatus::Executed;
+impl OrderType {
+    fn price(&self) -> Option<Price> {
+        match self {
+            OrderType::Market => None,
+            OrderType::Limit { price } | OrderType::FillOrKill { price } | OrderType::ImmediateOrCancel { price } => {
+                Some(*price)
+            }
+            OrderType::StopMarket { trigger_price, .. } => Some(*trigger_price),
         }
-        Err(err) => return Err(err),
     }
+}
 
-    SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(order_id, swap_order));
-
-    Ok(())
+// OrderType as stored before prices became fractions. Only used to decode
+// orders written by earlier versions.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum LegacyOrderType {
+    Market,
+    Limit { price: f64 },
+    FillOrKill { price: f64 },
+    ImmediateOrCancel { price: f64 },
+    StopMarket { trigger_price: f64, direction: StopDirection },
 }
 
-fn transfer_funds(from: StorablePrincipal, to: StorablePrincipal, amount: u64) -> Result<(), Error> {
-    if amount == 0 {
-        return Ok(()); // No need to transfer if the amount is zero
-    }
-    if from == to {
-        return Ok(()); // No need to transfer to self
+impl From<LegacyOrderType> for OrderType {
+    fn from(legacy: LegacyOrderType) -> Self {
+        match legacy {
+            LegacyOrderType::Market => OrderType::Market,
+            LegacyOrderType::Limit { price } => OrderType::Limit {
+                price: Price::from_legacy(price),
+            },
+            LegacyOrderType::FillOrKill { price } => OrderType::FillOrKill {
+                price: Price::from_legacy(price),
+            },
+            LegacyOrderType::ImmediateOrCancel { price } => OrderType::ImmediateOrCancel {
+                price: Price::from_legacy(price),
+            },
+            LegacyOrderType::StopMarket { trigger_price, direction } => OrderType::StopMarket {
+                trigger_price: Price::from_legacy(trigger_price),
+                direction,
+            },
+        }
     }
+}
 
-    USER_ACCOUNTS.with(|accounts| {
-        let mut accounts_borrowed = accounts.borrow_mut();
-        let mut from_account = accounts_borrowed.get(&from).as_ref().cloned().ok_or(Error::UserNotFound)?;
-        let mut to_account = accounts_borrowed.get(&to).as_ref().cloned().unwrap_or_else(|| {
-            accounts_borrowed.insert(to.clone(), UserAccount::default());
-            accounts_borrowed.get(&to).as_ref().cloned().unwrap()
-        });
+// Which way the rate (units of to_currency per unit of from_currency) has to
+// move for a stop order to trigger
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum StopDirection {
+    Below, // sell-stop: triggers once the rate falls to trigger_price or lower
+    Above, // buy-stop: triggers once the rate rises to trigger_price or higher
+}
 
-        if from_account.balance < amount {
-            Err(Error::InsufficientFunds)
-        } else {
-            from_account.balance -= amount;
-            to_account.balance += amount;
-            accounts_borrowed.insert(from.clone(), from_account);
-            accounts_borrowed.insert(to.clone(), to_account);
-            Ok(())
+impl StopDirection {
+    fn is_triggered(&self, trigger_price: Price, rate: f64) -> bool {
+        match self {
+            StopDirection::Below => trigger_price.is_at_least(rate),
+            StopDirection::Above => trigger_price.is_at_most(rate),
         }
-    })
+    }
 }
 
-#[ic_cdk::update]
-fn cancel_swap_order(order_id: u64) -> Result<(), Error> {
-    let caller_principal = StorablePrincipal::from(caller());
-    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow_mut().get(&order_id).as_ref().cloned())
-        .ok_or(Error::InvalidOrderId)?;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SwapOrder {
+    id: u64,
+    owner: candid::Principal,
+    from_currency: String,
+    to_currency: String,
+    from_amount: u128,
+    to_amount: u128,
+    order_type: OrderType,
+    created_at: u64,
+    status: SwapStatus,
+    filled_amount: Option<u128>, // from_amount already delivered to takers, None before the first fill
+    expires_at: Option<u64>,     // nanoseconds since epoch, None for orders that never expire
+    fees_paid: Option<u128>,     // trading fees withheld from the owner's proceeds, in to_currency
+    executed_by: Option<candid::Principal>, // executor of the most recent fill
+    executed_at: Option<u64>,               // time of the most recent fill
+    cancelled_at: Option<u64>,
+    triggered_at: Option<u64>, // when a stop order's trigger was hit, None while dormant
+    updated_at: Option<u64>,   // time of the most recent amendment
+    memo: Option<String>,      // client order id chosen by the owner, at most MAX_MEMO_BYTES
+    counterparty: Option<candid::Principal>, // the only principal allowed to fill an OTC order
+    admin_cancel_reason: Option<String>,     // set when the admin cancelled the order on the owner's behalf
+    accepted_by: Option<candid::Principal>,  // taker holding an Accepted order, kept once it settles
+    accepted_at: Option<u64>,
+    price_reached_at: Option<u64>,           // limit orders: when the rate scan first saw the price reached
+    linked_order_id: Option<u64>,            // the other leg of a one-cancels-other pair
+    display_amount: Option<u128>,            // iceberg limit orders: size of the tranche shown to others
+    tranche_shown_at: Option<u64>,           // when the current tranche was revealed, its time priority
+    creation_deposit: Option<OrderDeposit>, // locked while the order rests, see order_deposits
+    cancel_reason: Option<CancelReason>,    // why the order was cancelled or expired
+}
 
-    if swap_order.status != SwapStatus::Created {
-        return Err(Error::InvalidOrderStatus);
+impl SwapOrder {
+    fn filled(&self) -> u128 {
+        self.filled_amount.unwrap_or(0)
     }
 
-    if swap_order.owner != caller_principal.clone().into() {
-        return Err(Error::Unauthorized);
+    // from_amount still held in escrow for future fills
+    fn remaining(&self) -> u128 {
+        self.from_amount - self.filled()
     }
 
-    USER_ACCOUNTS.with(|accounts| {
-        let mut accounts_borrowed = accounts.borrow_mut();
-        let mut owner_account = accounts_borrowed.get(&caller_principal).as_ref().cloned().unwrap_or_else(|| {
-            accounts_borrowed.insert(caller_principal.clone(), UserAccount::default());
-            accounts_borrowed.get(&caller_principal).as_ref().cloned().unwrap()
-        });
-        owner_account.balance += swap_order.from_amount;
-        accounts_borrowed.insert(caller_principal, owner_account);
-        Ok(())
-    })?;
+    // Part of the remainder a single fill may take: the rest of the current
+    // tranche for an iceberg order, tranches being consecutive runs of
+    // display_amount, and the whole remainder otherwise
+    fn visible_remaining(&self) -> u128 {
+        match self.display_amount {
+            Some(display_amount) => (display_amount - self.filled() % display_amount).min(self.remaining()),
+            None => self.remaining(),
+        }
+    }
 
-    swap_order.status = SwapStatus::Cancelled;
-    SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(order_id, swap_order));
+    // Orders at the same price fill oldest first; an iceberg tranche counts
+    // from when it was revealed
+    fn book_priority(&self) -> u64 {
+        self.tranche_shown_at.unwrap_or(self.created_at)
+    }
 
-    Ok(())
+    // The order as someone other than its owner sees it: an iceberg order
+    // shows what it has filled plus its visible tranche, at its price
+    fn public_view(mut self) -> SwapOrder {
+        if self.display_amount.is_some() {
+            let shown = self.filled() + self.visible_remaining();
+            self.display_amount = None;
+            self.to_amount = mul_div_ceil(self.to_amount, shown, self.from_amount).unwrap_or(self.to_amount);
+            self.from_amount = shown;
+        }
+        self
+    }
+
+    fn viewed_by(self, viewer: &Principal) -> SwapOrder {
+        if self.owner == *viewer {
+            self
+        } else {
+            self.public_view()
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        matches!(self.status, SwapStatus::Created | SwapStatus::PartiallyFilled)
+    }
+
+    // Dormant stop orders are open but can't be filled, so they stay off the book
+    fn is_on_book(&self) -> bool {
+        self.is_open() && !self.is_dormant_stop()
+    }
+
+    fn is_dormant_stop(&self) -> bool {
+        matches!(self.order_type, OrderType::StopMarket { .. }) && self.triggered_at.is_none()
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    // Open or accepted, i.e. the owner's remainder is still in escrow
+    fn holds_escrow(&self) -> bool {
+        self.is_open() || self.status == SwapStatus::Accepted
+    }
+
+    fn is_acceptance_expired(&self, now: u64) -> bool {
+        self.status == SwapStatus::Accepted
+            && self.accepted_at.is_some_and(|accepted_at| accepted_at.saturating_add(ACCEPTANCE_TIMEOUT_NANOS) <= now)
+    }
+
+    // The order with every field it may still gain or grow over its life at
+    // its largest. Placing an order checks this fits, so the order stays
+    // within its bound whatever happens to it later.
+    fn at_largest(&self) -> SwapOrder {
+        let principal = Some(Principal::from_slice(&[u8::MAX; 29]));
+        let amount = Some(u128::MAX);
+        let timestamp = Some(u64::MAX);
+        SwapOrder {
+            id: u64::MAX,
+            from_amount: u128::MAX,
+            to_amount: u128::MAX,
+            created_at: u64::MAX,
+            filled_amount: amount,
+            expires_at: timestamp,
+            fees_paid: amount,
+            executed_by: principal,
+            executed_at: timestamp,
+            cancelled_at: timestamp,
+            triggered_at: timestamp,
+            updated_at: timestamp,
+            admin_cancel_reason: Some("x".repeat(MAX_CANCEL_REASON_BYTES)),
+            accepted_by: principal,
+            accepted_at: timestamp,
+            price_reached_at: timestamp,
+            linked_order_id: Some(u64::MAX),
+            display_amount: self.display_amount.and(amount),
+            tranche_shown_at: timestamp,
+            cancel_reason: Some(CancelReason::SelfTradePrevention),
+            ..self.clone()
+        }
+    }
+
+    fn can_be_filled_by(&self, executor: &Principal) -> bool {
+        self.counterparty.is_none_or(|counterparty| counterparty == *executor)
+    }
+
+    // Books a fill of `fill_amount` taken by `executor`, with `fee` withheld
+    // from the owner's proceeds
+    fn record_fill(&mut self, executor: Principal, fill_amount: u128, fee: u128) {
+        let filled = self.filled() + fill_amount;
+        // Using up a tranche reveals the next one at the back of its price
+        if let Some(display_amount) = self.display_amount {
+            if filled / display_amount > self.filled() / display_amount {
+                self.tranche_shown_at = Some(time());
+            }
+        }
+        self.fees_paid = Some(self.fees_paid.unwrap_or(0).saturating_add(fee));
+        self.executed_by = Some(executor);
+        self.executed_at = Some(time());
+        self.filled_amount = Some(filled);
+        self.status = if filled == self.from_amount {
+            SwapStatus::Executed
+        } else {
+            SwapStatus::PartiallyFilled
+        };
+    }
 }
 
-#[ic_cdk::query]
-fn get_user_balance() -> Option<u64> {
-    let caller_principal = StorablePrincipal::from(caller());
-    USER_ACCOUNTS.with(|accounts| accounts.borrow().get(&caller_principal).as_ref().cloned())
-        .map(|account| account.balance)
+impl Default for SwapOrder {
+    fn default() -> Self {
+        SwapOrder {
+            id: 0,
+            owner: candid::Principal::anonymous(),
+            from_currency: String::default(),
+            to_currency: String::default(),
+            from_amount: 0,
+            to_amount: 0,
+            order_type: OrderType::default(),
+            created_at: 0,
+            status: SwapStatus::default(),
+            filled_amount: None,
+            expires_at: None,
+            fees_paid: None,
+            executed_by: None,
+            executed_at: None,
+            cancelled_at: None,
+            triggered_at: None,
+            updated_at: None,
+            memo: None,
+            counterparty: None,
+            admin_cancel_reason: None,
+            accepted_by: None,
+            accepted_at: None,
+            price_reached_at: None,
+            linked_order_id: None,
+            display_amount: None,
+            tranche_shown_at: None,
+            creation_deposit: None,
+            cancel_reason: None,
+        }
+    }
 }
 
-#[ic_cdk::query]
-fn get_swap_order(order_id: u64) -> Option<SwapOrder> {
-    SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id).as_ref().cloned())
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+enum SwapStatus {
+    #[default]
+    Created,
+    PartiallyFilled,
+    Executed,
+    Cancelled,
+    Expired,
+    Killed,   // immediate-or-cancel order whose unfilled remainder was cancelled at creation
+    Accepted, // taken off the book by accept_swap_order, waiting for settle_swap_order
 }
 
-// Placeholder function to simulate price condition checking
-fn is_price_condition_met(price: f64) -> bool {
-    // Simulate a price check
-    price <= 1.2 // Example condition
+// Set on an order when it is cancelled or expires
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum CancelReason {
+    Owner,               // cancel_swap_order or cancel_all_my_orders, by the owner or its session key
+    Admin,               // admin_cancel_order, which also sets admin_cancel_reason
+    Expired,             // expires_at passed, or an accepted order wasn't settled in time
+    LinkedOrderFilled,   // the other leg of its one-cancels-other pair filled
+    SelfTradePrevention, // it met an order of the same owner, see set_self_trade_prevention
+    CounterOfferSettled, // the part left after the owner accepted a counter-offer
 }
 
-lazy_static! {
-    static ref CURRENCY_REGEX: Regex = Regex::new(r"^[A-Z]{3}$").unwrap();
+// Leading byte of a stored order, naming the layout the Candid after it was
+// encoded with. Orders stored before there was one start with Candid's own
+// "DIDL" magic instead and count as version 1. A change to SwapOrder that
+// older records can't decode as bumps the version and keeps the previous
+// layout as a type of its own, decoded under its version in from_bytes.
+const SWAP_ORDER_VERSION: u8 = 2;
+
+// Bumped by any change that adds a derived index or counter, or changes what
+// one holds or where, so the next upgrade rebuilds them all once
+const DERIVED_STATE_VERSION: u64 = 2;
+
+impl Storable for SwapOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![SWAP_ORDER_VERSION];
+        bytes.extend(Encode!(self).expect("Failed to encode SwapOrder"));
+        Cow::Owned(bytes)
+    }
+
+    // Older records are converted on read and stored in the current layout
+    // the next time they are written, or by rewrite_order_encodings
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        #[cfg(test)]
+        tests::count_decoded_order();
+        SwapOrder::try_from_bytes(&bytes).unwrap_or_else(|error| panic!("Failed to decode SwapOrder: {}", error))
+    }
 }
 
-fn is_valid_currency(currency: &str) -> bool {
-    CURRENCY_REGEX.is_match(currency)
+impl SwapOrder {
+    // from_bytes without trapping, for scan_for_corrupt_records
+    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<SwapOrder, String> {
+        match bytes.split_first() {
+            Some((&SWAP_ORDER_VERSION, candid)) => Decode!(candid, Self).map_err(|error| error.to_string()),
+            Some((version, _)) if *version != b'D' => Err(format!("unknown version {}", version)),
+            _ => decode_unversioned_order(bytes).map_err(|error| error.to_string()),
+        }
+    }
 }
 
-#[derive(candid::CandidType, Deserialize, Serialize, Debug, PartialEq)]
-enum Error {
-    InsufficientFunds,
-    InvalidOrderId,
-    InvalidOrderStatus,
-    Unauthorized,
-    UserNotFound,
-    PriceConditionNotMet,
-    InvalidAmount,
-    InvalidCurrency,
-    InvalidPrice,
-    AnonymousNotAllowed,
-    OwnerCannotExecute,
+// Version 1. Orders written while amounts were u64, or before prices became
+// fractions, fail to decode as the last unversioned layout and are converted
+// from the layout they were stored in instead.
+fn decode_unversioned_order(bytes: &[u8]) -> Result<SwapOrder, candid::Error> {
+    Decode!(bytes, SwapOrder)
+        .or_else(|_| Decode!(bytes, NarrowSwapOrder).map(SwapOrder::from))
+        .or_else(|_| Decode!(bytes, LegacySwapOrder).map(SwapOrder::from))
 }
 
-// need this to generate candid
-ic_cdk::export_candid!();
+// SwapOrder as stored while amounts were u64
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NarrowSwapOrder {
+    id: u64,
+    owner: candid::Principal,
+    from_currency: String,
+    to_currency: String,
+    from_amount: u64,
+    to_amount: u64,
+    order_type: OrderType,
+    created_at: u64,
+    status: SwapStatus,
+    filled_amount: Option<u64>,
+    expires_at: Option<u64>,
+    fees_paid: Option<u64>,
+    executed_by: Option<candid::Principal>,
+    executed_at: Option<u64>,
+    cancelled_at: Option<u64>,
+    triggered_at: Option<u64>,
+    updated_at: Option<u64>,
+    memo: Option<String>,
+    counterparty: Option<candid::Principal>,
+    admin_cancel_reason: Option<String>,
+    accepted_by: Option<candid::Principal>,
+    accepted_at: Option<u64>,
+    price_reached_at: Option<u64>,
+}
+
+impl From<NarrowSwapOrder> for SwapOrder {
+    fn from(narrow: NarrowSwapOrder) -> Self {
+        SwapOrder {
+            id: narrow.id,
+            owner: narrow.owner,
+            from_currency: narrow.from_currency,
+            to_currency: narrow.to_currency,
+            from_amount: narrow.from_amount.into(),
+            to_amount: narrow.to_amount.into(),
+            order_type: narrow.order_type,
+            created_at: narrow.created_at,
+            status: narrow.status,
+            filled_amount: narrow.filled_amount.map(u128::from),
+            expires_at: narrow.expires_at,
+            fees_paid: narrow.fees_paid.map(u128::from),
+            executed_by: narrow.executed_by,
+            executed_at: narrow.executed_at,
+            cancelled_at: narrow.cancelled_at,
+            triggered_at: narrow.triggered_at,
+            updated_at: narrow.updated_at,
+            memo: narrow.memo,
+            counterparty: narrow.counterparty,
+            admin_cancel_reason: narrow.admin_cancel_reason,
+            accepted_by: narrow.accepted_by,
+            accepted_at: narrow.accepted_at,
+            price_reached_at: narrow.price_reached_at,
+            linked_order_id: None,
+            display_amount: None,
+            tranche_shown_at: None,
+            creation_deposit: None,
+            cancel_reason: None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacySwapOrder {
+    id: u64,
+    owner: candid::Principal,
+    from_currency: String,
+    to_currency: String,
+    from_amount: u64,
+    to_amount: u64,
+    order_type: LegacyOrderType,
+    created_at: u64,
+    status: SwapStatus,
+    filled_amount: Option<u64>,
+    expires_at: Option<u64>,
+    fees_paid: Option<u64>,
+    executed_by: Option<candid::Principal>,
+    executed_at: Option<u64>,
+    cancelled_at: Option<u64>,
+    triggered_at: Option<u64>,
+    updated_at: Option<u64>,
+}
+
+impl From<LegacySwapOrder> for SwapOrder {
+    fn from(legacy: LegacySwapOrder) -> Self {
+        SwapOrder {
+            id: legacy.id,
+            owner: legacy.owner,
+            from_currency: legacy.from_currency,
+            to_currency: legacy.to_currency,
+            from_amount: legacy.from_amount.into(),
+            to_amount: legacy.to_amount.into(),
+            order_type: legacy.order_type.into(),
+            created_at: legacy.created_at,
+            status: legacy.status,
+            filled_amount: legacy.filled_amount.map(u128::from),
+            expires_at: legacy.expires_at,
+            fees_paid: legacy.fees_paid.map(u128::from),
+            executed_by: legacy.executed_by,
+            executed_at: legacy.executed_at,
+            cancelled_at: legacy.cancelled_at,
+            triggered_at: legacy.triggered_at,
+            updated_at: legacy.updated_at,
+            memo: None,
+            counterparty: None,
+            admin_cancel_reason: None,
+            accepted_by: None,
+            accepted_at: None,
+            price_reached_at: None,
+            linked_order_id: None,
+            display_amount: None,
+            tranche_shown_at: None,
+            creation_deposit: None,
+            cancel_reason: None,
+        }
+    }
+}
+
+// New fields are added as options so existing records keep decoding. The
+// bound can't be raised in place because the stable map rejects a larger size
+// than it was created with, so orders moved to a new memory when it outgrew
+// the original 512 bytes.
+impl BoundedStorable for SwapOrder {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Orders as stored in MemoryId 1 under the original 512 byte bound. Only read
+// by the upgrade migration.
+struct LegacyStoredOrder(SwapOrder);
+
+impl Storable for LegacyStoredOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        LegacyStoredOrder(SwapOrder::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for LegacyStoredOrder {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static LEGACY_USER_ACCOUNTS: RefCell<StableBTreeMap<StorablePrincipal, LegacyUserAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+    ));
+
+    static LEGACY_SWAP_ORDERS: RefCell<StableBTreeMap<u64, LegacyStoredOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static SWAP_ORDERS: RefCell<StableBTreeMap<u64, SwapOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    static ORDER_COUNTER: RefCell<OrderIdCounter> = RefCell::new(
+        OrderIdCounter::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+
+    // Next order id the expiry sweep resumes from; heap only, restarting from
+    // zero after an upgrade is harmless
+    static EXPIRY_SWEEP_CURSOR: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+
+    // SWAP_ORDER_VERSION every live and archived order was last rewritten
+    // in. Orders from before versioning count as 1.
+    static ORDERS_ENCODED_VERSION: RefCell<Cell<u8, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101))), 1)
+            .expect("Cannot create the order encoding version")
+    );
+
+    // DERIVED_STATE_VERSION the derived indexes and counters were last
+    // rebuilt for. 0 on canisters from before the version was kept.
+    static DERIVED_STATE_BUILT: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113))), 0)
+            .expect("Cannot create the derived state version")
+    );
+
+    // Next live, then archived, order id rewrite_order_encodings resumes
+    // from; None once that map is done. Heap only: an upgrade midway starts
+    // over, which only rewrites some orders twice.
+    static ORDER_REWRITE_CURSORS: RefCell<(Option<u64>, Option<u64>)> = const { RefCell::new((Some(0), Some(0))) };
+
+    static USER_ACCOUNTS: RefCell<StableBTreeMap<StorablePrincipal, UserAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // (owner, order id) pairs so per-user listings can range over one owner's
+    // orders instead of scanning the whole order map
+    static ORDERS_BY_OWNER: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Every open public order, keyed for price-time ordered scans of one side
+    // of a pair. Derived from SWAP_ORDERS, so rebuild_derived_state refills it.
+    static ORDER_BOOK: RefCell<StableBTreeMap<BookKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112)))
+    ));
+
+    // Open OTC orders under their counterparty, keyed as in ORDER_BOOK after
+    // it. Kept out of the public book so reading its best orders never has to
+    // step over deals meant for someone else.
+    static OTC_BOOK: RefCell<StableBTreeMap<(StorablePrincipal, BookKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114)))
+    ));
+
+    // (counterparty, order id) for OTC orders, so the named party can find
+    // the deals waiting for them
+    static ORDERS_BY_COUNTERPARTY: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct InitArgs {
+    admin: Principal,
+}
+
+#[ic_cdk::init]
+fn init(args: InitArgs) {
+    admin::set_admin(args.admin).expect("The admin must not be the anonymous principal");
+    certification::rebuild_certified_state();
+    start_timers();
+}
+
+// Drops anonymous update calls, calls to methods the canister doesn't have
+// and arguments that are bound to fail validation before the canister pays
+// for executing them. This only covers ingress messages; the endpoints check
+// again for calls made by other canisters.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if accepts_ingress(caller(), &ic_cdk::api::call::method_name(), &ic_cdk::api::call::arg_data_raw()) {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+fn accepts_ingress(sender: Principal, method: &str, arg: &[u8]) -> bool {
+    sender != Principal::anonymous() && inspect::is_plausible_call(method, arg)
+}
+
+// Everything, the admin included, lives in stable memory except the call windows saved here
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    rate_limit::backup_call_windows();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_legacy_accounts();
+    migrate_legacy_orders();
+    rewrite_order_encodings(ORDER_REWRITE_BATCH_SIZE);
+    withdrawals::migrate_legacy_withdrawals();
+    fee_tiers::migrate_legacy_fee_tiers();
+    transactions::migrate_legacy_transactions();
+    events::migrate_legacy_events(events::EVENT_MIGRATION_BATCH_SIZE);
+    rebuild_derived_state();
+    certification::rebuild_certified_state();
+    rate_limit::restore_call_windows();
+    // Timers don't survive upgrades and have to be registered again
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_SWEEP_INTERVAL, sweep_expired_orders);
+    ic_cdk_timers::set_timer_interval(dedup::DEDUP_PRUNE_INTERVAL, dedup::prune_expired_deposits);
+    ic_cdk_timers::set_timer_interval(limit_scan::LIMIT_SCAN_INTERVAL, limit_scan::scan_dormant_limit_orders);
+    ic_cdk_timers::set_timer_interval(rate_limit::CALL_WINDOW_PRUNE_INTERVAL, rate_limit::prune_idle_call_windows);
+    ic_cdk_timers::set_timer_interval(recurring::RECURRING_SWEEP_INTERVAL, recurring::run_due_recurring_orders);
+    ic_cdk_timers::set_timer_interval(
+        counter_offers::COUNTER_OFFER_SWEEP_INTERVAL,
+        counter_offers::expire_counter_offers,
+    );
+    ic_cdk_timers::set_timer_interval(
+        pending_withdrawals::PENDING_WITHDRAWAL_SWEEP_INTERVAL,
+        pending_withdrawals::expire_pending_withdrawals,
+    );
+    ic_cdk_timers::set_timer_interval(
+        fill_callbacks::NOTIFICATION_RETRY_INTERVAL,
+        fill_callbacks::retry_notifications,
+    );
+    ic_cdk_timers::set_timer_interval(webhooks::WEBHOOK_DELIVERY_INTERVAL, webhooks::deliver_webhooks);
+    ic_cdk_timers::set_timer_interval(
+        leaderboard::LEADERBOARD_REFRESH_INTERVAL,
+        leaderboard::refresh_leaderboard,
+    );
+    ic_cdk_timers::set_timer_interval(proposals::PROPOSAL_SWEEP_INTERVAL, proposals::expire_proposals);
+    ic_cdk_timers::set_timer_interval(ORDER_REWRITE_INTERVAL, || {
+        rewrite_order_encodings(ORDER_REWRITE_BATCH_SIZE);
+    });
+    ic_cdk_timers::set_timer_interval(events::EVENT_MIGRATION_INTERVAL, || {
+        events::migrate_legacy_events(events::EVENT_MIGRATION_BATCH_SIZE);
+    });
+}
+
+// Rebuilds every index and counter derived from the stored accounts and
+// orders, once per DERIVED_STATE_VERSION. Writes keep them in step from then
+// on, so later upgrades skip the full scans.
+fn rebuild_derived_state() {
+    if DERIVED_STATE_BUILT.with(|cell| *cell.borrow().get()) >= DERIVED_STATE_VERSION {
+        return;
+    }
+    rebuild_locked_balances();
+    solvency::rebuild_totals();
+    rebuild_owner_index();
+    order_queries::rebuild_order_query_indexes();
+    rebuild_order_book();
+    limit_scan::rebuild_dormant_limit_index();
+    order_limits::rebuild_open_order_counts();
+    stats::seed_order_counts();
+    DERIVED_STATE_BUILT.with(|cell| cell.borrow_mut().set(DERIVED_STATE_VERSION))
+        .expect("Failed to store the derived state version");
+}
+
+// Rewrites up to `budget` live and archived orders still stored in an older
+// layout, resuming where the last batch stopped, and records the current
+// version once every order has been rewritten. post_upgrade runs a first
+// batch; the timer finishes the rest in batches. Returns whether it is done.
+fn rewrite_order_encodings(budget: usize) -> bool {
+    if ORDERS_ENCODED_VERSION.with(|cell| *cell.borrow().get()) >= SWAP_ORDER_VERSION {
+        return true;
+    }
+
+    let (live_cursor, archive_cursor) = ORDER_REWRITE_CURSORS.with(|cursors| *cursors.borrow());
+    let mut remaining = budget;
+    let live_cursor =
+        live_cursor.and_then(|start| SWAP_ORDERS.with(|orders| rewrite_batch(orders, start, &mut remaining)));
+    let archive_cursor = archive_cursor
+        .and_then(|start| archive::ARCHIVED_ORDERS.with(|archive| rewrite_batch(archive, start, &mut remaining)));
+    ORDER_REWRITE_CURSORS.with(|cursors| *cursors.borrow_mut() = (live_cursor, archive_cursor));
+
+    let done = live_cursor.is_none() && archive_cursor.is_none();
+    if done {
+        ORDERS_ENCODED_VERSION.with(|cell| cell.borrow_mut().set(SWAP_ORDER_VERSION))
+            .expect("Failed to store the order encoding version");
+    }
+    done
+}
+
+// Reads and writes back orders from `start` on, which stores them in the
+// current layout, while the budget lasts. Returns where the next batch
+// starts, or None once the map is done.
+fn rewrite_batch(
+    orders: &RefCell<StableBTreeMap<u64, SwapOrder, Memory>>,
+    start: u64,
+    budget: &mut usize,
+) -> Option<u64> {
+    let mut batch: Vec<(u64, SwapOrder)> = orders.borrow().range(start..).take(*budget + 1).collect();
+    // The extra entry only tells us where the next batch starts
+    let next = if batch.len() > *budget { batch.pop().map(|(order_id, _)| order_id) } else { None };
+    *budget -= batch.len();
+    let mut orders_borrowed = orders.borrow_mut();
+    for (order_id, swap_order) in batch {
+        orders_borrowed.insert(order_id, swap_order);
+    }
+    next
+}
+
+// Moves orders out of the 512 byte map into the larger one. Indexes are keyed
+// by order id, so they stay valid and are only checked by the rebuilds after.
+fn migrate_legacy_orders() {
+    let legacy_orders: Vec<(u64, LegacyStoredOrder)> =
+        LEGACY_SWAP_ORDERS.with(|orders| orders.borrow().iter().collect());
+
+    SWAP_ORDERS.with(|orders| {
+        let mut orders_borrowed = orders.borrow_mut();
+        for (order_id, legacy_order) in &legacy_orders {
+            orders_borrowed.insert(*order_id, legacy_order.0.clone());
+        }
+    });
+
+    LEGACY_SWAP_ORDERS.with(|orders| {
+        let mut orders_borrowed = orders.borrow_mut();
+        for (order_id, _) in &legacy_orders {
+            orders_borrowed.remove(order_id);
+        }
+    });
+}
+
+// Moves single-balance accounts into the per-currency map. The stable map
+// can't be reinitialised with a larger MAX_SIZE in place, so the new layout
+// lives in its own memory and the legacy map is drained once.
+fn migrate_legacy_accounts() {
+    let legacy_accounts: Vec<(StorablePrincipal, LegacyUserAccount)> =
+        LEGACY_USER_ACCOUNTS.with(|accounts| accounts.borrow().iter().collect());
+
+    USER_ACCOUNTS.with(|accounts| {
+        let mut accounts_borrowed = accounts.borrow_mut();
+        for (principal, legacy_account) in &legacy_accounts {
+            let mut user_account = accounts_borrowed.get(principal).unwrap_or_default();
+            // Each principal is migrated exactly once into an account created
+            // after the upgrade, so the credit can't overflow
+            user_account.credit(LEGACY_CURRENCY, legacy_account.balance.into())
+                .expect("Legacy balance overflowed during migration");
+            accounts_borrowed.insert(principal.clone(), user_account);
+        }
+    });
+
+    LEGACY_USER_ACCOUNTS.with(|accounts| {
+        let mut accounts_borrowed = accounts.borrow_mut();
+        for (principal, _) in &legacy_accounts {
+            accounts_borrowed.remove(principal);
+        }
+    });
+}
+
+// Recomputes every account's locked amounts from the open orders. Orders placed
+// before escrow was tracked per account only show up here; run by
+// rebuild_derived_state and imports.
+fn rebuild_locked_balances() {
+    let locked_by_owner = escrow_by_owner();
+    USER_ACCOUNTS.with(|accounts| {
+        let mut accounts_borrowed = accounts.borrow_mut();
+        let stale: Vec<StorablePrincipal> = accounts_borrowed
+            .iter()
+            .filter(|(principal, account)| account.locked.is_some() && !locked_by_owner.contains_key(principal))
+            .map(|(principal, _)| principal)
+            .collect();
+        for principal in stale {
+            let mut user_account = accounts_borrowed.get(&principal).unwrap();
+            user_account.locked = None;
+            accounts_borrowed.insert(principal, user_account);
+        }
+        for (principal, locked) in locked_by_owner {
+            let mut user_account = accounts_borrowed.get(&principal).unwrap_or_default();
+            user_account.locked = Some(locked);
+            accounts_borrowed.insert(principal, user_account);
+        }
+    });
+}
+
+// What the open orders hold in escrow, per principal and currency: an order's
+// remainder and creation deposit for its owner and, while it is accepted, the
+// payment for it for the taker. Open counter-offers add their proposers'
+// payments.
+fn escrow_by_owner() -> BTreeMap<StorablePrincipal, BTreeMap<String, u128>> {
+    let mut locked_by_owner: BTreeMap<StorablePrincipal, BTreeMap<String, u128>> = BTreeMap::new();
+    SWAP_ORDERS.with(|orders| {
+        for (_, swap_order) in orders.borrow().iter().filter(|(_, order)| order.holds_escrow()) {
+            let locked = locked_by_owner.entry(StorablePrincipal::from(swap_order.owner)).or_default();
+            add_to_bucket(locked, &swap_order.from_currency, swap_order.remaining())
+                .expect("Escrowed amounts overflowed while rebuilding locked balances");
+            if let Some(deposit) = &swap_order.creation_deposit {
+                add_to_bucket(locked, &deposit.currency, deposit.amount)
+                    .expect("Escrowed amounts overflowed while rebuilding locked balances");
+            }
+            if let (SwapStatus::Accepted, Some(taker)) = (&swap_order.status, swap_order.accepted_by) {
+                let locked = locked_by_owner.entry(StorablePrincipal::from(taker)).or_default();
+                add_to_bucket(locked, &swap_order.to_currency, fill_payment(&swap_order, swap_order.remaining()))
+                    .expect("Escrowed amounts overflowed while rebuilding locked balances");
+            }
+        }
+    });
+    counter_offers::add_escrow(&mut locked_by_owner);
+    pending_withdrawals::add_escrow(&mut locked_by_owner);
+    locked_by_owner
+}
+
+// Orders are never deleted, only moved to the archive, and an order's owner
+// never changes, so the index is complete exactly when it has one entry per
+// live or archived order. Orders created before the
+// index existed are added on the first upgrade that sees the mismatch.
+fn rebuild_owner_index() {
+    let indexed = ORDERS_BY_OWNER.with(|index| index.borrow().len());
+    let stored = SWAP_ORDERS.with(|orders| orders.borrow().len()) + archive::archived_order_count();
+    if indexed == stored {
+        return;
+    }
+
+    ORDERS_BY_OWNER.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let mut add = |(order_id, swap_order): (u64, SwapOrder)| {
+            index_borrowed.insert((StorablePrincipal::from(swap_order.owner), order_id), ());
+        };
+        SWAP_ORDERS.with(|orders| orders.borrow().iter().for_each(&mut add));
+        archive::ARCHIVED_ORDERS.with(|archive| archive.borrow().iter().for_each(&mut add));
+    });
+}
+
+// Recomputes the order book index from the open orders, the same way locked
+// balances are, so it matches the stored orders
+fn rebuild_order_book() {
+    ORDER_BOOK.with(|book| snapshot::clear_map(&mut book.borrow_mut()));
+    OTC_BOOK.with(|book| snapshot::clear_map(&mut book.borrow_mut()));
+    SWAP_ORDERS.with(|orders| {
+        for (_, swap_order) in orders.borrow().iter().filter(|(_, order)| order.is_on_book()) {
+            list_on_book(&swap_order);
+        }
+    });
+}
+
+// Lists an order on the public book, or under its counterparty if it is an
+// OTC order. Returns whether it wasn't listed already.
+fn list_on_book(swap_order: &SwapOrder) -> bool {
+    let book_key = BookKey::for_order(swap_order);
+    match swap_order.counterparty {
+        None => ORDER_BOOK.with(|book| book.borrow_mut().insert(book_key, ())).is_none(),
+        Some(counterparty) => {
+            let otc_key = (StorablePrincipal::from(counterparty), book_key);
+            OTC_BOOK.with(|book| book.borrow_mut().insert(otc_key, ())).is_none()
+        }
+    }
+}
+
+fn unlist_from_book(swap_order: &SwapOrder) {
+    let book_key = BookKey::for_order(swap_order);
+    match swap_order.counterparty {
+        None => ORDER_BOOK.with(|book| book.borrow_mut().remove(&book_key)),
+        Some(counterparty) => {
+            let otc_key = (StorablePrincipal::from(counterparty), book_key);
+            OTC_BOOK.with(|book| book.borrow_mut().remove(&otc_key))
+        }
+    };
+}
+
+// Whether `book_key`, listed under `counterparty` if any, is the entry the
+// order it names would have today
+fn is_current_listing(counterparty: Option<Principal>, book_key: &BookKey) -> bool {
+    SWAP_ORDERS.with(|orders| orders.borrow().get(&book_key.order_id)).is_some_and(|order| {
+        order.is_on_book() && order.counterparty == counterparty && BookKey::for_order(&order) == *book_key
+    })
+}
+
+// Looks at up to `budget` entries of a book index after `after`, dropping
+// those `is_current` rejects. Returns where to resume, None once the index
+// is done.
+fn drop_stale_listings<K: BoundedStorable + Ord + Clone>(
+    index: &'static LocalKey<RefCell<StableBTreeMap<K, (), Memory>>>,
+    after: Option<K>,
+    budget: &mut usize,
+    removed: &mut u64,
+    is_current: impl Fn(&K) -> bool,
+) -> Option<K> {
+    let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let entries: Vec<K> =
+        index.with(|book| book.borrow().range((lower, Bound::Unbounded)).take(*budget).map(|(key, _)| key).collect());
+    *budget -= entries.len();
+    for key in entries.iter().filter(|key| !is_current(key)) {
+        index.with(|book| book.borrow_mut().remove(key));
+        *removed += 1;
+    }
+    entries.last().filter(|_| *budget == 0).cloned()
+}
+
+// Upper bound on index entries and orders one rebuild_book_index call looks at
+const MAX_BOOK_REBUILD_BATCH: u32 = 1000;
+
+// Where rebuild_book_index resumes; heap only like the archive cursor, a
+// rebuild cut short by an upgrade simply starts over
+enum BookRebuildCursor {
+    Entries(Option<BookKey>),                         // dropping stale public entries, after this key
+    OtcEntries(Option<(StorablePrincipal, BookKey)>), // then stale OTC entries
+    Orders(u64),                                      // adding missing entries, from this order id
+}
+
+thread_local! {
+    static BOOK_REBUILD_CURSOR: RefCell<BookRebuildCursor> = const { RefCell::new(BookRebuildCursor::Entries(None)) };
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BookRebuildProgress {
+    removed: u64, // entries of orders that are off the book or moved
+    added: u64,   // open orders that had no entry
+    done: bool,   // call again until true
+}
+
+// rebuild_order_book between upgrades, for an index suspected of having
+// drifted from the stored orders. Each call looks at up to `max_items` index
+// entries, then stored orders, and resumes where the last one stopped. Orders
+// written in between keep their entries in step as always, so the index is
+// complete once a pass finished.
+#[ic_cdk::update]
+fn rebuild_book_index(max_items: u32) -> Result<BookRebuildProgress, Error> {
+    admin::require_admin()?;
+    let mut budget = max_items.clamp(1, MAX_BOOK_REBUILD_BATCH) as usize;
+    let mut progress = BookRebuildProgress {
+        removed: 0,
+        added: 0,
+        done: false,
+    };
+
+    let mut cursor = BOOK_REBUILD_CURSOR.with(|cursor| cursor.replace(BookRebuildCursor::Entries(None)));
+    while budget > 0 && !progress.done {
+        cursor = match cursor {
+            BookRebuildCursor::Entries(after) => {
+                let is_current = |book_key: &BookKey| is_current_listing(None, book_key);
+                match drop_stale_listings(&ORDER_BOOK, after, &mut budget, &mut progress.removed, is_current) {
+                    Some(last) => BookRebuildCursor::Entries(Some(last)),
+                    None => BookRebuildCursor::OtcEntries(None),
+                }
+            }
+            BookRebuildCursor::OtcEntries(after) => {
+                let is_current = |(counterparty, book_key): &(StorablePrincipal, BookKey)| {
+                    is_current_listing(Some(counterparty.clone().into()), book_key)
+                };
+                match drop_stale_listings(&OTC_BOOK, after, &mut budget, &mut progress.removed, is_current) {
+                    Some(last) => BookRebuildCursor::OtcEntries(Some(last)),
+                    None => BookRebuildCursor::Orders(0),
+                }
+            }
+            BookRebuildCursor::Orders(start_id) => {
+                let batch: Vec<(u64, SwapOrder)> =
+                    SWAP_ORDERS.with(|orders| orders.borrow().range(start_id..).take(budget).collect());
+                budget -= batch.len();
+                for (_, swap_order) in batch.iter().filter(|(_, order)| order.is_on_book()) {
+                    if list_on_book(swap_order) {
+                        progress.added += 1;
+                    }
+                }
+                match batch.last() {
+                    Some((last_id, _)) if budget == 0 => BookRebuildCursor::Orders(last_id + 1),
+                    _ => {
+                        progress.done = true;
+                        BookRebuildCursor::Entries(None)
+                    }
+                }
+            }
+        };
+    }
+    BOOK_REBUILD_CURSOR.with(|stored| *stored.borrow_mut() = cursor);
+
+    Ok(progress)
+}
+
+// Writes an order and keeps the order book index in step with it: fillable
+// orders are listed under their pair, price and priority, everything else is
+// dropped. A fill on one leg of a one-cancels-other pair is passed on to the
+// other.
+fn store_order(swap_order: SwapOrder) {
+    let previous = SWAP_ORDERS.with(|orders| orders.borrow().get(&swap_order.id));
+    stats::record_status_change(previous.as_ref().map(|order| &order.status), &swap_order);
+    order_limits::record_open_change(
+        &StorablePrincipal::from(swap_order.owner),
+        previous.as_ref().is_some_and(SwapOrder::holds_escrow),
+        swap_order.holds_escrow(),
+    );
+    limit_scan::record_limit_change(previous.as_ref(), &swap_order);
+    trading_profiles::record_outcome(previous.as_ref().map(|order| &order.status), &swap_order);
+    if previous.as_ref().map(|order| &order.status) != Some(&swap_order.status) {
+        certification::certify_order(&swap_order);
+    }
+    if previous.as_ref().is_some_and(SwapOrder::holds_escrow) && !swap_order.holds_escrow() {
+        order_deposits::settle_deposit(&swap_order);
+        counter_offers::close_offers_for_order(swap_order.id);
+    }
+    let filled_before = previous.as_ref().map_or(0, SwapOrder::filled);
+    let linked_fill = (swap_order.linked_order_id.is_some() && swap_order.filled() > filled_before)
+        .then(|| swap_order.clone());
+
+    // The key moves when the terms change or an iceberg reveals a tranche
+    if let Some(previous) = previous.as_ref() {
+        unlist_from_book(previous);
+    }
+    if swap_order.is_on_book() {
+        list_on_book(&swap_order);
+    }
+    debug_assert!(validate_encoded_size(&swap_order).is_ok(), "Order outgrew its bound");
+    SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(swap_order.id, swap_order));
+    // The other leg is only touched once this one is written
+    if let Some(leg) = linked_fill {
+        oco::propagate_fill(filled_before, &leg);
+    }
+}
+
+// Ids of every order the principal has placed, oldest first
+fn owner_order_ids(owner: &StorablePrincipal) -> Vec<u64> {
+    ORDERS_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .range((owner.clone(), 0)..=(owner.clone(), u64::MAX))
+            .map(|((_, order_id), _)| order_id)
+            .collect()
+    })
+}
+
+// Ids of the principal's orders, newest first. The index is read one key per
+// id asked for, so a caller taking a page never reads past it.
+fn owner_order_ids_newest_first(owner: &StorablePrincipal) -> impl Iterator<Item = u64> {
+    let owner = owner.clone();
+    let mut below = u64::MAX;
+    std::iter::from_fn(move || {
+        let ((key_owner, order_id), _) =
+            ORDERS_BY_OWNER.with(|index| index.borrow().iter_upper_bound(&(owner.clone(), below)).next())?;
+        if key_owner != owner {
+            return None;
+        }
+        below = order_id;
+        Some(order_id)
+    })
+}
+
+// Open public orders selling `from_currency` for `to_currency`, cheapest
+// first and oldest first within a price. The book is read one entry per order
+// asked for, so a caller taking the best few never reads past them.
+fn book_side(from_currency: &str, to_currency: &str) -> impl Iterator<Item = SwapOrder> {
+    let start = BookKey::first_of_side(from_currency, to_currency);
+    listed_orders(&ORDER_BOOK, start.clone(), move |book_key| book_key.same_side(&start).then_some(book_key.order_id))
+}
+
+// book_side with the OTC orders meant for `taker` merged in at their place
+fn book_side_for(
+    taker: &StorablePrincipal,
+    from_currency: &str,
+    to_currency: &str,
+) -> impl Iterator<Item = SwapOrder> {
+    let start = (taker.clone(), BookKey::first_of_side(from_currency, to_currency));
+    let otc = listed_orders(&OTC_BOOK, start.clone(), move |(counterparty, book_key)| {
+        (*counterparty == start.0 && book_key.same_side(&start.1)).then_some(book_key.order_id)
+    });
+    let (mut public, mut otc) = (book_side(from_currency, to_currency).peekable(), otc.peekable());
+    std::iter::from_fn(move || match (public.peek(), otc.peek()) {
+        (Some(public_order), Some(otc_order)) if BookKey::for_order(otc_order) < BookKey::for_order(public_order) => {
+            otc.next()
+        }
+        (Some(_), _) => public.next(),
+        (None, _) => otc.next(),
+    })
+}
+
+// The orders a book index lists from `start` on, read one entry at a time,
+// for as long as `order_id` names an order for the entry
+fn listed_orders<K: BoundedStorable + Ord + Clone>(
+    index: &'static LocalKey<RefCell<StableBTreeMap<K, (), Memory>>>,
+    start: K,
+    order_id: impl Fn(&K) -> Option<u64>,
+) -> impl Iterator<Item = SwapOrder> {
+    let mut lower = Bound::Included(start);
+    std::iter::from_fn(move || {
+        let key = index.with(|book| book.borrow().range((lower.clone(), Bound::Unbounded)).next())?.0;
+        let id = order_id(&key)?;
+        lower = Bound::Excluded(key);
+        Some(id)
+    })
+    .filter_map(|order_id| SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id)))
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DepositArgs {
+    amount: u128,
+    currency: String,
+    dedup_id: Option<u64>, // a retry with the same id returns the first result instead of crediting again
+}
+
+#[ic_cdk::update]
+fn deposit(mut args: DepositArgs) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    args.currency = normalize_currency(&args.currency);
+    if args.amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if !is_valid_currency(&args.currency) {
+        return Err(Error::InvalidCurrency { provided: args.currency });
+    }
+
+    // Only the credit itself is deduplicated, invalid arguments fail the same
+    // way on every retry anyway
+    let caller_principal = StorablePrincipal::from(caller());
+    if let Some(result) = args.dedup_id.and_then(|dedup_id| dedup::replayed_deposit(&caller_principal, dedup_id)) {
+        return result;
+    }
+
+    let result = credit_deposit(&caller_principal, &args.currency, args.amount);
+    if let Some(dedup_id) = args.dedup_id {
+        dedup::remember_deposit(&caller_principal, dedup_id, &result);
+    }
+    result
+}
+
+fn credit_deposit(principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+    let mut changes = BalanceChanges::new();
+    changes.credit(principal, currency, amount)?;
+    changes.commit();
+
+    record_transaction(TransactionKind::Deposit, None, Some(principal.clone().into()), currency, amount, None);
+    record_event(EventKind::Deposit {
+        principal: principal.clone().into(),
+        currency: currency.to_string(),
+        amount,
+    });
+
+    Ok(())
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct WithdrawArgs {
+    amount: u128,
+    currency: String,
+}
+
+// Takes available funds out of the caller's account, subject to the daily
+// withdrawal limit. Disabled currencies can still be withdrawn. An amount
+// above the caller's confirmation threshold is only locked, and leaves once
+// confirm_withdrawal is called for it.
+#[ic_cdk::update]
+fn withdraw(mut args: WithdrawArgs) -> Result<WithdrawOutcome, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    args.currency = normalize_currency(&args.currency);
+    if args.amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if !is_known_currency(&args.currency) {
+        return Err(Error::InvalidCurrency { provided: args.currency });
+    }
+
+    let caller_principal = StorablePrincipal::from(caller());
+    withdrawals::check_withdrawal_limit(&caller_principal, &args.currency, args.amount)?;
+    if pending_withdrawals::needs_confirmation(&caller_principal, &args.currency, args.amount) {
+        let pending = pending_withdrawals::hold_withdrawal(caller(), args.currency, args.amount)?;
+        return Ok(WithdrawOutcome::PendingConfirmation(pending));
+    }
+
+    let mut changes = BalanceChanges::new();
+    changes.debit(&caller_principal, &args.currency, args.amount)?;
+    changes.commit();
+
+    withdrawals::record_withdrawal(&caller_principal, &args.currency, args.amount);
+    record_transaction(TransactionKind::Withdrawal, Some(caller()), None, &args.currency, args.amount, None);
+    record_event(EventKind::Withdrawal {
+        principal: caller(),
+        currency: args.currency,
+        amount: args.amount,
+    });
+
+    Ok(WithdrawOutcome::Completed)
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CreateSwapOrderArgs {
+    from_currency: String,
+    to_currency: String,
+    from_amount: u128,
+    to_amount: u128,
+    order_type: OrderType,
+    expires_at: Option<u64>,
+    memo: Option<String>,
+    counterparty: Option<Principal>, // makes an OTC order only this principal can fill
+    display_amount: Option<u128>,    // limit orders: shows only tranches of this size to others
+}
+
+// Memos are stored inside the order, whose encoding has to stay within
+// SwapOrder::MAX_SIZE
+const MAX_MEMO_BYTES: usize = 64;
+
+// What placing an order returns. An order that rests may lock the creation
+// deposit configured by set_order_deposit on top of its own escrow; it comes
+// back once the order executes or is cancelled, and goes to the fee account
+// if the order expires without a fill.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CreatedOrder {
+    order_id: u64,
+    creation_deposit: Option<OrderDeposit>, // None when nothing was locked besides the order's escrow
+}
+
+#[ic_cdk::update]
+fn create_swap_order(args: CreateSwapOrderArgs) -> Result<CreatedOrder, Error> {
+    place_swap_order(args)
+}
+
+// Upper bound on orders placed by a single create_swap_orders call
+const MAX_ORDER_BATCH_SIZE: usize = 50;
+
+// Places each order independently and returns one result per item, in order.
+// Every item validates, escrows and commits on its own, so a failing item
+// leaves the orders placed before and after it untouched.
+#[ic_cdk::update]
+fn create_swap_orders(batch: Vec<CreateSwapOrderArgs>) -> Vec<Result<CreatedOrder, Error>> {
+    if batch.len() > MAX_ORDER_BATCH_SIZE {
+        let max = MAX_ORDER_BATCH_SIZE as u64;
+        return batch.iter().map(|_| Err(Error::BatchTooLarge { max })).collect();
+    }
+    batch.into_iter().map(place_swap_order).collect()
+}
+
+// What validate_swap_order expects placing an order to lock, with the
+// arguments normalized the way create_swap_order stores them
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrderPreview {
+    from_currency: String,
+    to_currency: String,
+    from_amount: u128,
+    to_amount: u128,
+    // from_amount for an order that rests, less whatever crossing orders it
+    // takes on placement; immediate orders never lock anything
+    max_escrow: u128,
+    creation_deposit: Option<OrderDeposit>, // locked too if the order rests
+}
+
+// Runs every check create_swap_order would for the caller, balances included,
+// and returns the error placing the order would fail with or what it would
+// lock. Nothing is written and no order id is used up. Only the per-call rate
+// limit is left out, since a query can't count towards it.
+#[ic_cdk::query]
+fn validate_swap_order(mut args: CreateSwapOrderArgs) -> Result<OrderPreview, Error> {
+    admin::require_authenticated()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    check_new_order(session_keys::on_behalf_of(SessionAction::Create)?, &mut args)?;
+
+    let rests = !is_immediate(&args.order_type);
+    Ok(OrderPreview {
+        max_escrow: if rests { args.from_amount } else { 0 },
+        creation_deposit: order_deposits::current_deposit().filter(|_| rests),
+        from_currency: args.from_currency,
+        to_currency: args.to_currency,
+        from_amount: args.from_amount,
+        to_amount: args.to_amount,
+    })
+}
+
+// The checks that need nothing but the arguments, so inspect_message can
+// run them before the call is accepted
+fn check_order_args(args: &CreateSwapOrderArgs) -> Result<(), Error> {
+    if args.from_amount == 0 || args.to_amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    for currency in [&args.from_currency, &args.to_currency] {
+        if !is_well_formed_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    if normalize_currency(&args.from_currency) == normalize_currency(&args.to_currency) {
+        return Err(Error::SameCurrency);
+    }
+    if let Some(price) = args.order_type.price() {
+        if !price.is_valid() {
+            return Err(Error::InvalidPrice);
+        }
+    }
+    if matches!(&args.memo, Some(memo) if memo.len() > MAX_MEMO_BYTES) {
+        return Err(Error::InvalidMemo);
+    }
+    if let Some(display_amount) = args.display_amount {
+        if !matches!(args.order_type, OrderType::Limit { .. })
+            || display_amount == 0
+            || display_amount >= args.from_amount
+        {
+            return Err(Error::InvalidDisplayAmount);
+        }
+    }
+    Ok(())
+}
+
+fn place_swap_order(args: CreateSwapOrderArgs) -> Result<CreatedOrder, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let owner = session_keys::on_behalf_of(SessionAction::Create)?;
+    let order_id = open_order(owner, args)?;
+    let creation_deposit = SWAP_ORDERS
+        .with(|orders| orders.borrow().get(&order_id))
+        .and_then(|swap_order| swap_order.creation_deposit);
+    Ok(CreatedOrder {
+        order_id,
+        creation_deposit,
+    })
+}
+
+// Validates, matches and stores an order for `owner`. Callers check who may
+// trade first; recurring orders come through here with their owner instead
+// of the caller.
+fn open_order(owner: Principal, mut args: CreateSwapOrderArgs) -> Result<u64, Error> {
+    check_new_order(owner, &mut args)?;
+    place_checked_order(owner, args)
+}
+
+// Normalizes the arguments and runs every check placing them depends on, so
+// nothing is written for an order that can't be placed
+fn check_new_order(owner: Principal, args: &mut CreateSwapOrderArgs) -> Result<(), Error> {
+    args.from_currency = normalize_currency(&args.from_currency);
+    args.to_currency = normalize_currency(&args.to_currency);
+    check_order_args(args)?;
+    for currency in [&args.from_currency, &args.to_currency] {
+        if !is_valid_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    currencies::check_amount_precision(&args.from_currency, args.from_amount)?;
+    currencies::check_amount_precision(&args.to_currency, args.to_amount)?;
+    if let Some(display_amount) = args.display_amount {
+        currencies::check_amount_precision(&args.from_currency, display_amount)?;
+    }
+    pairs::pair_config(&args.from_currency, &args.to_currency).check_order(
+        args.from_amount,
+        args.to_amount,
+        args.order_type.price(),
+    )?;
+    if let Some(expires_at) = args.expires_at {
+        if expires_at <= time() {
+            return Err(Error::OrderExpired);
+        }
+    }
+    if args.counterparty.is_some_and(|counterparty| counterparty == Principal::anonymous() || counterparty == owner) {
+        return Err(Error::InvalidCounterparty);
+    }
+
+    let prospective = SwapOrder {
+        owner,
+        from_currency: args.from_currency.clone(),
+        to_currency: args.to_currency.clone(),
+        order_type: args.order_type.clone(),
+        memo: args.memo.clone(),
+        counterparty: args.counterparty,
+        display_amount: args.display_amount,
+        creation_deposit: order_deposits::current_deposit(),
+        ..SwapOrder::default()
+    };
+    validate_encoded_size(&prospective.at_largest())?;
+
+    let owner_principal = StorablePrincipal::from(owner);
+    trading_profiles::check_not_blocked(&owner_principal)?;
+    // Immediate orders never rest, so they don't count towards the limit
+    if !is_immediate(&args.order_type) {
+        order_limits::check_open_order_limit(&owner_principal)?;
+    }
+    let required = required_funds(args);
+    for (currency, amount) in &required {
+        require_available(&owner_principal, currency, *amount)?;
+    }
+    check_account_room(&owner_principal, args, &required)
+}
+
+// The owner's account once the order has been paid in to_currency and, if it
+// may rest, has escrowed `required`, with each of those entries at its
+// largest. Placing an order checks this fits, so neither the fills nor the
+// escrow that follow can outgrow the account's bound.
+fn check_account_room(
+    owner: &StorablePrincipal,
+    args: &CreateSwapOrderArgs,
+    required: &BTreeMap<String, u128>,
+) -> Result<(), Error> {
+    let mut user_account = USER_ACCOUNTS.with(|accounts| accounts.borrow().get(owner)).unwrap_or_default();
+    user_account.balances.insert(args.to_currency.clone(), u128::MAX);
+    for currency in required.keys() {
+        user_account.balances.insert(currency.clone(), u128::MAX);
+        if !is_immediate(&args.order_type) {
+            user_account.locked.get_or_insert_with(BTreeMap::new).insert(currency.clone(), u128::MAX);
+        }
+    }
+    validate_encoded_size(&user_account)
+}
+
+fn is_immediate(order_type: &OrderType) -> bool {
+    matches!(order_type, OrderType::FillOrKill { .. } | OrderType::ImmediateOrCancel { .. })
+}
+
+// What placing the order can take from the owner's available balance, per
+// currency: the from_amount and, for an order that may rest, the creation
+// deposit. Saturates, so an overflowing sum fails the balance check.
+fn required_funds(args: &CreateSwapOrderArgs) -> BTreeMap<String, u128> {
+    let mut required = BTreeMap::from([(args.from_currency.clone(), args.from_amount)]);
+    if let Some(deposit) = order_deposits::current_deposit().filter(|_| !is_immediate(&args.order_type)) {
+        let amount = required.entry(deposit.currency).or_default();
+        *amount = amount.saturating_add(deposit.amount);
+    }
+    required
+}
+
+// Matches and stores an order check_new_order passed
+fn place_checked_order(owner: Principal, args: CreateSwapOrderArgs) -> Result<u64, Error> {
+    let owner_principal = StorablePrincipal::from(owner);
+    // Priced orders first take whatever crossing orders the book already has,
+    // paying from available funds. Market and stop orders always rest for an
+    // executor.
+    let (book_fill, immediate) = match args.order_type {
+        OrderType::Market | OrderType::StopMarket { .. } => (BookFill::default(), false),
+        OrderType::Limit { price } => (matching::fill_against_book(&owner_principal, &args, price, false)?, false),
+        OrderType::ImmediateOrCancel { price } => {
+            (matching::fill_against_book(&owner_principal, &args, price, false)?, true)
+        }
+        OrderType::FillOrKill { price } => (matching::fill_against_book(&owner_principal, &args, price, true)?, true),
+    };
+
+    let order_id = ORDER_COUNTER.with(|counter| counter.borrow_mut().next_id())?;
+    let now = time();
+    let spent = book_fill.spent;
+    let remaining = args.from_amount - spent;
+    // Self-trade prevention that stopped matching cancels the rest of the order
+    let stopped = book_fill.self_trades.stopped_by.is_some();
+
+    // Only resting orders escrow their remainder and a creation deposit; the
+    // remainder of an immediate or stopped order is cancelled without ever
+    // leaving the owner's balance
+    let rests = remaining > 0 && !immediate && !stopped;
+    let creation_deposit = order_deposits::current_deposit().filter(|_| rests);
+    if rests {
+        let mut changes = BalanceChanges::new();
+        // check_new_order covered the whole from_amount and the deposit, and
+        // the account's room to escrow them
+        changes.lock(&owner_principal, &args.from_currency, remaining)?;
+        if let Some(deposit) = &creation_deposit {
+            order_deposits::lock_deposit(&mut changes, &owner_principal, deposit)?;
+        }
+        changes.commit();
+
+        record_transaction(
+            TransactionKind::Escrow,
+            Some(owner),
+            None,
+            &args.from_currency,
+            remaining,
+            Some(order_id),
+        );
+        if let Some(deposit) = &creation_deposit {
+            record_transaction(
+                TransactionKind::OrderDeposit,
+                Some(owner),
+                None,
+                &deposit.currency,
+                deposit.amount,
+                Some(order_id),
+            );
+        }
+    }
+
+    let status = if remaining == 0 {
+        SwapStatus::Executed
+    } else if stopped {
+        SwapStatus::Cancelled
+    } else if immediate {
+        SwapStatus::Killed
+    } else if spent > 0 {
+        SwapStatus::PartiallyFilled
+    } else {
+        SwapStatus::Created
+    };
+
+    let swap_order = SwapOrder {
+        id: order_id,
+        owner,
+        from_currency: args.from_currency,
+        to_currency: args.to_currency,
+        from_amount: args.from_amount,
+        to_amount: args.to_amount,
+        order_type: args.order_type,
+        created_at: now,
+        status,
+        filled_amount: (spent > 0).then_some(spent),
+        expires_at: args.expires_at,
+        fees_paid: None,
+        executed_by: None,
+        executed_at: (spent > 0).then_some(now),
+        cancelled_at: (remaining > 0 && (immediate || stopped)).then_some(now),
+        triggered_at: None,
+        updated_at: None,
+        memo: args.memo,
+        counterparty: args.counterparty,
+        admin_cancel_reason: None,
+        accepted_by: None,
+        accepted_at: None,
+        price_reached_at: None,
+        linked_order_id: None,
+        display_amount: args.display_amount,
+        tranche_shown_at: None,
+        creation_deposit,
+        cancel_reason: (remaining > 0 && stopped).then_some(CancelReason::SelfTradePrevention),
+    };
+
+    record_event(EventKind::OrderCreated {
+        order_id,
+        owner,
+        from_currency: swap_order.from_currency.clone(),
+        to_currency: swap_order.to_currency.clone(),
+        from_amount: swap_order.from_amount,
+        to_amount: swap_order.to_amount,
+    });
+    if let Some(reason) = swap_order.cancel_reason {
+        record_event(EventKind::OrderCancelled {
+            order_id,
+            owner,
+            reason: Some(reason),
+            initiator: None,
+        });
+    }
+    order_queries::index_order(&swap_order);
+    store_order(swap_order);
+    ORDERS_BY_OWNER.with(|index| index.borrow_mut().insert((owner_principal, order_id), ()));
+    if let Some(counterparty) = args.counterparty {
+        ORDERS_BY_COUNTERPARTY.with(|index| index.borrow_mut().insert((counterparty.into(), order_id), ()));
+    }
+    self_trade::record_self_trades(order_id, &book_fill.self_trades);
+
+    Ok(order_id)
+}
+
+// `amount` is the part of the order's from_amount the executor wants to take;
+// None fills whatever remains. `max_to_amount` caps what the executor pays and
+// `min_from_amount` sets the least they accept to receive, so a fill against
+// an order amended or partly taken in the meantime fails instead of settling
+// on terms the executor didn't expect.
+#[ic_cdk::update]
+async fn execute_swap_order(
+    order_id: u64,
+    amount: Option<u128>,
+    max_to_amount: Option<u128>,
+    min_from_amount: Option<u128>,
+) -> Result<ExecutionReceipt, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let executor_principal = StorablePrincipal::from(session_keys::on_behalf_of(SessionAction::Execute)?);
+    let rate = rate_for_fill(order_id).await?;
+
+    execute_swap_order_at_rate(executor_principal, order_id, amount, rate, max_to_amount, min_from_amount)
+}
+
+// Limit orders and dormant stop orders are priced against a live rate, which
+// may need a call to XRC. Other calls can run while we await, so callers read
+// the order again afterwards and every check happens on the fresh copy.
+async fn rate_for_fill(order_id: u64) -> Result<Option<f64>, Error> {
+    let pending_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+    let needs_rate = (matches!(pending_order.order_type, OrderType::Limit { .. }) || pending_order.is_dormant_stop())
+        && !pending_order.is_expired(time());
+    if !needs_rate {
+        return Ok(None);
+    }
+    Ok(Some(rates::current_rate(&pending_order.from_currency, &pending_order.to_currency).await?))
+}
+
+fn execute_swap_order_at_rate(
+    executor_principal: StorablePrincipal,
+    order_id: u64,
+    amount: Option<u128>,
+    rate: Option<f64>,
+    max_to_amount: Option<u128>,
+    min_from_amount: Option<u128>,
+) -> Result<ExecutionReceipt, Error> {
+    let (mut swap_order, fill_amount) =
+        prepare_fill(&executor_principal, order_id, amount, rate, max_to_amount, min_from_amount, time())?;
+
+    let owner_principal = StorablePrincipal::from(swap_order.owner);
+    let receipt = settle_fill(executor_principal.clone(), owner_principal, &swap_order, fill_amount)?;
+    swap_order.record_fill(executor_principal.into(), fill_amount, receipt.fee);
+    store_order(swap_order);
+
+    Ok(receipt)
+}
+
+// Runs every check a fill of the order by `executor_principal` has to pass
+// and returns the order with the amount to fill. A stop order that triggers
+// comes back with triggered_at set; it is only written if the fill settles.
+fn prepare_fill(
+    executor_principal: &StorablePrincipal,
+    order_id: u64,
+    amount: Option<u128>,
+    rate: Option<f64>,
+    max_to_amount: Option<u128>,
+    min_from_amount: Option<u128>,
+    now: u64,
+) -> Result<(SwapOrder, u128), Error> {
+    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id).as_ref().cloned())
+        .ok_or(Error::InvalidOrderId)?;
+
+    if !swap_order.is_open() {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status });
+    }
+
+    // Expired orders are settled here instead of waiting for the sweep, so a
+    // stale quote can never be filled. A refund that would overflow leaves the
+    // order open for the sweep to retry.
+    if swap_order.is_expired(now) {
+        let _ = expire_open_order(swap_order);
+        return Err(Error::OrderExpired);
+    }
+
+    // An iceberg order fills one tranche at a time
+    let remaining = swap_order.visible_remaining();
+    let fill_amount = amount.unwrap_or(remaining);
+    if fill_amount == 0 || fill_amount > remaining {
+        return Err(Error::InvalidAmount);
+    }
+
+    if max_to_amount.is_some_and(|max| fill_payment(&swap_order, fill_amount) > max)
+        || min_from_amount.is_some_and(|min| fill_amount < min)
+    {
+        return Err(Error::SlippageExceeded);
+    }
+
+    if StorablePrincipal::from(swap_order.owner) == *executor_principal {
+        return Err(Error::OwnerCannotExecute);
+    }
+
+    if !swap_order.can_be_filled_by(&executor_principal.clone().into()) {
+        return Err(Error::Unauthorized);
+    }
+
+    // Nobody trades with a blacklisted owner, whose orders can only be
+    // cancelled, nor with one taken off the allowlist
+    if blacklist::is_blacklisted(swap_order.owner) {
+        return Err(Error::Blacklisted);
+    }
+    if !allowlist::is_allowed(swap_order.owner) {
+        return Err(Error::NotAllowlisted);
+    }
+
+    match swap_order.order_type {
+        // Market orders fill at their own terms straight away
+        OrderType::Market => {}
+        OrderType::Limit { price } => {
+            // For limit orders, check the price against the current rate for the pair
+            let rate = rate.ok_or(Error::RateUnavailable)?;
+            if !is_price_condition_met(price, rate) {
+                return Err(Error::PriceConditionNotMet);
+            }
+        }
+        // Immediate orders are settled at creation and never open
+        OrderType::FillOrKill { .. } | OrderType::ImmediateOrCancel { .. } => {
+            return Err(Error::InvalidOrderStatus { current: swap_order.status })
+        }
+        OrderType::StopMarket { trigger_price, direction } => {
+            // Once triggered a stop order stays executable, whatever the rate does next
+            if swap_order.triggered_at.is_none() {
+                let rate = rate.ok_or(Error::RateUnavailable)?;
+                if !direction.is_triggered(trigger_price, rate) {
+                    return Err(Error::StopNotTriggered);
+                }
+                swap_order.triggered_at = Some(now);
+            }
+        }
+    }
+
+    // Checked last, against the payment for exactly this fill, so the
+    // shortfall reported is what the executor would need to add
+    require_available(executor_principal, &swap_order.to_currency, fill_payment(&swap_order, fill_amount))?;
+
+    Ok((swap_order, fill_amount))
+}
+
+// An accepted order has to be settled within this long, after which it
+// reverts and the taker's escrow is released
+const ACCEPTANCE_TIMEOUT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+// First half of a two-phase fill: escrows what the caller will pay for the
+// rest of the order and takes the order off the book as Accepted. Either
+// party then completes it with settle_swap_order before the timeout. Returns
+// the amount escrowed, in the order's to_currency.
+#[ic_cdk::update]
+async fn accept_swap_order(order_id: u64) -> Result<u128, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let taker_id = session_keys::on_behalf_of(SessionAction::Execute)?;
+    let taker = StorablePrincipal::from(taker_id);
+    let rate = rate_for_fill(order_id).await?;
+
+    let (mut swap_order, fill_amount) = prepare_fill(&taker, order_id, None, rate, None, None, time())?;
+    let payment = fill_payment(&swap_order, fill_amount);
+
+    let mut changes = BalanceChanges::new();
+    changes.lock(&taker, &swap_order.to_currency, payment)?;
+    changes.commit();
+    record_transaction(TransactionKind::Escrow, Some(taker_id), None, &swap_order.to_currency, payment, Some(order_id));
+
+    swap_order.status = SwapStatus::Accepted;
+    swap_order.accepted_by = Some(taker_id);
+    swap_order.accepted_at = Some(time());
+    store_order(swap_order);
+
+    Ok(payment)
+}
+
+// Second half of a two-phase fill, callable by the owner or the taker. Both
+// sides are already in escrow, so the fill can't fail for lack of funds.
+#[ic_cdk::update]
+fn settle_swap_order(order_id: u64) -> Result<ExecutionReceipt, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let acting = session_keys::on_behalf_of(SessionAction::Execute)?;
+    settle_accepted_order(acting, order_id, time())
+}
+
+fn settle_accepted_order(acting: Principal, order_id: u64, now: u64) -> Result<ExecutionReceipt, Error> {
+    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+
+    let taker = match swap_order.accepted_by {
+        Some(taker) if swap_order.status == SwapStatus::Accepted => taker,
+        _ => return Err(Error::InvalidOrderStatus { current: swap_order.status }),
+    };
+    if acting != swap_order.owner && acting != taker {
+        return Err(Error::Unauthorized);
+    }
+    // Left to time out, which releases the taker's escrow
+    if blacklist::is_blacklisted(swap_order.owner) || blacklist::is_blacklisted(taker) {
+        return Err(Error::Blacklisted);
+    }
+    if !allowlist::is_allowed(swap_order.owner) || !allowlist::is_allowed(taker) {
+        return Err(Error::NotAllowlisted);
+    }
+    // An order that expired while accepted is expired here, as prepare_fill
+    // does, instead of settling. A refund that fails leaves it to the sweep.
+    if swap_order.is_expired(now) {
+        let _ = expire_accepted_order(swap_order);
+        return Err(Error::OrderExpired);
+    }
+    if swap_order.is_acceptance_expired(now) {
+        revert_acceptance(swap_order)?;
+        return Err(Error::AcceptanceExpired);
+    }
+
+    let taker_principal = StorablePrincipal::from(taker);
+    let fill_amount = swap_order.remaining();
+    let mut changes = BalanceChanges::new();
+    changes.unlock(&taker_principal, &swap_order.to_currency, fill_payment(&swap_order, fill_amount))?;
+    changes.commit();
+
+    // The taker's payment was just unlocked and the owner's side has been in
+    // escrow all along, so a failure here is a broken invariant. Trapping
+    // rolls the unlock back with it.
+    let receipt = settle_fill(taker_principal, StorablePrincipal::from(swap_order.owner), &swap_order, fill_amount)
+        .expect("Escrowed fill failed to settle");
+    swap_order.record_fill(taker, fill_amount, receipt.fee);
+    store_order(swap_order);
+
+    Ok(receipt)
+}
+
+// Returns an accepted order to the book and releases the taker's escrow
+fn revert_acceptance(mut swap_order: SwapOrder) -> Result<(), Error> {
+    release_acceptance(&mut swap_order)?;
+    store_order(swap_order);
+
+    Ok(())
+}
+
+// Releases the taker's escrow, then the owner's with the order. An owner
+// refund that fails leaves the order on the book for the sweep to expire.
+fn expire_accepted_order(mut swap_order: SwapOrder) -> Result<(), Error> {
+    release_acceptance(&mut swap_order)?;
+    if let Err(error) = expire_open_order(swap_order.clone()) {
+        store_order(swap_order);
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+// revert_acceptance without storing the order
+fn release_acceptance(swap_order: &mut SwapOrder) -> Result<(), Error> {
+    let taker = swap_order.accepted_by.expect("Accepted orders record their taker");
+    let payment = fill_payment(swap_order, swap_order.remaining());
+
+    let mut changes = BalanceChanges::new();
+    changes.unlock(&StorablePrincipal::from(taker), &swap_order.to_currency, payment)?;
+    changes.commit();
+    record_transaction(TransactionKind::Refund, None, Some(taker), &swap_order.to_currency, payment, Some(swap_order.id));
+
+    swap_order.status = if swap_order.filled() > 0 {
+        SwapStatus::PartiallyFilled
+    } else {
+        SwapStatus::Created
+    };
+    swap_order.accepted_by = None;
+    swap_order.accepted_at = None;
+
+    Ok(())
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExecutionQuote {
+    receipt: ExecutionReceipt,         // what filling the rest of the order would settle now
+    simulated: bool,                   // always true, nothing was settled
+    error: Option<Error>,              // why execute_swap_order would fail right now
+    price_condition_met: Option<bool>, // limit and dormant stop orders, None without a known rate
+}
+
+// Previews filling the rest of the order as the caller, with the current fee
+// config and the caller's balance. Queries can't call XRC, so prices are
+// checked against the latest known rate; without one price_condition_met is
+// None and execute_swap_order decides on a fresh rate.
+#[ic_cdk::query]
+fn quote_execution(order_id: u64) -> Result<ExecutionQuote, Error> {
+    let swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+    let executor = caller();
+    let fill_amount = swap_order.visible_remaining();
+    let payment = fill_payment(&swap_order, fill_amount);
+
+    let rate = rates::latest_known_rate(&swap_order.from_currency, &swap_order.to_currency)
+        .map(|exchange_rate| exchange_rate.rate);
+    let price_condition_met = match swap_order.order_type {
+        OrderType::Limit { price } => rate.map(|rate| is_price_condition_met(price, rate)),
+        OrderType::StopMarket { trigger_price, direction } if swap_order.is_dormant_stop() => {
+            rate.map(|rate| direction.is_triggered(trigger_price, rate))
+        }
+        _ => None,
+    };
+    let error = check_quote(&swap_order, &executor, payment, price_condition_met).err();
+    let fees = fees::fees_for(
+        &StorablePrincipal::from(swap_order.owner),
+        &StorablePrincipal::from(executor),
+        payment,
+        fill_amount,
+    );
+
+    let receipt = ExecutionReceipt {
+        order_id,
+        owner: swap_order.owner,
+        executor,
+        paid_currency: swap_order.to_currency.clone(),
+        paid_amount: payment,
+        received_currency: swap_order.from_currency.clone(),
+        received_amount: fill_amount,
+        fee: fees.maker_fee,
+        taker_fee: Some(fees.taker_fee),
+        maker_rebate: Some(fees.maker_rebate),
+        executed_at: time(),
+    };
+
+    Ok(ExecutionQuote {
+        receipt,
+        simulated: true,
+        error,
+        price_condition_met,
+    })
+}
+
+// The checks execute_swap_order would fail on, in the same order
+fn check_quote(
+    swap_order: &SwapOrder,
+    executor: &Principal,
+    payment: u128,
+    price_condition_met: Option<bool>,
+) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    if !swap_order.is_open() {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status.clone() });
+    }
+    if swap_order.is_expired(time()) {
+        return Err(Error::OrderExpired);
+    }
+    if swap_order.owner == *executor {
+        return Err(Error::OwnerCannotExecute);
+    }
+    if !swap_order.can_be_filled_by(executor) {
+        return Err(Error::Unauthorized);
+    }
+    if blacklist::is_blacklisted(swap_order.owner) {
+        return Err(Error::Blacklisted);
+    }
+    if !allowlist::is_allowed(swap_order.owner) {
+        return Err(Error::NotAllowlisted);
+    }
+    match swap_order.order_type {
+        OrderType::FillOrKill { .. } | OrderType::ImmediateOrCancel { .. } => {
+            return Err(Error::InvalidOrderStatus { current: swap_order.status.clone() })
+        }
+        OrderType::Limit { .. } if price_condition_met == Some(false) => return Err(Error::PriceConditionNotMet),
+        OrderType::StopMarket { .. } if price_condition_met == Some(false) => return Err(Error::StopNotTriggered),
+        _ => {}
+    }
+
+    require_available(&StorablePrincipal::from(*executor), &swap_order.to_currency, payment)
+}
+
+// Settles both legs of a fill: the executor pays the proportional share of
+// `to_amount` in `to_currency`, split between the owner and the fee account
+// by the maker fee, and receives `fill_amount` of the `from_amount` escrowed
+// at creation less the taker fee, out of which any maker rebate goes to the
+// owner and any referral reward to the executor's referrer. All legs go
+// through one BalanceChanges, so either every leg lands or none does. Returns the receipt stored for the fill.
+fn settle_fill(
+    executor: StorablePrincipal,
+    owner: StorablePrincipal,
+    swap_order: &SwapOrder,
+    fill_amount: u128,
+) -> Result<ExecutionReceipt, Error> {
+    settle_fill_at(executor, owner, swap_order, fill_amount, fill_payment(swap_order, fill_amount))
+}
+
+// settle_fill with the payment set by the caller instead of the order's
+// price, for accepted counter-offers
+fn settle_fill_at(
+    executor: StorablePrincipal,
+    owner: StorablePrincipal,
+    swap_order: &SwapOrder,
+    fill_amount: u128,
+    payment: u128,
+) -> Result<ExecutionReceipt, Error> {
+    let fees = fees::fees_for(&owner, &executor, payment, fill_amount);
+    let fee_account = fees::fee_account();
+    let referral = referrals::reward_for(&executor, &fees);
+    stage_fill(&executor, &owner, swap_order, fill_amount, payment, &fees, &fee_account, referral.as_ref())?.commit();
+
+    let order_id = Some(swap_order.id);
+    record_transaction(
+        TransactionKind::Fill,
+        Some(executor.clone().into()),
+        Some(owner.clone().into()),
+        &swap_order.to_currency,
+        payment - fees.maker_fee,
+        order_id,
+    );
+    if fees.maker_fee > 0 {
+        record_transaction(
+            TransactionKind::Fee,
+            Some(executor.clone().into()),
+            Some(fee_account.clone().into()),
+            &swap_order.to_currency,
+            fees.maker_fee,
+            order_id,
+        );
+    }
+    record_transaction(
+        TransactionKind::Fill,
+        Some(owner.clone().into()),
+        Some(executor.clone().into()),
+        &swap_order.from_currency,
+        fill_amount - fees.taker_fee,
+        order_id,
+    );
+    if fees.taker_fee > 0 {
+        record_transaction(
+            TransactionKind::Fee,
+            Some(owner.clone().into()),
+            Some(fee_account.clone().into()),
+            &swap_order.from_currency,
+            fees.taker_fee,
+            order_id,
+        );
+    }
+    if fees.maker_rebate > 0 {
+        record_transaction(
+            TransactionKind::Rebate,
+            Some(fee_account.clone().into()),
+            Some(owner.clone().into()),
+            &swap_order.from_currency,
+            fees.maker_rebate,
+            order_id,
+        );
+    }
+    if let Some((referrer, reward)) = referral {
+        record_transaction(
+            TransactionKind::ReferralReward,
+            Some(fee_account.into()),
+            Some(referrer.clone().into()),
+            &swap_order.from_currency,
+            reward,
+            order_id,
+        );
+        referrals::record_reward(&referrer, &swap_order.from_currency, reward);
+    }
+
+    stats::record_fill_volume(&swap_order.from_currency, &swap_order.to_currency, fill_amount, payment);
+    ticker::record_trade(&swap_order.from_currency, &swap_order.to_currency, fill_amount, payment);
+    candles::record_trade(&swap_order.from_currency, &swap_order.to_currency, fill_amount, payment);
+    trade_feed::record_trade(&swap_order.from_currency, &swap_order.to_currency, fill_amount, payment);
+    fee_tiers::record_trade_volume(&executor, payment);
+    fee_tiers::record_trade_volume(&owner, fill_amount);
+    leaderboard::record_trade(&executor);
+    leaderboard::record_trade(&owner);
+    pnl::record_fill(
+        &owner,
+        &swap_order.from_currency,
+        fill_amount - fees.maker_rebate,
+        &swap_order.to_currency,
+        payment - fees.maker_fee,
+    );
+    pnl::record_fill(
+        &executor,
+        &swap_order.to_currency,
+        payment,
+        &swap_order.from_currency,
+        fill_amount - fees.taker_fee,
+    );
+
+    let receipt = ExecutionReceipt {
+        order_id: swap_order.id,
+        owner: owner.into(),
+        executor: executor.into(),
+        paid_currency: swap_order.to_currency.clone(),
+        paid_amount: payment,
+        received_currency: swap_order.from_currency.clone(),
+        received_amount: fill_amount,
+        fee: fees.maker_fee,
+        taker_fee: Some(fees.taker_fee),
+        maker_rebate: Some(fees.maker_rebate),
+        executed_at: time(),
+    };
+    let fill_number = store_receipt(receipt.clone());
+    record_event(EventKind::OrderExecuted(receipt.clone()));
+    fill_callbacks::notify_fill(&receipt, fill_number);
+
+    Ok(receipt)
+}
+
+// Every balance leg of a fill, staged and not yet committed, so settle_fill_at
+// writes all of them or, when one fails, none
+#[allow(clippy::too_many_arguments)]
+fn stage_fill(
+    executor: &StorablePrincipal,
+    owner: &StorablePrincipal,
+    swap_order: &SwapOrder,
+    fill_amount: u128,
+    payment: u128,
+    fees: &fees::FillFees,
+    fee_account: &StorablePrincipal,
+    referral: Option<&(StorablePrincipal, u128)>,
+) -> Result<BalanceChanges, Error> {
+    let referral_reward = referral.map_or(0, |(_, reward)| *reward);
+
+    let mut changes = BalanceChanges::new();
+    changes.debit(executor, &swap_order.to_currency, payment)?;
+    changes.credit(owner, &swap_order.to_currency, payment - fees.maker_fee)?;
+    changes.credit(fee_account, &swap_order.to_currency, fees.maker_fee)?;
+    changes.release(owner, &swap_order.from_currency, fill_amount)?;
+    changes.credit(executor, &swap_order.from_currency, fill_amount - fees.taker_fee)?;
+    changes.credit(fee_account, &swap_order.from_currency, fees.taker_fee - fees.maker_rebate - referral_reward)?;
+    changes.credit(owner, &swap_order.from_currency, fees.maker_rebate)?;
+    if let Some((referrer, reward)) = referral {
+        changes.credit(referrer, &swap_order.from_currency, *reward)?;
+    }
+    Ok(changes)
+}
+
+// Moves funds between two accounts and records the transfer, returning the
+// transaction id
+fn transfer_funds(from: StorablePrincipal, to: StorablePrincipal, currency: &str, amount: u128) -> Result<u64, Error> {
+    if amount == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if from == to {
+        return Err(Error::SelfTransfer);
+    }
+    require_available(&from, currency, amount)?;
+
+    let mut changes = BalanceChanges::new();
+    changes.debit(&from, currency, amount)?;
+    changes.credit(&to, currency, amount)?;
+    changes.commit();
+
+    Ok(record_transaction(TransactionKind::Transfer, Some(from.into()), Some(to.into()), currency, amount, None))
+}
+
+// Moves available funds to another account inside the canister. The
+// recipient's account is created if they have never held funds.
+#[ic_cdk::update]
+fn transfer(to: Principal, currency: String, amount: u128) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    let caller_principal = caller();
+    if to == Principal::anonymous() {
+        return Err(Error::AnonymousNotAllowed);
+    }
+    let currency = normalize_currency(&currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+
+    transfer_funds(StorablePrincipal::from(caller_principal), StorablePrincipal::from(to), &currency, amount)
+}
+
+// to_amount owed by the executor for filling `fill_amount` more of the order.
+// Computed as the difference of cumulative totals rounded up, so each partial
+// fill rounds in the maker's favour while a complete fill always sums to
+// exactly `to_amount`.
+fn fill_payment(swap_order: &SwapOrder, fill_amount: u128) -> u128 {
+    let already_paid = cumulative_payment(swap_order, swap_order.filled());
+    let paid_after_fill = cumulative_payment(swap_order, swap_order.filled() + fill_amount);
+    paid_after_fill - already_paid
+}
+
+// Total to_amount owed once `filled` of the order's from_amount has been
+// taken. Never more than to_amount, since filled never exceeds from_amount.
+fn cumulative_payment(swap_order: &SwapOrder, filled: u128) -> u128 {
+    mul_div_ceil(filled, swap_order.to_amount, swap_order.from_amount)
+        .expect("Cumulative payment exceeded the order's to_amount")
+}
+
+#[ic_cdk::update]
+fn cancel_swap_order(order_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let caller_principal = StorablePrincipal::from(session_keys::on_behalf_of(SessionAction::Cancel)?);
+    let swap_order = SWAP_ORDERS.with(|orders| orders.borrow_mut().get(&order_id).as_ref().cloned())
+        .ok_or(Error::InvalidOrderId)?;
+
+    if !swap_order.is_open() {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status });
+    }
+
+    if swap_order.owner != caller_principal.clone().into() {
+        return Err(Error::Unauthorized);
+    }
+
+    let owner = swap_order.owner;
+    cancel_open_order(swap_order, CancelReason::Owner, Some(owner))
+}
+
+// Kept short enough that the reason fits in the order and its audit event
+const MAX_CANCEL_REASON_BYTES: usize = 128;
+
+// Support's way to unwind an order the owner can't or shouldn't keep, e.g. a
+// lost key or a fat-fingered price. Escrow goes back to the owner as with a
+// normal cancel, and the audit event records who cancelled it and why.
+#[ic_cdk::update]
+fn admin_cancel_order(order_id: u64, reason: String) -> Result<(), Error> {
+    admin::require_admin()?;
+    if reason.trim().is_empty() || reason.len() > MAX_CANCEL_REASON_BYTES {
+        return Err(Error::InvalidReason);
+    }
+    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+
+    if !swap_order.is_open() {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status });
+    }
+
+    let owner = swap_order.owner;
+    swap_order.admin_cancel_reason = Some(reason.clone());
+    cancel_open_order(swap_order, CancelReason::Admin, Some(caller()))?;
+
+    record_event(EventKind::OrderCancelledByAdmin {
+        order_id,
+        owner,
+        admin: caller(),
+        reason,
+    });
+
+    Ok(())
+}
+
+// `initiator` is who asked for the cancel: the owner or an admin, or None when
+// it follows from something else, like a fill or a self-trade
+fn cancel_open_order(
+    mut swap_order: SwapOrder,
+    reason: CancelReason,
+    initiator: Option<Principal>,
+) -> Result<(), Error> {
+    refund_escrow(&swap_order)?;
+
+    swap_order.status = SwapStatus::Cancelled;
+    swap_order.cancelled_at = Some(time());
+    swap_order.cancel_reason = Some(reason);
+    record_event(EventKind::OrderCancelled {
+        order_id: swap_order.id,
+        owner: swap_order.owner,
+        reason: Some(reason),
+        initiator,
+    });
+    store_order(swap_order);
+
+    Ok(())
+}
+
+fn expire_open_order(mut swap_order: SwapOrder) -> Result<(), Error> {
+    refund_escrow(&swap_order)?;
+
+    swap_order.status = SwapStatus::Expired;
+    swap_order.cancel_reason = Some(CancelReason::Expired);
+    record_event(EventKind::OrderExpired {
+        order_id: swap_order.id,
+        owner: swap_order.owner,
+    });
+    store_order(swap_order);
+
+    Ok(())
+}
+
+// Changes the terms of an order nobody has filled yet. The order keeps its id,
+// so it keeps its time priority among orders at its new price. from_amount can
+// only shrink; the difference is released from escrow.
+#[ic_cdk::update]
+fn amend_swap_order(
+    order_id: u64,
+    new_to_amount: Option<u128>,
+    new_price: Option<Price>,
+    new_from_amount: Option<u128>,
+) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    let mut swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id))
+        .ok_or(Error::InvalidOrderId)?;
+
+    if swap_order.owner != caller() {
+        return Err(Error::Unauthorized);
+    }
+    if swap_order.status != SwapStatus::Created {
+        return Err(Error::InvalidOrderStatus { current: swap_order.status });
+    }
+    if new_to_amount == Some(0) {
+        return Err(Error::InvalidAmount);
+    }
+    if let Some(from_amount) = new_from_amount {
+        if from_amount == 0 || from_amount > swap_order.from_amount {
+            return Err(Error::InvalidAmount);
+        }
+        currencies::check_amount_precision(&swap_order.from_currency, from_amount)?;
+    }
+    if let Some(to_amount) = new_to_amount {
+        currencies::check_amount_precision(&swap_order.to_currency, to_amount)?;
+    }
+    // The same bound check_order_args holds an iceberg to, nothing being
+    // filled yet
+    if let Some(display_amount) = swap_order.display_amount {
+        if display_amount == 0 || display_amount >= new_from_amount.unwrap_or(swap_order.from_amount) {
+            return Err(Error::InvalidDisplayAmount);
+        }
+    }
+    if let Some(price) = new_price {
+        if !price.is_valid() {
+            return Err(Error::InvalidPrice);
+        }
+        match &mut swap_order.order_type {
+            OrderType::Limit { price: current } | OrderType::StopMarket { trigger_price: current, .. } => {
+                *current = price;
+            }
+            // Market orders have no price, and immediate orders are never open
+            _ => return Err(Error::InvalidPrice),
+        }
+    }
+    pairs::pair_config(&swap_order.from_currency, &swap_order.to_currency).check_order(
+        new_from_amount.unwrap_or(swap_order.from_amount),
+        new_to_amount.unwrap_or(swap_order.to_amount),
+        swap_order.order_type.price(),
+    )?;
+
+    let released = swap_order.from_amount - new_from_amount.unwrap_or(swap_order.from_amount);
+    if released > 0 {
+        let mut changes = BalanceChanges::new();
+        changes.unlock(&StorablePrincipal::from(swap_order.owner), &swap_order.from_currency, released)?;
+        changes.commit();
+
+        record_transaction(
+            TransactionKind::Refund,
+            None,
+            Some(swap_order.owner),
+            &swap_order.from_currency,
+            released,
+            Some(order_id),
+        );
+    }
+
+    swap_order.from_amount = new_from_amount.unwrap_or(swap_order.from_amount);
+    swap_order.to_amount = new_to_amount.unwrap_or(swap_order.to_amount);
+    swap_order.updated_at = Some(time());
+    // A new price goes back to waiting for the rate scan
+    if new_price.is_some() {
+        swap_order.price_reached_at = None;
+    }
+    store_order(swap_order);
+
+    Ok(())
+}
+
+// Upper bound on orders cancelled by a single cancel_all_my_orders call
+const MAX_CANCELS_PER_CALL: usize = 100;
+
+// Upper bound on orders a single cancel_all_my_orders call looks at, open or not
+const MAX_ORDERS_SCANNED_PER_CANCEL_ALL: usize = 1_000;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CancelAllResult {
+    cancelled: Vec<u64>,
+    next_start_id: Option<u64>, // pass as start_id to cancel the rest, None once every order was looked at
+}
+
+// Cancels the caller's open orders from `start_id` on, optionally only those
+// selling the pair's first currency for its second, and refunds their escrow.
+// At most MAX_CANCELS_PER_CALL orders are cancelled and
+// MAX_ORDERS_SCANNED_PER_CANCEL_ALL looked at per call to stay within the
+// instruction limit.
+#[ic_cdk::update]
+fn cancel_all_my_orders(pair: Option<(String, String)>, start_id: Option<u64>) -> Result<CancelAllResult, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let caller_principal = StorablePrincipal::from(session_keys::on_behalf_of(SessionAction::Cancel)?);
+    let pair = pair.map(|(from_currency, to_currency)| {
+        CurrencyPair::new(&normalize_currency(&from_currency), &normalize_currency(&to_currency))
+    });
+
+    let mut cancelled = Vec::new();
+    let mut next_start_id = start_id.unwrap_or(0);
+    let mut scanned = 0;
+    loop {
+        let next_key = ORDERS_BY_OWNER.with(|index| {
+            index
+                .borrow()
+                .range((caller_principal.clone(), next_start_id)..=(caller_principal.clone(), u64::MAX))
+                .next()
+        });
+        let Some(((_, order_id), _)) = next_key else {
+            return Ok(CancelAllResult { cancelled, next_start_id: None });
+        };
+        if scanned == MAX_ORDERS_SCANNED_PER_CANCEL_ALL || cancelled.len() == MAX_CANCELS_PER_CALL {
+            return Ok(CancelAllResult { cancelled, next_start_id: Some(order_id) });
+        }
+        scanned += 1;
+        next_start_id = order_id.saturating_add(1);
+
+        let swap_order = match SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id)) {
+            Some(swap_order) if swap_order.is_open() => swap_order,
+            _ => continue,
+        };
+        if let Some(pair) = &pair {
+            if swap_order.from_currency != pair.from_currency || swap_order.to_currency != pair.to_currency {
+                continue;
+            }
+        }
+        // An order whose refund would overflow the balance stays open, the
+        // same as in the expiry sweep
+        if cancel_open_order(swap_order, CancelReason::Owner, Some(caller_principal.0)).is_ok() {
+            cancelled.push(order_id);
+        }
+    }
+}
+
+// Returns the unfilled remainder of an order's escrow to its owner, in the
+// order's from_currency only. The owner's account must already exist since it
+// holds the locked funds, so a missing account fails with UserNotFound rather
+// than being created.
+fn refund_escrow(swap_order: &SwapOrder) -> Result<(), Error> {
+    stage_escrow_refund(swap_order)?.commit();
+
+    record_transaction(
+        TransactionKind::Refund,
+        None,
+        Some(swap_order.owner),
+        &swap_order.from_currency,
+        swap_order.remaining(),
+        Some(swap_order.id),
+    );
+
+    Ok(())
+}
+
+// Returns the order's remainder from escrow to the owner's available balance
+fn stage_escrow_refund(swap_order: &SwapOrder) -> Result<BalanceChanges, Error> {
+    let owner_principal = StorablePrincipal::from(swap_order.owner);
+    let mut changes = BalanceChanges::new();
+    changes.unlock(&owner_principal, &swap_order.from_currency, swap_order.remaining())?;
+    Ok(changes)
+}
+
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const ORDER_REWRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Orders rewritten per tick by rewrite_order_encodings, and by post_upgrade
+// before the timers start
+const ORDER_REWRITE_BATCH_SIZE: usize = 500;
+
+// Orders inspected per sweep tick, keeps a single tick well inside the
+// instruction limit no matter how many orders are stored
+const EXPIRY_SWEEP_BATCH_SIZE: usize = 200;
+
+// Expires and refunds open orders past their expires_at. Each tick scans the
+// next batch of ids after the cursor and wraps around once it reaches the end,
+// so the whole map is covered over successive ticks.
+fn sweep_expired_orders() {
+    let now = time();
+    let cursor = EXPIRY_SWEEP_CURSOR.with(|cursor| cursor.get());
+    let batch: Vec<(u64, SwapOrder)> = SWAP_ORDERS.with(|orders| {
+        orders.borrow().range(cursor..).take(EXPIRY_SWEEP_BATCH_SIZE).collect()
+    });
+
+    let next_cursor = match batch.last() {
+        Some((last_id, _)) if batch.len() == EXPIRY_SWEEP_BATCH_SIZE => last_id + 1,
+        _ => 0,
+    };
+    EXPIRY_SWEEP_CURSOR.with(|cursor| cursor.set(next_cursor));
+
+    for (_, swap_order) in batch {
+        // An order whose refund would overflow the owner's balance stays open
+        // and is retried on the next pass
+        if swap_order.is_open() && swap_order.is_expired(now) {
+            let _ = expire_open_order(swap_order);
+        } else if swap_order.is_acceptance_expired(now) {
+            let _ = revert_acceptance(swap_order);
+        }
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CurrencyBalance {
+    currency: String,
+    available: u128, // free to trade or withdraw
+    locked: u128,    // escrowed by open orders
+    total: u128,
+}
+
+// Balances of the caller for one currency, or for every currency they hold
+// when `currency` is None
+#[ic_cdk::query]
+fn get_user_balance(currency: Option<String>) -> Vec<CurrencyBalance> {
+    balances_of(&StorablePrincipal::from(caller()), currency)
+}
+
+fn balances_of(principal: &StorablePrincipal, currency: Option<String>) -> Vec<CurrencyBalance> {
+    let user_account = match USER_ACCOUNTS.with(|accounts| accounts.borrow().get(principal)) {
+        Some(user_account) => user_account,
+        None => return Vec::new(),
+    };
+
+    let currencies: Vec<String> = match currency {
+        Some(currency) => vec![normalize_currency(&currency)],
+        None => {
+            let mut held: Vec<String> = user_account.balances.keys().cloned().collect();
+            if let Some(locked) = &user_account.locked {
+                held.extend(locked.keys().cloned());
+            }
+            held.sort();
+            held.dedup();
+            held
+        }
+    };
+
+    currencies
+        .into_iter()
+        .map(|currency| {
+            let available = user_account.balance(&currency);
+            let locked = user_account.locked(&currency);
+            CurrencyBalance {
+                total: available.saturating_add(locked),
+                currency,
+                available,
+                locked,
+            }
+        })
+        .collect()
+}
+
+// The owner sees the whole order; anyone else sees an iceberg order's
+// current tranche only
+#[ic_cdk::query]
+fn get_swap_order(order_id: u64) -> Option<SwapOrder> {
+    archive::find_order(order_id).map(|order| order.viewed_by(&caller()))
+}
+
+// Upper bound on ids looked up by a single get_swap_orders call
+const MAX_ORDER_LOOKUP_SIZE: usize = 100;
+
+// get_swap_order for each id, in order, with None for ids that match no live
+// or archived order. A list longer than MAX_ORDER_LOOKUP_SIZE is rejected
+// whole rather than answered with a None per id, which would read as missing.
+#[ic_cdk::query]
+fn get_swap_orders(ids: Vec<u64>) -> Result<Vec<Option<SwapOrder>>, Error> {
+    if ids.len() > MAX_ORDER_LOOKUP_SIZE {
+        return Err(Error::BatchTooLarge { max: MAX_ORDER_LOOKUP_SIZE as u64 });
+    }
+    Ok(ids.into_iter().map(get_swap_order).collect())
+}
+
+// Upper bound on orders returned by a single page of get_my_orders
+const MAX_ORDERS_PAGE_SIZE: u64 = 100;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrdersPage {
+    orders: Vec<SwapOrder>,
+    total: u64,               // number of orders the caller has placed, before the status and label filters
+    labels: Vec<OrderLabels>, // set by tag_order, for the orders on this page that have any
+}
+
+// The caller's most recent order tagged with `memo`
+#[ic_cdk::query]
+fn find_order_by_memo(memo: String) -> Option<SwapOrder> {
+    let caller_principal = StorablePrincipal::from(caller());
+
+    owner_order_ids_newest_first(&caller_principal)
+        .filter_map(archive::find_order)
+        .find(|order| order.memo.as_deref() == Some(memo.as_str()))
+}
+
+// `filter_label` keeps only orders tag_order put that label on
+#[ic_cdk::query]
+fn get_my_orders(offset: u64, limit: u64, status: Option<SwapStatus>, filter_label: Option<String>) -> OrdersPage {
+    let caller_principal = StorablePrincipal::from(caller());
+    let limit = limit.min(MAX_ORDERS_PAGE_SIZE) as usize;
+
+    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+
+    // Labels are checked on the id, so only the status filter needs the order
+    // itself, and without one only the page's orders are read
+    let labelled = owner_order_ids_newest_first(&caller_principal)
+        .filter(|&order_id| filter_label.as_ref().is_none_or(|label| order_labels::has_label(order_id, label.trim())));
+    let orders: Vec<SwapOrder> = match &status {
+        None => labelled.skip(offset).take(limit).filter_map(archive::find_order).collect(),
+        Some(status) => labelled
+            .filter_map(archive::find_order)
+            .filter(|order| &order.status == status)
+            .skip(offset)
+            .take(limit)
+            .collect(),
+    };
+    let total = ORDERS_BY_OWNER.with(|index| {
+        index.borrow().range((caller_principal.clone(), 0)..=(caller_principal, u64::MAX)).count() as u64
+    });
+    let labels = order_labels::labels_for(orders.iter().map(|order| order.id));
+
+    OrdersPage { orders, total, labels }
+}
+
+// Open OTC orders naming the caller as counterparty, newest first
+#[ic_cdk::query]
+fn get_orders_for_me() -> Vec<SwapOrder> {
+    let caller_principal = StorablePrincipal::from(caller());
+    let order_ids: Vec<u64> = ORDERS_BY_COUNTERPARTY.with(|index| {
+        index
+            .borrow()
+            .range((caller_principal.clone(), 0)..=(caller_principal, u64::MAX))
+            .map(|((_, order_id), _)| order_id)
+            .collect()
+    });
+
+    order_ids
+        .into_iter()
+        .rev()
+        .filter_map(|order_id| SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id)))
+        .filter(SwapOrder::is_open)
+        .map(SwapOrder::public_view)
+        .collect()
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrdersCursorPage {
+    orders: Vec<SwapOrder>,
+    next_start_id: Option<u64>, // pass as start_id to fetch the next page, None once exhausted
+}
+
+// Pages through every live order in id order; archived orders are paged by
+// list_archived_orders. The range scan starts at the cursor
+// key and stops after the page, so the cost is independent of the map size.
+#[ic_cdk::query]
+fn list_orders(start_id: u64, limit: u16) -> OrdersCursorPage {
+    let limit = (limit as u64).min(MAX_ORDERS_PAGE_SIZE) as usize;
+    let viewer = caller();
+    let mut orders: Vec<SwapOrder> = SWAP_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .range(start_id..)
+            .take(limit + 1)
+            .map(|(_, order)| order.viewed_by(&viewer))
+            .collect()
+    });
+
+    // The extra entry only tells us where the next page starts
+    let next_start_id = if orders.len() > limit {
+        orders.pop().map(|order| order.id)
+    } else {
+        None
+    };
+
+    OrdersCursorPage { orders, next_start_id }
+}
+
+// Upper bound on the number of orders returned per side of the order book
+const MAX_ORDER_BOOK_DEPTH: u32 = 100;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrderBook {
+    asks: Vec<SwapOrder>, // open orders selling from_currency for to_currency
+    bids: Vec<SwapOrder>, // open orders selling to_currency for from_currency
+}
+
+#[ic_cdk::query]
+fn get_order_book(from_currency: String, to_currency: String, depth: u32) -> Result<OrderBook, Error> {
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    // Disabled currencies may still have open orders waiting to be cancelled
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+
+    let depth = depth.min(MAX_ORDER_BOOK_DEPTH) as usize;
+    // Iceberg orders show their current tranche only, to their owner too
+    let asks = book_side(&from_currency, &to_currency).take(depth).map(SwapOrder::public_view).collect();
+    let bids = book_side(&to_currency, &from_currency).take(depth).map(SwapOrder::public_view).collect();
+
+    Ok(OrderBook { asks, bids })
+}
+
+// A limit order asks for at least `price` units of to_currency per unit of
+// from_currency, so it becomes executable once the market rate reaches it
+fn is_price_condition_met(price: Price, rate: f64) -> bool {
+    price.is_at_most(rate)
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+enum Error {
+    InsufficientFunds { required: u128, available: u128, currency: String },
+    InvalidOrderId,
+    InvalidOrderStatus { current: SwapStatus },
+    Unauthorized,
+    UserNotFound,
+    PriceConditionNotMet,
+    InvalidAmount,
+    InvalidCurrency { provided: String },
+    InvalidPrice,
+    AnonymousNotAllowed,
+    OwnerCannotExecute,
+    OrderExpired,
+    RateUnavailable,
+    RateStale,
+    RateFetchFailed(String),
+    InvalidRateConfig,
+    InvalidFee,
+    TradingPaused,
+    Overflow,
+    SameCurrency,
+    SelfTransfer,
+    InsufficientLiquidity,
+    StopNotTriggered,
+    BatchTooLarge { max: u64 },
+    SlippageExceeded,
+    InvalidPairConfig,
+    BelowMinFromAmount { minimum: u128 },
+    BelowMinToAmount { minimum: u128 },
+    PriceNotOnTick { tick: Price },
+    WithdrawalLimitExceeded { remaining: u128 },
+    InvalidMemo,
+    LedgerNotConfigured { currency: String },
+    LedgerCallFailed(String),
+    InvalidAccount,
+    WithdrawalNotFound,
+    WithdrawalNotPending,
+    InvalidCounterparty,
+    InvalidReason,
+    Blacklisted,
+    NotAllowlisted,
+    RateLimited { retry_after_secs: u64 },
+    ExportNotFound,
+    InvalidChunkIndex,
+    ImportNotAllowed,
+    ImportIncomplete,
+    ImportHashMismatch,
+    InvalidSnapshot,
+    ImportInProgress,
+    TooManyOpenOrders { limit: u64 },
+    AcceptanceExpired,
+    OrderIdsExhausted,
+    StorageWriteFailed,
+    CertificateUnavailable,
+    InvalidDecimals,
+    AmountNotOnTick { currency: String, tick: u128 },
+    InvalidSchedule,
+    TooManyRecurringOrders { limit: u64 },
+    RecurringOrderNotFound,
+    RecurringOrderNotActive,
+    InvalidOcoOrders,
+    InvalidDisplayAmount, // needs a limit order and less than its from_amount
+    InvalidLabels,        // at most 5 labels of 1 to 16 bytes each
+    CounterOfferNotFound,
+    CounterOfferNotOpen,
+    CounterOfferStale, // the order has less left than the offer asks for
+    TooManyCounterOffers { limit: u64 },
+    InvalidRecoveryPrincipal, // anonymous or the owner themselves
+    InvalidRecoveryDelay,
+    RecoveryNotConfigured,
+    RecoveryNotRequested,
+    RecoveryAlreadyRequested,
+    RecoveryDelayNotElapsed { claimable_at: u64 },
+    InvalidSessionKey, // anonymous, the grantor, or an expiry in the past or too far ahead
+    SessionKeyInUse,   // already acting for another grantor
+    SessionKeyNotFound,
+    SessionKeyExpired,
+    SessionKeyNotPermitted,
+    TooManySessionKeys { limit: u64 },
+    DestinationAlreadyRegistered,
+    DestinationNotFound,
+    DestinationNotRegistered, // the caller restricted withdrawals to their registered destinations
+    DestinationNotYetActive { active_at: u64 },
+    TooManyWithdrawalDestinations { limit: u64 },
+    WithdrawalNotYetConfirmable { confirmable_at: u64 },
+    InvalidCallback, // needs a method of 1 to 64 bytes on some other canister
+    InvalidWebhook,  // an https:// URL of up to 256 bytes and a secret of 32 to 64 bytes
+    WebhookNotFound,
+    TooManyWebhooks { limit: u64 },
+    PoolNotFound,
+    PoolAlreadyExists,
+    InsufficientShares { held: u128 },
+    InvalidReferrer,
+    ReferrerAlreadyRegistered,
+    ReferralCycle, // the referrer was referred by the caller, directly or down the line
+    ProposalPending { proposal_id: u64 }, // held until enough admins call approve_proposal
+    ProposalNotFound,
+    ProposalNotPending,
+    AlreadyApproved,
+    AlreadyAdmin,
+    AdminNotFound,
+    TooManyAdmins { limit: u64 },
+    InvalidApprovalThreshold,
+    RecordTooLarge { size: u64, max_size: u32 }, // encoded bytes, over the record's bound
+    RecordNotFound,
+    RecordNotCorrupt,   // only records that fail to decode can be quarantined
+    InvalidRecordKey,   // the key's kind doesn't match the map
+    FilterNotCountable, // count_orders keeps no counter for a time range or owner
+    InvalidCancelRatioLimit,
+    OrderCreationBlocked { until: u64, cancel_ratio_bps: u16 }, // the owner cancelled too many of its orders
+    DepositNotFound,
+    DepositAlreadyCredited,
+    ExportInvalidated, // the state changed between create_export calls; start over
+}
+
+// need this to generate candid
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> StorablePrincipal {
+        StorablePrincipal::from(candid::Principal::from_slice(&[id]))
+    }
+
+    fn store_account(principal: &StorablePrincipal, balances: &[(&str, u128)], locked: &[(&str, u128)]) {
+        let user_account = UserAccount {
+            balances: balances.iter().map(|(currency, amount)| (currency.to_string(), *amount)).collect(),
+            locked: Some(locked.iter().map(|(currency, amount)| (currency.to_string(), *amount)).collect()),
+        };
+        USER_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(principal.clone(), user_account));
+    }
+
+    fn stored_account(principal: &StorablePrincipal) -> Option<UserAccount> {
+        USER_ACCOUNTS.with(|accounts| accounts.borrow().get(principal))
+    }
+
+    // A resting order selling 40 EUR for 30 USD
+    fn eur_order(owner: &StorablePrincipal) -> SwapOrder {
+        SwapOrder {
+            id: 1,
+            owner: owner.0,
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: 40,
+            to_amount: 30,
+            ..SwapOrder::default()
+        }
+    }
+
+    const NO_FEES: fees::FillFees = fees::FillFees { maker_fee: 0, taker_fee: 0, maker_rebate: 0 };
+
+    #[test]
+    fn fill_moves_both_legs() {
+        let (owner, executor) = (principal(10), principal(11));
+        store_account(&owner, &[], &[("EUR", 40)]);
+        store_account(&executor, &[("USD", 30)], &[]);
+
+        let changes = stage_fill(&executor, &owner, &eur_order(&owner), 40, 30, &NO_FEES, &principal(0), None).unwrap();
+
+        let (owner_after, executor_after) = (&changes.accounts[&owner], &changes.accounts[&executor]);
+        assert_eq!(owner_after.balance("USD"), 30);
+        assert_eq!(owner_after.locked("EUR"), 0);
+        assert_eq!(executor_after.balance("USD"), 0);
+        assert_eq!(executor_after.balance("EUR"), 40);
+    }
+
+    #[test]
+    fn fill_with_fees_conserves_every_currency() {
+        let (owner, executor, referrer) = (principal(14), principal(15), principal(16));
+        store_account(&owner, &[], &[("EUR", 40)]);
+        store_account(&executor, &[("USD", 30)], &[]);
+        let fees = fees::FillFees { maker_fee: 3, taker_fee: 4, maker_rebate: 1 };
+        let fee_account = principal(0);
+
+        let referral = (referrer.clone(), 2);
+        let changes =
+            stage_fill(&executor, &owner, &eur_order(&owner), 40, 30, &fees, &fee_account, Some(&referral)).unwrap();
+
+        let total = |currency: &str| {
+            changes
+                .accounts
+                .values()
+                .map(|user_account| user_account.balance(currency) + user_account.locked(currency))
+                .sum::<u128>()
+        };
+        assert_eq!(total("USD"), 30);
+        assert_eq!(total("EUR"), 40);
+        assert_eq!(changes.accounts[&owner].balance("USD"), 27);
+        assert_eq!(changes.accounts[&executor].balance("EUR"), 36);
+        assert_eq!(changes.accounts[&owner].balance("EUR"), 1);
+        assert_eq!(changes.accounts[&referrer].balance("EUR"), 2);
+        assert_eq!(changes.accounts[&fee_account].balance("EUR"), 1);
+    }
+
+    #[test]
+    fn fill_fails_whole_when_the_executor_cannot_pay() {
+        let (owner, executor) = (principal(12), principal(13));
+        store_account(&owner, &[], &[("EUR", 40)]);
+        store_account(&executor, &[("USD", 20)], &[]);
+
+        let staged = stage_fill(&executor, &owner, &eur_order(&owner), 40, 30, &NO_FEES, &principal(0), None);
+
+        assert_eq!(
+            staged.err(),
+            Some(Error::InsufficientFunds {
+                required: 30,
+                available: 20,
+                currency: "USD".to_string(),
+            })
+        );
+        assert_eq!(stored_account(&owner).unwrap().balance("USD"), 0);
+        assert_eq!(stored_account(&owner).unwrap().locked("EUR"), 40);
+        assert_eq!(stored_account(&executor).unwrap().balance("USD"), 20);
+        assert_eq!(stored_account(&executor).unwrap().balance("EUR"), 0);
+    }
+
+    #[test]
+    fn fill_fails_whole_when_the_escrow_is_short() {
+        // The executor's payment is staged before the release leg fails
+        let (owner, executor) = (principal(17), principal(18));
+        store_account(&owner, &[], &[("EUR", 25)]);
+        store_account(&executor, &[("USD", 30)], &[]);
+
+        let staged = stage_fill(&executor, &owner, &eur_order(&owner), 40, 30, &NO_FEES, &principal(0), None);
+
+        assert!(matches!(staged, Err(Error::InsufficientFunds { .. })));
+        assert_eq!(stored_account(&owner).unwrap().balance("USD"), 0);
+        assert_eq!(stored_account(&owner).unwrap().locked("EUR"), 25);
+        assert_eq!(stored_account(&executor).unwrap().balance("USD"), 30);
+        assert_eq!(stored_account(&executor).unwrap().balance("EUR"), 0);
+    }
+
+    #[test]
+    fn deposit_past_the_maximum_balance_overflows() {
+        let depositor = principal(20);
+        store_account(&depositor, &[("USD", u128::MAX - 5)], &[]);
+
+        let mut changes = BalanceChanges::new();
+        changes.credit(&depositor, "USD", 5).unwrap();
+        assert_eq!(changes.accounts[&depositor].balance("USD"), u128::MAX);
+        assert_eq!(changes.credit(&depositor, "USD", 1), Err(Error::Overflow));
+        assert_eq!(changes.accounts[&depositor].balance("USD"), u128::MAX);
+    }
+
+    #[test]
+    fn unlock_past_the_maximum_balance_overflows() {
+        let owner = principal(21);
+        store_account(&owner, &[("EUR", u128::MAX)], &[("EUR", 1)]);
+
+        let mut changes = BalanceChanges::new();
+        assert_eq!(changes.unlock(&owner, "EUR", 1), Err(Error::Overflow));
+        assert_eq!(stored_account(&owner).unwrap().balance("EUR"), u128::MAX);
+        assert_eq!(stored_account(&owner).unwrap().locked("EUR"), 1);
+    }
+
+    #[test]
+    fn lock_past_the_maximum_escrow_overflows() {
+        let owner = principal(22);
+        store_account(&owner, &[("EUR", 10)], &[("EUR", u128::MAX)]);
+
+        let mut changes = BalanceChanges::new();
+        assert_eq!(changes.lock(&owner, "EUR", 10), Err(Error::Overflow));
+    }
+
+    #[test]
+    fn fill_into_a_full_balance_overflows_without_wrapping() {
+        let (owner, executor) = (principal(23), principal(24));
+        store_account(&owner, &[("USD", u128::MAX - 10)], &[("EUR", 40)]);
+        store_account(&executor, &[("USD", 30)], &[]);
+
+        let staged = stage_fill(&executor, &owner, &eur_order(&owner), 40, 30, &NO_FEES, &principal(0), None);
+
+        assert_eq!(staged.err(), Some(Error::Overflow));
+        assert_eq!(stored_account(&owner).unwrap().balance("USD"), u128::MAX - 10);
+        assert_eq!(stored_account(&owner).unwrap().locked("EUR"), 40);
+        assert_eq!(stored_account(&executor).unwrap().balance("USD"), 30);
+        assert_eq!(stored_account(&executor).unwrap().balance("EUR"), 0);
+    }
+
+    #[test]
+    fn fill_near_the_maximum_balance_settles_exactly() {
+        let (owner, executor) = (principal(25), principal(26));
+        store_account(&owner, &[("USD", u128::MAX - 30)], &[("EUR", 40)]);
+        store_account(&executor, &[("USD", 30), ("EUR", u128::MAX - 40)], &[]);
+
+        let changes = stage_fill(&executor, &owner, &eur_order(&owner), 40, 30, &NO_FEES, &principal(0), None).unwrap();
+
+        assert_eq!(changes.accounts[&owner].balance("USD"), u128::MAX);
+        assert_eq!(changes.accounts[&executor].balance("EUR"), u128::MAX);
+        assert_eq!(changes.accounts[&executor].balance("USD"), 0);
+    }
+
+    pub(crate) fn order_args(from_currency: &str, to_currency: &str) -> CreateSwapOrderArgs {
+        CreateSwapOrderArgs {
+            from_currency: from_currency.to_string(),
+            to_currency: to_currency.to_string(),
+            from_amount: 100,
+            to_amount: 90,
+            order_type: OrderType::Market,
+            expires_at: None,
+            memo: None,
+            counterparty: None,
+            display_amount: None,
+        }
+    }
+
+    #[test]
+    fn same_currency_orders_are_rejected() {
+        admin::tests::set_caller(principal(5).0);
+        rate_limit::tests::exempt(principal(5).0);
+        currencies::tests::register("USD");
+
+        assert_eq!(create_swap_order(order_args("USD", "USD")).err(), Some(Error::SameCurrency));
+    }
+
+    #[test]
+    fn same_currency_orders_are_rejected_whatever_the_casing() {
+        admin::tests::set_caller(principal(5).0);
+        rate_limit::tests::exempt(principal(5).0);
+        currencies::tests::register("USD");
+
+        assert_eq!(create_swap_order(order_args("usd", "USD")).err(), Some(Error::SameCurrency));
+        assert_eq!(create_swap_order(order_args("Usd", "uSD")).err(), Some(Error::SameCurrency));
+        assert_eq!(create_swap_order(order_args(" usd", "USD ")).err(), Some(Error::SameCurrency));
+    }
+
+    thread_local! {
+        static DECODED_ORDERS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+
+    pub(super) fn count_decoded_order() {
+        DECODED_ORDERS.with(|decoded| decoded.set(decoded.get() + 1));
+    }
+
+    pub(crate) fn decoded_orders() -> u64 {
+        DECODED_ORDERS.with(|decoded| decoded.get())
+    }
+
+    fn store_orders(ids: impl Iterator<Item = u64>) {
+        let owner = principal(30);
+        SWAP_ORDERS.with(|orders| {
+            let mut orders_borrowed = orders.borrow_mut();
+            for id in ids {
+                orders_borrowed.insert(id, SwapOrder { id, ..eur_order(&owner) });
+            }
+        });
+    }
+
+    // Resting orders of `owner`, indexed the way store_order indexes them
+    fn store_owned_orders(owner: &StorablePrincipal, ids: impl Iterator<Item = u64>) {
+        for id in ids {
+            SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(id, SwapOrder { id, ..eur_order(owner) }));
+            ORDERS_BY_OWNER.with(|index| index.borrow_mut().insert((owner.clone(), id), ()));
+        }
+    }
+
+    #[test]
+    fn a_page_of_my_orders_reads_only_the_orders_on_it() {
+        let owner = principal(33);
+        store_owned_orders(&principal(34), 1..=10);
+        store_owned_orders(&owner, 11..=300);
+        admin::tests::set_caller(owner.0);
+
+        let decoded_before = decoded_orders();
+        let page = get_my_orders(10, 5, None, None);
+        assert_eq!(decoded_orders() - decoded_before, 5);
+        assert_eq!(page.orders.iter().map(|order| order.id).collect::<Vec<_>>(), vec![290, 289, 288, 287, 286]);
+        assert_eq!(page.total, 290);
+    }
+
+    #[test]
+    fn a_memo_lookup_stops_at_the_newest_match() {
+        let owner = principal(35);
+        store_owned_orders(&owner, 1..=300);
+        for id in [100, 290] {
+            let swap_order = SwapOrder { id, memo: Some("rent".to_string()), ..eur_order(&owner) };
+            SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(id, swap_order));
+        }
+        admin::tests::set_caller(owner.0);
+
+        let decoded_before = decoded_orders();
+        assert_eq!(find_order_by_memo("rent".to_string()).map(|order| order.id), Some(290));
+        assert_eq!(decoded_orders() - decoded_before, 11);
+    }
+
+    #[test]
+    fn cancel_all_looks_at_a_bounded_batch_and_resumes_after_it() {
+        let owner = principal(36);
+        let last_id = MAX_ORDERS_SCANNED_PER_CANCEL_ALL as u64 + 1;
+        // Without an account every refund fails, so no order is cancelled and
+        // each call stops only at the scan bound
+        store_owned_orders(&owner, 1..=last_id);
+        admin::tests::set_caller(owner.0);
+        rate_limit::tests::exempt(owner.0);
+
+        let decoded_before = decoded_orders();
+        let first = cancel_all_my_orders(None, None).unwrap();
+        assert_eq!(decoded_orders() - decoded_before, MAX_ORDERS_SCANNED_PER_CANCEL_ALL as u64);
+        assert!(first.cancelled.is_empty());
+        assert_eq!(first.next_start_id, Some(last_id));
+
+        let rest = cancel_all_my_orders(None, first.next_start_id).unwrap();
+        assert!(rest.cancelled.is_empty());
+        assert_eq!(rest.next_start_id, None);
+    }
+
+    #[test]
+    fn an_order_lookup_over_the_batch_bound_is_rejected() {
+        store_orders(1..=2);
+        let ids: Vec<u64> = (1..=MAX_ORDER_LOOKUP_SIZE as u64).collect();
+        let found = get_swap_orders(ids).unwrap();
+        assert_eq!(found.len(), MAX_ORDER_LOOKUP_SIZE);
+        assert_eq!(found.iter().filter(|order| order.is_some()).count(), 2);
+
+        let ids: Vec<u64> = (1..=MAX_ORDER_LOOKUP_SIZE as u64 + 1).collect();
+        assert_eq!(get_swap_orders(ids).err(), Some(Error::BatchTooLarge { max: MAX_ORDER_LOOKUP_SIZE as u64 }));
+    }
+
+    #[test]
+    fn an_order_batch_over_the_bound_is_rejected_item_by_item() {
+        let batch = create_swap_orders(vec![order_args("USD", "EUR"); MAX_ORDER_BATCH_SIZE]);
+        assert!(batch.iter().all(|result| result.as_ref().err() == Some(&Error::AnonymousNotAllowed)));
+
+        let batch = create_swap_orders(vec![order_args("USD", "EUR"); MAX_ORDER_BATCH_SIZE + 1]);
+        let max = MAX_ORDER_BATCH_SIZE as u64;
+        assert_eq!(batch.len(), MAX_ORDER_BATCH_SIZE + 1);
+        assert!(batch.iter().all(|result| result.as_ref().err() == Some(&Error::BatchTooLarge { max })));
+    }
+
+    // eur_order stored with its 40 EUR in escrow, and the clock set
+    fn resting_eur_order(owner: &StorablePrincipal) {
+        store_account(owner, &[], &[("EUR", 40)]);
+        SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(1, eur_order(owner)));
+        admin::tests::set_time(1_000);
+    }
+
+    #[test]
+    fn an_owner_cancel_names_the_owner_as_initiator() {
+        let owner = principal(37);
+        resting_eur_order(&owner);
+        admin::tests::set_caller(owner.0);
+        rate_limit::tests::exempt(owner.0);
+
+        assert_eq!(cancel_swap_order(1), Ok(()));
+        let cancelled = SWAP_ORDERS.with(|orders| orders.borrow().get(&1)).unwrap();
+        assert_eq!(cancelled.cancel_reason, Some(CancelReason::Owner));
+        assert_eq!(events::tests::cancel_initiators(1), vec![Some(owner.0)]);
+    }
+
+    #[test]
+    fn an_admin_cancel_names_the_admin_as_initiator() {
+        let admin = principal(38);
+        resting_eur_order(&principal(37));
+        admin::set_admin(admin.0).unwrap();
+        admin::tests::set_caller(admin.0);
+
+        assert_eq!(admin_cancel_order(1, "lost key".to_string()), Ok(()));
+        let cancelled = SWAP_ORDERS.with(|orders| orders.borrow().get(&1)).unwrap();
+        assert_eq!(cancelled.cancel_reason, Some(CancelReason::Admin));
+        assert_eq!(events::tests::cancel_initiators(1), vec![Some(admin.0)]);
+    }
+
+    #[test]
+    fn an_expiry_records_no_cancel_initiator() {
+        let owner = principal(37);
+        resting_eur_order(&owner);
+        admin::tests::set_caller(owner.0);
+
+        assert_eq!(expire_open_order(eur_order(&owner)), Ok(()));
+        let expired = SWAP_ORDERS.with(|orders| orders.borrow().get(&1)).unwrap();
+        assert_eq!(expired.cancel_reason, Some(CancelReason::Expired));
+        assert!(events::tests::cancel_initiators(1).is_empty());
+    }
+
+    fn page_ids(page: &OrdersCursorPage) -> Vec<u64> {
+        page.orders.iter().map(|order| order.id).collect()
+    }
+
+    // Asks of 40 EUR for `usd` USD each, ids from `first_id` on, listed the
+    // way store_order lists them
+    pub(crate) fn list_asks(first_id: u64, usd: impl Iterator<Item = u128>, counterparty: Option<Principal>) {
+        let owner = principal(31);
+        for (id, to_amount) in (first_id..).zip(usd) {
+            let swap_order = SwapOrder { id, to_amount, counterparty, ..eur_order(&owner) };
+            list_on_book(&swap_order);
+            SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(id, swap_order));
+        }
+    }
+
+    #[test]
+    fn a_book_query_of_depth_n_reads_only_n_orders() {
+        currencies::tests::register("EUR");
+        currencies::tests::register("USD");
+        // OTC orders at better prices than any public one stay off the book
+        list_asks(1, 1..=5, Some(principal(32).0));
+        list_asks(100, 30..=49, None);
+
+        let decoded_before = decoded_orders();
+        let book = get_order_book("EUR".to_string(), "USD".to_string(), 3).unwrap();
+        assert_eq!(decoded_orders() - decoded_before, 3);
+        assert_eq!(book.asks.iter().map(|order| order.id).collect::<Vec<_>>(), vec![100, 101, 102]);
+    }
+
+    #[test]
+    fn otc_orders_are_matched_only_by_their_counterparty() {
+        let counterparty = principal(33);
+        list_asks(1, [35].into_iter(), Some(counterparty.0));
+        list_asks(2, [30, 40].into_iter(), None);
+
+        let ids = |taker: &StorablePrincipal| {
+            book_side_for(taker, "EUR", "USD").map(|order| order.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&counterparty), vec![2, 1, 3]);
+        assert_eq!(ids(&principal(34)), vec![2, 3]);
+    }
+
+    #[test]
+    fn a_book_rebuild_moves_otc_orders_off_the_public_book() {
+        let admin = Principal::from_slice(&[35]);
+        admin::set_admin(admin).unwrap();
+        admin::tests::set_caller(admin);
+        let otc_order = SwapOrder { counterparty: Some(principal(36).0), ..eur_order(&principal(37)) };
+        ORDER_BOOK.with(|book| book.borrow_mut().insert(BookKey::for_order(&otc_order), ()));
+        SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(otc_order.id, otc_order));
+
+        let progress = rebuild_book_index(100).unwrap();
+
+        assert_eq!((progress.removed, progress.added, progress.done), (1, 1, true));
+        assert!(ORDER_BOOK.with(|book| book.borrow().is_empty()));
+        assert_eq!(OTC_BOOK.with(|book| book.borrow().len()), 1);
+    }
+
+    #[test]
+    fn a_page_of_50k_orders_reads_only_the_page() {
+        store_orders(1..=50_000);
+
+        // A full scan would decode all 50k orders, the range scan decodes the
+        // 100 it returns and the one it peeks at for the cursor
+        let decoded_before = decoded_orders();
+        let page = list_orders(25_000, 100);
+        assert_eq!(decoded_orders() - decoded_before, 101);
+
+        assert_eq!(page_ids(&page), (25_000..25_100).collect::<Vec<_>>());
+        assert_eq!(page.next_start_id, Some(25_100));
+    }
+
+    #[test]
+    fn pages_are_capped_and_end_without_a_cursor() {
+        store_orders(1..=250);
+
+        let first = list_orders(0, u16::MAX);
+        assert_eq!(first.orders.len(), MAX_ORDERS_PAGE_SIZE as usize);
+        assert_eq!(first.next_start_id, Some(101));
+
+        let last = list_orders(201, 100);
+        assert_eq!(page_ids(&last), (201..=250).collect::<Vec<_>>());
+        assert_eq!(last.next_start_id, None);
+    }
+
+    #[test]
+    fn cursors_skip_gaps_in_the_ids() {
+        store_orders([1, 2, 5, 9, 10].into_iter());
+
+        let first = list_orders(0, 2);
+        assert_eq!(page_ids(&first), vec![1, 2]);
+        assert_eq!(first.next_start_id, Some(5));
+
+        let second = list_orders(3, 2);
+        assert_eq!(page_ids(&second), vec![5, 9]);
+        assert_eq!(second.next_start_id, Some(10));
+    }
+
+    // An account holding 60 EUR and 50 USD available and 40 EUR escrowed by
+    // an open EUR order
+    fn store_eur_order_owner(owner: &StorablePrincipal) {
+        store_account(owner, &[("EUR", 60), ("USD", 50)], &[("EUR", 40)]);
+    }
+
+    #[test]
+    fn cancel_refund_returns_the_escrowed_currency_only() {
+        let owner = principal(1);
+        store_eur_order_owner(&owner);
+
+        let changes = stage_escrow_refund(&eur_order(&owner)).unwrap();
+
+        let refunded = &changes.accounts[&owner];
+        assert_eq!(refunded.balance("EUR"), 100);
+        assert_eq!(refunded.locked("EUR"), 0);
+        assert_eq!(refunded.balance("USD"), 50);
+        assert_eq!(refunded.locked("USD"), 0);
+    }
+
+    #[test]
+    fn cancel_refund_cannot_come_out_of_another_currency() {
+        let owner = principal(2);
+        store_eur_order_owner(&owner);
+        let usd_order = SwapOrder {
+            from_currency: "USD".to_string(),
+            to_currency: "EUR".to_string(),
+            ..eur_order(&owner)
+        };
+
+        assert_eq!(
+            stage_escrow_refund(&usd_order).err(),
+            Some(Error::InsufficientFunds {
+                required: 40,
+                available: 0,
+                currency: "USD".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn cancel_refund_does_not_create_a_missing_account() {
+        let owner = principal(3);
+
+        assert_eq!(stage_escrow_refund(&eur_order(&owner)).err(), Some(Error::UserNotFound));
+        assert!(stored_account(&owner).is_none());
+    }
+
+    // The endpoints reject an anonymous caller before they await anything,
+    // so one poll runs them to completion
+    pub(crate) fn run_now<T>(future: impl std::future::Future<Output = T>) -> T {
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match std::pin::pin!(future).poll(&mut context) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("The call awaited"),
+        }
+    }
+
+    // Unit tests call the endpoints as the anonymous principal
+    #[test]
+    fn anonymous_deposits_are_rejected() {
+        let args = DepositArgs {
+            amount: 100,
+            currency: "USD".to_string(),
+            dedup_id: None,
+        };
+        assert_eq!(deposit(args), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_withdrawals_are_rejected() {
+        let args = WithdrawArgs {
+            amount: 100,
+            currency: "USD".to_string(),
+        };
+        assert_eq!(withdraw(args).err(), Some(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_orders_are_rejected() {
+        assert_eq!(create_swap_order(order_args("USD", "EUR")).err(), Some(Error::AnonymousNotAllowed));
+        let batch = create_swap_orders(vec![order_args("USD", "EUR")]);
+        assert_eq!(batch.into_iter().map(Result::err).collect::<Vec<_>>(), vec![Some(Error::AnonymousNotAllowed)]);
+    }
+
+    #[test]
+    fn anonymous_amends_are_rejected() {
+        assert_eq!(amend_swap_order(1, Some(10), None, None), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_cancels_are_rejected() {
+        assert_eq!(cancel_swap_order(1), Err(Error::AnonymousNotAllowed));
+        assert_eq!(cancel_all_my_orders(None, None).err(), Some(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_executions_are_rejected() {
+        assert_eq!(run_now(execute_swap_order(1, None, None, None)).err(), Some(Error::AnonymousNotAllowed));
+        assert_eq!(run_now(accept_swap_order(1)), Err(Error::AnonymousNotAllowed));
+        assert_eq!(settle_swap_order(1).err(), Some(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_transfers_are_rejected() {
+        assert_eq!(transfer(principal(40).0, "USD".to_string(), 100), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn anonymous_ingress_is_dropped_before_it_runs() {
+        assert!(!accepts_ingress(Principal::anonymous(), "get_stats", &[]));
+        assert!(accepts_ingress(principal(41).0, "get_stats", &[]));
+    }
+
+    #[test]
+    fn orders_expire_once_the_clock_reaches_expires_at() {
+        let owner = principal(50);
+        let order = SwapOrder { expires_at: Some(1_000), ..eur_order(&owner) };
+
+        assert!(!order.is_expired(999));
+        assert!(order.is_expired(1_000));
+        assert!(order.is_expired(1_001));
+        assert!(!eur_order(&owner).is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn an_order_past_expires_at_cannot_be_filled() {
+        // Without a stored owner account the refund fails, so the order stays
+        // open and nothing is committed
+        let (owner, executor) = (principal(54), principal(55));
+        store_account(&executor, &[("USD", 30)], &[]);
+        let order = SwapOrder { expires_at: Some(1_000), ..eur_order(&owner) };
+        SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order));
+
+        let before = prepare_fill(&executor, 1, None, None, None, None, 999).map(|(_, fill_amount)| fill_amount);
+        assert_eq!(before, Ok(40));
+        let after = prepare_fill(&executor, 1, None, None, None, None, 1_000).map(|(_, fill_amount)| fill_amount);
+        assert_eq!(after, Err(Error::OrderExpired));
+    }
+
+    #[test]
+    fn an_accepted_order_past_expires_at_cannot_be_settled() {
+        // Without a stored taker account the release fails, so the order
+        // stays accepted and nothing is committed
+        let (owner, taker) = (principal(56), principal(57));
+        let accepted = SwapOrder {
+            expires_at: Some(1_000),
+            status: SwapStatus::Accepted,
+            accepted_by: Some(taker.0),
+            accepted_at: Some(900),
+            ..eur_order(&owner)
+        };
+        SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(accepted.id, accepted));
+
+        assert_eq!(settle_accepted_order(taker.0, 1, 1_001).err(), Some(Error::OrderExpired));
+        let stored = SWAP_ORDERS.with(|orders| orders.borrow().get(&1)).unwrap();
+        assert_eq!((stored.status, stored.accepted_by), (SwapStatus::Accepted, Some(taker.0)));
+    }
+
+    #[test]
+    fn an_expired_order_refunds_its_whole_escrow() {
+        let owner = principal(51);
+        store_account(&owner, &[("EUR", 5)], &[("EUR", 40)]);
+
+        let changes = stage_escrow_refund(&eur_order(&owner)).unwrap();
+
+        assert_eq!(changes.accounts[&owner].balance("EUR"), 45);
+        assert_eq!(changes.accounts[&owner].locked("EUR"), 0);
+    }
+
+    #[test]
+    fn an_expired_partly_filled_order_refunds_only_its_remainder() {
+        // 15 of the 40 EUR were filled before the order expired, another
+        // order of the owner's still has 10 EUR escrowed
+        let owner = principal(52);
+        store_account(&owner, &[], &[("EUR", 35)]);
+        let order = SwapOrder { filled_amount: Some(15), ..eur_order(&owner) };
+
+        let changes = stage_escrow_refund(&order).unwrap();
+
+        assert_eq!(changes.accounts[&owner].balance("EUR"), 25);
+        assert_eq!(changes.accounts[&owner].locked("EUR"), 10);
+    }
+
+    #[test]
+    fn an_expiry_refund_that_would_overflow_leaves_the_escrow_alone() {
+        let owner = principal(53);
+        store_account(&owner, &[("EUR", u128::MAX)], &[("EUR", 40)]);
+
+        assert_eq!(stage_escrow_refund(&eur_order(&owner)).err(), Some(Error::Overflow));
+        assert_eq!(stored_account(&owner).unwrap().locked("EUR"), 40);
+    }
+
+    const ABOVE_U64: u128 = u64::MAX as u128 * 1_000;
+
+    #[test]
+    fn accounts_stored_while_amounts_were_u64_widen_on_read() {
+        let narrow = NarrowUserAccount {
+            balances: BTreeMap::from([("USD".to_string(), u64::MAX)]),
+            locked: Some(BTreeMap::from([("EUR".to_string(), 5)])),
+        };
+
+        let user_account = UserAccount::from_bytes(Cow::Owned(candid::Encode!(&narrow).unwrap()));
+
+        assert_eq!(user_account.balance("USD"), u64::MAX as u128);
+        assert_eq!(user_account.locked("EUR"), 5);
+    }
+
+    #[test]
+    fn accounts_above_u64_survive_a_round_trip() {
+        let mut user_account = UserAccount::default();
+        user_account.credit("USD", ABOVE_U64).unwrap();
+        user_account.lock("USD", ABOVE_U64 / 2).unwrap();
+
+        let decoded = UserAccount::from_bytes(user_account.to_bytes());
+
+        assert_eq!(decoded.balance("USD"), ABOVE_U64 / 2);
+        assert_eq!(decoded.locked("USD"), ABOVE_U64 / 2);
+    }
+
+    #[test]
+    fn orders_stored_while_amounts_were_u64_widen_on_read() {
+        let narrow = NarrowSwapOrder {
+            id: 7,
+            owner: principal(60).0,
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: u64::MAX,
+            to_amount: u64::MAX - 1,
+            order_type: OrderType::Market,
+            created_at: 1,
+            status: SwapStatus::Created,
+            filled_amount: Some(3),
+            expires_at: None,
+            fees_paid: Some(2),
+            executed_by: None,
+            executed_at: None,
+            cancelled_at: None,
+            triggered_at: None,
+            updated_at: None,
+            memo: None,
+            counterparty: None,
+            admin_cancel_reason: None,
+            accepted_by: None,
+            accepted_at: None,
+            price_reached_at: None,
+        };
+
+        let swap_order = SwapOrder::from_bytes(Cow::Owned(candid::Encode!(&narrow).unwrap()));
+
+        assert_eq!((swap_order.id, swap_order.from_amount), (7, u64::MAX as u128));
+        assert_eq!(swap_order.to_amount, u64::MAX as u128 - 1);
+        assert_eq!((swap_order.filled(), swap_order.fees_paid), (3, Some(2)));
+    }
+
+    #[test]
+    fn orders_above_u64_survive_a_round_trip() {
+        let owner = principal(61);
+        let swap_order = SwapOrder {
+            from_amount: ABOVE_U64,
+            to_amount: ABOVE_U64 * 2,
+            filled_amount: Some(ABOVE_U64 / 4),
+            ..eur_order(&owner)
+        };
+
+        let decoded = SwapOrder::from_bytes(swap_order.to_bytes());
+
+        assert_eq!(decoded.from_amount, ABOVE_U64);
+        assert_eq!(decoded.to_amount, ABOVE_U64 * 2);
+        assert_eq!(decoded.remaining(), ABOVE_U64 / 4 * 3);
+    }
+
+    #[test]
+    fn deposits_and_fills_above_u64_settle_exactly() {
+        let (owner, executor) = (principal(62), principal(63));
+        store_account(&owner, &[], &[("EUR", ABOVE_U64)]);
+        let mut deposit = BalanceChanges::new();
+        deposit.credit(&executor, "USD", ABOVE_U64 * 3).unwrap();
+        let deposited = deposit.accounts[&executor].clone();
+        assert_eq!(deposited.balance("USD"), ABOVE_U64 * 3);
+        USER_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(executor.clone(), deposited));
+
+        // Half the order, paid at two USD per EUR
+        let swap_order = SwapOrder { from_amount: ABOVE_U64, to_amount: ABOVE_U64 * 2, ..eur_order(&owner) };
+        let fill_amount = ABOVE_U64 / 2;
+        let payment = fill_payment(&swap_order, fill_amount);
+        assert_eq!(payment, ABOVE_U64);
+        let changes =
+            stage_fill(&executor, &owner, &swap_order, fill_amount, payment, &NO_FEES, &principal(0), None).unwrap();
+
+        assert_eq!(changes.accounts[&owner].balance("USD"), ABOVE_U64);
+        assert_eq!(changes.accounts[&owner].locked("EUR"), ABOVE_U64 / 2);
+        assert_eq!(changes.accounts[&executor].balance("USD"), ABOVE_U64 * 2);
+        assert_eq!(changes.accounts[&executor].balance("EUR"), ABOVE_U64 / 2);
+    }
+
+    // 100 EUR for 50 USD, shown 30 at a time
+    fn iceberg_order(owner: &StorablePrincipal, filled: u128) -> SwapOrder {
+        SwapOrder {
+            from_amount: 100,
+            to_amount: 50,
+            filled_amount: Some(filled),
+            display_amount: Some(30),
+            ..eur_order(owner)
+        }
+    }
+
+    #[test]
+    fn an_iceberg_order_fills_one_tranche_at_a_time() {
+        let owner = principal(70);
+
+        assert_eq!(iceberg_order(&owner, 0).visible_remaining(), 30);
+        assert_eq!(iceberg_order(&owner, 45).visible_remaining(), 15);
+        assert_eq!(iceberg_order(&owner, 90).visible_remaining(), 10);
+    }
+
+    #[test]
+    fn only_the_owner_sees_past_the_visible_tranche() {
+        let (owner, other) = (principal(71), principal(72));
+
+        let public = iceberg_order(&owner, 45).viewed_by(&other.0);
+        assert_eq!((public.from_amount, public.to_amount, public.display_amount), (60, 30, None));
+        let own = iceberg_order(&owner, 45).viewed_by(&owner.0);
+        assert_eq!((own.from_amount, own.to_amount, own.display_amount), (100, 50, Some(30)));
+    }
+
+    #[test]
+    fn resting_orders_also_need_the_creation_deposit() {
+        order_deposits::tests::take_deposit("USD", 5);
+
+        let same_currency = order_args("USD", "EUR");
+        assert_eq!(required_funds(&same_currency), BTreeMap::from([("USD".to_string(), 105)]));
+        let other_currency = order_args("GBP", "EUR");
+        assert_eq!(
+            required_funds(&other_currency),
+            BTreeMap::from([("GBP".to_string(), 100), ("USD".to_string(), 5)])
+        );
+        let immediate = CreateSwapOrderArgs {
+            order_type: OrderType::ImmediateOrCancel { price: Price { numerator: 9, denominator: 10 } },
+            ..order_args("USD", "EUR")
+        };
+        assert_eq!(required_funds(&immediate), BTreeMap::from([("USD".to_string(), 100)]));
+    }
+
+    // The unversioned layout before one-cancels-other legs, icebergs and
+    // creation deposits
+    #[derive(candid::CandidType, Serialize)]
+    struct SwapOrderV1 {
+        id: u64,
+        owner: Principal,
+        from_currency: String,
+        to_currency: String,
+        from_amount: u128,
+        to_amount: u128,
+        order_type: OrderType,
+        created_at: u64,
+        status: SwapStatus,
+        filled_amount: Option<u128>,
+        expires_at: Option<u64>,
+        memo: Option<String>,
+    }
+
+    fn v1_order(id: u64) -> SwapOrderV1 {
+        SwapOrderV1 {
+            id,
+            owner: principal(70).0,
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: 40,
+            to_amount: 30,
+            order_type: OrderType::Limit { price: Price { numerator: 3, denominator: 4 } },
+            created_at: 5,
+            status: SwapStatus::Created,
+            filled_amount: Some(10),
+            expires_at: None,
+            memo: Some("v1".to_string()),
+        }
+    }
+
+    // Reads and writes the live order map's memory without going through
+    // SwapOrder's encoding
+    struct RawOrder(Vec<u8>);
+
+    impl Storable for RawOrder {
+        fn to_bytes(&self) -> Cow<'_, [u8]> {
+            Cow::Borrowed(&self.0)
+        }
+
+        fn from_bytes(bytes: Cow<[u8]>) -> Self {
+            RawOrder(bytes.into_owned())
+        }
+    }
+
+    impl BoundedStorable for RawOrder {
+        const MAX_SIZE: u32 = SwapOrder::MAX_SIZE;
+        const IS_FIXED_SIZE: bool = false;
+    }
+
+    fn raw_orders() -> StableBTreeMap<u64, RawOrder, Memory> {
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))))
+    }
+
+    #[test]
+    fn v1_bytes_decode_with_the_later_fields_unset() {
+        let v1_bytes = candid::Encode!(&v1_order(3)).unwrap();
+        assert_eq!(&v1_bytes[..4], b"DIDL");
+
+        let swap_order = SwapOrder::from_bytes(Cow::Owned(v1_bytes));
+
+        assert_eq!((swap_order.id, swap_order.from_amount, swap_order.to_amount), (3, 40, 30));
+        assert_eq!(swap_order.filled(), 10);
+        assert_eq!(swap_order.memo.as_deref(), Some("v1"));
+        let price = Price { numerator: 3, denominator: 4 };
+        assert!(matches!(swap_order.order_type, OrderType::Limit { price: decoded } if decoded == price));
+        assert_eq!((swap_order.linked_order_id, swap_order.display_amount), (None, None));
+    }
+
+    #[test]
+    fn v1_bytes_with_u64_amounts_and_float_prices_decode() {
+        let legacy = LegacySwapOrder {
+            id: 4,
+            owner: principal(71).0,
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: 40,
+            to_amount: 60,
+            order_type: LegacyOrderType::Limit { price: 1.5 },
+            created_at: 5,
+            status: SwapStatus::PartiallyFilled,
+            filled_amount: Some(20),
+            expires_at: None,
+            fees_paid: None,
+            executed_by: None,
+            executed_at: None,
+            cancelled_at: None,
+            triggered_at: None,
+            updated_at: None,
+        };
+
+        let swap_order = SwapOrder::from_bytes(Cow::Owned(candid::Encode!(&legacy).unwrap()));
+
+        let price = Price { numerator: 150_000_000, denominator: LEGACY_PRICE_SCALE };
+        assert!(matches!(swap_order.order_type, OrderType::Limit { price: converted } if converted == price));
+        assert_eq!((swap_order.from_amount, swap_order.filled()), (40, 20));
+        assert_eq!(swap_order.status, SwapStatus::PartiallyFilled);
+    }
+
+    #[test]
+    fn orders_are_written_under_the_current_version() {
+        let owner = principal(72);
+        let bytes = eur_order(&owner).to_bytes().into_owned();
+
+        assert_eq!(bytes[0], SWAP_ORDER_VERSION);
+        assert_eq!(SwapOrder::from_bytes(Cow::Owned(bytes)).from_amount, 40);
+    }
+
+    #[test]
+    fn unknown_versions_are_reported_instead_of_guessed() {
+        let owner = principal(73);
+        let mut bytes = eur_order(&owner).to_bytes().into_owned();
+        bytes[0] = SWAP_ORDER_VERSION + 1;
+
+        let expected = format!("unknown version {}", SWAP_ORDER_VERSION + 1);
+        assert_eq!(SwapOrder::try_from_bytes(&bytes).err(), Some(expected));
+    }
+
+    #[test]
+    fn the_upgrade_rewrite_stores_v1_records_under_the_current_version() {
+        let mut raw = raw_orders();
+        for id in 1..=3 {
+            raw.insert(id, RawOrder(candid::Encode!(&v1_order(id)).unwrap()));
+        }
+        drop(raw);
+        let stored_version = |id: u64| raw_orders().get(&id).unwrap().0[0];
+
+        // Read through the live map before any rewrite
+        assert_eq!(SWAP_ORDERS.with(|orders| orders.borrow().get(&2)).unwrap().memo.as_deref(), Some("v1"));
+
+        assert!(!rewrite_order_encodings(2));
+        let versions = [stored_version(1), stored_version(2), stored_version(3)];
+        assert_eq!(versions, [SWAP_ORDER_VERSION, SWAP_ORDER_VERSION, b'D']);
+        assert!(rewrite_order_encodings(2));
+        assert_eq!(stored_version(3), SWAP_ORDER_VERSION);
+        assert_eq!(ORDERS_ENCODED_VERSION.with(|cell| *cell.borrow().get()), SWAP_ORDER_VERSION);
+
+        let rewritten = SWAP_ORDERS.with(|orders| orders.borrow().get(&3)).unwrap();
+        assert_eq!((rewritten.id, rewritten.filled(), rewritten.memo.as_deref()), (3, 10, Some("v1")));
+    }
+
+    #[test]
+    fn an_order_the_account_has_no_room_for_is_rejected_before_matching() {
+        let holder = principal(84);
+        let mut added = 0;
+        while credit_currencies(&mut BalanceChanges::new(), &holder, added + 1).is_ok() {
+            added += 1;
+        }
+        let mut changes = BalanceChanges::new();
+        credit_currencies(&mut changes, &holder, added).unwrap();
+        USER_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(holder.clone(), changes.accounts[&holder].clone()));
+        let args = CreateSwapOrderArgs { from_amount: 1, ..order_args("CUR0000000", "USD") };
+
+        assert!(matches!(
+            check_account_room(&holder, &args, &required_funds(&args)),
+            Err(Error::RecordTooLarge { max_size: UserAccount::MAX_SIZE, .. })
+        ));
+        assert_eq!(check_account_room(&principal(85), &args, &required_funds(&args)), Ok(()));
+    }
+
+    // Credits 1 of each of `count` ten-character currencies
+    fn credit_currencies(changes: &mut BalanceChanges, holder: &StorablePrincipal, count: usize) -> Result<(), Error> {
+        (0..count).try_for_each(|index| changes.credit(holder, &format!("CUR{:07}", index), 1))
+    }
+
+    #[test]
+    fn a_credit_that_outgrows_the_account_bound_is_rejected() {
+        let holder = principal(80);
+        let mut changes = BalanceChanges::new();
+        let mut added = 0;
+        while credit_currencies(&mut changes, &holder, added + 1).is_ok() {
+            added += 1;
+        }
+
+        // The last currency that fits leaves the account within its bound,
+        // the next one is reported instead of trapping the insert
+        let fitting = {
+            let mut changes = BalanceChanges::new();
+            credit_currencies(&mut changes, &holder, added).unwrap();
+            changes.accounts[&holder].to_bytes().len()
+        };
+        assert!(fitting <= UserAccount::MAX_SIZE as usize);
+        assert!(matches!(
+            credit_currencies(&mut BalanceChanges::new(), &holder, added + 1),
+            Err(Error::RecordTooLarge { size, max_size: UserAccount::MAX_SIZE }) if size > UserAccount::MAX_SIZE as u64
+        ));
+    }
+
+    #[test]
+    fn an_escrow_that_outgrows_the_account_bound_is_rejected() {
+        let holder = principal(81);
+        let mut added = 0;
+        while credit_currencies(&mut BalanceChanges::new(), &holder, added + 1).is_ok() {
+            added += 1;
+        }
+        let mut changes = BalanceChanges::new();
+        (0..added).try_for_each(|index| changes.credit(&holder, &format!("CUR{:07}", index), 2)).unwrap();
+
+        // Locking part of a balance adds an escrow entry as large as the
+        // currency that no longer fitted
+        assert!(matches!(changes.lock(&holder, "CUR0000000", 1), Err(Error::RecordTooLarge { .. })));
+        // Locking all of it moves the entry instead, which still fits
+        let mut changes = BalanceChanges::new();
+        credit_currencies(&mut changes, &holder, added).unwrap();
+        assert_eq!(changes.lock(&holder, "CUR0000000", 1), Ok(()));
+    }
+
+    // What check_new_order sizes up for an order with the longest fields the
+    // arguments allow
+    fn largest_prospective_order(memo: String) -> SwapOrder {
+        let trigger_price = Price { numerator: u64::MAX, denominator: u64::MAX };
+        SwapOrder {
+            owner: Principal::from_slice(&[u8::MAX; 29]),
+            from_currency: "A".repeat(10),
+            to_currency: "B".repeat(10),
+            order_type: OrderType::StopMarket { trigger_price, direction: StopDirection::Above },
+            memo: Some(memo),
+            counterparty: Some(Principal::from_slice(&[u8::MAX; 29])),
+            display_amount: Some(1),
+            creation_deposit: Some(OrderDeposit { currency: "C".repeat(10), amount: u128::MAX }),
+            ..SwapOrder::default()
+        }
+    }
+
+    #[test]
+    fn the_largest_order_the_arguments_allow_fits_its_bound() {
+        let largest = largest_prospective_order("m".repeat(MAX_MEMO_BYTES)).at_largest();
+
+        assert_eq!(validate_encoded_size(&largest), Ok(()));
+        assert!(largest.to_bytes().len() <= SwapOrder::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn an_order_that_would_outgrow_its_bound_is_rejected() {
+        let oversized = largest_prospective_order("m".repeat(SwapOrder::MAX_SIZE as usize)).at_largest();
+
+        assert!(matches!(
+            validate_encoded_size(&oversized),
+            Err(Error::RecordTooLarge { size, max_size: SwapOrder::MAX_SIZE }) if size > SwapOrder::MAX_SIZE as u64
+        ));
+    }
+
+    #[test]
+    fn an_order_one_byte_over_its_bound_is_rejected() {
+        // Grows the memo until the encoding is exactly at the bound
+        let order_with_memo = |memo_bytes| largest_prospective_order("m".repeat(memo_bytes)).at_largest();
+        let mut memo_bytes = MAX_MEMO_BYTES;
+        while order_with_memo(memo_bytes + 1).to_bytes().len() <= SwapOrder::MAX_SIZE as usize {
+            memo_bytes += 1;
+        }
+
+        assert_eq!(validate_encoded_size(&order_with_memo(memo_bytes)), Ok(()));
+        assert_eq!(
+            validate_encoded_size(&order_with_memo(memo_bytes + 1)),
+            Err(Error::RecordTooLarge { size: SwapOrder::MAX_SIZE as u64 + 1, max_size: SwapOrder::MAX_SIZE })
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::rates::latest_known_rate;
+use crate::{
+    book_side, is_price_condition_met, store_order, CurrencyPair, Error, Memory, OrderType, Price, SwapOrder,
+    MAX_ORDER_BOOK_DEPTH, MEMORY_MANAGER, SWAP_ORDERS,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::time::Duration;
+
+pub(crate) const LIMIT_SCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+// Index entries visited per tick, keeps a single tick well inside the
+// instruction limit however many limit orders rest
+const LIMIT_SCAN_BATCH_SIZE: usize = 200;
+
+// Dormant limit order index key: grouped by pair, then by limit price, lowest
+// first, so the orders a rate makes executable are a prefix of their pair
+#[derive(Debug, Clone)]
+struct LimitKey {
+    pair: CurrencyPair,
+    price: Price,
+    order_id: u64,
+}
+
+impl LimitKey {
+    fn for_order(swap_order: &SwapOrder, price: Price) -> Self {
+        LimitKey {
+            pair: CurrencyPair::new(&swap_order.from_currency, &swap_order.to_currency),
+            price,
+            order_id: swap_order.id,
+        }
+    }
+
+    // Sorts after every order of the pair, for skipping the rest of it
+    fn last_of_pair(pair: &CurrencyPair) -> Self {
+        LimitKey {
+            pair: pair.clone(),
+            price: Price {
+                numerator: u64::MAX,
+                denominator: 1,
+            },
+            order_id: u64::MAX,
+        }
+    }
+}
+
+impl Ord for LimitKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Cross-multiplying in u128 keeps the price comparison exact
+        let lhs = self.price.numerator as u128 * other.price.denominator as u128;
+        let rhs = other.price.numerator as u128 * self.price.denominator as u128;
+        self.pair
+            .cmp(&other.pair)
+            .then(lhs.cmp(&rhs))
+            .then(self.order_id.cmp(&other.order_id))
+    }
+}
+
+impl PartialOrd for LimitKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for LimitKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for LimitKey {}
+
+// Encoded like BookKey: numerator, denominator and order id in big-endian,
+// followed by the pair
+impl Storable for LimitKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.extend_from_slice(&self.price.numerator.to_be_bytes());
+        bytes.extend_from_slice(&self.price.denominator.to_be_bytes());
+        bytes.extend_from_slice(&self.order_id.to_be_bytes());
+        bytes.extend_from_slice(&self.pair.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let read_u64 = |offset: usize| {
+            u64::from_be_bytes(bytes[offset..offset + 8].try_into().expect("Failed to decode LimitKey"))
+        };
+        LimitKey {
+            price: Price {
+                numerator: read_u64(0),
+                denominator: read_u64(8),
+            },
+            order_id: read_u64(16),
+            pair: CurrencyPair::from_bytes(Cow::Borrowed(&bytes[24..])),
+        }
+    }
+}
+
+impl BoundedStorable for LimitKey {
+    const MAX_SIZE: u32 = 24 + CurrencyPair::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TimerStatus {
+    last_run_at: Option<u64>,
+    items_processed: u64,  // index entries visited by the last tick
+    orders_marked: u64,    // limit orders the last tick marked executable
+    total_marked: u64,     // since the last upgrade
+    passes_completed: u64, // times the scan reached the end of the index
+}
+
+thread_local! {
+    // Open limit orders whose price no known rate has reached yet
+    static DORMANT_LIMIT_ORDERS: RefCell<StableBTreeMap<LimitKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    // Last entry the scan visited, None to start from the beginning. Heap
+    // only like the expiry sweep cursor, as is the status.
+    static LIMIT_SCAN_CURSOR: RefCell<Option<LimitKey>> = const { RefCell::new(None) };
+
+    static LIMIT_SCAN_STATUS: RefCell<TimerStatus> = RefCell::new(TimerStatus::default());
+}
+
+#[ic_cdk::query]
+fn get_timer_status() -> TimerStatus {
+    LIMIT_SCAN_STATUS.with(|status| status.borrow().clone())
+}
+
+// Limit orders selling `from_currency` for `to_currency` whose price a known
+// rate has reached, cheapest first. execute_swap_order still checks the price
+// against a fresh rate, which may have moved back since.
+#[ic_cdk::query]
+fn get_executable_limit_orders(
+    from_currency: String,
+    to_currency: String,
+    limit: u32,
+) -> Result<Vec<SwapOrder>, Error> {
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+
+    Ok(book_side(&from_currency, &to_currency)
+        .filter(|order| matches!(order.order_type, OrderType::Limit { .. }) && order.price_reached_at.is_some())
+        .take(limit.min(MAX_ORDER_BOOK_DEPTH) as usize)
+        .map(SwapOrder::public_view)
+        .collect())
+}
+
+// Called by store_order to keep the index in step with the order
+pub(crate) fn record_limit_change(previous: Option<&SwapOrder>, current: &SwapOrder) {
+    DORMANT_LIMIT_ORDERS.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        if let Some(key) = previous.and_then(dormant_key) {
+            index_borrowed.remove(&key);
+        }
+        if let Some(key) = dormant_key(current) {
+            index_borrowed.insert(key, ());
+        }
+    });
+}
+
+pub(crate) fn rebuild_dormant_limit_index() {
+    DORMANT_LIMIT_ORDERS.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let stale: Vec<LimitKey> = index_borrowed.iter().map(|(key, _)| key).collect();
+        for key in stale {
+            index_borrowed.remove(&key);
+        }
+        SWAP_ORDERS.with(|orders| {
+            for (_, swap_order) in orders.borrow().iter() {
+                if let Some(key) = dormant_key(&swap_order) {
+                    index_borrowed.insert(key, ());
+                }
+            }
+        });
+    });
+}
+
+// Marks dormant limit orders executable once the latest known rate for their
+// pair reaches their price. Each tick resumes after the last entry it visited
+// and skips the rest of a pair at its first order the rate doesn't reach,
+// wrapping around at the end of the index.
+pub(crate) fn scan_dormant_limit_orders() {
+    let now = time();
+    let mut cursor = LIMIT_SCAN_CURSOR.with(|cursor| cursor.borrow().clone());
+    let mut pair_rate: Option<(CurrencyPair, Option<f64>)> = None;
+    let mut items_processed = 0;
+    let mut orders_marked = 0;
+    let mut pass_completed = false;
+
+    while items_processed < LIMIT_SCAN_BATCH_SIZE as u64 {
+        let Some(key) = next_key(cursor.as_ref()) else {
+            cursor = None;
+            pass_completed = true;
+            break;
+        };
+        items_processed += 1;
+
+        if pair_rate.as_ref().is_none_or(|(pair, _)| *pair != key.pair) {
+            let rate = latest_known_rate(&key.pair.from_currency, &key.pair.to_currency)
+                .map(|exchange_rate| exchange_rate.rate);
+            pair_rate = Some((key.pair.clone(), rate));
+        }
+        let rate = pair_rate.as_ref().and_then(|(_, rate)| *rate);
+        if !rate.is_some_and(|rate| is_price_condition_met(key.price, rate)) {
+            // Every later order of the pair asks for at least this price
+            cursor = Some(LimitKey::last_of_pair(&key.pair));
+            continue;
+        }
+
+        let swap_order = SWAP_ORDERS.with(|orders| orders.borrow().get(&key.order_id));
+        // Expired orders are left for the expiry sweep
+        if let Some(mut swap_order) = swap_order.filter(|order| !order.is_expired(now)) {
+            swap_order.price_reached_at = Some(now);
+            store_order(swap_order);
+            orders_marked += 1;
+        }
+        cursor = Some(key);
+    }
+
+    LIMIT_SCAN_CURSOR.with(|stored| *stored.borrow_mut() = cursor);
+    LIMIT_SCAN_STATUS.with(|status| {
+        let mut status = status.borrow_mut();
+        status.last_run_at = Some(now);
+        status.items_processed = items_processed;
+        status.orders_marked = orders_marked;
+        status.total_marked += orders_marked;
+        status.passes_completed += pass_completed as u64;
+    });
+}
+
+// First entry after `cursor`, or the first of the index without one
+fn next_key(cursor: Option<&LimitKey>) -> Option<LimitKey> {
+    let start = cursor.map_or(Bound::Unbounded, |key| Bound::Excluded(key.clone()));
+    DORMANT_LIMIT_ORDERS.with(|index| index.borrow().range((start, Bound::Unbounded)).next().map(|(key, _)| key))
+}
+
+fn dormant_key(swap_order: &SwapOrder) -> Option<LimitKey> {
+    match swap_order.order_type {
+        OrderType::Limit { price } if swap_order.is_open() && swap_order.price_reached_at.is_none() => {
+            Some(LimitKey::for_order(swap_order, price))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,449 @@
+use crate::allowlist::is_allowed;
+use crate::amounts::{cmp_products, mul_div};
+use crate::blacklist::is_blacklisted;
+use crate::self_trade::{self, SelfTradePrevention, SelfTrades};
+use crate::{
+    book_side_for, cancel_open_order, cumulative_payment, fees, fill_payment, settle_fill, store_order,
+    CancelReason, CreateSwapOrderArgs, Error, Price, StorablePrincipal, SwapOrder,
+};
+use candid::Principal;
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+// A resting order the incoming order takes from, and what the taker pays for it
+struct PlannedFill {
+    maker: SwapOrder,
+    amount: u128,  // maker's from_amount taken
+    payment: u128, // taker's from_currency paid to the maker
+}
+
+// Where matching the taker's own resting orders leaves the walk
+struct SelfTradePlan {
+    cancels: Vec<SwapOrder>, // own resting orders to cancel
+    fills: Vec<u64>,         // own resting orders filled under Allow
+    stopped_by: Option<u64>,
+}
+
+// What fill_against_book did for an incoming order
+#[derive(Default)]
+pub(crate) struct BookFill {
+    pub(crate) spent: u128, // of its from_amount
+    pub(crate) self_trades: SelfTrades,
+}
+
+// Fills an incoming priced order against the resting orders on the other side
+// of its pair. The taker pays from available funds, which the caller has
+// already checked cover the whole from_amount. With `all_or_nothing` nothing
+// is settled, and no order cancelled, unless the entire from_amount can be
+// matched.
+pub(crate) fn fill_against_book(
+    taker: &StorablePrincipal,
+    args: &CreateSwapOrderArgs,
+    price: Price,
+    all_or_nothing: bool,
+) -> Result<BookFill, Error> {
+    let prevention = self_trade::prevention_for(taker);
+    let (fills, plan) = plan_fills(
+        taker,
+        args.counterparty,
+        &args.from_currency,
+        &args.to_currency,
+        args.from_amount,
+        Some(price),
+        prevention,
+    );
+    // Payments are taken out of the budget one by one, so the sum can't overflow
+    let spent: u128 = fills.iter().map(|fill| fill.payment).sum();
+    if all_or_nothing && spent < args.from_amount {
+        return Err(Error::InsufficientLiquidity);
+    }
+
+    for fill in fills {
+        let mut maker = fill.maker;
+        // The plan was made in this message against the current balances and
+        // book, so a failure here is a broken invariant. Trapping rolls back
+        // the fills already settled instead of leaving the order half done.
+        let receipt = settle_fill(taker.clone(), StorablePrincipal::from(maker.owner), &maker, fill.amount)
+            .expect("Planned fill failed to settle");
+        maker.record_fill(taker.clone().into(), fill.amount, receipt.fee);
+        store_order(maker);
+    }
+
+    let mut makers: Vec<(u64, bool)> = plan.fills.into_iter().map(|order_id| (order_id, false)).collect();
+    for maker in plan.cancels {
+        let order_id = maker.id;
+        // One whose refund fails stays open, as in cancel_all_my_orders
+        makers.push((order_id, cancel_open_order(maker, CancelReason::SelfTradePrevention, None).is_ok()));
+    }
+    if let Some(order_id) = plan.stopped_by.filter(|order_id| makers.iter().all(|(maker_id, _)| maker_id != order_id)) {
+        makers.push((order_id, false));
+    }
+
+    Ok(BookFill {
+        spent,
+        self_trades: SelfTrades {
+            prevention,
+            makers,
+            stopped_by: plan.stopped_by,
+        },
+    })
+}
+
+// What spending up to `budget` of from_currency against the book would do,
+// worked out by the same plan fill_against_book settles
+pub(crate) struct BookQuote {
+    pub(crate) spent: u128,                      // of from_currency, at most the budget
+    pub(crate) received: u128,                   // of to_currency, before the taker fee
+    pub(crate) taker_fee: u128,                  // withheld from received
+    pub(crate) best_price: Option<(u128, u128)>, // (to_currency, from_currency) of the first maker
+}
+
+pub(crate) fn quote_against_book(
+    taker: &StorablePrincipal,
+    from_currency: &str,
+    to_currency: &str,
+    budget: u128,
+) -> BookQuote {
+    let (fills, _) = plan_fills(
+        taker,
+        None,
+        from_currency,
+        to_currency,
+        budget,
+        None,
+        self_trade::prevention_for(taker),
+    );
+    let best_price = fills.first().map(|fill| (fill.maker.from_amount, fill.maker.to_amount));
+    let mut quote = BookQuote {
+        spent: 0,
+        received: 0,
+        taker_fee: 0,
+        best_price,
+    };
+    for fill in fills {
+        let owner = StorablePrincipal::from(fill.maker.owner);
+        quote.spent += fill.payment;
+        quote.received = quote.received.saturating_add(fill.amount);
+        let taker_fee = fees::fees_for(&owner, taker, fill.payment, fill.amount).taker_fee;
+        quote.taker_fee = quote.taker_fee.saturating_add(taker_fee);
+    }
+    quote
+}
+
+// Walks the resting orders selling `to_currency` for `from_currency` in
+// price-time priority until `budget` is spent or the next maker offers fewer
+// than `price` units of to_currency per unit of from_currency, if given. Each maker is
+// filled on its own terms. OTC orders are only met by their counterparty.
+// Expired orders are skipped, as are those of owners who are blacklisted or
+// off the allowlist. A taker with a counterparty only trades with that principal's
+// orders. The taker's own orders are handled by its self-trade prevention.
+// An iceberg whose tranche runs out shows the next one at the back of its
+// price level and keeps filling from there, one fill per maker either way.
+fn plan_fills(
+    taker: &StorablePrincipal,
+    counterparty: Option<Principal>,
+    from_currency: &str,
+    to_currency: &str,
+    budget: u128,
+    price: Option<Price>,
+    prevention: SelfTradePrevention,
+) -> (Vec<PlannedFill>, SelfTradePlan) {
+    let now = time();
+    let makers = book_side_for(taker, to_currency, from_currency)
+        .filter(|order| !order.is_expired(now))
+        .filter(|order| !is_blacklisted(order.owner) && is_allowed(order.owner))
+        .filter(|order| counterparty.is_none_or(|counterparty| order.owner == counterparty));
+    walk_makers(taker, makers, budget, price, prevention)
+}
+
+// The walk plan_fills does over the makers left once its filters have run, in
+// the order given
+fn walk_makers(
+    taker: &StorablePrincipal,
+    makers: impl Iterator<Item = SwapOrder>,
+    budget: u128,
+    price: Option<Price>,
+    prevention: SelfTradePrevention,
+) -> (Vec<PlannedFill>, SelfTradePlan) {
+    let mut makers = makers
+        // The taker receives the maker's from_currency for its to_currency
+        .take_while(|order| price.is_none_or(|price| price.is_met_by(order.from_amount, order.to_amount)))
+        .peekable();
+
+    let mut fills: Vec<PlannedFill> = Vec::new();
+    // Icebergs with a fresh tranche, as their planned fill leaves them, and
+    // the index of that fill
+    let mut refilled: VecDeque<(usize, SwapOrder)> = VecDeque::new();
+    let mut self_trades = SelfTradePlan {
+        cancels: Vec::new(),
+        fills: Vec::new(),
+        stopped_by: None,
+    };
+    let mut budget = budget;
+    while budget > 0 {
+        // A fresh tranche waits for the orders already at its price
+        let refill_due = refilled
+            .front()
+            .is_some_and(|(_, iceberg)| makers.peek().is_none_or(|next| !same_price(iceberg, next)));
+        if refill_due {
+            let (index, iceberg) = refilled.pop_front().expect("Checked above");
+            let amount = max_fill_within(&iceberg, budget);
+            if amount == 0 {
+                break;
+            }
+            let payment = fill_payment(&iceberg, amount);
+            budget -= payment;
+            // Payments of consecutive fills add up to the payment for both
+            fills[index].amount += amount;
+            fills[index].payment += payment;
+            if let Some(iceberg) = refill_after(&iceberg, amount) {
+                refilled.push_back((index, iceberg));
+            }
+            continue;
+        }
+
+        let Some(maker) = makers.next() else {
+            break;
+        };
+        if StorablePrincipal::from(maker.owner) == *taker {
+            match prevention {
+                SelfTradePrevention::CancelNewest => {
+                    self_trades.stopped_by = Some(maker.id);
+                    break;
+                }
+                SelfTradePrevention::CancelOldest => {
+                    self_trades.cancels.push(maker);
+                    continue;
+                }
+                SelfTradePrevention::CancelBoth => {
+                    self_trades.stopped_by = Some(maker.id);
+                    self_trades.cancels.push(maker);
+                    break;
+                }
+                SelfTradePrevention::Allow => {}
+            }
+        }
+        let amount = max_fill_within(&maker, budget);
+        if amount == 0 {
+            // Even one unit costs more than what is left, and makers further
+            // down only get more expensive
+            break;
+        }
+        let payment = fill_payment(&maker, amount);
+        budget -= payment;
+        if StorablePrincipal::from(maker.owner) == *taker {
+            self_trades.fills.push(maker.id);
+        }
+        if let Some(iceberg) = refill_after(&maker, amount) {
+            refilled.push_back((fills.len(), iceberg));
+        }
+        fills.push(PlannedFill { maker, amount, payment });
+    }
+    (fills, self_trades)
+}
+
+// The maker once `amount` more is filled, if that used up an iceberg tranche
+// and another is left to show
+fn refill_after(maker: &SwapOrder, amount: u128) -> Option<SwapOrder> {
+    if maker.display_amount.is_none() || amount < maker.visible_remaining() || amount == maker.remaining() {
+        return None;
+    }
+    let mut iceberg = maker.clone();
+    iceberg.filled_amount = Some(maker.filled() + amount);
+    Some(iceberg)
+}
+
+fn same_price(a: &SwapOrder, b: &SwapOrder) -> bool {
+    cmp_products(a.to_amount, b.from_amount, b.to_amount, a.from_amount) == Ordering::Equal
+}
+
+// Largest part of the maker's visible remainder whose fill_payment fits in
+// `budget`
+fn max_fill_within(maker: &SwapOrder, budget: u128) -> u128 {
+    let already_paid = cumulative_payment(maker, maker.filled());
+    let affordable = already_paid
+        .checked_add(budget)
+        .and_then(|total| mul_div(total, maker.from_amount, maker.to_amount))
+        // Past u128 the budget covers more than the whole remainder
+        .unwrap_or(u128::MAX);
+    (affordable.min(maker.from_amount) - maker.filled()).min(maker.visible_remaining())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{list_asks, order_args};
+    use crate::{currencies, OrderType, UserAccount, SWAP_ORDERS, USER_ACCOUNTS};
+
+    fn principal(id: u8) -> StorablePrincipal {
+        StorablePrincipal::from(Principal::from_slice(&[id]))
+    }
+
+    // A resting order selling `eur` EUR for `usd` USD
+    fn maker(id: u64, owner: u8, eur: u128, usd: u128) -> SwapOrder {
+        SwapOrder {
+            id,
+            owner: principal(owner).into(),
+            from_currency: "EUR".to_string(),
+            to_currency: "USD".to_string(),
+            from_amount: eur,
+            to_amount: usd,
+            order_type: OrderType::Limit { price: Price { numerator: usd as u64, denominator: eur as u64 } },
+            ..SwapOrder::default()
+        }
+    }
+
+    // (maker id, EUR taken, USD paid) for each planned fill
+    fn walk(makers: Vec<SwapOrder>, budget: u128, price: Option<Price>) -> Vec<(u64, u128, u128)> {
+        let prevention = SelfTradePrevention::CancelNewest;
+        let (fills, _) = walk_makers(&principal(1), makers.into_iter(), budget, price, prevention);
+        fills.iter().map(|fill| (fill.maker.id, fill.amount, fill.payment)).collect()
+    }
+
+    #[test]
+    fn a_self_trade_cancel_records_no_initiator() {
+        currencies::tests::register("EUR");
+        currencies::tests::register("USD");
+        // list_asks places its asks for principal 31
+        let owner = principal(31);
+        let user_account = UserAccount {
+            locked: Some([("EUR".to_string(), 40)].into_iter().collect()),
+            ..UserAccount::default()
+        };
+        USER_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(owner.clone(), user_account));
+        list_asks(1, [30].into_iter(), None);
+        self_trade::tests::set_prevention(&owner, SelfTradePrevention::CancelOldest);
+
+        let args = CreateSwapOrderArgs { from_amount: 30, to_amount: 40, ..order_args("USD", "EUR") };
+        let fill = fill_against_book(&owner, &args, Price { numerator: 40, denominator: 30 }, false).unwrap();
+        assert_eq!(fill.self_trades.makers, vec![(1, true)]);
+        let cancelled = SWAP_ORDERS.with(|orders| orders.borrow().get(&1)).unwrap();
+        assert_eq!(cancelled.cancel_reason, Some(CancelReason::SelfTradePrevention));
+        assert_eq!(crate::events::tests::cancel_initiators(1), vec![None]);
+    }
+
+    #[test]
+    fn a_taker_fills_across_several_makers() {
+        let makers = vec![maker(1, 2, 100, 100), maker(2, 3, 100, 110)];
+
+        // The second maker gets what is left, rounded in its favour
+        assert_eq!(walk(makers, 150, None), vec![(1, 100, 100), (2, 45, 50)]);
+    }
+
+    #[test]
+    fn a_taker_stops_at_the_first_maker_past_its_price() {
+        let makers = vec![maker(1, 2, 100, 100), maker(2, 3, 100, 110), maker(3, 4, 100, 100)];
+        let one_for_one = Price { numerator: 1, denominator: 1 };
+
+        assert_eq!(walk(makers, 500, Some(one_for_one)), vec![(1, 100, 100)]);
+    }
+
+    #[test]
+    fn a_maker_exactly_at_the_taker_price_fills() {
+        let makers = vec![maker(1, 2, 200, 100)];
+        let two_for_one = Price { numerator: 2, denominator: 1 };
+
+        assert_eq!(walk(makers, 100, Some(two_for_one)), vec![(1, 200, 100)]);
+    }
+
+    #[test]
+    fn an_iceberg_refills_behind_its_price_level_and_keeps_filling() {
+        let iceberg = SwapOrder {
+            display_amount: Some(30),
+            ..maker(1, 2, 100, 100)
+        };
+        let makers = vec![iceberg, maker(2, 3, 50, 50), maker(3, 4, 100, 110)];
+
+        // 30 from the first tranche, the other maker at that price, then two
+        // more tranches until the budget runs out before the worse price
+        assert_eq!(walk(makers, 120, None), vec![(1, 70, 70), (2, 50, 50)]);
+    }
+
+    #[test]
+    fn makers_at_the_same_price_fill_in_time_order() {
+        let makers = vec![maker(7, 2, 100, 100), maker(3, 3, 100, 100)];
+
+        assert_eq!(walk(makers, 150, None), vec![(7, 100, 100), (3, 50, 50)]);
+    }
+
+    #[test]
+    fn a_partly_filled_maker_fills_its_remainder_on_its_own_terms() {
+        let mut partly_filled = maker(1, 2, 100, 30);
+        partly_filled.filled_amount = Some(40);
+
+        // 60 EUR remain, owed 30 - ceil(40 * 30 / 100) = 18 USD
+        assert_eq!(walk(vec![partly_filled], 1_000, None), vec![(1, 60, 18)]);
+    }
+
+    #[test]
+    fn a_budget_too_small_for_one_unit_fills_nothing() {
+        let makers = vec![maker(1, 2, 10, 100)];
+
+        assert_eq!(walk(makers, 9, None), vec![]);
+    }
+
+    // The taker's own order is all there is at its price, another maker's
+    // sits behind it at a worse one
+    fn own_order_at_the_price_level(
+        prevention: SelfTradePrevention,
+        price: Option<Price>,
+    ) -> (Vec<u64>, SelfTradePlan) {
+        let makers = vec![maker(1, 1, 100, 100), maker(2, 3, 100, 110)];
+        let (fills, plan) = walk_makers(&principal(1), makers.into_iter(), 500, price, prevention);
+        (fills.iter().map(|fill| fill.maker.id).collect(), plan)
+    }
+
+    fn cancelled_ids(plan: &SelfTradePlan) -> Vec<u64> {
+        plan.cancels.iter().map(|order| order.id).collect()
+    }
+
+    const ONE_FOR_ONE: Option<Price> = Some(Price { numerator: 1, denominator: 1 });
+
+    #[test]
+    fn cancel_newest_stops_at_the_own_order() {
+        let (fills, plan) = own_order_at_the_price_level(SelfTradePrevention::CancelNewest, None);
+
+        assert_eq!(fills, Vec::<u64>::new());
+        assert_eq!(plan.stopped_by, Some(1));
+        assert_eq!(cancelled_ids(&plan), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn cancel_oldest_cancels_the_own_order_and_leaves_the_level_empty() {
+        let (fills, plan) = own_order_at_the_price_level(SelfTradePrevention::CancelOldest, ONE_FOR_ONE);
+
+        assert_eq!(fills, Vec::<u64>::new());
+        assert_eq!(plan.stopped_by, None);
+        assert_eq!(cancelled_ids(&plan), vec![1]);
+    }
+
+    #[test]
+    fn cancel_oldest_goes_on_to_the_next_level_when_the_price_allows() {
+        let (fills, plan) = own_order_at_the_price_level(SelfTradePrevention::CancelOldest, None);
+
+        assert_eq!(fills, vec![2]);
+        assert_eq!(cancelled_ids(&plan), vec![1]);
+    }
+
+    #[test]
+    fn cancel_both_cancels_the_own_order_and_stops() {
+        let (fills, plan) = own_order_at_the_price_level(SelfTradePrevention::CancelBoth, None);
+
+        assert_eq!(fills, Vec::<u64>::new());
+        assert_eq!(plan.stopped_by, Some(1));
+        assert_eq!(cancelled_ids(&plan), vec![1]);
+    }
+
+    #[test]
+    fn allow_fills_against_the_own_order() {
+        let (fills, plan) = own_order_at_the_price_level(SelfTradePrevention::Allow, ONE_FOR_ONE);
+
+        assert_eq!(fills, vec![1]);
+        assert_eq!(plan.fills, vec![1]);
+        assert_eq!((plan.stopped_by, cancelled_ids(&plan)), (None, Vec::<u64>::new()));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::amounts::mul_div;
+use crate::session_keys::{self, SessionAction};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{
+    admin, allowlist, blacklist, cancel_open_order, check_new_order, order_limits, place_checked_order, rate_limit,
+    require_available, required_funds, store_order, BalanceChanges, CancelReason, CreateSwapOrderArgs, Error,
+    OrderType, StorablePrincipal, SwapOrder, SwapStatus, SWAP_ORDERS,
+};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+
+// Places two orders linked one-cancels-other and returns their ids. Once
+// either leg is fully executed the other is cancelled and its escrow released;
+// a partial fill shrinks the other leg's remainder by the same share it took
+// of the filled leg's remainder. A leg cancelled or expired on its own leaves
+// the other as it is.
+//
+// Both legs are checked before either is placed, including the funds for both
+// when they sell the same currency or lock creation deposits. Immediate orders never rest, so they
+// can't be a leg. A leg that matches as it is placed counts as a fill like
+// any later one. The pair is placed whole or not at all.
+#[ic_cdk::update]
+fn create_oco_orders(mut first: CreateSwapOrderArgs, mut second: CreateSwapOrderArgs) -> Result<(u64, u64), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    for args in [&first, &second] {
+        if matches!(args.order_type, OrderType::FillOrKill { .. } | OrderType::ImmediateOrCancel { .. }) {
+            return Err(Error::InvalidOcoOrders);
+        }
+    }
+    let owner = session_keys::on_behalf_of(SessionAction::Create)?;
+    check_new_order(owner, &mut first)?;
+    check_new_order(owner, &mut second)?;
+    let owner_principal = StorablePrincipal::from(owner);
+    let mut required = required_funds(&first);
+    for (currency, amount) in required_funds(&second) {
+        let total = required.entry(currency).or_default();
+        *total = total.checked_add(amount).ok_or(Error::Overflow)?;
+    }
+    for (currency, amount) in required {
+        require_available(&owner_principal, &currency, amount)?;
+    }
+    order_limits::check_open_order_limit_for(&owner_principal, 2)?;
+
+    let first_id = place_checked_order(owner, first)?;
+    // The second leg was checked with the first, so it only fails on something
+    // the first's placement changed, like fills it matched. Cancelling the
+    // first leg would leave those fills standing, so trap to roll both back.
+    let second_id = match place_checked_order(owner, second) {
+        Ok(second_id) => second_id,
+        Err(err) => ic_cdk::trap(&format!("second OCO leg failed after the first was placed: {err:?}")),
+    };
+    link(first_id, second_id);
+    link(second_id, first_id);
+    // Fills on placement happened before the legs were linked
+    for (leg_id, other_id) in [(first_id, second_id), (second_id, first_id)] {
+        if let Some(leg) = SWAP_ORDERS.with(|orders| orders.borrow().get(&leg_id)) {
+            if leg.filled() > 0 {
+                apply_fill(0, &leg, other_id);
+            }
+        }
+    }
+
+    Ok((first_id, second_id))
+}
+
+fn link(order_id: u64, linked_order_id: u64) {
+    if let Some(mut swap_order) = SWAP_ORDERS.with(|orders| orders.borrow().get(&order_id)) {
+        swap_order.linked_order_id = Some(linked_order_id);
+        store_order(swap_order);
+    }
+}
+
+// Called by store_order after a write that filled more of a linked leg, with
+// how much of it was filled before
+pub(crate) fn propagate_fill(filled_before: u128, leg: &SwapOrder) {
+    if let Some(other_id) = leg.linked_order_id {
+        apply_fill(filled_before, leg, other_id);
+    }
+}
+
+fn apply_fill(filled_before: u128, leg: &SwapOrder, other_id: u64) {
+    let Some(other) = SWAP_ORDERS.with(|orders| orders.borrow().get(&other_id)).filter(SwapOrder::is_open) else {
+        return;
+    };
+    // A refund that would overflow the owner's balance leaves the other leg
+    // open, the same as in the expiry sweep
+    match linked_leg_change(filled_before, leg, &other) {
+        LinkedLegChange::Cancel => {
+            let _ = cancel_open_order(other, CancelReason::LinkedOrderFilled, None);
+        }
+        LinkedLegChange::Shrink { from_amount, to_amount } => {
+            let _ = shrink_order(other, from_amount, to_amount);
+        }
+        LinkedLegChange::Keep => {}
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum LinkedLegChange {
+    Cancel,
+    Shrink { from_amount: u128, to_amount: u128 },
+    Keep, // nothing more of the leg was filled
+}
+
+// What a fill of `leg`, which had `filled_before` filled, does to the other
+// leg of the pair
+fn linked_leg_change(filled_before: u128, leg: &SwapOrder, other: &SwapOrder) -> LinkedLegChange {
+    if leg.status == SwapStatus::Executed {
+        return LinkedLegChange::Cancel;
+    }
+
+    let remaining_before = leg.from_amount - filled_before;
+    let keep = mul_div(other.remaining(), leg.remaining(), remaining_before).unwrap_or(0);
+    let new_from_amount = other.filled() + keep;
+    let new_to_amount = mul_div(other.to_amount, new_from_amount, other.from_amount).unwrap_or(0);
+    if keep == 0 || new_to_amount == 0 {
+        LinkedLegChange::Cancel
+    } else if new_from_amount < other.from_amount {
+        LinkedLegChange::Shrink {
+            from_amount: new_from_amount,
+            to_amount: new_to_amount,
+        }
+    } else {
+        LinkedLegChange::Keep
+    }
+}
+
+// Lowers an open order's amounts at its price and releases the difference in
+// escrow, like amend_swap_order does for the owner
+fn shrink_order(mut swap_order: SwapOrder, new_from_amount: u128, new_to_amount: u128) -> Result<(), Error> {
+    let released = swap_order.from_amount - new_from_amount;
+    let mut changes = BalanceChanges::new();
+    changes.unlock(&StorablePrincipal::from(swap_order.owner), &swap_order.from_currency, released)?;
+    changes.commit();
+    record_transaction(
+        TransactionKind::Refund,
+        None,
+        Some(swap_order.owner),
+        &swap_order.from_currency,
+        released,
+        Some(swap_order.id),
+    );
+
+    swap_order.from_amount = new_from_amount;
+    swap_order.to_amount = new_to_amount;
+    swap_order.updated_at = Some(time());
+    store_order(swap_order);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::order_args;
+    use crate::{UserAccount, USER_ACCOUNTS};
+    use candid::Principal;
+
+    // Sells `from_amount` of `from_currency` for twice as much of the other
+    fn leg(id: u64, from_currency: &str, to_currency: &str, from_amount: u128, filled: u128) -> SwapOrder {
+        SwapOrder {
+            id,
+            owner: Principal::from_slice(&[1]),
+            from_currency: from_currency.to_string(),
+            to_currency: to_currency.to_string(),
+            from_amount,
+            to_amount: from_amount * 2,
+            filled_amount: Some(filled),
+            linked_order_id: Some(if id == 1 { 2 } else { 1 }),
+            ..SwapOrder::default()
+        }
+    }
+
+    #[test]
+    fn anonymous_oco_pairs_are_rejected() {
+        assert_eq!(
+            create_oco_orders(order_args("EUR", "USD"), order_args("EUR", "GBP")),
+            Err(Error::AnonymousNotAllowed)
+        );
+    }
+
+    fn locked_eur() -> u128 {
+        let owner = StorablePrincipal::from(Principal::from_slice(&[1]));
+        USER_ACCOUNTS.with(|accounts| accounts.borrow().get(&owner)).unwrap().locked("EUR")
+    }
+
+    #[test]
+    fn storing_a_fill_of_one_leg_shrinks_then_cancels_the_other() {
+        let owner = StorablePrincipal::from(Principal::from_slice(&[1]));
+        let user_account = UserAccount {
+            locked: Some([("EUR".to_string(), 300)].into_iter().collect()),
+            ..UserAccount::default()
+        };
+        USER_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(owner, user_account));
+        store_order(leg(1, "EUR", "USD", 100, 0));
+        store_order(leg(2, "EUR", "GBP", 200, 0));
+        admin::tests::set_time(1_000);
+
+        // A quarter of the first leg fills, so the other gives up a quarter
+        store_order(leg(1, "EUR", "USD", 100, 25));
+        let other = SWAP_ORDERS.with(|orders| orders.borrow().get(&2)).unwrap();
+        assert_eq!((other.from_amount, other.to_amount), (150, 300));
+        assert_eq!(other.updated_at, Some(1_000));
+        assert_eq!(locked_eur(), 250);
+
+        store_order(SwapOrder { status: SwapStatus::Executed, ..leg(1, "EUR", "USD", 100, 100) });
+        let other = SWAP_ORDERS.with(|orders| orders.borrow().get(&2)).unwrap();
+        assert_eq!(other.status, SwapStatus::Cancelled);
+        assert_eq!(other.cancel_reason, Some(CancelReason::LinkedOrderFilled));
+        assert_eq!(other.cancelled_at, Some(1_000));
+        assert_eq!(crate::events::tests::cancel_initiators(2), vec![None]);
+        assert_eq!(locked_eur(), 100);
+    }
+
+    #[test]
+    fn a_fully_executed_leg_cancels_the_other() {
+        let filled = SwapOrder { status: SwapStatus::Executed, ..leg(1, "EUR", "USD", 100, 100) };
+
+        assert_eq!(linked_leg_change(60, &filled, &leg(2, "EUR", "GBP", 100, 0)), LinkedLegChange::Cancel);
+    }
+
+    #[test]
+    fn a_partial_fill_shrinks_the_other_leg_by_the_same_share() {
+        // A quarter of the first leg is filled, so a quarter of the other goes
+        let filled = leg(1, "EUR", "USD", 100, 25);
+
+        assert_eq!(
+            linked_leg_change(0, &filled, &leg(2, "EUR", "GBP", 200, 0)),
+            LinkedLegChange::Shrink { from_amount: 150, to_amount: 300 }
+        );
+    }
+
+    #[test]
+    fn the_share_is_of_what_was_left_of_each_leg() {
+        // Half of the first leg's remaining 80 is filled; the other leg had
+        // 40 of its 200 filled already and keeps half of its remaining 160
+        let filled = leg(1, "EUR", "USD", 100, 60);
+
+        assert_eq!(
+            linked_leg_change(20, &filled, &leg(2, "EUR", "GBP", 200, 40)),
+            LinkedLegChange::Shrink { from_amount: 120, to_amount: 240 }
+        );
+    }
+
+    #[test]
+    fn the_other_leg_rounds_down_so_any_fill_takes_a_unit() {
+        // A millionth of the first leg still takes one of the other's 10
+        let barely_filled = leg(1, "EUR", "USD", 1_000_000, 1);
+
+        assert_eq!(
+            linked_leg_change(0, &barely_filled, &leg(2, "EUR", "GBP", 10, 0)),
+            LinkedLegChange::Shrink { from_amount: 9, to_amount: 18 }
+        );
+    }
+
+    #[test]
+    fn a_write_without_a_new_fill_keeps_the_other_leg() {
+        let filled = leg(1, "EUR", "USD", 100, 30);
+
+        assert_eq!(linked_leg_change(30, &filled, &leg(2, "EUR", "GBP", 200, 0)), LinkedLegChange::Keep);
+    }
+
+    #[test]
+    fn a_shrink_below_one_unit_cancels_the_other_leg() {
+        let filled = leg(1, "EUR", "USD", 100, 99);
+
+        assert_eq!(linked_leg_change(0, &filled, &leg(2, "EUR", "GBP", 50, 0)), LinkedLegChange::Cancel);
+    }
+
+    #[test]
+    fn a_shrink_keeps_the_other_leg_at_its_price() {
+        let filled = leg(1, "EUR", "USD", 3, 1);
+        let other = SwapOrder { to_amount: 7, ..leg(2, "EUR", "GBP", 9, 0) };
+
+        // 6 of 9 left, the to_amount scaled down to 7 * 6 / 9 rounded down
+        assert_eq!(linked_leg_change(0, &filled, &other), LinkedLegChange::Shrink { from_amount: 6, to_amount: 4 });
+    }
+}
@@ -0,0 +1,149 @@
+use crate::admin::require_admin;
+use crate::currencies::{self, is_known_currency, normalize_currency};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{fees, BalanceChanges, Error, Memory, StorablePrincipal, SwapOrder, SwapStatus, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Locked from the owner's balance while an order rests, on top of the
+// order's own escrow
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct OrderDeposit {
+    pub(crate) currency: String,
+    pub(crate) amount: u128,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrderDepositConfig {
+    deposit: Option<OrderDeposit>, // None takes no deposit
+}
+
+impl Storable for OrderDepositConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode OrderDepositConfig"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode OrderDepositConfig")
+    }
+}
+
+thread_local! {
+    // Off until the admin sets a deposit
+    static ORDER_DEPOSIT_CONFIG: RefCell<Cell<OrderDepositConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67))), OrderDepositConfig::default())
+            .expect("Cannot create the order deposit config")
+    );
+}
+
+#[ic_cdk::query]
+fn get_order_deposit() -> Option<OrderDeposit> {
+    current_deposit()
+}
+
+// Sets what each new resting order locks, or None to stop taking deposits.
+// Orders already resting keep the deposit they were placed with.
+#[ic_cdk::update]
+fn set_order_deposit(deposit: Option<OrderDeposit>) -> Result<(), Error> {
+    require_admin()?;
+    let deposit = match deposit {
+        Some(deposit) => {
+            let currency = normalize_currency(&deposit.currency);
+            if !is_known_currency(&currency) {
+                return Err(Error::InvalidCurrency { provided: currency });
+            }
+            if deposit.amount == 0 {
+                return Err(Error::InvalidAmount);
+            }
+            currencies::check_amount_precision(&currency, deposit.amount)?;
+            Some(OrderDeposit {
+                currency,
+                amount: deposit.amount,
+            })
+        }
+        None => None,
+    };
+
+    ORDER_DEPOSIT_CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(OrderDepositConfig { deposit })
+            .expect("Failed to store the order deposit config")
+    });
+    Ok(())
+}
+
+// The deposit a new order would lock
+pub(crate) fn current_deposit() -> Option<OrderDeposit> {
+    ORDER_DEPOSIT_CONFIG.with(|config| config.borrow().get().deposit.clone())
+}
+
+// Locks the deposit for an order about to rest; check_new_order made sure it
+// is available
+pub(crate) fn lock_deposit(
+    changes: &mut BalanceChanges,
+    owner: &StorablePrincipal,
+    deposit: &OrderDeposit,
+) -> Result<(), Error> {
+    changes.lock(owner, &deposit.currency, deposit.amount)
+}
+
+// Called by store_order once an order stops holding escrow. An order that
+// expires without a single fill forfeits its deposit to the fee account;
+// every other way out returns it to the owner.
+pub(crate) fn settle_deposit(swap_order: &SwapOrder) {
+    let Some(deposit) = swap_order.creation_deposit.as_ref() else {
+        return;
+    };
+    let owner_principal = StorablePrincipal::from(swap_order.owner);
+    let forfeited = swap_order.status == SwapStatus::Expired && swap_order.filled() == 0;
+    let mut changes = BalanceChanges::new();
+    if forfeited {
+        changes
+            .release(&owner_principal, &deposit.currency, deposit.amount)
+            .expect("Order deposit was not locked");
+        changes
+            .credit(&fees::fee_account(), &deposit.currency, deposit.amount)
+            .expect("Forfeited order deposit overflowed the fee account");
+    } else {
+        changes
+            .unlock(&owner_principal, &deposit.currency, deposit.amount)
+            .expect("Order deposit was not locked");
+    }
+    changes.commit();
+
+    if forfeited {
+        record_transaction(
+            TransactionKind::DepositForfeited,
+            Some(swap_order.owner),
+            Some(fees::fee_account().into()),
+            &deposit.currency,
+            deposit.amount,
+            Some(swap_order.id),
+        );
+    } else {
+        record_transaction(
+            TransactionKind::DepositRefund,
+            None,
+            Some(swap_order.owner),
+            &deposit.currency,
+            deposit.amount,
+            Some(swap_order.id),
+        );
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn take_deposit(currency: &str, amount: u128) {
+        let config = OrderDepositConfig {
+            deposit: Some(OrderDeposit { currency: currency.to_string(), amount }),
+        };
+        ORDER_DEPOSIT_CONFIG.with(|cell| cell.borrow_mut().set(config)).unwrap();
+    }
+}
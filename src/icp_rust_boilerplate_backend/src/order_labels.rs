@@ -0,0 +1,177 @@
+use crate::snapshot::{clear_map, entries_after};
+use crate::{admin, archive, rate_limit, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const MAX_LABELS: usize = 5;
+
+const MAX_LABEL_BYTES: usize = 16;
+
+// Labels the owner put on one of their orders. Kept out of SwapOrder so no
+// order query can show them to anyone else.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct StoredLabels(Vec<String>);
+
+impl Storable for StoredLabels {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode StoredLabels"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode StoredLabels")
+    }
+}
+
+impl BoundedStorable for StoredLabels {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Order id -> labels, only for orders that have any
+    static ORDER_LABELS: RefCell<StableBTreeMap<u64, StoredLabels, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrderLabels {
+    order_id: u64,
+    labels: Vec<String>,
+}
+
+// Replaces the labels on one of the caller's orders, live or archived. An
+// empty list removes them. Labels are trimmed and duplicates dropped.
+#[ic_cdk::update]
+fn tag_order(order_id: u64, labels: Vec<String>) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let swap_order = archive::find_order(order_id).ok_or(Error::InvalidOrderId)?;
+    if swap_order.owner != caller() {
+        return Err(Error::Unauthorized);
+    }
+
+    let mut cleaned: Vec<String> = Vec::new();
+    for label in labels {
+        let label = label.trim().to_string();
+        if label.is_empty() || label.len() > MAX_LABEL_BYTES {
+            return Err(Error::InvalidLabels);
+        }
+        if !cleaned.contains(&label) {
+            cleaned.push(label);
+        }
+    }
+    if cleaned.len() > MAX_LABELS {
+        return Err(Error::InvalidLabels);
+    }
+
+    ORDER_LABELS.with(|stored| {
+        if cleaned.is_empty() {
+            stored.borrow_mut().remove(&order_id);
+        } else {
+            stored.borrow_mut().insert(order_id, StoredLabels(cleaned));
+        }
+    });
+    Ok(())
+}
+
+pub(crate) fn labels_of(order_id: u64) -> Vec<String> {
+    ORDER_LABELS.with(|stored| stored.borrow().get(&order_id)).unwrap_or_default().0
+}
+
+pub(crate) fn has_label(order_id: u64, label: &str) -> bool {
+    labels_of(order_id).iter().any(|stored| stored == label)
+}
+
+// The labels of the orders given, leaving out those without any
+pub(crate) fn labels_for(order_ids: impl Iterator<Item = u64>) -> Vec<OrderLabels> {
+    order_ids
+        .map(|order_id| OrderLabels {
+            order_id,
+            labels: labels_of(order_id),
+        })
+        .filter(|order_labels| !order_labels.labels.is_empty())
+        .collect()
+}
+
+// Called by prune_archive for each order it deletes
+pub(crate) fn forget_labels(order_id: u64) {
+    ORDER_LABELS.with(|stored| stored.borrow_mut().remove(&order_id));
+}
+
+// Up to `limit` labelled orders after the id `after`, for create_export
+pub(crate) fn export_labels(after: Option<u64>, limit: usize) -> Vec<(u64, OrderLabels)> {
+    let page = ORDER_LABELS.with(|stored| entries_after(&stored.borrow(), after, limit));
+    page.into_iter()
+        .map(|(order_id, StoredLabels(labels))| (order_id, OrderLabels { order_id, labels }))
+        .collect()
+}
+
+// Replaces every label with those of an imported snapshot
+pub(crate) fn restore_labels(restored: Vec<OrderLabels>) {
+    ORDER_LABELS.with(|stored| {
+        let mut stored_borrowed = stored.borrow_mut();
+        clear_map(&mut stored_borrowed);
+        for order_labels in restored.into_iter().filter(|order_labels| !order_labels.labels.is_empty()) {
+            stored_borrowed.insert(order_labels.order_id, StoredLabels(order_labels.labels));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::tests::set_caller;
+    use crate::{SwapOrder, SWAP_ORDERS};
+    use candid::Principal;
+
+    fn tagging_as(owner: Principal, order_owner: Principal) {
+        set_caller(owner);
+        crate::rate_limit::tests::exempt(owner);
+        let swap_order = SwapOrder { id: 9, owner: order_owner, ..SwapOrder::default() };
+        SWAP_ORDERS.with(|orders| orders.borrow_mut().insert(9, swap_order));
+    }
+
+    #[test]
+    fn anonymous_tagging_is_rejected() {
+        assert_eq!(tag_order(9, vec!["rent".to_string()]), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn labels_are_trimmed_and_deduplicated() {
+        let owner = Principal::from_slice(&[80]);
+        tagging_as(owner, owner);
+
+        let labels = vec![" rent ".to_string(), "rent".to_string(), "q3".to_string()];
+        assert_eq!(tag_order(9, labels), Ok(()));
+        assert_eq!(labels_of(9), vec!["rent".to_string(), "q3".to_string()]);
+        assert_eq!(tag_order(9, Vec::new()), Ok(()));
+        assert!(labels_of(9).is_empty());
+    }
+
+    #[test]
+    fn only_the_owner_may_label_an_order() {
+        tagging_as(Principal::from_slice(&[81]), Principal::from_slice(&[82]));
+
+        assert_eq!(tag_order(9, vec!["rent".to_string()]), Err(Error::Unauthorized));
+    }
+
+    #[test]
+    fn blank_long_or_too_many_labels_are_rejected() {
+        let owner = Principal::from_slice(&[83]);
+        tagging_as(owner, owner);
+
+        assert_eq!(tag_order(9, vec!["  ".to_string()]), Err(Error::InvalidLabels));
+        assert_eq!(tag_order(9, vec!["x".repeat(MAX_LABEL_BYTES + 1)]), Err(Error::InvalidLabels));
+        let too_many = (0..=MAX_LABELS).map(|label| label.to_string()).collect();
+        assert_eq!(tag_order(9, too_many), Err(Error::InvalidLabels));
+    }
+}
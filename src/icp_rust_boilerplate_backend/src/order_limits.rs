@@ -0,0 +1,125 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER, SWAP_ORDERS};
+use candid::Principal;
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+const DEFAULT_MAX_OPEN_ORDERS: u64 = 100;
+
+thread_local! {
+    // Principal -> orders currently open or accepted, kept in step
+    // by store_order and recounted by rebuild_derived_state
+    static OPEN_ORDER_COUNTS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static MAX_OPEN_ORDERS: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), DEFAULT_MAX_OPEN_ORDERS)
+            .expect("Cannot create the open order limit")
+    );
+
+    // Market makers the admin allows more (or fewer) open orders than the default
+    static OPEN_ORDER_LIMIT_OVERRIDES: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OpenOrderAllowance {
+    limit: u64,
+    open: u64,
+}
+
+#[ic_cdk::update]
+fn set_max_open_orders(limit: u64) -> Result<(), Error> {
+    require_admin()?;
+    MAX_OPEN_ORDERS.with(|cell| cell.borrow_mut().set(limit))
+        .expect("Failed to store the open order limit");
+    Ok(())
+}
+
+// Gives a principal its own limit; None puts it back on the default
+#[ic_cdk::update]
+fn set_open_order_limit_override(principal: Principal, limit: Option<u64>) -> Result<(), Error> {
+    require_admin()?;
+
+    OPEN_ORDER_LIMIT_OVERRIDES.with(|overrides| {
+        let mut overrides_borrowed = overrides.borrow_mut();
+        match limit {
+            Some(limit) => overrides_borrowed.insert(StorablePrincipal::from(principal), limit),
+            None => overrides_borrowed.remove(&StorablePrincipal::from(principal)),
+        };
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_open_order_allowance() -> OpenOrderAllowance {
+    let principal = StorablePrincipal::from(caller());
+    OpenOrderAllowance {
+        limit: open_order_limit(&principal),
+        open: open_orders(&principal),
+    }
+}
+
+// Fails once the principal already has as many open orders as it may hold
+pub(crate) fn check_open_order_limit(principal: &StorablePrincipal) -> Result<(), Error> {
+    check_open_order_limit_for(principal, 1)
+}
+
+// Fails unless `new_orders` more open orders fit under the principal's limit
+pub(crate) fn check_open_order_limit_for(principal: &StorablePrincipal, new_orders: u64) -> Result<(), Error> {
+    let limit = open_order_limit(principal);
+    if open_orders(principal).saturating_add(new_orders) > limit {
+        return Err(Error::TooManyOpenOrders { limit });
+    }
+    Ok(())
+}
+
+// Called by store_order with whether the order was open before and after the write
+pub(crate) fn record_open_change(owner: &StorablePrincipal, was_open: bool, is_open: bool) {
+    if was_open == is_open {
+        return;
+    }
+    OPEN_ORDER_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let count = counts_borrowed.get(owner).unwrap_or(0);
+        match (is_open, count) {
+            (true, _) => counts_borrowed.insert(owner.clone(), count + 1),
+            (false, 0 | 1) => counts_borrowed.remove(owner),
+            (false, _) => counts_borrowed.insert(owner.clone(), count - 1),
+        };
+    });
+}
+
+// Recounts from the stored orders, like the order book index
+pub(crate) fn rebuild_open_order_counts() {
+    OPEN_ORDER_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let stale: Vec<StorablePrincipal> = counts_borrowed.iter().map(|(principal, _)| principal).collect();
+        for principal in stale {
+            counts_borrowed.remove(&principal);
+        }
+        SWAP_ORDERS.with(|orders| {
+            for (_, swap_order) in orders.borrow().iter().filter(|(_, order)| order.holds_escrow()) {
+                let owner = StorablePrincipal::from(swap_order.owner);
+                let count = counts_borrowed.get(&owner).unwrap_or(0);
+                counts_borrowed.insert(owner, count + 1);
+            }
+        });
+    });
+}
+
+fn open_order_limit(principal: &StorablePrincipal) -> u64 {
+    OPEN_ORDER_LIMIT_OVERRIDES
+        .with(|overrides| overrides.borrow().get(principal))
+        .unwrap_or_else(|| MAX_OPEN_ORDERS.with(|cell| *cell.borrow().get()))
+}
+
+fn open_orders(principal: &StorablePrincipal) -> u64 {
+    OPEN_ORDER_COUNTS.with(|counts| counts.borrow().get(principal)).unwrap_or(0)
+}
@@ -0,0 +1,210 @@
+use crate::admin::require_admin;
+use crate::currencies::normalize_currency;
+use crate::{
+    archive, stats, CurrencyPair, Error, Memory, OrdersCursorPage, StorablePrincipal, SwapOrder, SwapStatus,
+    MAX_ORDERS_PAGE_SIZE, MEMORY_MANAGER, ORDERS_BY_OWNER, SWAP_ORDERS,
+};
+use candid::Principal;
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Index entries inspected per call, keeps a call inside the instruction
+// limit even when few of the inspected orders match
+const ORDER_QUERY_SCAN_LIMIT: usize = 2000;
+
+// Every field left as None matches all orders
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct OrderFilter {
+    created_from: Option<u64>,         // nanoseconds since epoch, inclusive
+    created_to: Option<u64>,           // nanoseconds since epoch, exclusive
+    statuses: Option<Vec<SwapStatus>>, // any of these
+    pair: Option<(String, String)>,    // from_currency, to_currency
+    owner: Option<Principal>,          // anyone but the caller needs the admin
+}
+
+thread_local! {
+    // (created_at, order id) for every live or archived order. Ids grow with
+    // creation time, so this turns a time range into an id range.
+    static ORDERS_BY_CREATED: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103)))
+    ));
+
+    // (pair, order id) for every live or archived order
+    static ORDERS_BY_PAIR: RefCell<StableBTreeMap<(CurrencyPair, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104)))
+    ));
+}
+
+// Live and archived orders matching the filter, in id order and so oldest
+// first. The owner or pair index narrows the scan to matching orders and the
+// time range to an id range; statuses are checked on the orders found. A page
+// can come back short, even empty, with a cursor when the scan limit is hit
+// first: pass next_start_id as the cursor until it is None.
+#[ic_cdk::query]
+fn query_orders(filter: OrderFilter, cursor: Option<u64>, limit: u16) -> Result<OrdersCursorPage, Error> {
+    let viewer = caller();
+    if filter.owner.is_some_and(|owner| owner != viewer) {
+        require_admin()?;
+    }
+    let limit = (limit as u64).min(MAX_ORDERS_PAGE_SIZE) as usize;
+    let pair = filter.pair.as_ref().map(|(from_currency, to_currency)| {
+        CurrencyPair::new(&normalize_currency(from_currency), &normalize_currency(to_currency))
+    });
+
+    let start = filter.created_from.map_or(0, first_id_created_from).max(cursor.unwrap_or(0));
+    let end = filter.created_to.map_or(u64::MAX, first_id_created_from);
+    let candidates: Vec<SwapOrder> = if start >= end {
+        Vec::new()
+    } else if let Some(owner) = filter.owner {
+        let owner = StorablePrincipal::from(owner);
+        let order_ids: Vec<u64> = ORDERS_BY_OWNER.with(|index| {
+            index
+                .borrow()
+                .range((owner.clone(), start)..(owner, end))
+                .take(ORDER_QUERY_SCAN_LIMIT + 1)
+                .map(|((_, order_id), _)| order_id)
+                .collect()
+        });
+        order_ids.into_iter().filter_map(archive::find_order).collect()
+    } else if let Some(pair) = pair.clone() {
+        let order_ids: Vec<u64> = ORDERS_BY_PAIR.with(|index| {
+            index
+                .borrow()
+                .range((pair.clone(), start)..(pair, end))
+                .take(ORDER_QUERY_SCAN_LIMIT + 1)
+                .map(|((_, order_id), _)| order_id)
+                .collect()
+        });
+        order_ids.into_iter().filter_map(archive::find_order).collect()
+    } else {
+        orders_in_range(start, end)
+    };
+
+    let mut orders = Vec::new();
+    let mut next_start_id = None;
+    for (scanned, swap_order) in candidates.into_iter().enumerate() {
+        if orders.len() == limit || scanned == ORDER_QUERY_SCAN_LIMIT {
+            next_start_id = Some(swap_order.id);
+            break;
+        }
+        let status_matches = filter.statuses.as_ref().is_none_or(|statuses| statuses.contains(&swap_order.status));
+        let pair_matches = pair.as_ref().is_none_or(|pair| {
+            swap_order.from_currency == pair.from_currency && swap_order.to_currency == pair.to_currency
+        });
+        if status_matches && pair_matches {
+            orders.push(swap_order.viewed_by(&viewer));
+        }
+    }
+
+    Ok(OrdersCursorPage { orders, next_start_id })
+}
+
+// Number of live and archived orders matching the filter, read off the
+// per-status and per-pair counters. Those can't answer a time range or an
+// owner, so such filters are refused; page through query_orders for them.
+#[ic_cdk::query]
+fn count_orders(filter: OrderFilter) -> Result<u64, Error> {
+    if filter.created_from.is_some() || filter.created_to.is_some() || filter.owner.is_some() {
+        return Err(Error::FilterNotCountable);
+    }
+    let pair = filter.pair.map(|(from_currency, to_currency)| {
+        CurrencyPair::new(&normalize_currency(&from_currency), &normalize_currency(&to_currency))
+    });
+    Ok(stats::order_count(pair.as_ref(), filter.statuses.as_deref()))
+}
+
+// Called once for every new order
+pub(crate) fn index_order(swap_order: &SwapOrder) {
+    ORDERS_BY_CREATED.with(|index| index.borrow_mut().insert((swap_order.created_at, swap_order.id), ()));
+    ORDERS_BY_PAIR.with(|index| index.borrow_mut().insert((pair_of(swap_order), swap_order.id), ()));
+}
+
+// Called when an archived order is deleted
+pub(crate) fn forget_order(swap_order: &SwapOrder) {
+    ORDERS_BY_CREATED.with(|index| index.borrow_mut().remove(&(swap_order.created_at, swap_order.id)));
+    ORDERS_BY_PAIR.with(|index| index.borrow_mut().remove(&(pair_of(swap_order), swap_order.id)));
+}
+
+// forget_order for an order that can no longer be read, so its keys have to
+// be searched for
+pub(crate) fn forget_order_id(order_id: u64) {
+    ORDERS_BY_CREATED.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let stale: Vec<(u64, u64)> =
+            index_borrowed.iter().map(|(key, _)| key).filter(|(_, id)| *id == order_id).collect();
+        for key in stale {
+            index_borrowed.remove(&key);
+        }
+    });
+    ORDERS_BY_PAIR.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let stale: Vec<(CurrencyPair, u64)> =
+            index_borrowed.iter().map(|(key, _)| key).filter(|(_, id)| *id == order_id).collect();
+        for key in stale {
+            index_borrowed.remove(&key);
+        }
+    });
+}
+
+// Like the owner index, complete exactly when both indexes have one entry
+// per live or archived order. Orders created before the indexes existed are
+// added on the first upgrade that sees the mismatch.
+pub(crate) fn rebuild_order_query_indexes() {
+    let stored = SWAP_ORDERS.with(|orders| orders.borrow().len()) + archive::archived_order_count();
+    let by_created = ORDERS_BY_CREATED.with(|index| index.borrow().len());
+    let by_pair = ORDERS_BY_PAIR.with(|index| index.borrow().len());
+    if by_created == stored && by_pair == stored {
+        return;
+    }
+
+    clear_order_query_indexes();
+    SWAP_ORDERS.with(|orders| orders.borrow().iter().for_each(|(_, swap_order)| index_order(&swap_order)));
+    archive::ARCHIVED_ORDERS
+        .with(|archive| archive.borrow().iter().for_each(|(_, swap_order)| index_order(&swap_order)));
+}
+
+pub(crate) fn clear_order_query_indexes() {
+    ORDERS_BY_CREATED.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let keys: Vec<(u64, u64)> = index_borrowed.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index_borrowed.remove(&key);
+        }
+    });
+    ORDERS_BY_PAIR.with(|index| {
+        let mut index_borrowed = index.borrow_mut();
+        let keys: Vec<(CurrencyPair, u64)> = index_borrowed.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            index_borrowed.remove(&key);
+        }
+    });
+}
+
+// Lowest id of an order created at or after `created_at`, u64::MAX when
+// there is none
+fn first_id_created_from(created_at: u64) -> u64 {
+    ORDERS_BY_CREATED.with(|index| {
+        index.borrow().range((created_at, 0)..).next().map_or(u64::MAX, |((_, order_id), _)| order_id)
+    })
+}
+
+// Live and archived orders with ids in start..end, merged in id order, up to
+// one past the scan limit
+fn orders_in_range(start: u64, end: u64) -> Vec<SwapOrder> {
+    let take = |map: &RefCell<StableBTreeMap<u64, SwapOrder, Memory>>| -> Vec<SwapOrder> {
+        map.borrow().range(start..end).take(ORDER_QUERY_SCAN_LIMIT + 1).map(|(_, order)| order).collect()
+    };
+    let mut orders = SWAP_ORDERS.with(take);
+    orders.extend(archive::ARCHIVED_ORDERS.with(take));
+    orders.sort_by_key(|order| order.id);
+    orders.truncate(ORDER_QUERY_SCAN_LIMIT + 1);
+    orders
+}
+
+fn pair_of(swap_order: &SwapOrder) -> CurrencyPair {
+    CurrencyPair::new(&swap_order.from_currency, &swap_order.to_currency)
+}
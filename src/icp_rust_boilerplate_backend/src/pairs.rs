@@ -0,0 +1,158 @@
+use crate::admin::require_admin;
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::{CurrencyPair, Error, Memory, Price, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Limits on the orders accepted for a pair, keeping dust orders off the book
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct PairConfig {
+    min_from_amount: u128,
+    min_to_amount: u128,
+    price_tick: Option<Price>, // order prices must be whole multiples of this, None allows any price
+}
+
+impl Default for PairConfig {
+    fn default() -> Self {
+        PairConfig {
+            min_from_amount: 1,
+            min_to_amount: 1,
+            price_tick: None,
+        }
+    }
+}
+
+impl PairConfig {
+    fn validate(&self) -> Result<(), Error> {
+        match self.price_tick {
+            Some(tick) if !tick.is_valid() => Err(Error::InvalidPairConfig),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_order(&self, from_amount: u128, to_amount: u128, price: Option<Price>) -> Result<(), Error> {
+        if from_amount < self.min_from_amount {
+            return Err(Error::BelowMinFromAmount { minimum: self.min_from_amount });
+        }
+        if to_amount < self.min_to_amount {
+            return Err(Error::BelowMinToAmount { minimum: self.min_to_amount });
+        }
+        if let (Some(price), Some(tick)) = (price, self.price_tick) {
+            if !price.is_multiple_of(tick) {
+                return Err(Error::PriceNotOnTick { tick });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storable for PairConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode PairConfig"))
+    }
+
+    // Configs set while amounts were u64 are widened on the way out
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), NarrowPairConfig).map(PairConfig::from))
+            .expect("Failed to decode PairConfig")
+    }
+}
+
+impl BoundedStorable for PairConfig {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// PairConfig as stored while amounts were u64
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NarrowPairConfig {
+    min_from_amount: u64,
+    min_to_amount: u64,
+    price_tick: Option<Price>,
+}
+
+impl From<NarrowPairConfig> for PairConfig {
+    fn from(narrow: NarrowPairConfig) -> Self {
+        PairConfig {
+            min_from_amount: narrow.min_from_amount.into(),
+            min_to_amount: narrow.min_to_amount.into(),
+            price_tick: narrow.price_tick,
+        }
+    }
+}
+
+thread_local! {
+    // Keyed by the pair an order sells from and to, so each direction of a
+    // market is configured separately
+    static PAIR_CONFIGS: RefCell<StableBTreeMap<CurrencyPair, PairConfig, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Applies to every pair without its own entry
+    static DEFAULT_PAIR_CONFIG: RefCell<Cell<PairConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))), PairConfig::default())
+            .expect("Cannot create the default pair config")
+    );
+}
+
+// The pair's own config, or the default when it has none
+#[ic_cdk::query]
+fn get_pair_config(from_currency: String, to_currency: String) -> PairConfig {
+    pair_config(&normalize_currency(&from_currency), &normalize_currency(&to_currency))
+}
+
+#[ic_cdk::update]
+fn set_pair_config(from_currency: String, to_currency: String, config: PairConfig) -> Result<(), Error> {
+    require_admin()?;
+    let pair = checked_pair(&from_currency, &to_currency)?;
+    config.validate()?;
+
+    PAIR_CONFIGS.with(|configs| configs.borrow_mut().insert(pair, config));
+    Ok(())
+}
+
+// Drops the pair's own config so it falls back to the default
+#[ic_cdk::update]
+fn remove_pair_config(from_currency: String, to_currency: String) -> Result<(), Error> {
+    require_admin()?;
+    let pair = checked_pair(&from_currency, &to_currency)?;
+
+    PAIR_CONFIGS.with(|configs| configs.borrow_mut().remove(&pair));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_default_pair_config(config: PairConfig) -> Result<(), Error> {
+    require_admin()?;
+    config.validate()?;
+
+    DEFAULT_PAIR_CONFIG.with(|default| {
+        default.borrow_mut().set(config).map_err(|_| Error::InvalidPairConfig)?;
+        Ok(())
+    })
+}
+
+pub(crate) fn pair_config(from_currency: &str, to_currency: &str) -> PairConfig {
+    PAIR_CONFIGS
+        .with(|configs| configs.borrow().get(&CurrencyPair::new(from_currency, to_currency)))
+        .unwrap_or_else(|| DEFAULT_PAIR_CONFIG.with(|default| default.borrow().get().clone()))
+}
+
+fn checked_pair(from_currency: &str, to_currency: &str) -> Result<CurrencyPair, Error> {
+    let from_currency = normalize_currency(from_currency);
+    let to_currency = normalize_currency(to_currency);
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    if from_currency == to_currency {
+        return Err(Error::SameCurrency);
+    }
+    Ok(CurrencyPair::new(&from_currency, &to_currency))
+}
@@ -0,0 +1,456 @@
+use crate::currencies::{is_known_currency, normalize_currency, CurrencySymbol};
+use crate::events::{record_event, EventKind};
+use crate::snapshot::{clear_map, entries_after};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{
+    add_to_bucket, admin, blacklist, rate_limit, require_available, withdrawals, BalanceChanges, Error, Memory,
+    StorablePrincipal, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub(crate) const PENDING_WITHDRAWAL_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Pending withdrawals expired per sweep tick
+const PENDING_WITHDRAWAL_SWEEP_BATCH_SIZE: usize = 200;
+
+// Shortest wait between requesting a withdrawal and confirming it
+const CONFIRMATION_DELAY_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+// How long a pending withdrawal waits for its confirmation before the funds
+// go back to the owner's available balance
+const PENDING_WITHDRAWAL_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// How long raising or removing a threshold takes to apply, so a stolen key
+// can't switch confirmations off and withdraw straight away
+const THRESHOLD_RELAX_DELAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// An owner's threshold for one currency. A change that loosens it waits in
+// `threshold` until `effective_at`, with `previous` still applying meanwhile.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredThreshold {
+    threshold: Option<u128>, // None once removed
+    previous: Option<u128>,
+    effective_at: u64,
+}
+
+impl StoredThreshold {
+    fn in_force(&self, now: u64) -> Option<u128> {
+        if self.effective_at <= now {
+            self.threshold
+        } else {
+            self.previous
+        }
+    }
+}
+
+impl Storable for StoredThreshold {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode StoredThreshold"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode StoredThreshold")
+    }
+}
+
+impl BoundedStorable for StoredThreshold {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum PendingWithdrawalStatus {
+    Pending,
+    Confirmed,
+    Cancelled, // by the owner, or by a recovery of their account
+    Expired,   // not confirmed within PENDING_WITHDRAWAL_TTL_NANOS
+}
+
+// A withdrawal above the owner's threshold. The amount stays locked until it
+// is confirmed, cancelled or expires.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PendingWithdrawal {
+    id: u64,
+    owner: Principal,
+    currency: String,
+    amount: u128,
+    created_at: u64,
+    confirmable_at: u64,
+    expires_at: u64,
+    status: PendingWithdrawalStatus,
+    closed_at: Option<u64>,
+}
+
+impl Storable for PendingWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode PendingWithdrawal"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode PendingWithdrawal")
+    }
+}
+
+impl BoundedStorable for PendingWithdrawal {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (owner, currency) -> confirmation threshold; currencies without an
+    // entry withdraw immediately
+    static CONFIRMATION_THRESHOLDS: RefCell<StableBTreeMap<(StorablePrincipal, CurrencySymbol), StoredThreshold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    // Every withdrawal that needed a confirmation, keyed by id starting at 1
+    static PENDING_WITHDRAWALS: RefCell<StableBTreeMap<u64, PendingWithdrawal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+
+    // (owner, withdrawal id)
+    static PENDING_WITHDRAWALS_BY_OWNER: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+
+    // (expires_at, withdrawal id) for pending withdrawals only, walked by the sweep
+    static PENDING_WITHDRAWALS_BY_EXPIRY: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum WithdrawOutcome {
+    Completed,
+    PendingConfirmation(PendingWithdrawal), // confirm_withdrawal it between confirmable_at and expires_at
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ThresholdChange {
+    threshold: Option<u128>,
+    effective_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ConfirmationThreshold {
+    currency: String,
+    threshold: Option<u128>,                 // applying now
+    pending_change: Option<ThresholdChange>, // a raise or removal still waiting to apply
+}
+
+// Withdrawals of more than `threshold` wait for confirm_withdrawal; None
+// removes the threshold. Lowering or adding one applies immediately, raising
+// or removing it only after THRESHOLD_RELAX_DELAY_NANOS.
+#[ic_cdk::update]
+fn set_withdrawal_confirmation_threshold(currency: String, threshold: Option<u128>) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let currency = normalize_currency(&currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+
+    let key = (StorablePrincipal::from(caller()), CurrencySymbol(currency));
+    let now = time();
+    let current = CONFIRMATION_THRESHOLDS
+        .with(|thresholds| thresholds.borrow().get(&key))
+        .and_then(|stored| stored.in_force(now));
+    let tightens = match (threshold, current) {
+        (Some(threshold), Some(current)) => threshold <= current,
+        (Some(_), None) => true,
+        (None, _) => current.is_none(),
+    };
+    let stored = if tightens {
+        StoredThreshold {
+            threshold,
+            previous: None,
+            effective_at: now,
+        }
+    } else {
+        StoredThreshold {
+            threshold,
+            previous: current,
+            effective_at: now.saturating_add(THRESHOLD_RELAX_DELAY_NANOS),
+        }
+    };
+    CONFIRMATION_THRESHOLDS.with(|thresholds| {
+        let mut thresholds_borrowed = thresholds.borrow_mut();
+        if stored.threshold.is_none() && stored.previous.is_none() {
+            thresholds_borrowed.remove(&key);
+        } else {
+            thresholds_borrowed.insert(key, stored);
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_withdrawal_confirmation_thresholds() -> Vec<ConfirmationThreshold> {
+    let owner = StorablePrincipal::from(caller());
+    let now = time();
+    CONFIRMATION_THRESHOLDS.with(|thresholds| {
+        thresholds
+            .borrow()
+            .range((owner.clone(), CurrencySymbol::default())..)
+            .take_while(|((stored_owner, _), _)| *stored_owner == owner)
+            .map(|((_, currency), stored)| ConfirmationThreshold {
+                currency: currency.0,
+                threshold: stored.in_force(now),
+                pending_change: (stored.effective_at > now).then_some(ThresholdChange {
+                    threshold: stored.threshold,
+                    effective_at: stored.effective_at,
+                }),
+            })
+            .collect()
+    })
+}
+
+// Completes a pending withdrawal once the confirmation delay has passed. The
+// daily withdrawal limit is checked again, since it may have filled up since
+// the request.
+#[ic_cdk::update]
+fn confirm_withdrawal(withdrawal_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    let withdrawal = find_own_pending(withdrawal_id)?;
+    let now = time();
+    if withdrawal.expires_at <= now {
+        close_withdrawal(withdrawal, PendingWithdrawalStatus::Expired);
+        return Err(Error::WithdrawalNotPending);
+    }
+    if now < withdrawal.confirmable_at {
+        return Err(Error::WithdrawalNotYetConfirmable { confirmable_at: withdrawal.confirmable_at });
+    }
+
+    let owner_principal = StorablePrincipal::from(withdrawal.owner);
+    withdrawals::check_withdrawal_limit(&owner_principal, &withdrawal.currency, withdrawal.amount)?;
+    let mut changes = BalanceChanges::new();
+    changes
+        .release(&owner_principal, &withdrawal.currency, withdrawal.amount)
+        .expect("Pending withdrawal was not locked");
+    changes.commit();
+
+    withdrawals::record_withdrawal(&owner_principal, &withdrawal.currency, withdrawal.amount);
+    record_transaction(
+        TransactionKind::Withdrawal,
+        Some(withdrawal.owner),
+        None,
+        &withdrawal.currency,
+        withdrawal.amount,
+        None,
+    );
+    record_event(EventKind::Withdrawal {
+        principal: withdrawal.owner,
+        currency: withdrawal.currency.clone(),
+        amount: withdrawal.amount,
+    });
+    finish_withdrawal(withdrawal, PendingWithdrawalStatus::Confirmed);
+    Ok(())
+}
+
+// Returns the locked amount to the caller's available balance
+#[ic_cdk::update]
+fn cancel_pending_withdrawal(withdrawal_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let withdrawal = find_own_pending(withdrawal_id)?;
+    close_withdrawal(withdrawal, PendingWithdrawalStatus::Cancelled);
+    Ok(())
+}
+
+// The caller's withdrawals that needed a confirmation, newest first
+#[ic_cdk::query]
+fn list_my_pending_withdrawals() -> Vec<PendingWithdrawal> {
+    let owner = StorablePrincipal::from(caller());
+    let ids: Vec<u64> = PENDING_WITHDRAWALS_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .range((owner.clone(), 0)..=(owner.clone(), u64::MAX))
+            .map(|((_, withdrawal_id), _)| withdrawal_id)
+            .collect()
+    });
+    ids.into_iter()
+        .rev()
+        .filter_map(|withdrawal_id| PENDING_WITHDRAWALS.with(|withdrawals| withdrawals.borrow().get(&withdrawal_id)))
+        .collect()
+}
+
+// Whether withdraw has to hold `amount` for a confirmation
+pub(crate) fn needs_confirmation(owner: &StorablePrincipal, currency: &str, amount: u128) -> bool {
+    let key = (owner.clone(), CurrencySymbol(currency.to_string()));
+    CONFIRMATION_THRESHOLDS
+        .with(|thresholds| thresholds.borrow().get(&key))
+        .and_then(|stored| stored.in_force(time()))
+        .is_some_and(|threshold| amount > threshold)
+}
+
+// Called by withdraw for an amount above the owner's threshold: locks it and
+// stores the pending withdrawal
+pub(crate) fn hold_withdrawal(owner: Principal, currency: String, amount: u128) -> Result<PendingWithdrawal, Error> {
+    let owner_principal = StorablePrincipal::from(owner);
+    require_available(&owner_principal, &currency, amount)?;
+    let mut changes = BalanceChanges::new();
+    changes.lock(&owner_principal, &currency, amount)?;
+    changes.commit();
+
+    let now = time();
+    let id = PENDING_WITHDRAWALS.with(|withdrawals| {
+        withdrawals.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1)
+    });
+    let withdrawal = PendingWithdrawal {
+        id,
+        owner,
+        currency,
+        amount,
+        created_at: now,
+        confirmable_at: now.saturating_add(CONFIRMATION_DELAY_NANOS),
+        expires_at: now.saturating_add(PENDING_WITHDRAWAL_TTL_NANOS),
+        status: PendingWithdrawalStatus::Pending,
+        closed_at: None,
+    };
+    PENDING_WITHDRAWALS_BY_OWNER.with(|index| index.borrow_mut().insert((owner_principal, id), ()));
+    PENDING_WITHDRAWALS_BY_EXPIRY.with(|index| index.borrow_mut().insert((withdrawal.expires_at, id), ()));
+    PENDING_WITHDRAWALS.with(|withdrawals| withdrawals.borrow_mut().insert(id, withdrawal.clone()));
+    Ok(withdrawal)
+}
+
+// Returns the funds of pending withdrawals past their expiry, earliest first
+pub(crate) fn expire_pending_withdrawals() {
+    let now = time();
+    let due: Vec<u64> = PENDING_WITHDRAWALS_BY_EXPIRY.with(|index| {
+        index
+            .borrow()
+            .range(..(now, u64::MAX))
+            .take(PENDING_WITHDRAWAL_SWEEP_BATCH_SIZE)
+            .map(|((_, withdrawal_id), _)| withdrawal_id)
+            .collect()
+    });
+    for withdrawal_id in due {
+        if let Some(withdrawal) = PENDING_WITHDRAWALS.with(|withdrawals| withdrawals.borrow().get(&withdrawal_id)) {
+            close_withdrawal(withdrawal, PendingWithdrawalStatus::Expired);
+        }
+    }
+}
+
+// Called by claim_account before it moves the owner's balances, so the
+// amounts come back to available and move with the rest
+pub(crate) fn cancel_all_for(owner: &StorablePrincipal) {
+    let ids: Vec<u64> = PENDING_WITHDRAWALS_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .range((owner.clone(), 0)..=(owner.clone(), u64::MAX))
+            .map(|((_, withdrawal_id), _)| withdrawal_id)
+            .collect()
+    });
+    for withdrawal_id in ids {
+        let Some(withdrawal) = PENDING_WITHDRAWALS.with(|withdrawals| withdrawals.borrow().get(&withdrawal_id)) else {
+            continue;
+        };
+        if withdrawal.status == PendingWithdrawalStatus::Pending {
+            close_withdrawal(withdrawal, PendingWithdrawalStatus::Cancelled);
+        }
+    }
+}
+
+// Adds what pending withdrawals hold locked, for rebuilding locked balances
+pub(crate) fn add_escrow(locked_by_owner: &mut BTreeMap<StorablePrincipal, BTreeMap<String, u128>>) {
+    PENDING_WITHDRAWALS_BY_EXPIRY.with(|index| {
+        for ((_, withdrawal_id), _) in index.borrow().iter() {
+            let Some(withdrawal) = PENDING_WITHDRAWALS.with(|withdrawals| withdrawals.borrow().get(&withdrawal_id))
+            else {
+                continue;
+            };
+            let locked = locked_by_owner.entry(StorablePrincipal::from(withdrawal.owner)).or_default();
+            add_to_bucket(locked, &withdrawal.currency, withdrawal.amount)
+                .expect("Escrowed amounts overflowed while rebuilding locked balances");
+        }
+    });
+}
+
+// Up to `limit` pending withdrawals after the id `after`, for create_export
+pub(crate) fn export_pending_withdrawals(after: Option<u64>, limit: usize) -> Vec<(u64, PendingWithdrawal)> {
+    PENDING_WITHDRAWALS.with(|withdrawals| entries_after(&withdrawals.borrow(), after, limit))
+}
+
+// Replaces every pending withdrawal with those of an imported snapshot,
+// listing the ones still pending for expiry again
+pub(crate) fn restore_pending_withdrawals(restored: Vec<PendingWithdrawal>) {
+    PENDING_WITHDRAWALS_BY_OWNER.with(|index| clear_map(&mut index.borrow_mut()));
+    PENDING_WITHDRAWALS_BY_EXPIRY.with(|index| clear_map(&mut index.borrow_mut()));
+    PENDING_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals_borrowed = withdrawals.borrow_mut();
+        clear_map(&mut withdrawals_borrowed);
+        for withdrawal in restored {
+            let owner = StorablePrincipal::from(withdrawal.owner);
+            PENDING_WITHDRAWALS_BY_OWNER.with(|index| index.borrow_mut().insert((owner, withdrawal.id), ()));
+            if withdrawal.status == PendingWithdrawalStatus::Pending {
+                PENDING_WITHDRAWALS_BY_EXPIRY
+                    .with(|index| index.borrow_mut().insert((withdrawal.expires_at, withdrawal.id), ()));
+            }
+            withdrawals_borrowed.insert(withdrawal.id, withdrawal);
+        }
+    });
+}
+
+fn find_own_pending(withdrawal_id: u64) -> Result<PendingWithdrawal, Error> {
+    let withdrawal = PENDING_WITHDRAWALS
+        .with(|withdrawals| withdrawals.borrow().get(&withdrawal_id))
+        .filter(|withdrawal| withdrawal.owner == caller())
+        .ok_or(Error::WithdrawalNotFound)?;
+    if withdrawal.status != PendingWithdrawalStatus::Pending {
+        return Err(Error::WithdrawalNotPending);
+    }
+    Ok(withdrawal)
+}
+
+// Unlocks the amount and stores the final status
+fn close_withdrawal(withdrawal: PendingWithdrawal, status: PendingWithdrawalStatus) {
+    let mut changes = BalanceChanges::new();
+    changes
+        .unlock(&StorablePrincipal::from(withdrawal.owner), &withdrawal.currency, withdrawal.amount)
+        .expect("Pending withdrawal was not locked");
+    changes.commit();
+    finish_withdrawal(withdrawal, status);
+}
+
+fn finish_withdrawal(mut withdrawal: PendingWithdrawal, status: PendingWithdrawalStatus) {
+    PENDING_WITHDRAWALS_BY_EXPIRY.with(|index| index.borrow_mut().remove(&(withdrawal.expires_at, withdrawal.id)));
+    withdrawal.status = status;
+    withdrawal.closed_at = Some(time());
+    PENDING_WITHDRAWALS.with(|withdrawals| withdrawals.borrow_mut().insert(withdrawal.id, withdrawal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_confirmation_calls_are_rejected() {
+        assert_eq!(set_withdrawal_confirmation_threshold("USD".to_string(), Some(100)), Err(Error::AnonymousNotAllowed));
+        assert_eq!(confirm_withdrawal(1), Err(Error::AnonymousNotAllowed));
+        assert_eq!(cancel_pending_withdrawal(1), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn a_changed_threshold_applies_the_previous_one_until_it_takes_effect() {
+        let raised = StoredThreshold { threshold: Some(500), previous: Some(100), effective_at: 1_000 };
+        assert_eq!(raised.in_force(999), Some(100));
+        assert_eq!(raised.in_force(1_000), Some(500));
+
+        let removed = StoredThreshold { threshold: None, previous: Some(100), effective_at: 1_000 };
+        assert_eq!(removed.in_force(999), Some(100));
+        assert_eq!(removed.in_force(1_000), None);
+    }
+}
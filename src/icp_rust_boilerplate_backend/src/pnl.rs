@@ -0,0 +1,154 @@
+use crate::amounts::mul_div;
+use crate::candles::stored_pair;
+use crate::{CurrencyPair, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::caller;
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// What a principal traded on a pair in a day. Pairs are kept in one direction
+// as candles are (see candles::stored_pair): buying means receiving the first
+// currency, the base, for the second, the quote. Amounts are net of fees.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DailyTrades {
+    bought: u128,         // base received
+    quote_spent: u128,    // quote paid for it
+    sold: u128,           // base delivered
+    quote_received: u128, // quote received for it
+}
+
+impl DailyTrades {
+    fn add(&mut self, other: &DailyTrades) {
+        self.bought = self.bought.saturating_add(other.bought);
+        self.quote_spent = self.quote_spent.saturating_add(other.quote_spent);
+        self.sold = self.sold.saturating_add(other.sold);
+        self.quote_received = self.quote_received.saturating_add(other.quote_received);
+    }
+}
+
+impl Storable for DailyTrades {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode DailyTrades"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode DailyTrades")
+    }
+}
+
+impl BoundedStorable for DailyTrades {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// (principal, (days since epoch, pair))
+type DailyTradesKey = (StorablePrincipal, (u64, CurrencyPair));
+
+thread_local! {
+    // What each principal traded on each pair per day, added to on every fill
+    static DAILY_TRADES: RefCell<StableBTreeMap<DailyTradesKey, DailyTrades, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PairPnl {
+    base_currency: String,
+    quote_currency: String,
+    bought: u128,
+    quote_spent: u128,
+    sold: u128,
+    quote_received: u128,
+    average_buy_price: Option<f64>,  // quote per base, None without buys
+    average_sell_price: Option<f64>, // quote per base, None without sells
+    // Quote gained on the base both bought and sold in the range, each side
+    // at its average price. Base bought and not sold, or sold and not bought,
+    // is left out.
+    realized_pnl: i128,
+}
+
+// The caller's trades per pair over the days from_ts to to_ts fall in, both
+// included. Each pair is reported once, with its currencies in alphabetical
+// order.
+#[ic_cdk::query]
+fn get_my_pnl(from_ts: u64, to_ts: u64) -> Vec<PairPnl> {
+    let principal = StorablePrincipal::from(caller());
+    let first_day = from_ts / NANOS_PER_DAY;
+    let last_day = to_ts / NANOS_PER_DAY;
+    let mut by_pair: BTreeMap<CurrencyPair, DailyTrades> = BTreeMap::new();
+    if first_day <= last_day {
+        DAILY_TRADES.with(|trades| {
+            let start = (principal.clone(), (first_day, CurrencyPair::default()));
+            let end = (principal.clone(), (last_day.saturating_add(1), CurrencyPair::default()));
+            for ((_, (_, pair)), day) in trades.borrow().range(start..end) {
+                by_pair.entry(pair).or_default().add(&day);
+            }
+        });
+    }
+
+    by_pair.into_iter().map(|(pair, trades)| pair_pnl(pair, trades)).collect()
+}
+
+// Called by settle_fill for each side of a fill, with what the principal gave
+// and got net of fees
+pub(crate) fn record_fill(
+    principal: &StorablePrincipal,
+    gave_currency: &str,
+    gave_amount: u128,
+    got_currency: &str,
+    got_amount: u128,
+) {
+    let (pair, inverted) = stored_pair(gave_currency, got_currency);
+    // The stored pair's base is got_currency when the direction is inverted
+    let fill = if inverted {
+        DailyTrades {
+            bought: got_amount,
+            quote_spent: gave_amount,
+            ..Default::default()
+        }
+    } else {
+        DailyTrades {
+            sold: gave_amount,
+            quote_received: got_amount,
+            ..Default::default()
+        }
+    };
+
+    let key = (principal.clone(), (time() / NANOS_PER_DAY, pair));
+    DAILY_TRADES.with(|trades| {
+        let mut trades_borrowed = trades.borrow_mut();
+        let mut day = trades_borrowed.get(&key).unwrap_or_default();
+        day.add(&fill);
+        trades_borrowed.insert(key, day);
+    });
+}
+
+fn pair_pnl(pair: CurrencyPair, trades: DailyTrades) -> PairPnl {
+    let average = |quote: u128, base: u128| (base > 0).then(|| quote as f64 / base as f64);
+    let matched = trades.bought.min(trades.sold);
+    let matched_received = mul_div(trades.quote_received, matched, trades.sold).unwrap_or(0);
+    let matched_spent = mul_div(trades.quote_spent, matched, trades.bought).unwrap_or(0);
+    let to_signed = |amount: u128| i128::try_from(amount).unwrap_or(i128::MAX);
+
+    PairPnl {
+        average_buy_price: average(trades.quote_spent, trades.bought),
+        average_sell_price: average(trades.quote_received, trades.sold),
+        realized_pnl: to_signed(matched_received).saturating_sub(to_signed(matched_spent)),
+        base_currency: pair.from_currency,
+        quote_currency: pair.to_currency,
+        bought: trades.bought,
+        quote_spent: trades.quote_spent,
+        sold: trades.sold,
+        quote_received: trades.quote_received,
+    }
+}
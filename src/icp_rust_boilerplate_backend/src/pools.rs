@@ -0,0 +1,612 @@
+use crate::admin::{self, require_admin};
+use crate::amounts::{cmp_products, mul_div, mul_div_ceil};
+use crate::currencies::{is_known_currency, is_valid_currency, normalize_currency, CurrencySymbol};
+use crate::snapshot::{clear_map, entries_after};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{allowlist, blacklist, rate_limit, BalanceChanges, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+
+// Share of every amount swapped in that stays in the pool for its LPs
+const POOL_FEE_BPS: u128 = 30;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+
+// Holds the reserves of every pool, so balance totals, certification and the
+// solvency checks see them like any other funds. A reserved principal (class
+// 0x7f), so no caller can ever act as it.
+const POOL_ACCOUNT_ID: &[u8] = b"liquidity-pools\x7f";
+
+// Currencies sorted, so a pair has one key whichever way it is named
+pub(crate) type PoolKey = (CurrencySymbol, CurrencySymbol);
+
+// A constant-product pool. Its reserves sit in the pool account, whose
+// balance in a currency is the sum of the reserves of every pool holding it.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Pool {
+    pub(crate) currency_a: String, // sorts before currency_b
+    pub(crate) currency_b: String,
+    pub(crate) reserve_a: u128,
+    pub(crate) reserve_b: u128,
+    pub(crate) total_shares: u128,
+    pub(crate) fee_bps: u128,
+    created_at: u64,
+}
+
+impl Storable for Pool {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Pool"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Pool")
+    }
+}
+
+impl BoundedStorable for Pool {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static POOLS: RefCell<StableBTreeMap<PoolKey, Pool, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))
+    ));
+
+    // (pool, provider) -> LP shares held, only while non-zero
+    static LP_SHARES: RefCell<StableBTreeMap<(PoolKey, StorablePrincipal), u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+}
+
+// The two currencies of a pool, in either order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PoolPair {
+    currency_a: String,
+    currency_b: String,
+}
+
+// What add_liquidity took or remove_liquidity paid out, in the pair's order
+// as the caller named it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LiquidityReceipt {
+    amount_a: u128,
+    amount_b: u128,
+    shares: u128,      // minted or burned
+    shares_held: u128, // by the caller afterwards
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PoolSwapReceipt {
+    from_currency: String,
+    to_currency: String,
+    amount_in: u128,
+    amount_out: u128,
+    fee: u128, // part of amount_in left in the pool for its LPs
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LiquidityPosition {
+    pool: Pool,
+    shares: u128,
+}
+
+// One provider's shares in one pool, as exported
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LpHolding {
+    pool: PoolPair,
+    provider: Principal,
+    shares: u128,
+}
+
+// The output of a swap into a pool. quote_swap and swap_via_pool both come
+// through here, so a quote is what the swap would pay.
+pub(crate) struct PoolSwap {
+    pub(crate) amount_out: u128,
+    pub(crate) fee: u128,
+    pub(crate) reserve_in: u128,
+    pub(crate) reserve_out: u128,
+}
+
+impl Pool {
+    // (reserve of from_currency, reserve of the other)
+    pub(crate) fn reserves_from(&self, from_currency: &str) -> (u128, u128) {
+        if from_currency == self.currency_a {
+            (self.reserve_a, self.reserve_b)
+        } else {
+            (self.reserve_b, self.reserve_a)
+        }
+    }
+
+    fn set_reserves_from(&mut self, from_currency: &str, reserve_in: u128, reserve_out: u128) {
+        if from_currency == self.currency_a {
+            (self.reserve_a, self.reserve_b) = (reserve_in, reserve_out);
+        } else {
+            (self.reserve_b, self.reserve_a) = (reserve_in, reserve_out);
+        }
+    }
+
+    // Constant product on what is left of amount_in after the fee, rounded
+    // down so the pool never pays out more than the curve allows
+    pub(crate) fn swap_output(&self, from_currency: &str, amount_in: u128) -> Result<PoolSwap, Error> {
+        let (reserve_in, reserve_out) = self.reserves_from(from_currency);
+        if reserve_in == 0 || reserve_out == 0 {
+            return Err(Error::InsufficientLiquidity);
+        }
+        let fee = mul_div_ceil(amount_in, self.fee_bps, BPS_DENOMINATOR).ok_or(Error::Overflow)?;
+        let amount_in_after_fee = amount_in - fee;
+        let grown_reserve = reserve_in.checked_add(amount_in_after_fee).ok_or(Error::Overflow)?;
+        let amount_out = mul_div(reserve_out, amount_in_after_fee, grown_reserve).ok_or(Error::Overflow)?;
+        Ok(PoolSwap {
+            amount_out,
+            fee,
+            reserve_in,
+            reserve_out,
+        })
+    }
+}
+
+// Opens an empty pool for the pair; the first add_liquidity sets its price
+#[ic_cdk::update]
+fn create_pool(currency_a: String, currency_b: String) -> Result<Pool, Error> {
+    require_admin()?;
+    let key = pool_key(&currency_a, &currency_b)?;
+    if POOLS.with(|pools| pools.borrow().contains_key(&key)) {
+        return Err(Error::PoolAlreadyExists);
+    }
+    let pool = Pool {
+        currency_a: key.0 .0.clone(),
+        currency_b: key.1 .0.clone(),
+        reserve_a: 0,
+        reserve_b: 0,
+        total_shares: 0,
+        fee_bps: POOL_FEE_BPS,
+        created_at: time(),
+    };
+    require_enabled(&pool)?;
+    POOLS.with(|pools| pools.borrow_mut().insert(key, pool.clone()));
+    Ok(pool)
+}
+
+#[ic_cdk::query]
+fn get_pool(pair: PoolPair) -> Option<Pool> {
+    let key = pool_key(&pair.currency_a, &pair.currency_b).ok()?;
+    POOLS.with(|pools| pools.borrow().get(&key))
+}
+
+#[ic_cdk::query]
+fn list_pools() -> Vec<Pool> {
+    POOLS.with(|pools| pools.borrow().iter().map(|(_, pool)| pool).collect())
+}
+
+#[ic_cdk::query]
+fn get_my_liquidity() -> Vec<LiquidityPosition> {
+    let provider = StorablePrincipal::from(caller());
+    LP_SHARES.with(|shares| {
+        shares
+            .borrow()
+            .iter()
+            .filter(|((_, holder), _)| *holder == provider)
+            .filter_map(|((key, _), held)| {
+                POOLS.with(|pools| pools.borrow().get(&key)).map(|pool| LiquidityPosition { pool, shares: held })
+            })
+            .collect()
+    })
+}
+
+// Deposits up to amount_a and amount_b into the pool for LP shares. The first
+// deposit into an empty pool sets its price and takes both amounts whole,
+// minting amount_a shares; later ones take as much of each as keeps the
+// current price and mint shares in proportion.
+#[ic_cdk::update]
+fn add_liquidity(pair: PoolPair, amount_a: u128, amount_b: u128) -> Result<LiquidityReceipt, Error> {
+    require_pool_access()?;
+    if amount_a == 0 || amount_b == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    let (key, mut pool) = find_pool(&pair)?;
+    require_enabled(&pool)?;
+    // Line the amounts up with the pool's sorted currencies
+    let swapped = normalize_currency(&pair.currency_a) != pool.currency_a;
+    let (offered_a, offered_b) = if swapped { (amount_b, amount_a) } else { (amount_a, amount_b) };
+
+    let (shares, taken_a, taken_b) = if pool.total_shares == 0 {
+        (offered_a, offered_a, offered_b)
+    } else {
+        let shares = mul_div(offered_a, pool.total_shares, pool.reserve_a)
+            .zip(mul_div(offered_b, pool.total_shares, pool.reserve_b))
+            .map(|(by_a, by_b)| by_a.min(by_b))
+            .ok_or(Error::Overflow)?;
+        if shares == 0 {
+            return Err(Error::InvalidAmount);
+        }
+        // Rounded up, so a deposit never dilutes the shares already issued
+        let taken_a = mul_div_ceil(shares, pool.reserve_a, pool.total_shares).ok_or(Error::Overflow)?;
+        let taken_b = mul_div_ceil(shares, pool.reserve_b, pool.total_shares).ok_or(Error::Overflow)?;
+        (shares, taken_a, taken_b)
+    };
+
+    let provider = StorablePrincipal::from(caller());
+    let mut changes = BalanceChanges::new();
+    changes.debit(&provider, &pool.currency_a, taken_a)?;
+    changes.debit(&provider, &pool.currency_b, taken_b)?;
+    changes.credit(&pool_account(), &pool.currency_a, taken_a)?;
+    changes.credit(&pool_account(), &pool.currency_b, taken_b)?;
+    pool.reserve_a = pool.reserve_a.checked_add(taken_a).ok_or(Error::Overflow)?;
+    pool.reserve_b = pool.reserve_b.checked_add(taken_b).ok_or(Error::Overflow)?;
+    pool.total_shares = pool.total_shares.checked_add(shares).ok_or(Error::Overflow)?;
+    let held = shares_of(&key, &provider).checked_add(shares).ok_or(Error::Overflow)?;
+    changes.commit();
+
+    set_shares(&key, &provider, held);
+    for (currency, amount) in [(&pool.currency_a, taken_a), (&pool.currency_b, taken_b)] {
+        record_transaction(
+            TransactionKind::LiquidityAdded,
+            Some(caller()),
+            Some(pool_account().into()),
+            currency,
+            amount,
+            None,
+        );
+    }
+    POOLS.with(|pools| pools.borrow_mut().insert(key, pool));
+    let (amount_a, amount_b) = if swapped { (taken_b, taken_a) } else { (taken_a, taken_b) };
+    Ok(LiquidityReceipt {
+        amount_a,
+        amount_b,
+        shares,
+        shares_held: held,
+    })
+}
+
+// Burns LP shares for their part of both reserves, rounded down
+#[ic_cdk::update]
+fn remove_liquidity(pair: PoolPair, shares: u128) -> Result<LiquidityReceipt, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    admin::require_trading_active()?;
+    if shares == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    let (key, mut pool) = find_pool(&pair)?;
+    let provider = StorablePrincipal::from(caller());
+    let held = shares_of(&key, &provider);
+    if shares > held {
+        return Err(Error::InsufficientShares { held });
+    }
+
+    let paid_a = mul_div(shares, pool.reserve_a, pool.total_shares).ok_or(Error::Overflow)?;
+    let paid_b = mul_div(shares, pool.reserve_b, pool.total_shares).ok_or(Error::Overflow)?;
+    let mut changes = BalanceChanges::new();
+    changes.debit(&pool_account(), &pool.currency_a, paid_a)?;
+    changes.debit(&pool_account(), &pool.currency_b, paid_b)?;
+    changes.credit(&provider, &pool.currency_a, paid_a)?;
+    changes.credit(&provider, &pool.currency_b, paid_b)?;
+    pool.reserve_a -= paid_a;
+    pool.reserve_b -= paid_b;
+    pool.total_shares -= shares;
+    changes.commit();
+
+    set_shares(&key, &provider, held - shares);
+    for (currency, amount) in [(&pool.currency_a, paid_a), (&pool.currency_b, paid_b)] {
+        record_transaction(
+            TransactionKind::LiquidityRemoved,
+            Some(pool_account().into()),
+            Some(caller()),
+            currency,
+            amount,
+            None,
+        );
+    }
+    let swapped = normalize_currency(&pair.currency_a) != pool.currency_a;
+    POOLS.with(|pools| pools.borrow_mut().insert(key, pool));
+    let (amount_a, amount_b) = if swapped { (paid_b, paid_a) } else { (paid_a, paid_b) };
+    Ok(LiquidityReceipt {
+        amount_a,
+        amount_b,
+        shares,
+        shares_held: held - shares,
+    })
+}
+
+// Swaps against the pair's pool instead of the order book, failing with
+// SlippageExceeded when it would pay out less than min_out
+#[ic_cdk::update]
+fn swap_via_pool(
+    from_currency: String,
+    to_currency: String,
+    amount_in: u128,
+    min_out: u128,
+) -> Result<PoolSwapReceipt, Error> {
+    require_pool_access()?;
+    if amount_in == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    let (key, mut pool) = find_pool(&PoolPair {
+        currency_a: from_currency.clone(),
+        currency_b: to_currency.clone(),
+    })?;
+    require_enabled(&pool)?;
+
+    let swap = pool.swap_output(&from_currency, amount_in)?;
+    if swap.amount_out == 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if swap.amount_out < min_out {
+        return Err(Error::SlippageExceeded);
+    }
+    let reserve_in = swap.reserve_in.checked_add(amount_in).ok_or(Error::Overflow)?;
+    let reserve_out = swap.reserve_out - swap.amount_out;
+    // Rounding and the fee only ever grow the product
+    assert!(
+        cmp_products(reserve_in, reserve_out, swap.reserve_in, swap.reserve_out) != Ordering::Less,
+        "Pool swap shrank the constant product"
+    );
+
+    let trader = StorablePrincipal::from(caller());
+    let mut changes = BalanceChanges::new();
+    changes.debit(&trader, &from_currency, amount_in)?;
+    changes.credit(&pool_account(), &from_currency, amount_in)?;
+    changes.debit(&pool_account(), &to_currency, swap.amount_out)?;
+    changes.credit(&trader, &to_currency, swap.amount_out)?;
+    changes.commit();
+    pool.set_reserves_from(&from_currency, reserve_in, reserve_out);
+    POOLS.with(|pools| pools.borrow_mut().insert(key, pool));
+
+    record_transaction(
+        TransactionKind::PoolSwap,
+        Some(caller()),
+        Some(pool_account().into()),
+        &from_currency,
+        amount_in,
+        None,
+    );
+    record_transaction(
+        TransactionKind::PoolSwap,
+        Some(pool_account().into()),
+        Some(caller()),
+        &to_currency,
+        swap.amount_out,
+        None,
+    );
+    Ok(PoolSwapReceipt {
+        from_currency,
+        to_currency,
+        amount_in,
+        amount_out: swap.amount_out,
+        fee: swap.fee,
+    })
+}
+
+// The pair's pool, if there is one
+pub(crate) fn pool_for(from_currency: &str, to_currency: &str) -> Option<Pool> {
+    let key = pool_key(from_currency, to_currency).ok()?;
+    POOLS.with(|pools| pools.borrow().get(&key))
+}
+
+// Up to `limit` pools after the key `after`, for create_export
+pub(crate) fn export_pools(after: Option<PoolKey>, limit: usize) -> Vec<(PoolKey, Pool)> {
+    POOLS.with(|pools| entries_after(&pools.borrow(), after, limit))
+}
+
+// Up to `limit` LP positions after the key `after`, for create_export
+pub(crate) fn export_lp_shares(
+    after: Option<(PoolKey, StorablePrincipal)>,
+    limit: usize,
+) -> Vec<((PoolKey, StorablePrincipal), LpHolding)> {
+    let page = LP_SHARES.with(|shares| entries_after(&shares.borrow(), after, limit));
+    page.into_iter()
+        .map(|((pool_key, provider), shares)| {
+            let holding = LpHolding {
+                pool: PoolPair {
+                    currency_a: pool_key.0 .0.clone(),
+                    currency_b: pool_key.1 .0.clone(),
+                },
+                provider: provider.0,
+                shares,
+            };
+            ((pool_key, provider), holding)
+        })
+        .collect()
+}
+
+// Replaces every pool and LP position with those of an imported snapshot.
+// The reserves themselves come back with the pool account's balances.
+pub(crate) fn restore_pools(pools: Vec<Pool>, holdings: Vec<LpHolding>) {
+    POOLS.with(|stored| {
+        let mut stored_borrowed = stored.borrow_mut();
+        clear_map(&mut stored_borrowed);
+        for pool in pools {
+            let key = (CurrencySymbol(pool.currency_a.clone()), CurrencySymbol(pool.currency_b.clone()));
+            stored_borrowed.insert(key, pool);
+        }
+    });
+    LP_SHARES.with(|stored| clear_map(&mut stored.borrow_mut()));
+    for holding in holdings {
+        let key = (CurrencySymbol(holding.pool.currency_a), CurrencySymbol(holding.pool.currency_b));
+        set_shares(&key, &StorablePrincipal::from(holding.provider), holding.shares);
+    }
+}
+
+pub(crate) fn pool_account() -> StorablePrincipal {
+    StorablePrincipal::from(Principal::from_slice(POOL_ACCOUNT_ID))
+}
+
+fn require_pool_access() -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()
+}
+
+fn pool_key(currency_a: &str, currency_b: &str) -> Result<PoolKey, Error> {
+    let currency_a = normalize_currency(currency_a);
+    let currency_b = normalize_currency(currency_b);
+    if currency_a == currency_b {
+        return Err(Error::SameCurrency);
+    }
+    for currency in [&currency_a, &currency_b] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    if currency_a < currency_b {
+        Ok((CurrencySymbol(currency_a), CurrencySymbol(currency_b)))
+    } else {
+        Ok((CurrencySymbol(currency_b), CurrencySymbol(currency_a)))
+    }
+}
+
+// Disabled currencies can still leave a pool through remove_liquidity, like
+// they can still be withdrawn
+fn require_enabled(pool: &Pool) -> Result<(), Error> {
+    for currency in [&pool.currency_a, &pool.currency_b] {
+        if !is_valid_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    Ok(())
+}
+
+fn find_pool(pair: &PoolPair) -> Result<(PoolKey, Pool), Error> {
+    let key = pool_key(&pair.currency_a, &pair.currency_b)?;
+    let pool = POOLS.with(|pools| pools.borrow().get(&key)).ok_or(Error::PoolNotFound)?;
+    Ok((key, pool))
+}
+
+fn shares_of(key: &PoolKey, provider: &StorablePrincipal) -> u128 {
+    LP_SHARES.with(|shares| shares.borrow().get(&(key.clone(), provider.clone()))).unwrap_or(0)
+}
+
+fn set_shares(key: &PoolKey, provider: &StorablePrincipal, held: u128) {
+    LP_SHARES.with(|shares| {
+        let mut shares_borrowed = shares.borrow_mut();
+        if held == 0 {
+            shares_borrowed.remove(&(key.clone(), provider.clone()));
+        } else {
+            shares_borrowed.insert((key.clone(), provider.clone()), held);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+
+    fn pool(reserve_a: u128, reserve_b: u128) -> Pool {
+        Pool {
+            currency_a: "EUR".to_string(),
+            currency_b: "USD".to_string(),
+            reserve_a,
+            reserve_b,
+            total_shares: reserve_a,
+            fee_bps: POOL_FEE_BPS,
+            created_at: 0,
+        }
+    }
+
+    fn eur_usd() -> PoolPair {
+        PoolPair {
+            currency_a: "EUR".to_string(),
+            currency_b: "USD".to_string(),
+        }
+    }
+
+    #[test]
+    fn anonymous_pool_calls_are_rejected() {
+        assert_eq!(add_liquidity(eur_usd(), 100, 100).err(), Some(Error::AnonymousNotAllowed));
+        assert_eq!(remove_liquidity(eur_usd(), 100).err(), Some(Error::AnonymousNotAllowed));
+        let swap = swap_via_pool("EUR".to_string(), "USD".to_string(), 100, 1);
+        assert_eq!(swap.err(), Some(Error::AnonymousNotAllowed));
+    }
+
+    fn product(a: u128, b: u128) -> Nat {
+        Nat::from(a) * Nat::from(b)
+    }
+
+    // Swaps `amount_in` EUR into the pool and checks the constant product:
+    // with the whole amount_in kept in the pool it never shrinks, and on
+    // the amount after the fee it grows by less than one unit of output,
+    // which is only rounding
+    fn assert_invariant(pool: &Pool, amount_in: u128) -> PoolSwap {
+        let swap = pool.swap_output("EUR", amount_in).unwrap();
+        let k = product(swap.reserve_in, swap.reserve_out);
+        let reserve_out_after = swap.reserve_out - swap.amount_out;
+
+        let with_fee = product(swap.reserve_in + amount_in, reserve_out_after);
+        assert!(with_fee >= k);
+        let after_fee_reserve_in = swap.reserve_in + amount_in - swap.fee;
+        let without_fee = product(after_fee_reserve_in, reserve_out_after);
+        assert!(without_fee >= k);
+        assert!(without_fee - k < after_fee_reserve_in);
+        swap
+    }
+
+    #[test]
+    fn a_swap_keeps_the_constant_product() {
+        let swap = assert_invariant(&pool(1_000_000, 2_000_000), 10_000);
+
+        // 9_970 after the 0.3% fee, 2_000_000 * 9_970 / 1_009_970 rounded down
+        assert_eq!((swap.fee, swap.amount_out), (30, 19_743));
+    }
+
+    #[test]
+    fn a_tiny_swap_pays_out_nothing_for_its_fee() {
+        let swap = assert_invariant(&pool(1_000_000, 2_000_000), 1);
+
+        assert_eq!((swap.fee, swap.amount_out), (1, 0));
+    }
+
+    #[test]
+    fn a_small_swap_rounds_in_favour_of_the_pool() {
+        let swap = assert_invariant(&pool(1_000_000, 1_000_000), 1_000);
+
+        // The curve gives 996.006 after the fee; the pool pays 996
+        assert_eq!((swap.fee, swap.amount_out), (3, 996));
+    }
+
+    #[test]
+    fn a_huge_swap_never_empties_the_pool() {
+        let reserve = 10u128.pow(36);
+        let swap = assert_invariant(&pool(reserve, reserve), reserve * 100);
+
+        assert!(swap.amount_out < reserve);
+        assert!(swap.amount_out > reserve / 100 * 99);
+    }
+
+    #[test]
+    fn swaps_in_either_direction_keep_the_constant_product() {
+        let pool = pool(5_000, 7 * 10u128.pow(30));
+        let swap = pool.swap_output("USD", 10u128.pow(28)).unwrap();
+        let reserve_out_after = swap.reserve_out - swap.amount_out;
+
+        assert_eq!((swap.reserve_in, swap.reserve_out), (pool.reserve_b, pool.reserve_a));
+        let k = product(pool.reserve_a, pool.reserve_b);
+        assert!(product(swap.reserve_in + 10u128.pow(28), reserve_out_after) >= k);
+        assert_eq!(swap.amount_out, 7);
+    }
+
+    #[test]
+    fn a_swap_past_the_largest_reserve_overflows() {
+        let reserve = u128::MAX / 2;
+
+        assert_eq!(pool(reserve, reserve).swap_output("EUR", u128::MAX).err(), Some(Error::Overflow));
+    }
+
+    #[test]
+    fn an_empty_pool_cannot_swap() {
+        assert_eq!(pool(0, 0).swap_output("EUR", 100).err(), Some(Error::InsufficientLiquidity));
+    }
+}
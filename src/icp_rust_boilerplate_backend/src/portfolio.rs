@@ -0,0 +1,67 @@
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::rates::fresh_known_rate;
+use crate::{balances_of, Error, StorablePrincipal};
+use ic_cdk::api::caller;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PortfolioEntry {
+    currency: String,
+    available: u128,
+    locked: u128,
+    rate: Option<f64>,   // units of the quote currency per unit, None without a fresh rate
+    value: Option<u128>, // of available and locked together
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PortfolioValue {
+    quote_currency: String,
+    entries: Vec<PortfolioEntry>,
+    total_value: u128,     // sum over the valued entries
+    unvalued: Vec<String>, // currencies held without a fresh rate to the quote currency
+}
+
+// Values every balance of the caller, locked amounts included, in `quote`.
+// Rates are applied to smallest units, the same way limit prices are checked
+// against them. Queries can't call XRC, so only rates already known are used
+// and XRC rates past the configured staleness are left out.
+#[ic_cdk::query]
+fn get_portfolio_value(quote: String) -> Result<PortfolioValue, Error> {
+    let quote_currency = normalize_currency(&quote);
+    if !is_known_currency(&quote_currency) {
+        return Err(Error::InvalidCurrency { provided: quote_currency });
+    }
+
+    let mut total_value: u128 = 0;
+    let mut unvalued = Vec::new();
+    let entries = balances_of(&StorablePrincipal::from(caller()), None)
+        .into_iter()
+        .filter(|balance| balance.total > 0)
+        .map(|balance| {
+            let rate = if balance.currency == quote_currency {
+                Some(1.0)
+            } else {
+                fresh_known_rate(&balance.currency, &quote_currency).map(|exchange_rate| exchange_rate.rate)
+            };
+            // Float to integer casts saturate, so a huge value reads as u128::MAX
+            let value = rate.map(|rate| (balance.total as f64 * rate) as u128);
+            match value {
+                Some(value) => total_value = total_value.saturating_add(value),
+                None => unvalued.push(balance.currency.clone()),
+            }
+            PortfolioEntry {
+                currency: balance.currency,
+                available: balance.available,
+                locked: balance.locked,
+                rate,
+                value,
+            }
+        })
+        .collect();
+
+    Ok(PortfolioValue {
+        quote_currency,
+        entries,
+        total_value,
+        unvalued,
+    })
+}
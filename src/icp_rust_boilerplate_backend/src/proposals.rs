@@ -0,0 +1,230 @@
+use crate::admin::{self, require_admin};
+use crate::{adjustments, fees, snapshot, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub(crate) const PROPOSAL_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How long a proposal waits for approvals
+const PROPOSAL_TTL_NANOS: u64 = 72 * 60 * 60 * 1_000_000_000;
+
+// Upper bound on proposals returned by a single page of list_proposals
+const MAX_PROPOSALS_PAGE_SIZE: usize = 100;
+
+// Kept short enough for the proposal to stay within its bound
+const MAX_PROPOSAL_ERROR_BYTES: usize = 256;
+
+// A sensitive admin call, held until enough admins approve it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) enum ProposedAction {
+    SetMakerFeeBps { maker_fee_bps: i16 },
+    SetTakerFeeBps { taker_fee_bps: u16 },
+    AdjustBalance {
+        principal: Principal,
+        currency: String,
+        delta: i128,
+        reason: String,
+    },
+    FinalizeImport { expected_hash: Vec<u8> },
+    TransferAdmin { new_admin: Principal },
+    AddAdmin { principal: Principal },
+    RemoveAdmin { principal: Principal },
+    SetApprovalThreshold { threshold: u8 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum ProposalStatus {
+    Pending,
+    Executed,
+    Failed { error: String }, // approved, but the action itself returned an error
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Proposal {
+    id: u64,
+    action: ProposedAction,
+    proposer: Principal,
+    approvals: Vec<Principal>, // in order, the proposer first
+    created_at: u64,
+    expires_at: u64,
+    status: ProposalStatus,
+    closed_at: Option<u64>,
+}
+
+impl Storable for Proposal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Proposal"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Proposal")
+    }
+}
+
+impl BoundedStorable for Proposal {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ProposalsPage {
+    proposals: Vec<Proposal>,
+    next_start_id: Option<u64>, // pass as start_id to fetch the next page, None once exhausted
+}
+
+thread_local! {
+    // Every proposal ever made, keyed by id starting at 1
+    static PROPOSALS: RefCell<StableBTreeMap<u64, Proposal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))
+    ));
+
+    // Ids of Pending proposals
+    static PENDING_PROPOSALS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100)))
+    ));
+}
+
+// Called by sensitive endpoints once their arguments are checked. Ok means
+// the call goes ahead: a threshold of 1 needs no other admin. Otherwise the
+// call becomes a proposal carrying the caller's approval and its id comes
+// back in ProposalPending.
+pub(crate) fn require_approval(action: ProposedAction) -> Result<(), Error> {
+    if admin::approval_threshold() <= 1 {
+        return Ok(());
+    }
+
+    let now = time();
+    let id = PROPOSALS.with(|proposals| proposals.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let proposal = Proposal {
+        id,
+        action,
+        proposer: caller(),
+        approvals: vec![caller()],
+        created_at: now,
+        expires_at: now.saturating_add(PROPOSAL_TTL_NANOS),
+        status: ProposalStatus::Pending,
+        closed_at: None,
+    };
+    PROPOSALS.with(|proposals| proposals.borrow_mut().insert(id, proposal));
+    PENDING_PROPOSALS.with(|pending| pending.borrow_mut().insert(id, ()));
+    Err(Error::ProposalPending { proposal_id: id })
+}
+
+// Adds the caller's approval. The action runs in this call once approvals
+// from current admins reach the threshold, with the caller as the admin
+// behind it; the proposal records whether it succeeded.
+#[ic_cdk::update]
+fn approve_proposal(proposal_id: u64) -> Result<Proposal, Error> {
+    require_admin()?;
+    let mut proposal = PROPOSALS.with(|proposals| proposals.borrow().get(&proposal_id)).ok_or(Error::ProposalNotFound)?;
+    if proposal.status != ProposalStatus::Pending {
+        return Err(Error::ProposalNotPending);
+    }
+    if proposal.expires_at <= time() {
+        close(&mut proposal, ProposalStatus::Expired);
+        return Err(Error::ProposalNotPending);
+    }
+    if proposal.approvals.contains(&caller()) {
+        return Err(Error::AlreadyApproved);
+    }
+
+    proposal.approvals.push(caller());
+    // Admins removed since approving no longer count
+    let approvals = proposal.approvals.iter().filter(|approver| admin::is_admin(approver)).count();
+    if approvals < usize::from(admin::approval_threshold()) {
+        PROPOSALS.with(|proposals| proposals.borrow_mut().insert(proposal_id, proposal.clone()));
+        return Ok(proposal);
+    }
+
+    let status = match execute(&proposal.action) {
+        Ok(()) => ProposalStatus::Executed,
+        Err(error) => {
+            let mut error = format!("{:?}", error);
+            error.truncate(MAX_PROPOSAL_ERROR_BYTES);
+            ProposalStatus::Failed { error }
+        }
+    };
+    close(&mut proposal, status);
+    Ok(proposal)
+}
+
+#[ic_cdk::query]
+fn get_proposal(proposal_id: u64) -> Result<Proposal, Error> {
+    require_admin()?;
+    PROPOSALS.with(|proposals| proposals.borrow().get(&proposal_id)).ok_or(Error::ProposalNotFound)
+}
+
+// Pages through every proposal in id order, closed ones included, for audit
+#[ic_cdk::query]
+fn list_proposals(start_id: u64, limit: u16) -> Result<ProposalsPage, Error> {
+    require_admin()?;
+    let limit = (limit as usize).min(MAX_PROPOSALS_PAGE_SIZE);
+    let mut proposals: Vec<Proposal> = PROPOSALS.with(|proposals| {
+        proposals
+            .borrow()
+            .range(start_id..)
+            .take(limit + 1)
+            .map(|(_, proposal)| proposal)
+            .collect()
+    });
+
+    // The extra entry only tells us where the next page starts
+    let next_start_id = if proposals.len() > limit {
+        proposals.pop().map(|proposal| proposal.id)
+    } else {
+        None
+    };
+
+    Ok(ProposalsPage {
+        proposals,
+        next_start_id,
+    })
+}
+
+// Closes Pending proposals whose 72 hours are up
+pub(crate) fn expire_proposals() {
+    let now = time();
+    let pending: Vec<u64> = PENDING_PROPOSALS.with(|pending| pending.borrow().iter().map(|(id, _)| id).collect());
+    for proposal_id in pending {
+        let Some(mut proposal) = PROPOSALS.with(|proposals| proposals.borrow().get(&proposal_id)) else {
+            PENDING_PROPOSALS.with(|pending| pending.borrow_mut().remove(&proposal_id));
+            continue;
+        };
+        if proposal.expires_at <= now {
+            close(&mut proposal, ProposalStatus::Expired);
+        }
+    }
+}
+
+fn execute(action: &ProposedAction) -> Result<(), Error> {
+    match action.clone() {
+        ProposedAction::SetMakerFeeBps { maker_fee_bps } => fees::apply_maker_fee_bps(maker_fee_bps),
+        ProposedAction::SetTakerFeeBps { taker_fee_bps } => fees::apply_taker_fee_bps(taker_fee_bps),
+        ProposedAction::AdjustBalance {
+            principal,
+            currency,
+            delta,
+            reason,
+        } => adjustments::apply_adjustment(principal, currency, delta, reason).map(|_| ()),
+        ProposedAction::FinalizeImport { expected_hash } => snapshot::apply_import(expected_hash),
+        ProposedAction::TransferAdmin { new_admin } => admin::set_admin(new_admin),
+        ProposedAction::AddAdmin { principal } => admin::apply_add_admin(principal),
+        ProposedAction::RemoveAdmin { principal } => admin::apply_remove_admin(principal),
+        ProposedAction::SetApprovalThreshold { threshold } => admin::apply_approval_threshold(threshold),
+    }
+}
+
+fn close(proposal: &mut Proposal, status: ProposalStatus) {
+    proposal.status = status;
+    proposal.closed_at = Some(time());
+    PENDING_PROPOSALS.with(|pending| pending.borrow_mut().remove(&proposal.id));
+    PROPOSALS.with(|proposals| proposals.borrow_mut().insert(proposal.id, proposal.clone()));
+}
@@ -0,0 +1,112 @@
+use crate::currencies::{is_valid_currency, normalize_currency};
+use crate::{matching, pools, Error, StorablePrincipal};
+use ic_cdk::api::caller;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) enum SwapQuote {
+    Quoted(QuoteDetails),
+    InsufficientLiquidity { max_fillable: u128 }, // most of amount_in the route can take
+}
+
+// Prices are units of to_currency per unit of from_currency
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct QuoteDetails {
+    amount_in: u128,
+    amount_out: u128,        // what the caller would receive, every fee taken
+    effective_price: f64,    // amount_out / amount_in
+    reference_price: f64,    // the pool's mid price, or the best price on the book
+    price_impact_pct: f64,   // how far the price before fees falls short of reference_price
+    pool_fee: Option<u128>,  // in from_currency, left in the pool for its LPs
+    taker_fee: Option<u128>, // in to_currency, withheld from what the book fills
+}
+
+// One quote per route. The order book quote is what an immediate order
+// placed by the caller with no price limit would fill.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SwapQuotes {
+    pool: Option<SwapQuote>, // None when the pair has no pool
+    order_book: SwapQuote,
+}
+
+// Works the quotes out with the same functions swap_via_pool and the
+// matching engine settle with, so they only differ from the real thing when
+// the pool or the book moves before the swap
+#[ic_cdk::query]
+fn quote_swap(from_currency: String, to_currency: String, amount_in: u128) -> Result<SwapQuotes, Error> {
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    if from_currency == to_currency {
+        return Err(Error::SameCurrency);
+    }
+    for currency in [&from_currency, &to_currency] {
+        if !is_valid_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    if amount_in == 0 {
+        return Err(Error::InvalidAmount);
+    }
+
+    Ok(SwapQuotes {
+        pool: pools::pool_for(&from_currency, &to_currency).map(|pool| quote_pool(&pool, &from_currency, amount_in)),
+        order_book: quote_book(&from_currency, &to_currency, amount_in),
+    })
+}
+
+fn quote_pool(pool: &pools::Pool, from_currency: &str, amount_in: u128) -> SwapQuote {
+    let Ok(swap) = pool.swap_output(from_currency, amount_in) else {
+        // An empty pool takes nothing; otherwise the amount overflowed the
+        // reserve it would be added to
+        let (reserve_in, _) = pool.reserves_from(from_currency);
+        return SwapQuote::InsufficientLiquidity {
+            max_fillable: if reserve_in == 0 { 0 } else { u128::MAX - reserve_in },
+        };
+    };
+    let reference_price = ratio(swap.reserve_out, swap.reserve_in);
+    SwapQuote::Quoted(QuoteDetails {
+        amount_in,
+        amount_out: swap.amount_out,
+        effective_price: ratio(swap.amount_out, amount_in),
+        reference_price,
+        price_impact_pct: impact_pct(ratio(swap.amount_out, amount_in - swap.fee), reference_price),
+        pool_fee: Some(swap.fee),
+        taker_fee: None,
+    })
+}
+
+fn quote_book(from_currency: &str, to_currency: &str, amount_in: u128) -> SwapQuote {
+    let taker = StorablePrincipal::from(caller());
+    let quote = matching::quote_against_book(&taker, from_currency, to_currency, amount_in);
+    let Some((best_received, best_paid)) = quote.best_price else {
+        return SwapQuote::InsufficientLiquidity { max_fillable: 0 };
+    };
+    if quote.spent < amount_in {
+        return SwapQuote::InsufficientLiquidity { max_fillable: quote.spent };
+    }
+    let amount_out = quote.received - quote.taker_fee;
+    let reference_price = ratio(best_received, best_paid);
+    SwapQuote::Quoted(QuoteDetails {
+        amount_in,
+        amount_out,
+        effective_price: ratio(amount_out, amount_in),
+        reference_price,
+        price_impact_pct: impact_pct(ratio(quote.received, quote.spent), reference_price),
+        pool_fee: None,
+        taker_fee: Some(quote.taker_fee),
+    })
+}
+
+// Only for display; every amount is worked out exactly
+fn ratio(numerator: u128, denominator: u128) -> f64 {
+    if denominator == 0 {
+        return 0.0;
+    }
+    numerator as f64 / denominator as f64
+}
+
+fn impact_pct(price: f64, reference_price: f64) -> f64 {
+    if reference_price == 0.0 {
+        return 0.0;
+    }
+    ((1.0 - price / reference_price) * 100.0).max(0.0)
+}
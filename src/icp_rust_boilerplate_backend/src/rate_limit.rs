@@ -0,0 +1,327 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const RATE_WINDOW_NANOS: u64 = 60 * NANOS_PER_SEC;
+
+const DEFAULT_MAX_CALLS_PER_WINDOW: u64 = 30;
+
+pub(crate) const CALL_WINDOW_PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+pub(crate) enum CallLimit {
+    PerWindow(u64), // balance-mutating calls allowed in any 60 second window
+    Unlimited,
+}
+
+impl Storable for CallLimit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode CallLimit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode CallLimit")
+    }
+}
+
+impl BoundedStorable for CallLimit {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Calls counted in the current fixed window and the one before it. The
+// sliding window count weighs the previous window by how much of it still
+// overlaps the last 60 seconds, which needs no per-call timestamps.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug)]
+struct CallWindow {
+    window: u64, // fixed windows since epoch
+    count: u64,
+    previous_count: u64,
+}
+
+impl CallWindow {
+    // Moves the counts forward to `window`
+    fn roll(&mut self, window: u64) {
+        if self.window == window {
+            return;
+        }
+        self.previous_count = if self.window + 1 == window { self.count } else { 0 };
+        self.count = 0;
+        self.window = window;
+    }
+
+    // Calls in the 60 seconds before `now`, rounded up
+    fn sliding_count(&self, now: u64) -> u64 {
+        let remaining = (RATE_WINDOW_NANOS - now % RATE_WINDOW_NANOS) as u128;
+        let previous = (self.previous_count as u128 * remaining).div_ceil(RATE_WINDOW_NANOS as u128);
+        previous as u64 + self.count
+    }
+
+    // Seconds until the sliding count drops below `limit`, for a window
+    // already rolled to `now` and at the limit
+    fn retry_after_secs(&self, now: u64, limit: u64) -> u64 {
+        let elapsed = now % RATE_WINDOW_NANOS;
+        // Time into a window after which a previous window of `weight` calls
+        // counts for at most `budget` of them
+        let offset_for = |weight: u64, budget: u64| -> u64 {
+            let overlap = (budget as u128 * RATE_WINDOW_NANOS as u128 / weight as u128) as u64;
+            RATE_WINDOW_NANOS - overlap.min(RATE_WINDOW_NANOS)
+        };
+        let wait_nanos = if self.count < limit {
+            offset_for(self.previous_count, limit - self.count - 1).saturating_sub(elapsed)
+        } else {
+            // Only the next window can help, where this one becomes the previous
+            RATE_WINDOW_NANOS - elapsed + offset_for(self.count, limit - 1)
+        };
+        wait_nanos.div_ceil(NANOS_PER_SEC).max(1)
+    }
+}
+
+impl Storable for CallWindow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode CallWindow"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode CallWindow")
+    }
+}
+
+impl BoundedStorable for CallWindow {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Counted on every balance-mutating call, so kept on the heap and only
+    // written to stable memory around upgrades
+    static CALL_WINDOWS: RefCell<HashMap<Principal, CallWindow>> = RefCell::new(HashMap::new());
+
+    static MAX_CALLS_PER_WINDOW: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))), DEFAULT_MAX_CALLS_PER_WINDOW)
+            .expect("Cannot create the call rate limit")
+    );
+
+    // Market makers the admin allows a higher limit, or none at all
+    static CALL_LIMIT_OVERRIDES: RefCell<StableBTreeMap<StorablePrincipal, CallLimit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // CALL_WINDOWS as of the last pre_upgrade, emptied again by post_upgrade
+    static CALL_WINDOWS_BACKUP: RefCell<StableBTreeMap<StorablePrincipal, CallWindow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+}
+
+#[ic_cdk::update]
+fn set_max_calls_per_window(max_calls: u64) -> Result<(), Error> {
+    require_admin()?;
+    if max_calls == 0 {
+        return Err(Error::InvalidAmount);
+    }
+
+    MAX_CALLS_PER_WINDOW.with(|cell| cell.borrow_mut().set(max_calls))
+        .expect("Failed to store the call rate limit");
+    Ok(())
+}
+
+// Gives a principal its own limit; None puts it back on the default
+#[ic_cdk::update]
+fn set_call_limit_override(principal: Principal, limit: Option<CallLimit>) -> Result<(), Error> {
+    require_admin()?;
+    if matches!(limit, Some(CallLimit::PerWindow(0))) {
+        return Err(Error::InvalidAmount);
+    }
+
+    CALL_LIMIT_OVERRIDES.with(|overrides| {
+        let mut overrides_borrowed = overrides.borrow_mut();
+        match limit {
+            Some(limit) => overrides_borrowed.insert(StorablePrincipal::from(principal), limit),
+            None => overrides_borrowed.remove(&StorablePrincipal::from(principal)),
+        };
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_call_limit() -> CallLimit {
+    call_limit(&caller())
+}
+
+// Counts a balance-mutating call by the caller, or fails once it has made
+// as many in the last 60 seconds as its limit allows. Rejected calls aren't
+// counted, so retrying after retry_after_secs succeeds.
+pub(crate) fn check_call_rate() -> Result<(), Error> {
+    let principal = caller();
+    let limit = match call_limit(&principal) {
+        CallLimit::Unlimited => return Ok(()),
+        CallLimit::PerWindow(limit) => limit,
+    };
+    let now = time();
+
+    CALL_WINDOWS.with(|windows| count_call(windows.borrow_mut().entry(principal).or_default(), now, limit))
+}
+
+fn count_call(call_window: &mut CallWindow, now: u64, limit: u64) -> Result<(), Error> {
+    call_window.roll(now / RATE_WINDOW_NANOS);
+    if call_window.sliding_count(now) >= limit {
+        return Err(Error::RateLimited {
+            retry_after_secs: call_window.retry_after_secs(now, limit),
+        });
+    }
+    call_window.count += 1;
+    Ok(())
+}
+
+// Drops windows that no longer count towards any limit, so the map only
+// holds principals active in the last two minutes
+pub(crate) fn prune_idle_call_windows() {
+    let current_window = time() / RATE_WINDOW_NANOS;
+    CALL_WINDOWS.with(|windows| {
+        windows.borrow_mut().retain(|_, call_window| call_window.window + 1 >= current_window)
+    });
+}
+
+// Called from pre_upgrade, when the heap is about to be lost
+pub(crate) fn backup_call_windows() {
+    prune_idle_call_windows();
+    CALL_WINDOWS.with(|windows| {
+        CALL_WINDOWS_BACKUP.with(|backup| {
+            let mut backup_borrowed = backup.borrow_mut();
+            for (principal, call_window) in windows.borrow().iter() {
+                backup_borrowed.insert(StorablePrincipal::from(*principal), *call_window);
+            }
+        })
+    });
+}
+
+pub(crate) fn restore_call_windows() {
+    CALL_WINDOWS_BACKUP.with(|backup| {
+        let mut backup_borrowed = backup.borrow_mut();
+        let saved: Vec<(StorablePrincipal, CallWindow)> = backup_borrowed.iter().collect();
+        CALL_WINDOWS.with(|windows| {
+            let mut windows_borrowed = windows.borrow_mut();
+            for (principal, call_window) in saved {
+                backup_borrowed.remove(&principal);
+                windows_borrowed.insert(principal.into(), call_window);
+            }
+        });
+    });
+}
+
+fn call_limit(principal: &Principal) -> CallLimit {
+    CALL_LIMIT_OVERRIDES
+        .with(|overrides| overrides.borrow().get(&StorablePrincipal::from(*principal)))
+        .unwrap_or_else(|| CallLimit::PerWindow(MAX_CALLS_PER_WINDOW.with(|cell| *cell.borrow().get())))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const SEC: u64 = NANOS_PER_SEC;
+
+    // Lets endpoint tests get past check_call_rate, whose clock traps off the replica
+    pub(crate) fn exempt(principal: Principal) {
+        CALL_LIMIT_OVERRIDES
+            .with(|overrides| overrides.borrow_mut().insert(StorablePrincipal::from(principal), CallLimit::Unlimited));
+    }
+
+    fn retry_after(result: Result<(), Error>) -> Option<u64> {
+        match result {
+            Err(Error::RateLimited { retry_after_secs }) => Some(retry_after_secs),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn calls_past_the_limit_are_rejected_and_not_counted() {
+        let mut call_window = CallWindow::default();
+        for second in 0..3 {
+            assert_eq!(count_call(&mut call_window, second * SEC, 3), Ok(()));
+        }
+
+        assert!(retry_after(count_call(&mut call_window, 3 * SEC, 3)).is_some());
+        assert!(retry_after(count_call(&mut call_window, 4 * SEC, 3)).is_some());
+        assert_eq!(call_window.count, 3);
+    }
+
+    #[test]
+    fn the_previous_window_counts_in_full_at_the_boundary() {
+        let mut call_window = CallWindow::default();
+        for _ in 0..3 {
+            count_call(&mut call_window, 59 * SEC, 3).unwrap();
+        }
+
+        // 60s is the first instant of the next fixed window, all three calls
+        // still fall in the last 60 seconds
+        assert!(retry_after(count_call(&mut call_window, 60 * SEC, 3)).is_some());
+        assert_eq!((call_window.window, call_window.previous_count, call_window.count), (1, 3, 0));
+        assert_eq!(call_window.sliding_count(60 * SEC), 3);
+    }
+
+    #[test]
+    fn the_previous_window_fades_as_the_current_one_fills() {
+        let mut call_window = CallWindow { window: 1, count: 0, previous_count: 30 };
+
+        assert_eq!(call_window.sliding_count(60 * SEC), 30);
+        assert_eq!(call_window.sliding_count(90 * SEC), 15);
+        assert_eq!(call_window.sliding_count(120 * SEC - 1), 1);
+        call_window.roll(2);
+        assert_eq!(call_window.sliding_count(120 * SEC), 0);
+    }
+
+    #[test]
+    fn an_idle_window_forgets_both_counts() {
+        let mut call_window = CallWindow { window: 1, count: 5, previous_count: 7 };
+
+        call_window.roll(2);
+        assert_eq!((call_window.previous_count, call_window.count), (5, 0));
+        call_window.roll(4);
+        assert_eq!((call_window.previous_count, call_window.count), (0, 0));
+    }
+
+    #[test]
+    fn retrying_after_retry_after_secs_succeeds() {
+        let mut call_window = CallWindow::default();
+        for second in 0..3 {
+            count_call(&mut call_window, second * SEC, 3).unwrap();
+        }
+
+        // Three calls at the start of the first window weigh 2 of them 20s
+        // into the next, 80s after the start
+        let now = 3 * SEC;
+        let retry_after_secs = retry_after(count_call(&mut call_window, now, 3)).unwrap();
+        assert_eq!(retry_after_secs, 77);
+
+        let mut too_early = call_window;
+        assert!(retry_after(count_call(&mut too_early, now + (retry_after_secs - 1) * SEC, 3)).is_some());
+        assert_eq!(count_call(&mut call_window, now + retry_after_secs * SEC, 3), Ok(()));
+    }
+
+    #[test]
+    fn a_retry_within_the_same_window_waits_for_the_previous_one_to_fade() {
+        let mut call_window = CallWindow { window: 1, count: 1, previous_count: 4 };
+
+        // 30s in, the previous window still weighs 2 calls, together 3
+        let now = 90 * SEC;
+        let retry_after_secs = retry_after(count_call(&mut call_window, now, 3)).unwrap();
+        assert_eq!(retry_after_secs, 15);
+        assert_eq!(count_call(&mut call_window, now + retry_after_secs * SEC, 3), Ok(()));
+    }
+}
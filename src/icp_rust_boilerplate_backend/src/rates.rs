@@ -0,0 +1,299 @@
+use crate::admin::require_admin;
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::{CurrencyPair, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::call_with_payment;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Exchange rate canister on the IC mainnet
+const XRC_CANISTER_ID: &str = "uxrrr-q7777-77774-qaaaq-cai";
+
+// Cycles attached to each XRC request; the unused part is refunded
+const XRC_CALL_CYCLES: u64 = 1_000_000_000;
+
+// Symbols priced by XRC as cryptocurrencies, everything else is treated as fiat
+const CRYPTO_SYMBOLS: &[&str] = &["BTC", "ETH", "ICP"];
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ExchangeRate {
+    pub(crate) rate: f64,       // units of to_currency per unit of from_currency
+    pub(crate) updated_at: u64, // nanoseconds since epoch
+}
+
+impl Storable for ExchangeRate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode ExchangeRate"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode ExchangeRate")
+    }
+}
+
+impl BoundedStorable for ExchangeRate {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct RateConfig {
+    cache_ttl_secs: u64,     // how long a rate fetched from XRC is reused
+    max_staleness_secs: u64, // XRC rates older than this are refused
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        RateConfig {
+            cache_ttl_secs: 60,
+            // Forex sources don't publish over weekends
+            max_staleness_secs: 3 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Storable for RateConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode RateConfig"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode RateConfig")
+    }
+}
+
+thread_local! {
+    // Rates published by set_rate, these take precedence over XRC
+    static EXCHANGE_RATES: RefCell<StableBTreeMap<CurrencyPair, ExchangeRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static RATE_CONFIG: RefCell<Cell<RateConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), RateConfig::default())
+            .expect("Cannot create the rate config")
+    );
+
+    // Last rate fetched from XRC per pair, updated_at is the time of the fetch
+    static XRC_RATE_CACHE: RefCell<StableBTreeMap<CurrencyPair, ExchangeRate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum XrcAssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct XrcAsset {
+    symbol: String,
+    class: XrcAssetClass,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct XrcGetExchangeRateRequest {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct XrcExchangeRateMetadata {
+    decimals: u32,
+    base_asset_num_queried_sources: u64,
+    base_asset_num_received_rates: u64,
+    quote_asset_num_queried_sources: u64,
+    quote_asset_num_received_rates: u64,
+    standard_deviation: u64,
+    forex_timestamp: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct XrcExchangeRate {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: u64, // seconds since epoch
+    rate: u64,      // scaled by 10^metadata.decimals
+    metadata: XrcExchangeRateMetadata,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct XrcOtherError {
+    code: u32,
+    description: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum XrcExchangeRateError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other(XrcOtherError),
+}
+
+#[ic_cdk::update]
+fn set_rate(from_currency: String, to_currency: String, rate: f64) -> Result<(), Error> {
+    require_admin()?;
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+    if from_currency == to_currency {
+        return Err(Error::SameCurrency);
+    }
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(Error::InvalidPrice);
+    }
+
+    let pair = CurrencyPair::new(&from_currency, &to_currency);
+    let exchange_rate = ExchangeRate { rate, updated_at: time() };
+    EXCHANGE_RATES.with(|rates| rates.borrow_mut().insert(pair, exchange_rate));
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_rate_config(config: RateConfig) -> Result<(), Error> {
+    require_admin()?;
+
+    RATE_CONFIG.with(|rate_config| rate_config.borrow_mut().set(config))
+        .map_err(|_| Error::InvalidRateConfig)?;
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_rate_config() -> RateConfig {
+    RATE_CONFIG.with(|rate_config| rate_config.borrow().get().clone())
+}
+
+// Latest known rate for the pair, either published by set_rate or last
+// fetched from XRC. Never calls out, so the result may be stale.
+#[ic_cdk::query]
+fn get_rate(from_currency: String, to_currency: String) -> Option<ExchangeRate> {
+    latest_known_rate(&normalize_currency(&from_currency), &normalize_currency(&to_currency))
+}
+
+pub(crate) fn latest_known_rate(from_currency: &str, to_currency: &str) -> Option<ExchangeRate> {
+    lookup_rate(from_currency, to_currency).or_else(|| {
+        let pair = CurrencyPair::new(from_currency, to_currency);
+        XRC_RATE_CACHE.with(|cache| cache.borrow().get(&pair))
+    })
+}
+
+// Like latest_known_rate, but an XRC rate older than the configured max
+// staleness is treated as missing. Published rates are used as they are, as
+// current_rate does.
+pub(crate) fn fresh_known_rate(from_currency: &str, to_currency: &str) -> Option<ExchangeRate> {
+    lookup_rate(from_currency, to_currency).or_else(|| {
+        let pair = CurrencyPair::new(from_currency, to_currency);
+        let config = get_rate_config();
+        XRC_RATE_CACHE
+            .with(|cache| cache.borrow().get(&pair))
+            .filter(|exchange_rate| !is_stale(exchange_rate.updated_at / NANOS_PER_SEC, &config))
+    })
+}
+
+// Whether a rate published at `timestamp` (seconds since epoch) is too old
+fn is_stale(timestamp: u64, config: &RateConfig) -> bool {
+    (time() / NANOS_PER_SEC).saturating_sub(timestamp) > config.max_staleness_secs
+}
+
+// Latest rate for the pair. Falls back to inverting the opposite direction so
+// the admin doesn't have to publish both sides of every pair.
+pub(crate) fn lookup_rate(from_currency: &str, to_currency: &str) -> Option<ExchangeRate> {
+    EXCHANGE_RATES.with(|rates| {
+        let rates = rates.borrow();
+        if let Some(exchange_rate) = rates.get(&CurrencyPair::new(from_currency, to_currency)) {
+            return Some(exchange_rate);
+        }
+        rates
+            .get(&CurrencyPair::new(to_currency, from_currency))
+            .map(|inverse| ExchangeRate {
+                rate: 1.0 / inverse.rate,
+                updated_at: inverse.updated_at,
+            })
+    })
+}
+
+// Rate used to check limit orders: a rate published by set_rate if there is
+// one, otherwise the XRC rate, served from the cache while it is younger than
+// the configured TTL and fetched again once it isn't.
+pub(crate) async fn current_rate(from_currency: &str, to_currency: &str) -> Result<f64, Error> {
+    if let Some(exchange_rate) = lookup_rate(from_currency, to_currency) {
+        return Ok(exchange_rate.rate);
+    }
+
+    let config = get_rate_config();
+    let pair = CurrencyPair::new(from_currency, to_currency);
+    let cached = XRC_RATE_CACHE.with(|cache| cache.borrow().get(&pair));
+    if let Some(exchange_rate) = cached {
+        if time().saturating_sub(exchange_rate.updated_at) <= config.cache_ttl_secs * NANOS_PER_SEC {
+            return Ok(exchange_rate.rate);
+        }
+    }
+
+    let fetched = fetch_xrc_rate(from_currency, to_currency).await?;
+    let now = time();
+    if is_stale(fetched.timestamp, &config) {
+        return Err(Error::RateStale);
+    }
+
+    let rate = fetched.rate as f64 / 10f64.powi(fetched.metadata.decimals as i32);
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(Error::RateUnavailable);
+    }
+
+    let exchange_rate = ExchangeRate { rate, updated_at: now };
+    XRC_RATE_CACHE.with(|cache| cache.borrow_mut().insert(pair, exchange_rate));
+
+    Ok(rate)
+}
+
+async fn fetch_xrc_rate(from_currency: &str, to_currency: &str) -> Result<XrcExchangeRate, Error> {
+    let request = XrcGetExchangeRateRequest {
+        base_asset: xrc_asset(from_currency),
+        quote_asset: xrc_asset(to_currency),
+        timestamp: None,
+    };
+    let xrc = Principal::from_text(XRC_CANISTER_ID).expect("Invalid XRC canister id");
+
+    let (result,): (Result<XrcExchangeRate, XrcExchangeRateError>,) =
+        call_with_payment(xrc, "get_exchange_rate", (request,), XRC_CALL_CYCLES)
+            .await
+            .map_err(|(code, message)| Error::RateFetchFailed(format!("XRC call rejected ({:?}): {}", code, message)))?;
+
+    result.map_err(|err| Error::RateFetchFailed(format!("XRC returned {:?}", err)))
+}
+
+fn xrc_asset(symbol: &str) -> XrcAsset {
+    let class = if CRYPTO_SYMBOLS.contains(&symbol) {
+        XrcAssetClass::Cryptocurrency
+    } else {
+        XrcAssetClass::FiatCurrency
+    };
+    XrcAsset { symbol: symbol.to_string(), class }
+}
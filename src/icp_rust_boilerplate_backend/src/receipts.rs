@@ -0,0 +1,111 @@
+use crate::{Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Authoritative record of one fill, as settled
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct ExecutionReceipt {
+    pub(crate) order_id: u64,
+    pub(crate) owner: Principal,
+    pub(crate) executor: Principal,
+    pub(crate) paid_currency: String,     // the order's to_currency
+    pub(crate) paid_amount: u128,          // paid by the executor, fee included
+    pub(crate) received_currency: String,  // the order's from_currency
+    pub(crate) received_amount: u128,      // taken from the owner's escrow by the executor
+    pub(crate) fee: u128,                  // maker fee, withheld from the owner's proceeds, in paid_currency
+    pub(crate) taker_fee: Option<u128>,    // withheld from what the executor received, in received_currency
+    pub(crate) maker_rebate: Option<u128>, // paid to the owner out of taker_fee, in received_currency
+    pub(crate) executed_at: u64,
+}
+
+impl Storable for ExecutionReceipt {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode ExecutionReceipt"))
+    }
+
+    // Receipts settled while amounts were u64 are widened on the way out
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), NarrowExecutionReceipt).map(ExecutionReceipt::from))
+            .expect("Failed to decode ExecutionReceipt")
+    }
+}
+
+impl BoundedStorable for ExecutionReceipt {
+    const MAX_SIZE: u32 = 384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// ExecutionReceipt as stored while amounts were u64, also inside events
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NarrowExecutionReceipt {
+    order_id: u64,
+    owner: Principal,
+    executor: Principal,
+    paid_currency: String,
+    paid_amount: u64,
+    received_currency: String,
+    received_amount: u64,
+    fee: u64,
+    taker_fee: Option<u64>,
+    maker_rebate: Option<u64>,
+    executed_at: u64,
+}
+
+impl From<NarrowExecutionReceipt> for ExecutionReceipt {
+    fn from(narrow: NarrowExecutionReceipt) -> Self {
+        ExecutionReceipt {
+            order_id: narrow.order_id,
+            owner: narrow.owner,
+            executor: narrow.executor,
+            paid_currency: narrow.paid_currency,
+            paid_amount: narrow.paid_amount.into(),
+            received_currency: narrow.received_currency,
+            received_amount: narrow.received_amount.into(),
+            fee: narrow.fee.into(),
+            taker_fee: narrow.taker_fee.map(u128::from),
+            maker_rebate: narrow.maker_rebate.map(u128::from),
+            executed_at: narrow.executed_at,
+        }
+    }
+}
+
+thread_local! {
+    // Keyed by (order id, fill number) so an order's receipts sit together in
+    // the order they were settled
+    static RECEIPTS: RefCell<StableBTreeMap<(u64, u64), ExecutionReceipt, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+}
+
+// Returns the receipt's fill number
+pub(crate) fn store_receipt(receipt: ExecutionReceipt) -> u64 {
+    RECEIPTS.with(|receipts| {
+        let mut receipts_borrowed = receipts.borrow_mut();
+        let order_id = receipt.order_id;
+        let fill_number = receipts_borrowed.range((order_id, 0)..=(order_id, u64::MAX)).count() as u64;
+        receipts_borrowed.insert((order_id, fill_number), receipt);
+        fill_number
+    })
+}
+
+pub(crate) fn find_receipt(order_id: u64, fill_number: u64) -> Option<ExecutionReceipt> {
+    RECEIPTS.with(|receipts| receipts.borrow().get(&(order_id, fill_number)))
+}
+
+// Every fill of the order, oldest first. Partially filled orders have one
+// receipt per fill.
+#[ic_cdk::query]
+fn get_execution_receipts(order_id: u64) -> Vec<ExecutionReceipt> {
+    RECEIPTS.with(|receipts| {
+        receipts
+            .borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, receipt)| receipt)
+            .collect()
+    })
+}
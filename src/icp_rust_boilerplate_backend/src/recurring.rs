@@ -0,0 +1,306 @@
+use crate::currencies::normalize_currency;
+use crate::snapshot::{clear_map, entries_after};
+use crate::transactions::{record_transaction, TransactionKind};
+use crate::{
+    admin, allowlist, blacklist, open_order, rate_limit, CreateSwapOrderArgs, Error, Memory, StorablePrincipal,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub(crate) const RECURRING_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Occurrences can't come closer together than the sweep runs
+const MIN_RECURRING_INTERVAL_SECS: u64 = 60;
+
+const MAX_OCCURRENCES: u32 = 10_000;
+
+// Active schedules a principal may have at once
+const MAX_ACTIVE_RECURRING_ORDERS: u64 = 20;
+
+// Occurrences placed per sweep tick; the rest wait for the next tick
+const RECURRING_SWEEP_BATCH_SIZE: usize = 50;
+
+// Stop reasons are kept short so a schedule stays within MAX_SIZE
+const MAX_STOP_REASON_BYTES: usize = 128;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum RecurringStatus {
+    Active,
+    Completed, // every occurrence was placed
+    Cancelled,
+    Stopped, // an occurrence failed, see stop_reason
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecurringOrder {
+    id: u64,
+    owner: Principal,
+    args: CreateSwapOrderArgs, // placed as given at every occurrence
+    interval_secs: u64,
+    occurrences: u32, // planned in total
+    placed: u32,      // occurrences placed so far
+    created_at: u64,
+    next_run_at: Option<u64>, // None once the schedule ended
+    last_order_id: Option<u64>,
+    status: RecurringStatus,
+    stop_reason: Option<String>,
+}
+
+impl Storable for RecurringOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode RecurringOrder"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode RecurringOrder")
+    }
+}
+
+impl BoundedStorable for RecurringOrder {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RECURRING_ORDERS: RefCell<StableBTreeMap<u64, RecurringOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    static RECURRING_BY_OWNER: RefCell<StableBTreeMap<(StorablePrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // (next_run_at, schedule id) of every active schedule, earliest first
+    static RECURRING_DUE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+}
+
+// Places `args` now and again every `interval_secs` until `occurrences`
+// orders were placed. The first order is placed by this call, so arguments
+// that can't be placed fail here instead of on the first tick. Orders expire
+// on their own, so `expires_at` must be left unset.
+#[ic_cdk::update]
+fn create_recurring_order(mut args: CreateSwapOrderArgs, interval_secs: u64, occurrences: u32) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    allowlist::require_allowlisted()?;
+    admin::require_trading_active()?;
+    if interval_secs < MIN_RECURRING_INTERVAL_SECS
+        || occurrences == 0
+        || occurrences > MAX_OCCURRENCES
+        || args.expires_at.is_some()
+    {
+        return Err(Error::InvalidSchedule);
+    }
+    args.from_currency = normalize_currency(&args.from_currency);
+    args.to_currency = normalize_currency(&args.to_currency);
+    let interval_nanos = interval_secs.checked_mul(1_000_000_000).ok_or(Error::InvalidSchedule)?;
+    let owner = StorablePrincipal::from(caller());
+    if active_recurring_orders(&owner) >= MAX_ACTIVE_RECURRING_ORDERS {
+        return Err(Error::TooManyRecurringOrders { limit: MAX_ACTIVE_RECURRING_ORDERS });
+    }
+
+    let id = RECURRING_ORDERS.with(|orders| orders.borrow().last_key_value().map_or(1, |(last_id, _)| last_id + 1));
+    let now = time();
+    let mut recurring_order = RecurringOrder {
+        id,
+        owner: caller(),
+        args: args.clone(),
+        interval_secs,
+        occurrences,
+        placed: 0,
+        created_at: now,
+        next_run_at: None,
+        last_order_id: None,
+        status: RecurringStatus::Active,
+        stop_reason: None,
+    };
+    let order_id = open_order(caller(), args)?;
+    record_occurrence(&mut recurring_order, order_id, now.saturating_add(interval_nanos));
+
+    RECURRING_BY_OWNER.with(|index| index.borrow_mut().insert((owner, id), ()));
+    store_recurring_order(recurring_order);
+    Ok(id)
+}
+
+// Ends the schedule. Orders it already placed stay and are cancelled like any
+// other order.
+#[ic_cdk::update]
+fn cancel_recurring_order(id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    let mut recurring_order = RECURRING_ORDERS
+        .with(|orders| orders.borrow().get(&id))
+        .filter(|recurring_order| recurring_order.owner == caller())
+        .ok_or(Error::RecurringOrderNotFound)?;
+    if recurring_order.status != RecurringStatus::Active {
+        return Err(Error::RecurringOrderNotActive);
+    }
+
+    unschedule(&recurring_order);
+    recurring_order.status = RecurringStatus::Cancelled;
+    recurring_order.next_run_at = None;
+    store_recurring_order(recurring_order);
+    Ok(())
+}
+
+// Every schedule of the caller, oldest first
+#[ic_cdk::query]
+fn list_my_recurring_orders() -> Vec<RecurringOrder> {
+    let owner = StorablePrincipal::from(caller());
+    let ids: Vec<u64> = RECURRING_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .range((owner.clone(), 0)..=(owner, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    RECURRING_ORDERS.with(|orders| {
+        let orders = orders.borrow();
+        ids.into_iter().filter_map(|id| orders.get(&id)).collect()
+    })
+}
+
+// Places the occurrences that are due, earliest first. An occurrence while
+// trading is paused is skipped and doesn't count; any other failure, running
+// out of funds most of all, stops the schedule with the error as its reason.
+pub(crate) fn run_due_recurring_orders() {
+    let now = time();
+    let due: Vec<(u64, u64)> = RECURRING_DUE.with(|due| {
+        due.borrow()
+            .range(..=(now, u64::MAX))
+            .take(RECURRING_SWEEP_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for (run_at, id) in due {
+        RECURRING_DUE.with(|due| due.borrow_mut().remove(&(run_at, id)));
+        let Some(mut recurring_order) = RECURRING_ORDERS.with(|orders| orders.borrow().get(&id)) else {
+            continue;
+        };
+        // Late ticks don't pile up occurrences to catch up
+        let next_run_at = run_at.saturating_add(recurring_order.interval_secs * 1_000_000_000).max(now);
+        match place_occurrence(&recurring_order) {
+            Ok(order_id) => record_occurrence(&mut recurring_order, order_id, next_run_at),
+            Err(Error::TradingPaused) => recurring_order.next_run_at = Some(next_run_at),
+            Err(error) => {
+                let mut reason = format!("{:?}", error);
+                let mut cut = reason.len().min(MAX_STOP_REASON_BYTES);
+                while !reason.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                reason.truncate(cut);
+                recurring_order.status = RecurringStatus::Stopped;
+                recurring_order.stop_reason = Some(reason);
+                recurring_order.next_run_at = None;
+            }
+        }
+        store_recurring_order(recurring_order);
+    }
+}
+
+// The owner goes through the same gates as a caller placing the order
+// themselves, except the per-call rate limit
+fn place_occurrence(recurring_order: &RecurringOrder) -> Result<u64, Error> {
+    if blacklist::is_blacklisted(recurring_order.owner) {
+        return Err(Error::Blacklisted);
+    }
+    if !allowlist::is_allowed(recurring_order.owner) {
+        return Err(Error::NotAllowlisted);
+    }
+    admin::require_trading_active()?;
+    open_order(recurring_order.owner, recurring_order.args.clone())
+}
+
+// Counts a placed order and logs it against the schedule so each automated
+// occurrence can be audited
+fn record_occurrence(recurring_order: &mut RecurringOrder, order_id: u64, next_run_at: u64) {
+    record_transaction(
+        TransactionKind::RecurringOrder,
+        Some(recurring_order.owner),
+        None,
+        &recurring_order.args.from_currency,
+        recurring_order.args.from_amount,
+        Some(order_id),
+    );
+    recurring_order.placed += 1;
+    recurring_order.last_order_id = Some(order_id);
+    if recurring_order.placed == recurring_order.occurrences {
+        recurring_order.status = RecurringStatus::Completed;
+        recurring_order.next_run_at = None;
+    } else {
+        recurring_order.next_run_at = Some(next_run_at);
+    }
+}
+
+// Writes the schedule and lists it as due at its next run, if it has one
+fn store_recurring_order(recurring_order: RecurringOrder) {
+    if let Some(next_run_at) = recurring_order.next_run_at {
+        RECURRING_DUE.with(|due| due.borrow_mut().insert((next_run_at, recurring_order.id), ()));
+    }
+    RECURRING_ORDERS.with(|orders| orders.borrow_mut().insert(recurring_order.id, recurring_order));
+}
+
+// Up to `limit` schedules after the id `after`, for create_export
+pub(crate) fn export_recurring_orders(after: Option<u64>, limit: usize) -> Vec<(u64, RecurringOrder)> {
+    RECURRING_ORDERS.with(|orders| entries_after(&orders.borrow(), after, limit))
+}
+
+// Replaces every schedule with those of an imported snapshot, listing them
+// under their owner and next run again
+pub(crate) fn restore_recurring_orders(restored: Vec<RecurringOrder>) {
+    RECURRING_BY_OWNER.with(|index| clear_map(&mut index.borrow_mut()));
+    RECURRING_DUE.with(|due| clear_map(&mut due.borrow_mut()));
+    RECURRING_ORDERS.with(|orders| clear_map(&mut orders.borrow_mut()));
+    for recurring_order in restored {
+        let owner = StorablePrincipal::from(recurring_order.owner);
+        RECURRING_BY_OWNER.with(|index| index.borrow_mut().insert((owner, recurring_order.id), ()));
+        store_recurring_order(recurring_order);
+    }
+}
+
+fn unschedule(recurring_order: &RecurringOrder) {
+    if let Some(next_run_at) = recurring_order.next_run_at {
+        RECURRING_DUE.with(|due| due.borrow_mut().remove(&(next_run_at, recurring_order.id)));
+    }
+}
+
+fn active_recurring_orders(owner: &StorablePrincipal) -> u64 {
+    let ids: Vec<u64> = RECURRING_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .range((owner.clone(), 0)..=(owner.clone(), u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    RECURRING_ORDERS.with(|orders| {
+        let orders = orders.borrow();
+        ids.into_iter()
+            .filter_map(|id| orders.get(&id))
+            .filter(|recurring_order| recurring_order.status == RecurringStatus::Active)
+            .count() as u64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::order_args;
+
+    #[test]
+    fn anonymous_recurring_orders_are_rejected() {
+        assert_eq!(create_recurring_order(order_args("EUR", "USD"), 3_600, 3), Err(Error::AnonymousNotAllowed));
+        assert_eq!(cancel_recurring_order(1), Err(Error::AnonymousNotAllowed));
+    }
+}
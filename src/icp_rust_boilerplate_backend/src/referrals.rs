@@ -0,0 +1,198 @@
+use crate::admin::{self, require_admin};
+use crate::amounts::mul_div;
+use crate::currencies::CurrencySymbol;
+use crate::fees::FillFees;
+use crate::{rate_limit, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::Principal;
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+// The whole taker fee, less whatever the maker rebate already takes of it
+const MAX_REWARD_BPS: u16 = 10_000;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RewardTotal {
+    currency: String,
+    amount: u128,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ReferralStats {
+    referrer: Option<Principal>, // who referred the caller
+    referees: u64,               // users who registered the caller as their referrer
+    rewards: Vec<RewardTotal>,   // earned from their taker fees, one per currency
+}
+
+thread_local! {
+    // Referee -> referrer, set once
+    static REFERRERS: RefCell<StableBTreeMap<StorablePrincipal, StorablePrincipal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+
+    // Referrer -> number of referees
+    static REFEREE_COUNTS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+
+    // (referrer, currency) -> rewards paid to the referrer in that currency
+    static REFERRAL_REWARDS: RefCell<StableBTreeMap<(StorablePrincipal, CurrencySymbol), u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+
+    // Share of a referee's taker fee paid to their referrer; starts disabled
+    static REFERRAL_REWARD_BPS: RefCell<Cell<u16, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92))), 0)
+            .expect("Cannot create the referral reward rate")
+    );
+}
+
+#[ic_cdk::query]
+fn get_referral_reward_bps() -> u16 {
+    REFERRAL_REWARD_BPS.with(|cell| *cell.borrow().get())
+}
+
+// Applies to fills from now on; rewards already paid are kept
+#[ic_cdk::update]
+fn set_referral_reward_bps(reward_bps: u16) -> Result<(), Error> {
+    require_admin()?;
+    if reward_bps > MAX_REWARD_BPS {
+        return Err(Error::InvalidFee);
+    }
+    REFERRAL_REWARD_BPS.with(|cell| cell.borrow_mut().set(reward_bps))
+        .expect("Failed to store the referral reward rate");
+    Ok(())
+}
+
+// Names the principal credited with a share of the taker fee on every fill
+// the caller takes from now on. Can only be done once, and not to anyone
+// the caller referred, directly or down the line.
+#[ic_cdk::update]
+fn register_referrer(referrer: Principal) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let referee = StorablePrincipal::from(caller());
+    if referrer == Principal::anonymous() || referrer == caller() {
+        return Err(Error::InvalidReferrer);
+    }
+    if REFERRERS.with(|referrers| referrers.borrow().contains_key(&referee)) {
+        return Err(Error::ReferrerAlreadyRegistered);
+    }
+    // The graph has no cycles yet, so following the referrer's chain ends
+    let referrer = StorablePrincipal::from(referrer);
+    let mut next = Some(referrer.clone());
+    while let Some(ancestor) = next {
+        if ancestor == referee {
+            return Err(Error::ReferralCycle);
+        }
+        next = referrer_of(&ancestor);
+    }
+
+    REFERRERS.with(|referrers| referrers.borrow_mut().insert(referee, referrer.clone()));
+    REFEREE_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let count = counts_borrowed.get(&referrer).unwrap_or(0);
+        counts_borrowed.insert(referrer, count + 1);
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_referral_stats() -> ReferralStats {
+    let principal = StorablePrincipal::from(caller());
+    let rewards = REFERRAL_REWARDS.with(|rewards| {
+        rewards
+            .borrow()
+            .range((principal.clone(), CurrencySymbol::default())..)
+            .take_while(|((referrer, _), _)| *referrer == principal)
+            .map(|((_, currency), amount)| RewardTotal {
+                currency: currency.0,
+                amount,
+            })
+            .collect()
+    });
+    ReferralStats {
+        referrer: referrer_of(&principal).map(Principal::from),
+        referees: REFEREE_COUNTS.with(|counts| counts.borrow().get(&principal)).unwrap_or(0),
+        rewards,
+    }
+}
+
+// The taker's referrer and what it is owed on a fill. It comes out of the
+// fee account's share, so it is capped at the taker fee left after the maker
+// rebate. None without a referrer or when nothing is owed.
+pub(crate) fn reward_for(taker: &StorablePrincipal, fees: &FillFees) -> Option<(StorablePrincipal, u128)> {
+    let referrer = referrer_of(taker)?;
+    let reward = mul_div(fees.taker_fee, get_referral_reward_bps().into(), BPS_DENOMINATOR)
+        .expect("Referral reward exceeded the taker fee")
+        .min(fees.taker_fee - fees.maker_rebate);
+    (reward > 0).then_some((referrer, reward))
+}
+
+// Called once the reward has been credited
+pub(crate) fn record_reward(referrer: &StorablePrincipal, currency: &str, reward: u128) {
+    let key = (referrer.clone(), CurrencySymbol(currency.to_string()));
+    REFERRAL_REWARDS.with(|rewards| {
+        let mut rewards_borrowed = rewards.borrow_mut();
+        let total = rewards_borrowed.get(&key).unwrap_or(0);
+        rewards_borrowed.insert(key, total.saturating_add(reward));
+    });
+}
+
+fn referrer_of(referee: &StorablePrincipal) -> Option<StorablePrincipal> {
+    REFERRERS.with(|referrers| referrers.borrow().get(referee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::tests::set_caller;
+
+    fn registering_as(principal: Principal) {
+        set_caller(principal);
+        rate_limit::tests::exempt(principal);
+    }
+
+    #[test]
+    fn anonymous_referrals_are_rejected() {
+        assert_eq!(register_referrer(Principal::from_slice(&[90])), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn a_referral_that_closes_a_cycle_is_rejected() {
+        let (first, second, third) =
+            (Principal::from_slice(&[91]), Principal::from_slice(&[92]), Principal::from_slice(&[93]));
+        registering_as(second);
+        assert_eq!(register_referrer(first), Ok(()));
+        registering_as(third);
+        assert_eq!(register_referrer(second), Ok(()));
+
+        registering_as(first);
+        assert_eq!(register_referrer(third), Err(Error::ReferralCycle));
+        assert_eq!(register_referrer(first), Err(Error::InvalidReferrer));
+        registering_as(third);
+        assert_eq!(register_referrer(first), Err(Error::ReferrerAlreadyRegistered));
+    }
+
+    #[test]
+    fn the_reward_never_exceeds_the_taker_fee_left_after_the_rebate() {
+        let (referrer, taker) = (Principal::from_slice(&[94]), Principal::from_slice(&[95]));
+        registering_as(taker);
+        register_referrer(referrer).unwrap();
+        REFERRAL_REWARD_BPS.with(|cell| cell.borrow_mut().set(10_000)).unwrap();
+
+        let fees = FillFees { maker_fee: 0, taker_fee: 10, maker_rebate: 4 };
+        assert_eq!(reward_for(&StorablePrincipal::from(taker), &fees), Some((StorablePrincipal::from(referrer), 6)));
+        let no_fee = FillFees { maker_fee: 0, taker_fee: 0, maker_rebate: 0 };
+        assert_eq!(reward_for(&StorablePrincipal::from(taker), &no_fee), None);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::snapshot::clear_map;
+use crate::{admin, archive, rate_limit, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// What the matching engine does when an incoming order would fill against a
+// resting order of the same owner
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub(crate) enum SelfTradePrevention {
+    #[default]
+    CancelNewest, // matching stops there and the incoming order's remainder is cancelled
+    CancelOldest, // the resting order is cancelled and matching goes on past it
+    CancelBoth,   // both are cancelled, the resting one and the incoming remainder
+    Allow,        // the orders fill against each other like anyone else's
+}
+
+// Stored as the variant's position in one byte; the Candid encoding carries
+// the type table of every variant and outgrows MAX_SIZE
+impl Storable for SelfTradePrevention {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match bytes.as_ref() {
+            [0] => SelfTradePrevention::CancelNewest,
+            [1] => SelfTradePrevention::CancelOldest,
+            [2] => SelfTradePrevention::CancelBoth,
+            [3] => SelfTradePrevention::Allow,
+            _ => panic!("Failed to decode SelfTradePrevention"),
+        }
+    }
+}
+
+impl BoundedStorable for SelfTradePrevention {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// The incoming order's own resting orders that matching met, filled under
+// Allow and otherwise acted on
+#[derive(Default)]
+pub(crate) struct SelfTrades {
+    pub(crate) prevention: SelfTradePrevention,
+    pub(crate) makers: Vec<(u64, bool)>, // (resting order id, whether it was cancelled), in book order
+    pub(crate) stopped_by: Option<u64>,  // resting order that ended matching, the incoming remainder is cancelled
+}
+
+// Self-trade prevention applied to an order, one per order of the same owner
+// on the other side
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SelfTradeRecord {
+    order_id: u64,
+    other_order_id: u64,
+    prevention: SelfTradePrevention, // the incoming order owner's setting at the time
+    cancelled: bool,                 // whether this order was cancelled by it
+    at: u64,
+}
+
+impl Storable for SelfTradeRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode SelfTradeRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode SelfTradeRecord")
+    }
+}
+
+impl BoundedStorable for SelfTradeRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Principal -> its setting, absent for the default
+    static SELF_TRADE_PREVENTION: RefCell<StableBTreeMap<StorablePrincipal, SelfTradePrevention, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109)))
+    ));
+
+    // (order id, other order id) -> what was applied, kept under both orders
+    static SELF_TRADE_RECORDS: RefCell<StableBTreeMap<(u64, u64), SelfTradeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_my_self_trade_prevention() -> SelfTradePrevention {
+    prevention_for(&StorablePrincipal::from(caller()))
+}
+
+// Applies to the caller's orders placed from now on
+#[ic_cdk::update]
+fn set_self_trade_prevention(prevention: SelfTradePrevention) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let principal = StorablePrincipal::from(caller());
+    SELF_TRADE_PREVENTION.with(|settings| {
+        if prevention == SelfTradePrevention::default() {
+            settings.borrow_mut().remove(&principal);
+        } else {
+            settings.borrow_mut().insert(principal, prevention);
+        }
+    });
+    Ok(())
+}
+
+// For the order's owner and the admin
+#[ic_cdk::query]
+fn get_order_self_trades(order_id: u64) -> Result<Vec<SelfTradeRecord>, Error> {
+    let swap_order = archive::find_order(order_id).ok_or(Error::InvalidOrderId)?;
+    if swap_order.owner != caller() {
+        admin::require_admin()?;
+    }
+    Ok(SELF_TRADE_RECORDS.with(|records| {
+        records
+            .borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, record)| record)
+            .collect()
+    }))
+}
+
+pub(crate) fn prevention_for(principal: &StorablePrincipal) -> SelfTradePrevention {
+    SELF_TRADE_PREVENTION.with(|settings| settings.borrow().get(principal)).unwrap_or_default()
+}
+
+// Records what matching did for the incoming order under both it and each
+// resting order involved
+pub(crate) fn record_self_trades(order_id: u64, self_trades: &SelfTrades) {
+    let now = time();
+    let record = |order_id: u64, other_order_id: u64, cancelled: bool| SelfTradeRecord {
+        order_id,
+        other_order_id,
+        prevention: self_trades.prevention,
+        cancelled,
+        at: now,
+    };
+    SELF_TRADE_RECORDS.with(|records| {
+        let mut records_borrowed = records.borrow_mut();
+        for &(maker_id, maker_cancelled) in &self_trades.makers {
+            let incoming_cancelled = self_trades.stopped_by == Some(maker_id);
+            records_borrowed.insert((order_id, maker_id), record(order_id, maker_id, incoming_cancelled));
+            records_borrowed.insert((maker_id, order_id), record(maker_id, order_id, maker_cancelled));
+        }
+    });
+}
+
+// Called when prune_archive deletes the order
+pub(crate) fn forget_self_trades(order_id: u64) {
+    SELF_TRADE_RECORDS.with(|records| {
+        let mut records_borrowed = records.borrow_mut();
+        let keys: Vec<(u64, u64)> =
+            records_borrowed.range((order_id, 0)..=(order_id, u64::MAX)).map(|(key, _)| key).collect();
+        for key in keys {
+            records_borrowed.remove(&key);
+        }
+    });
+}
+
+// Called by restore_snapshot: the records are about orders of the state being
+// replaced
+pub(crate) fn clear_self_trades() {
+    SELF_TRADE_RECORDS.with(|records| clear_map(&mut records.borrow_mut()));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use candid::Principal;
+
+    pub(crate) fn set_prevention(principal: &StorablePrincipal, prevention: SelfTradePrevention) {
+        SELF_TRADE_PREVENTION.with(|settings| settings.borrow_mut().insert(principal.clone(), prevention));
+    }
+
+    #[test]
+    fn anonymous_callers_cannot_change_the_setting() {
+        assert_eq!(set_self_trade_prevention(SelfTradePrevention::Allow), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn principals_without_a_setting_get_cancel_newest() {
+        let principal = StorablePrincipal::from(Principal::from_slice(&[1]));
+
+        assert_eq!(SelfTradePrevention::default(), SelfTradePrevention::CancelNewest);
+        assert_eq!(prevention_for(&principal), SelfTradePrevention::CancelNewest);
+    }
+
+    #[test]
+    fn a_stored_setting_applies_to_its_principal_only() {
+        let (principal, other) = (
+            StorablePrincipal::from(Principal::from_slice(&[2])),
+            StorablePrincipal::from(Principal::from_slice(&[3])),
+        );
+        SELF_TRADE_PREVENTION
+            .with(|settings| settings.borrow_mut().insert(principal.clone(), SelfTradePrevention::Allow));
+
+        assert_eq!(prevention_for(&principal), SelfTradePrevention::Allow);
+        assert_eq!(prevention_for(&other), SelfTradePrevention::CancelNewest);
+    }
+
+    #[test]
+    fn every_setting_fits_its_bound_and_decodes_back() {
+        use SelfTradePrevention::*;
+        for prevention in [CancelNewest, CancelOldest, CancelBoth, Allow] {
+            let bytes = prevention.to_bytes();
+            assert!(bytes.len() <= SelfTradePrevention::MAX_SIZE as usize);
+            assert_eq!(SelfTradePrevention::from_bytes(bytes), prevention);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{admin, allowlist, blacklist, rate_limit, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::caller;
+#[cfg(not(test))]
+use ic_cdk::api::caller;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Keys one grantor can have authorized at a time, expired ones included
+const MAX_SESSION_KEYS_PER_GRANTOR: u64 = 10;
+
+// Longest a key can be authorized for in one go
+const MAX_SESSION_DURATION_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct SessionPermissions {
+    can_create: bool,  // create_swap_order, create_swap_orders and create_oco_orders
+    can_cancel: bool,  // cancel_swap_order and cancel_all_my_orders
+    can_execute: bool, // execute_swap_order, accept_swap_order and settle_swap_order
+}
+
+// What a call from a session key wants to do for its grantor
+pub(crate) enum SessionAction {
+    Create,
+    Cancel,
+    Execute,
+}
+
+impl SessionPermissions {
+    fn allows(&self, action: &SessionAction) -> bool {
+        match action {
+            SessionAction::Create => self.can_create,
+            SessionAction::Cancel => self.can_cancel,
+            SessionAction::Execute => self.can_execute,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SessionKey {
+    key_principal: Principal,
+    grantor: Principal,
+    permissions: SessionPermissions,
+    authorized_at: u64,
+    expires_at: u64,
+}
+
+impl Storable for SessionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode SessionKey"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode SessionKey")
+    }
+}
+
+impl BoundedStorable for SessionKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Key principal -> its delegation; a key acts for a single grantor
+    static SESSION_KEYS: RefCell<StableBTreeMap<StorablePrincipal, SessionKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    // (grantor, key principal)
+    static SESSION_KEYS_BY_GRANTOR: RefCell<StableBTreeMap<(StorablePrincipal, StorablePrincipal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+}
+
+// Lets calls from `key_principal` create, cancel or execute orders as the
+// caller until `expires_at`, as far as `permissions` allow. A key never
+// withdraws or transfers the grantor's funds: those calls always act on the
+// key's own account. Authorizing a key again replaces its permissions and
+// expiry.
+#[ic_cdk::update]
+fn authorize_session_key(
+    key_principal: Principal,
+    permissions: SessionPermissions,
+    expires_at: u64,
+) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let grantor = caller();
+    let now = time();
+    // Delegations are one hop; a key can't hand its grant on
+    if is_session_key(grantor) {
+        return Err(Error::Unauthorized);
+    }
+    if key_principal == Principal::anonymous() || key_principal == grantor {
+        return Err(Error::InvalidSessionKey);
+    }
+    if expires_at <= now || expires_at - now > MAX_SESSION_DURATION_NANOS {
+        return Err(Error::InvalidSessionKey);
+    }
+    let key = StorablePrincipal::from(key_principal);
+    match SESSION_KEYS.with(|keys| keys.borrow().get(&key)) {
+        Some(existing) if existing.grantor != grantor => return Err(Error::SessionKeyInUse),
+        Some(_) => {}
+        None => {
+            if grantor_keys(&StorablePrincipal::from(grantor)).len() as u64 >= MAX_SESSION_KEYS_PER_GRANTOR {
+                return Err(Error::TooManySessionKeys { limit: MAX_SESSION_KEYS_PER_GRANTOR });
+            }
+        }
+    }
+
+    let session_key = SessionKey {
+        key_principal,
+        grantor,
+        permissions,
+        authorized_at: now,
+        expires_at,
+    };
+    SESSION_KEYS_BY_GRANTOR.with(|index| index.borrow_mut().insert((StorablePrincipal::from(grantor), key.clone()), ()));
+    SESSION_KEYS.with(|keys| keys.borrow_mut().insert(key, session_key));
+    Ok(())
+}
+
+// Takes effect for the key's next call
+#[ic_cdk::update]
+fn revoke_session_key(key_principal: Principal) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let grantor = StorablePrincipal::from(caller());
+    let key = StorablePrincipal::from(key_principal);
+    let removed = SESSION_KEYS_BY_GRANTOR.with(|index| index.borrow_mut().remove(&(grantor, key.clone())));
+    if removed.is_none() {
+        return Err(Error::SessionKeyNotFound);
+    }
+    SESSION_KEYS.with(|keys| keys.borrow_mut().remove(&key));
+    Ok(())
+}
+
+// The caller's keys, expired ones included until revoked
+#[ic_cdk::query]
+fn list_my_session_keys() -> Vec<SessionKey> {
+    grantor_keys(&StorablePrincipal::from(caller()))
+}
+
+// The principal an order call acts for: the grantor when the caller is a
+// session key allowed to `action`, otherwise the caller. A key calling past
+// its expiry or outside its permissions fails instead of falling back to its
+// own account. Creating and executing also need the grantor to pass the
+// blacklist and allowlist.
+pub(crate) fn on_behalf_of(action: SessionAction) -> Result<Principal, Error> {
+    let key_principal = caller();
+    let Some(session_key) = SESSION_KEYS.with(|keys| keys.borrow().get(&StorablePrincipal::from(key_principal))) else {
+        return Ok(key_principal);
+    };
+    if session_key.expires_at <= time() {
+        return Err(Error::SessionKeyExpired);
+    }
+    if !session_key.permissions.allows(&action) {
+        return Err(Error::SessionKeyNotPermitted);
+    }
+    if matches!(action, SessionAction::Create | SessionAction::Execute) {
+        if blacklist::is_blacklisted(session_key.grantor) {
+            return Err(Error::Blacklisted);
+        }
+        if !allowlist::is_allowed(session_key.grantor) {
+            return Err(Error::NotAllowlisted);
+        }
+    }
+    Ok(session_key.grantor)
+}
+
+fn is_session_key(principal: Principal) -> bool {
+    SESSION_KEYS.with(|keys| keys.borrow().contains_key(&StorablePrincipal::from(principal)))
+}
+
+fn grantor_keys(grantor: &StorablePrincipal) -> Vec<SessionKey> {
+    let keys: Vec<StorablePrincipal> = SESSION_KEYS_BY_GRANTOR.with(|index| {
+        index
+            .borrow()
+            .range((grantor.clone(), StorablePrincipal::default())..)
+            .take_while(|((indexed_grantor, _), _)| indexed_grantor == grantor)
+            .map(|((_, key), _)| key)
+            .collect()
+    });
+    keys.into_iter()
+        .filter_map(|key| SESSION_KEYS.with(|stored| stored.borrow().get(&key)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::tests::set_caller;
+
+    #[test]
+    fn anonymous_session_key_calls_are_rejected() {
+        let key_principal = Principal::from_slice(&[85]);
+        let permissions = SessionPermissions::default();
+        assert_eq!(authorize_session_key(key_principal, permissions, 1), Err(Error::AnonymousNotAllowed));
+        assert_eq!(revoke_session_key(key_principal), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn a_caller_without_a_session_key_acts_for_itself() {
+        let principal = Principal::from_slice(&[86]);
+        set_caller(principal);
+
+        assert_eq!(on_behalf_of(SessionAction::Create), Ok(principal));
+    }
+}
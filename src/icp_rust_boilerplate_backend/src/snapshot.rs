@@ -0,0 +1,617 @@
+use crate::admin::{require_admin, set_paused};
+use crate::archive::ARCHIVED_ORDERS;
+use crate::counter_offers::{self, CounterOffer};
+use crate::fill_callbacks::{self, FillCallback};
+use crate::ledgers::{self, LedgerDeposit, LedgerWithdrawal};
+use crate::order_labels::{self, OrderLabels};
+use crate::pending_withdrawals::{self, PendingWithdrawal};
+use crate::pools::{self, LpHolding, Pool, PoolKey};
+use crate::proposals::{self, ProposedAction};
+use crate::recurring::{self, RecurringOrder};
+use crate::{
+    certification, dedup, events, limit_scan, order_limits, order_queries, rebuild_locked_balances,
+    rebuild_order_book, rebuild_owner_index, self_trade, solvency, stats, transactions, Error, Memory,
+    NarrowSwapOrder, NarrowUserAccount, StorablePrincipal, SwapOrder, UserAccount, MEMORY_MANAGER,
+    ORDERS_BY_COUNTERPARTY, ORDERS_BY_OWNER, ORDER_COUNTER, SWAP_ORDERS, USER_ACCOUNTS,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+// Kept well under the query response limit, like the HTTP responses
+const MAX_EXPORT_CHUNK_BYTES: usize = 1024 * 1024;
+
+// Upper bound on records one create_export call gathers
+const MAX_EXPORT_BATCH: u32 = 1000;
+
+// Bounds what an import can stage on the heap before it is finalized
+const MAX_IMPORT_CHUNKS: u32 = 512;
+
+// SHA-256
+const HASH_BYTES: usize = 32;
+
+// Everything needed to rebuild accounts and orders, and the stores that hold
+// funds or hang off orders. Indexes, stats and the order book are all derived
+// from these and rebuilt on the way back in. Exports made before the side
+// stores were included decode with them as None; importing one empties them.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StateSnapshot {
+    pub(crate) accounts: Vec<(Principal, UserAccount)>,
+    pub(crate) orders: Vec<SwapOrder>,
+    pub(crate) order_counter: u64,
+    counter_offers: Option<Vec<CounterOffer>>,
+    pending_withdrawals: Option<Vec<PendingWithdrawal>>,
+    ledger_withdrawals: Option<Vec<LedgerWithdrawal>>,
+    ledger_deposits: Option<Vec<LedgerDeposit>>,
+    order_labels: Option<Vec<OrderLabels>>,
+    order_callbacks: Option<Vec<(u64, FillCallback)>>,
+    recurring_orders: Option<Vec<RecurringOrder>>,
+    pools: Option<Vec<Pool>>,
+    lp_shares: Option<Vec<LpHolding>>,
+}
+
+// StateSnapshot as exported while amounts were u64, so those exports can
+// still be imported
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct NarrowStateSnapshot {
+    accounts: Vec<(Principal, NarrowUserAccount)>,
+    orders: Vec<NarrowSwapOrder>,
+    order_counter: u64,
+}
+
+impl From<NarrowStateSnapshot> for StateSnapshot {
+    fn from(narrow: NarrowStateSnapshot) -> Self {
+        StateSnapshot {
+            accounts: narrow.accounts.into_iter().map(|(principal, account)| (principal, account.into())).collect(),
+            orders: narrow.orders.into_iter().map(SwapOrder::from).collect(),
+            order_counter: narrow.order_counter,
+            counter_offers: None,
+            pending_withdrawals: None,
+            ledger_withdrawals: None,
+            ledger_deposits: None,
+            order_labels: None,
+            order_callbacks: None,
+            recurring_orders: None,
+            pools: None,
+            lp_shares: None,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ExportManifest {
+    version: u64, // changes with every create_export, chunks carry it too
+    created_at: u64,
+    total_bytes: u64,
+    chunk_hashes: Vec<Vec<u8>>, // SHA-256 of each chunk, in order
+    snapshot_hash: Vec<u8>,     // SHA-256 of the whole snapshot, for finalize_import
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExportChunk {
+    version: u64,
+    index: u32,
+    data: Vec<u8>,
+}
+
+struct Export {
+    manifest: ExportManifest,
+    chunks: Vec<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExportProgress {
+    gathered: u64,                    // records so far, across calls
+    manifest: Option<ExportManifest>, // once every store is gathered; call again until then
+}
+
+// Where create_export resumes: the store it is gathering and the key of the
+// last record it took from it
+enum ExportCursor {
+    Accounts(Option<StorablePrincipal>),
+    Orders(Option<u64>),
+    ArchivedOrders(Option<u64>),
+    CounterOffers(Option<u64>),
+    PendingWithdrawals(Option<u64>),
+    LedgerWithdrawals(Option<u64>),
+    LedgerDeposits(Option<u64>),
+    OrderLabels(Option<u64>),
+    OrderCallbacks(Option<u64>),
+    RecurringOrders(Option<u64>),
+    Pools(Option<PoolKey>),
+    LpShares(Option<(PoolKey, StorablePrincipal)>),
+}
+
+impl ExportCursor {
+    // The store gathered after this one, if any
+    fn next(&self) -> Option<ExportCursor> {
+        match self {
+            ExportCursor::Accounts(_) => Some(ExportCursor::Orders(None)),
+            ExportCursor::Orders(_) => Some(ExportCursor::ArchivedOrders(None)),
+            ExportCursor::ArchivedOrders(_) => Some(ExportCursor::CounterOffers(None)),
+            ExportCursor::CounterOffers(_) => Some(ExportCursor::PendingWithdrawals(None)),
+            ExportCursor::PendingWithdrawals(_) => Some(ExportCursor::LedgerWithdrawals(None)),
+            ExportCursor::LedgerWithdrawals(_) => Some(ExportCursor::LedgerDeposits(None)),
+            ExportCursor::LedgerDeposits(_) => Some(ExportCursor::OrderLabels(None)),
+            ExportCursor::OrderLabels(_) => Some(ExportCursor::OrderCallbacks(None)),
+            ExportCursor::OrderCallbacks(_) => Some(ExportCursor::RecurringOrders(None)),
+            ExportCursor::RecurringOrders(_) => Some(ExportCursor::Pools(None)),
+            ExportCursor::Pools(_) => Some(ExportCursor::LpShares(None)),
+            ExportCursor::LpShares(_) => None,
+        }
+    }
+}
+
+// Changes with every balance, order status, event and transaction, so
+// create_export can tell whether the state moved between its calls
+#[derive(PartialEq)]
+struct StateFingerprint {
+    latest_event: u64,
+    latest_transaction: u64,
+    certified_root: [u8; 32],
+}
+
+impl StateFingerprint {
+    fn current() -> Self {
+        StateFingerprint {
+            latest_event: events::latest_seq(),
+            latest_transaction: transactions::latest_transaction_id(),
+            certified_root: certification::certified_root(),
+        }
+    }
+}
+
+// An export create_export is still gathering
+struct ExportBuild {
+    snapshot: StateSnapshot,
+    cursor: Option<ExportCursor>, // None once every store is gathered
+    fingerprint: StateFingerprint,
+    gathered: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub(crate) struct RecoveryStatus {
+    enabled: bool,
+    updated_by: Option<Principal>, // admin who last entered or left recovery mode
+    updated_at: Option<u64>,
+}
+
+impl Storable for RecoveryStatus {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode RecoveryStatus"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode RecoveryStatus")
+    }
+}
+
+thread_local! {
+    // The latest export, on the heap only: it is rebuilt on demand and an
+    // upgrade in the middle of a download simply means starting a new one
+    static CURRENT_EXPORT: RefCell<Option<Export>> = const { RefCell::new(None) };
+
+    static LAST_EXPORT_VERSION: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+
+    // Heap only like the export itself; an upgrade starts the gathering over
+    static EXPORT_BUILD: RefCell<Option<ExportBuild>> = const { RefCell::new(None) };
+
+    // Stable so an upgrade during a recovery can't reopen trading on the
+    // state being replaced
+    static RECOVERY_STATUS: RefCell<Cell<RecoveryStatus, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))), RecoveryStatus::default())
+            .expect("Cannot create the recovery status")
+    );
+
+    // Chunks uploaded by import_state, by index. Heap only: an import cut off
+    // by an upgrade is started over.
+    static STAGED_IMPORT: RefCell<BTreeMap<u32, Vec<u8>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// Gathers accounts, orders, the order counter and the side stores for an
+// export, up to `max_items` records per call, resuming where the last call
+// stopped. The call that gathers the last of them encodes everything as one
+// candid StateSnapshot split into chunks and replaces any earlier export.
+// Every chunk of a version has to belong to the same state, so a balance,
+// order status, event or transaction written between two calls drops what
+// was gathered and the next call starts over: pause trading first.
+#[ic_cdk::update]
+fn create_export(max_items: u32) -> Result<ExportProgress, Error> {
+    require_admin()?;
+    let budget = max_items.clamp(1, MAX_EXPORT_BATCH) as usize;
+
+    let fingerprint = StateFingerprint::current();
+    let mut build = match EXPORT_BUILD.with(|build| build.borrow_mut().take()) {
+        Some(build) if build.fingerprint != fingerprint => return Err(Error::ExportInvalidated),
+        Some(build) => build,
+        None => ExportBuild {
+            snapshot: StateSnapshot {
+                accounts: Vec::new(),
+                orders: Vec::new(),
+                order_counter: ORDER_COUNTER.with(|counter| counter.borrow().last_id()),
+                counter_offers: Some(Vec::new()),
+                pending_withdrawals: Some(Vec::new()),
+                ledger_withdrawals: Some(Vec::new()),
+                ledger_deposits: Some(Vec::new()),
+                order_labels: Some(Vec::new()),
+                order_callbacks: Some(Vec::new()),
+                recurring_orders: Some(Vec::new()),
+                pools: Some(Vec::new()),
+                lp_shares: Some(Vec::new()),
+            },
+            cursor: Some(ExportCursor::Accounts(None)),
+            fingerprint,
+            gathered: 0,
+        },
+    };
+    gather_records(&mut build, budget);
+    if build.cursor.is_some() {
+        let progress = ExportProgress {
+            gathered: build.gathered,
+            manifest: None,
+        };
+        EXPORT_BUILD.with(|stored| *stored.borrow_mut() = Some(build));
+        return Ok(progress);
+    }
+
+    let encoded = Encode!(&build.snapshot).expect("Failed to encode StateSnapshot");
+    let chunks: Vec<Vec<u8>> = encoded.chunks(MAX_EXPORT_CHUNK_BYTES).map(<[u8]>::to_vec).collect();
+
+    let version = LAST_EXPORT_VERSION.with(|last| {
+        last.set(last.get() + 1);
+        last.get()
+    });
+    let manifest = ExportManifest {
+        version,
+        created_at: time(),
+        total_bytes: encoded.len() as u64,
+        chunk_hashes: chunks.iter().map(|chunk| chunk_hash(chunk)).collect(),
+        snapshot_hash: chunk_hash(&encoded),
+    };
+    CURRENT_EXPORT.with(|export| {
+        *export.borrow_mut() = Some(Export {
+            manifest: manifest.clone(),
+            chunks,
+        })
+    });
+    Ok(ExportProgress {
+        gathered: build.gathered,
+        manifest: Some(manifest),
+    })
+}
+
+// Takes up to `budget` records into the build, moving on to the next store
+// whenever one runs out
+fn gather_records(build: &mut ExportBuild, mut budget: usize) {
+    while budget > 0 {
+        let Some(cursor) = build.cursor.as_mut() else {
+            return;
+        };
+        let snapshot = &mut build.snapshot;
+        let taken = match cursor {
+            ExportCursor::Accounts(after) => {
+                let page = USER_ACCOUNTS.with(|accounts| entries_after(&accounts.borrow(), after.clone(), budget));
+                let page = page.into_iter().map(|(principal, account)| (principal.clone(), (principal.into(), account)));
+                gather(page.collect(), after, &mut snapshot.accounts)
+            }
+            // Archived orders come back as live ones and are archived again later
+            ExportCursor::Orders(after) => {
+                let page = SWAP_ORDERS.with(|orders| entries_after(&orders.borrow(), *after, budget));
+                gather(page, after, &mut snapshot.orders)
+            }
+            ExportCursor::ArchivedOrders(after) => {
+                let page = ARCHIVED_ORDERS.with(|archive| entries_after(&archive.borrow(), *after, budget));
+                gather(page, after, &mut snapshot.orders)
+            }
+            ExportCursor::CounterOffers(after) => {
+                let page = counter_offers::export_offers(*after, budget);
+                gather(page, after, snapshot.counter_offers.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::PendingWithdrawals(after) => {
+                let page = pending_withdrawals::export_pending_withdrawals(*after, budget);
+                gather(page, after, snapshot.pending_withdrawals.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::LedgerWithdrawals(after) => {
+                let page = ledgers::export_withdrawals(*after, budget);
+                gather(page, after, snapshot.ledger_withdrawals.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::LedgerDeposits(after) => {
+                let page = ledgers::export_deposits(*after, budget);
+                gather(page, after, snapshot.ledger_deposits.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::OrderLabels(after) => {
+                let page = order_labels::export_labels(*after, budget);
+                gather(page, after, snapshot.order_labels.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::OrderCallbacks(after) => {
+                let page = fill_callbacks::export_callbacks(*after, budget);
+                gather(page, after, snapshot.order_callbacks.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::RecurringOrders(after) => {
+                let page = recurring::export_recurring_orders(*after, budget);
+                gather(page, after, snapshot.recurring_orders.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::Pools(after) => {
+                let page = pools::export_pools(after.clone(), budget);
+                gather(page, after, snapshot.pools.get_or_insert_with(Vec::new))
+            }
+            ExportCursor::LpShares(after) => {
+                let page = pools::export_lp_shares(after.clone(), budget);
+                gather(page, after, snapshot.lp_shares.get_or_insert_with(Vec::new))
+            }
+        };
+        build.gathered += taken as u64;
+        if taken < budget {
+            build.cursor = cursor.next();
+        }
+        budget -= taken;
+    }
+}
+
+// Appends one page of a store, leaving `after` at its last key, and returns
+// how many records it held
+fn gather<K, R>(page: Vec<(K, R)>, after: &mut Option<K>, records: &mut Vec<R>) -> usize {
+    let taken = page.len();
+    for (key, record) in page {
+        *after = Some(key);
+        records.push(record);
+    }
+    taken
+}
+
+// Up to `limit` entries of `map` after the key `after`, or from the first one
+// without it, for the stores create_export pages through
+pub(crate) fn entries_after<K: BoundedStorable + Ord + Clone, V: BoundedStorable>(
+    map: &StableBTreeMap<K, V, Memory>,
+    after: Option<K>,
+    limit: usize,
+) -> Vec<(K, V)> {
+    let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+    map.range((lower, Bound::Unbounded)).take(limit).collect()
+}
+
+#[ic_cdk::query]
+fn get_export_manifest() -> Result<ExportManifest, Error> {
+    require_admin()?;
+    CURRENT_EXPORT.with(|export| export.borrow().as_ref().map(|export| export.manifest.clone()))
+        .ok_or(Error::ExportNotFound)
+}
+
+#[ic_cdk::query]
+fn export_state(chunk: u32) -> Result<ExportChunk, Error> {
+    require_admin()?;
+    CURRENT_EXPORT.with(|export| {
+        let export = export.borrow();
+        let export = export.as_ref().ok_or(Error::ExportNotFound)?;
+        let data = export.chunks.get(chunk as usize).ok_or(Error::InvalidChunkIndex)?;
+        Ok(ExportChunk {
+            version: export.manifest.version,
+            index: chunk,
+            data: data.clone(),
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_recovery_status() -> RecoveryStatus {
+    RECOVERY_STATUS.with(|cell| cell.borrow().get().clone())
+}
+
+// Allows importing over existing state. Entering pauses trading; leaving
+// drops anything staged without touching the current state.
+#[ic_cdk::update]
+fn set_recovery_mode(enabled: bool) -> Result<(), Error> {
+    require_admin()?;
+    let status = RecoveryStatus {
+        enabled,
+        updated_by: Some(caller()),
+        updated_at: Some(time()),
+    };
+    RECOVERY_STATUS.with(|cell| cell.borrow_mut().set(status))
+        .expect("Failed to store the recovery status");
+    if enabled {
+        set_paused(true);
+    } else {
+        STAGED_IMPORT.with(|staged| staged.borrow_mut().clear());
+    }
+    Ok(())
+}
+
+// Stages one chunk of a StateSnapshot, as produced by export_state, and
+// returns its SHA-256 to compare with the export manifest. Uploading an index
+// again replaces it. Nothing is applied until finalize_import.
+#[ic_cdk::update]
+fn import_state(chunk_index: u32, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    require_admin()?;
+    require_import_allowed()?;
+    if chunk_index >= MAX_IMPORT_CHUNKS || data.is_empty() || data.len() > MAX_EXPORT_CHUNK_BYTES {
+        return Err(Error::InvalidChunkIndex);
+    }
+
+    set_paused(true);
+    let hash = chunk_hash(&data);
+    STAGED_IMPORT.with(|staged| staged.borrow_mut().insert(chunk_index, data));
+    Ok(hash)
+}
+
+// Replaces accounts, orders, the order counter and the side stores with the
+// staged snapshot once every chunk from 0 up is present and the whole matches
+// `expected_hash`, then rebuilds everything derived from them. Trading stays
+// paused for the admin to check the result and unpause. Needs the admins'
+// approval.
+#[ic_cdk::update]
+fn finalize_import(expected_hash: Vec<u8>) -> Result<(), Error> {
+    require_admin()?;
+    require_import_allowed()?;
+    // No other length can match, and the proposal would carry it
+    if expected_hash.len() != HASH_BYTES {
+        return Err(Error::ImportHashMismatch);
+    }
+    proposals::require_approval(ProposedAction::FinalizeImport {
+        expected_hash: expected_hash.clone(),
+    })?;
+    apply_import(expected_hash)
+}
+
+// Chunks staged after the proposal was made only land if they still match
+// the approved hash
+pub(crate) fn apply_import(expected_hash: Vec<u8>) -> Result<(), Error> {
+    require_import_allowed()?;
+
+    let encoded = STAGED_IMPORT.with(|staged| {
+        let staged = staged.borrow();
+        let contiguous = staged.keys().enumerate().all(|(position, index)| position as u32 == *index);
+        if staged.is_empty() || !contiguous {
+            return Err(Error::ImportIncomplete);
+        }
+        Ok(staged.values().flatten().copied().collect::<Vec<u8>>())
+    })?;
+    if chunk_hash(&encoded) != expected_hash {
+        return Err(Error::ImportHashMismatch);
+    }
+    let snapshot = Decode!(&encoded, StateSnapshot)
+        .or_else(|_| Decode!(&encoded, NarrowStateSnapshot).map(StateSnapshot::from))
+        .map_err(|_| Error::InvalidSnapshot)?;
+
+    restore_snapshot(snapshot);
+    STAGED_IMPORT.with(|staged| staged.borrow_mut().clear());
+    let status = RecoveryStatus {
+        enabled: false,
+        updated_by: Some(caller()),
+        updated_at: Some(time()),
+    };
+    RECOVERY_STATUS.with(|cell| cell.borrow_mut().set(status))
+        .expect("Failed to store the recovery status");
+    Ok(())
+}
+
+// Whether unpausing must wait for an import to finish or be abandoned
+pub(crate) fn import_in_progress() -> bool {
+    RECOVERY_STATUS.with(|cell| cell.borrow().get().enabled)
+        || STAGED_IMPORT.with(|staged| !staged.borrow().is_empty())
+}
+
+// Imports only ever land on a fresh canister or one the admin put in recovery
+// mode, never over live state by accident
+fn require_import_allowed() -> Result<(), Error> {
+    let recovering = RECOVERY_STATUS.with(|cell| cell.borrow().get().enabled);
+    let fresh = USER_ACCOUNTS.with(|accounts| accounts.borrow().is_empty())
+        && SWAP_ORDERS.with(|orders| orders.borrow().is_empty())
+        && ARCHIVED_ORDERS.with(|archive| archive.borrow().is_empty())
+        && ORDER_COUNTER.with(|counter| counter.borrow().last_id()) == 0;
+    if recovering || fresh {
+        Ok(())
+    } else {
+        Err(Error::ImportNotAllowed)
+    }
+}
+
+fn restore_snapshot(snapshot: StateSnapshot) {
+    USER_ACCOUNTS.with(|accounts| {
+        let mut accounts_borrowed = accounts.borrow_mut();
+        clear_map(&mut accounts_borrowed);
+        for (principal, account) in snapshot.accounts {
+            accounts_borrowed.insert(StorablePrincipal::from(principal), account);
+        }
+    });
+
+    ORDERS_BY_OWNER.with(|index| clear_map(&mut index.borrow_mut()));
+    ORDERS_BY_COUNTERPARTY.with(|index| clear_map(&mut index.borrow_mut()));
+    order_queries::clear_order_query_indexes();
+    ARCHIVED_ORDERS.with(|archive| clear_map(&mut archive.borrow_mut()));
+    // Never hand out an id an imported order already has
+    let highest_id = snapshot.orders.iter().map(|order| order.id).max().unwrap_or(0);
+    SWAP_ORDERS.with(|orders| {
+        let mut orders_borrowed = orders.borrow_mut();
+        clear_map(&mut orders_borrowed);
+        for swap_order in snapshot.orders {
+            if let Some(counterparty) = swap_order.counterparty {
+                ORDERS_BY_COUNTERPARTY.with(|index| {
+                    index.borrow_mut().insert((StorablePrincipal::from(counterparty), swap_order.id), ())
+                });
+            }
+            orders_borrowed.insert(swap_order.id, swap_order);
+        }
+    });
+    ORDER_COUNTER.with(|counter| counter.borrow_mut().set_last_id(snapshot.order_counter.max(highest_id)))
+        .expect("Failed to store the order counter");
+
+    // Nothing of the state being replaced stays behind, so locks below only
+    // count what the snapshot holds
+    counter_offers::restore_offers(snapshot.counter_offers.unwrap_or_default());
+    pending_withdrawals::restore_pending_withdrawals(snapshot.pending_withdrawals.unwrap_or_default());
+    ledgers::restore_journals(
+        snapshot.ledger_withdrawals.unwrap_or_default(),
+        snapshot.ledger_deposits.unwrap_or_default(),
+    );
+    order_labels::restore_labels(snapshot.order_labels.unwrap_or_default());
+    fill_callbacks::restore_callbacks(snapshot.order_callbacks.unwrap_or_default());
+    recurring::restore_recurring_orders(snapshot.recurring_orders.unwrap_or_default());
+    pools::restore_pools(snapshot.pools.unwrap_or_default(), snapshot.lp_shares.unwrap_or_default());
+    self_trade::clear_self_trades();
+    dedup::clear_deposit_results();
+
+    rebuild_locked_balances();
+    solvency::rebuild_totals();
+    rebuild_owner_index();
+    order_queries::rebuild_order_query_indexes();
+    rebuild_order_book();
+    limit_scan::rebuild_dormant_limit_index();
+    order_limits::rebuild_open_order_counts();
+    stats::recount_orders();
+    certification::rebuild_certified_state();
+}
+
+pub(crate) fn clear_map<K: BoundedStorable + Ord + Clone, V: BoundedStorable>(map: &mut StableBTreeMap<K, V, Memory>) {
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in keys {
+        map.remove(&key);
+    }
+}
+
+pub(crate) fn chunk_hash(chunk: &[u8]) -> Vec<u8> {
+    Sha256::digest(chunk).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::MemoryManager;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    fn map_of(keys: impl IntoIterator<Item = u64>) -> StableBTreeMap<u64, u64, Memory> {
+        let mut map = StableBTreeMap::init(MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)));
+        for key in keys {
+            map.insert(key, key * 10);
+        }
+        map
+    }
+
+    #[test]
+    fn pages_resume_after_the_last_key_and_cover_every_entry() {
+        let map = map_of(1..=5);
+        let (mut after, mut records) = (None, Vec::new());
+
+        assert_eq!(gather(entries_after(&map, after, 2), &mut after, &mut records), 2);
+        assert_eq!(after, Some(2));
+        assert_eq!(gather(entries_after(&map, after, 2), &mut after, &mut records), 2);
+        assert_eq!(gather(entries_after(&map, after, 2), &mut after, &mut records), 1);
+        assert_eq!(gather(entries_after(&map, after, 2), &mut after, &mut records), 0);
+        assert_eq!(records, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn clearing_a_map_removes_every_entry() {
+        let mut map = map_of(1..=3);
+
+        clear_map(&mut map);
+
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::currencies::CurrencySymbol;
+use crate::{fees, Memory, UserAccount, MEMORY_MANAGER, USER_ACCOUNTS};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Sums over every account, the fee account included
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct BalanceTotals {
+    available: u128,
+    locked: u128, // escrowed by open and accepted orders
+}
+
+impl Storable for BalanceTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode BalanceTotals"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode BalanceTotals")
+    }
+}
+
+impl BoundedStorable for BalanceTotals {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Currency -> totals over all accounts, moved by every BalanceChanges
+    // commit and recomputed by rebuild_derived_state and imports
+    static CURRENCY_TOTALS: RefCell<StableBTreeMap<CurrencySymbol, BalanceTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CurrencySolvency {
+    currency: String,
+    user_balances: u128, // available to their owners, the fee account left out
+    escrowed: u128,      // held for open and accepted orders
+    fees: u128,          // available in the fee account
+    total: u128,         // what the canister owes in the currency
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SolvencyReport {
+    currencies: Vec<CurrencySolvency>,
+    generated_at: u64,
+}
+
+// Per-currency liabilities from the running totals, so the cost doesn't grow
+// with the number of accounts. Compare `total` with what the ledgers hold for
+// the canister.
+#[ic_cdk::query]
+fn get_solvency_report() -> SolvencyReport {
+    let fee_account = USER_ACCOUNTS.with(|accounts| accounts.borrow().get(&fees::fee_account())).unwrap_or_default();
+    let currencies = CURRENCY_TOTALS.with(|totals| {
+        totals
+            .borrow()
+            .iter()
+            .map(|(currency, totals)| {
+                let fees = fee_account.balance(&currency.0);
+                CurrencySolvency {
+                    user_balances: totals.available.saturating_sub(fees),
+                    escrowed: totals.locked,
+                    fees,
+                    total: totals.available.saturating_add(totals.locked),
+                    currency: currency.0,
+                }
+            })
+            .collect()
+    });
+
+    SolvencyReport {
+        currencies,
+        generated_at: time(),
+    }
+}
+
+// A currency whose running totals disagree with a scan of the accounts, or
+// whose scanned locked amounts disagree with the open orders
+#[cfg(debug_assertions)]
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TotalsDrift {
+    currency: String,
+    tracked_available: u128,
+    scanned_available: u128,
+    tracked_locked: u128,
+    scanned_locked: u128,
+    escrowed_by_orders: u128,
+}
+
+#[cfg(debug_assertions)]
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct InvariantReport {
+    accounts_scanned: u64,
+    drift: Vec<TotalsDrift>,                               // empty when everything adds up
+    order_count_drift: Vec<crate::stats::OrderCountDrift>, // likewise for the counters behind count_orders
+}
+
+// Recomputes the totals from every account, the escrow from every open order
+// and the order counters from every order, and reports where they drift from
+// the running values. Scans all of them, so it is only built into debug
+// canisters.
+#[cfg(debug_assertions)]
+#[ic_cdk::update]
+fn verify_invariants() -> Result<InvariantReport, crate::Error> {
+    crate::admin::require_admin()?;
+    let scanned = scan_totals();
+    let mut escrowed: BTreeMap<String, u128> = BTreeMap::new();
+    for locked in crate::escrow_by_owner().into_values() {
+        for (currency, amount) in locked {
+            let total = escrowed.entry(currency).or_default();
+            *total = total.saturating_add(amount);
+        }
+    }
+    let tracked: BTreeMap<String, BalanceTotals> =
+        CURRENCY_TOTALS.with(|totals| totals.borrow().iter().map(|(currency, totals)| (currency.0, totals)).collect());
+
+    let currencies: BTreeSet<&String> = scanned.keys().chain(tracked.keys()).chain(escrowed.keys()).collect();
+    let drift = currencies
+        .into_iter()
+        .filter_map(|currency| {
+            let tracked = tracked.get(currency).cloned().unwrap_or_default();
+            let scanned = scanned.get(currency).cloned().unwrap_or_default();
+            let escrowed_by_orders = escrowed.get(currency).copied().unwrap_or(0);
+            (tracked != scanned || scanned.locked != escrowed_by_orders).then(|| TotalsDrift {
+                currency: currency.clone(),
+                tracked_available: tracked.available,
+                scanned_available: scanned.available,
+                tracked_locked: tracked.locked,
+                scanned_locked: scanned.locked,
+                escrowed_by_orders,
+            })
+        })
+        .collect();
+
+    Ok(InvariantReport {
+        accounts_scanned: USER_ACCOUNTS.with(|accounts| accounts.borrow().len()),
+        drift,
+        order_count_drift: crate::stats::order_count_drift(),
+    })
+}
+
+// Called by BalanceChanges::commit for each account it writes
+pub(crate) fn record_account_change(previous: &UserAccount, current: &UserAccount) {
+    let mut currencies: BTreeSet<&String> = previous.balances.keys().chain(current.balances.keys()).collect();
+    for locked in [&previous.locked, &current.locked].into_iter().flatten() {
+        currencies.extend(locked.keys());
+    }
+
+    CURRENCY_TOTALS.with(|totals| {
+        let mut totals_borrowed = totals.borrow_mut();
+        for currency in currencies {
+            let (available_before, available_after) = (previous.balance(currency), current.balance(currency));
+            let (locked_before, locked_after) = (previous.locked(currency), current.locked(currency));
+            if available_before == available_after && locked_before == locked_after {
+                continue;
+            }
+            let key = CurrencySymbol(currency.clone());
+            let mut currency_totals = totals_borrowed.get(&key).unwrap_or_default();
+            currency_totals.available =
+                currency_totals.available.saturating_sub(available_before).saturating_add(available_after);
+            currency_totals.locked = currency_totals.locked.saturating_sub(locked_before).saturating_add(locked_after);
+            totals_borrowed.insert(key, currency_totals);
+        }
+    });
+}
+
+// Replaces the running totals with a scan of the accounts, for after upgrade
+// migrations and imports that write accounts without a commit
+pub(crate) fn rebuild_totals() {
+    let scanned = scan_totals();
+    CURRENCY_TOTALS.with(|totals| {
+        let mut totals_borrowed = totals.borrow_mut();
+        let stale: Vec<CurrencySymbol> = totals_borrowed.iter().map(|(currency, _)| currency).collect();
+        for currency in stale {
+            totals_borrowed.remove(&currency);
+        }
+        for (currency, currency_totals) in scanned {
+            totals_borrowed.insert(CurrencySymbol(currency), currency_totals);
+        }
+    });
+}
+
+fn scan_totals() -> BTreeMap<String, BalanceTotals> {
+    let mut scanned: BTreeMap<String, BalanceTotals> = BTreeMap::new();
+    USER_ACCOUNTS.with(|accounts| {
+        for (_, user_account) in accounts.borrow().iter() {
+            for (currency, amount) in &user_account.balances {
+                let totals = scanned.entry(currency.clone()).or_default();
+                totals.available = totals.available.saturating_add(*amount);
+            }
+            for (currency, amount) in user_account.locked.iter().flatten() {
+                let totals = scanned.entry(currency.clone()).or_default();
+                totals.locked = totals.locked.saturating_add(*amount);
+            }
+        }
+    });
+    scanned
+}
@@ -0,0 +1,360 @@
+use crate::archive::{self, ARCHIVED_ORDERS};
+use crate::{CurrencyPair, Memory, SwapOrder, SwapStatus, MEMORY_MANAGER, SWAP_ORDERS, USER_ACCOUNTS};
+use candid::{Decode, Encode};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+// Hourly buckets summed for the rolling volume, the current hour included
+const ROLLING_VOLUME_HOURS: u64 = 24;
+
+// Every status in the order get_stats reports them. The position is the key
+// the count is stored under, so new statuses go at the end.
+const ALL_STATUSES: [SwapStatus; 7] = [
+    SwapStatus::Created,
+    SwapStatus::PartiallyFilled,
+    SwapStatus::Executed,
+    SwapStatus::Cancelled,
+    SwapStatus::Expired,
+    SwapStatus::Killed,
+    SwapStatus::Accepted,
+];
+
+// Amounts traded on a pair, in the currencies the orders sold and received
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+struct Volume {
+    from_volume: u128, // from_currency delivered by order owners
+    to_volume: u128,   // to_currency paid by executors, fees included
+}
+
+impl Volume {
+    fn add(&mut self, other: &Volume) {
+        self.from_volume = self.from_volume.saturating_add(other.from_volume);
+        self.to_volume = self.to_volume.saturating_add(other.to_volume);
+    }
+}
+
+impl Storable for Volume {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Volume"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Volume")
+    }
+}
+
+impl BoundedStorable for Volume {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Status index (see ALL_STATUSES) -> number of orders currently in it
+    static ORDER_COUNTS: RefCell<StableBTreeMap<u8, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // (pair, status index) -> number of the pair's orders currently in it,
+    // summing to ORDER_COUNTS over all pairs
+    static ORDER_COUNTS_BY_PAIR: RefCell<StableBTreeMap<(CurrencyPair, u8), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105)))
+    ));
+
+    static TOTAL_VOLUME: RefCell<StableBTreeMap<CurrencyPair, Volume, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // (pair, hours since epoch) -> volume traded in that hour. Buckets older
+    // than the rolling window are pruned when the pair trades again.
+    static HOURLY_VOLUME: RefCell<StableBTreeMap<(CurrencyPair, u64), Volume, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PairStats {
+    from_currency: String,
+    to_currency: String,
+    total_volume: Volume,
+    volume_24h: Volume,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Stats {
+    users: u64,
+    orders_by_status: Vec<(SwapStatus, u64)>,
+    pairs: Vec<PairStats>, // one entry per direction that has traded
+}
+
+// A status count that disagrees with a scan of the stored orders; pair is
+// None for the count over all pairs
+#[cfg(debug_assertions)]
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrderCountDrift {
+    pair: Option<(String, String)>,
+    status: SwapStatus,
+    tracked: u64,
+    scanned: u64,
+}
+
+#[ic_cdk::query]
+fn count_users() -> u64 {
+    USER_ACCOUNTS.with(|accounts| accounts.borrow().len())
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_stats() -> Stats {
+    let current_hour = time() / NANOS_PER_HOUR;
+    let orders_by_status = ALL_STATUSES
+        .iter()
+        .map(|status| {
+            let count = ORDER_COUNTS.with(|counts| counts.borrow().get(&status_index(status))).unwrap_or(0);
+            (status.clone(), count)
+        })
+        .collect();
+    let pairs = TOTAL_VOLUME.with(|volumes| {
+        volumes
+            .borrow()
+            .iter()
+            .map(|(pair, total_volume)| PairStats {
+                volume_24h: rolling_volume(&pair, current_hour),
+                from_currency: pair.from_currency,
+                to_currency: pair.to_currency,
+                total_volume,
+            })
+            .collect()
+    });
+
+    Stats {
+        users: USER_ACCOUNTS.with(|accounts| accounts.borrow().len()),
+        orders_by_status,
+        pairs,
+    }
+}
+
+// Moves an order between status counters, overall and for its pair.
+// `previous` is None for a new order.
+pub(crate) fn record_status_change(previous: Option<&SwapStatus>, swap_order: &SwapOrder) {
+    if previous == Some(&swap_order.status) {
+        return;
+    }
+    if let Some(previous) = previous {
+        uncount(swap_order, previous);
+    }
+    let index = status_index(&swap_order.status);
+    ORDER_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let count = counts_borrowed.get(&index).unwrap_or(0);
+        counts_borrowed.insert(index, count + 1);
+    });
+    ORDER_COUNTS_BY_PAIR.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let key = (pair_of(swap_order), index);
+        let count = counts_borrowed.get(&key).unwrap_or(0);
+        counts_borrowed.insert(key, count + 1);
+    });
+}
+
+// Called when prune_archive deletes an order, so the counters keep summing
+// to the stored orders
+pub(crate) fn record_order_removed(swap_order: &SwapOrder) {
+    uncount(swap_order, &swap_order.status);
+}
+
+// Orders currently in any of the statuses, every status when None, on the
+// pair or on all pairs. Reads at most one counter per status.
+pub(crate) fn order_count(pair: Option<&CurrencyPair>, statuses: Option<&[SwapStatus]>) -> u64 {
+    let mut indexes: Vec<u8> = statuses.unwrap_or(&ALL_STATUSES).iter().map(status_index).collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+        .into_iter()
+        .map(|index| match pair {
+            Some(pair) => ORDER_COUNTS_BY_PAIR.with(|counts| counts.borrow().get(&(pair.clone(), index))),
+            None => ORDER_COUNTS.with(|counts| counts.borrow().get(&index)),
+        })
+        .fold(0u64, |total, count| total.saturating_add(count.unwrap_or(0)))
+}
+
+// Adds a fill to the pair's running total and its current hourly bucket
+pub(crate) fn record_fill_volume(from_currency: &str, to_currency: &str, from_amount: u128, to_amount: u128) {
+    let pair = CurrencyPair::new(from_currency, to_currency);
+    let fill = Volume {
+        from_volume: from_amount,
+        to_volume: to_amount,
+    };
+    let current_hour = time() / NANOS_PER_HOUR;
+
+    TOTAL_VOLUME.with(|volumes| {
+        let mut volumes_borrowed = volumes.borrow_mut();
+        let mut total = volumes_borrowed.get(&pair).unwrap_or_default();
+        total.add(&fill);
+        volumes_borrowed.insert(pair.clone(), total);
+    });
+
+    HOURLY_VOLUME.with(|volumes| {
+        let mut volumes_borrowed = volumes.borrow_mut();
+        let expired: Vec<(CurrencyPair, u64)> = volumes_borrowed
+            .range((pair.clone(), 0)..(pair.clone(), window_start(current_hour)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            volumes_borrowed.remove(&key);
+        }
+
+        let key = (pair, current_hour);
+        let mut bucket = volumes_borrowed.get(&key).unwrap_or_default();
+        bucket.add(&fill);
+        volumes_borrowed.insert(key, bucket);
+    });
+}
+
+// Counts orders stored before the counters existed. The counters always sum
+// to the number of stored orders, archived ones included, so a mismatch means they need seeding.
+pub(crate) fn seed_order_counts() {
+    let counted = ORDER_COUNTS.with(|counts| counts.borrow().iter().map(|(_, count)| count).sum::<u64>());
+    let counted_by_pair =
+        ORDER_COUNTS_BY_PAIR.with(|counts| counts.borrow().iter().map(|(_, count)| count).sum::<u64>());
+    let stored = SWAP_ORDERS.with(|orders| orders.borrow().len()) + archive::archived_order_count();
+    if counted == stored && counted_by_pair == stored {
+        return;
+    }
+    recount_orders();
+}
+
+// Recounts every status from the stored orders, replacing the current counts
+pub(crate) fn recount_orders() {
+    let (seeded, seeded_by_pair) = scan_order_counts();
+    ORDER_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        for (index, count) in seeded.into_iter().enumerate() {
+            counts_borrowed.insert(index as u8, count);
+        }
+    });
+    ORDER_COUNTS_BY_PAIR.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let stale: Vec<(CurrencyPair, u8)> = counts_borrowed.iter().map(|(key, _)| key).collect();
+        for key in stale {
+            counts_borrowed.remove(&key);
+        }
+        for (key, count) in seeded_by_pair {
+            counts_borrowed.insert(key, count);
+        }
+    });
+}
+
+// Compares every counter with a scan of the stored orders, for
+// verify_invariants
+#[cfg(debug_assertions)]
+pub(crate) fn order_count_drift() -> Vec<OrderCountDrift> {
+    let (scanned, scanned_by_pair) = scan_order_counts();
+    let mut drift: Vec<OrderCountDrift> = ALL_STATUSES
+        .iter()
+        .zip(scanned)
+        .filter_map(|(status, scanned)| {
+            let tracked = ORDER_COUNTS.with(|counts| counts.borrow().get(&status_index(status))).unwrap_or(0);
+            (tracked != scanned).then(|| OrderCountDrift {
+                pair: None,
+                status: status.clone(),
+                tracked,
+                scanned,
+            })
+        })
+        .collect();
+
+    let tracked_by_pair: BTreeMap<(CurrencyPair, u8), u64> =
+        ORDER_COUNTS_BY_PAIR.with(|counts| counts.borrow().iter().filter(|(_, count)| *count > 0).collect());
+    let keys: std::collections::BTreeSet<&(CurrencyPair, u8)> =
+        tracked_by_pair.keys().chain(scanned_by_pair.keys()).collect();
+    for key in keys {
+        let tracked = tracked_by_pair.get(key).copied().unwrap_or(0);
+        let scanned = scanned_by_pair.get(key).copied().unwrap_or(0);
+        if tracked != scanned {
+            let (pair, index) = key.clone();
+            drift.push(OrderCountDrift {
+                pair: Some((pair.from_currency, pair.to_currency)),
+                status: ALL_STATUSES[index as usize].clone(),
+                tracked,
+                scanned,
+            });
+        }
+    }
+    drift
+}
+
+// (from_volume, to_volume) traded on the pair in the rolling window
+pub(crate) fn volume_24h(from_currency: &str, to_currency: &str) -> (u128, u128) {
+    let volume = rolling_volume(&CurrencyPair::new(from_currency, to_currency), time() / NANOS_PER_HOUR);
+    (volume.from_volume, volume.to_volume)
+}
+
+fn rolling_volume(pair: &CurrencyPair, current_hour: u64) -> Volume {
+    HOURLY_VOLUME.with(|volumes| {
+        let mut volume = Volume::default();
+        for (_, bucket) in volumes
+            .borrow()
+            .range((pair.clone(), window_start(current_hour))..=(pair.clone(), current_hour))
+        {
+            volume.add(&bucket);
+        }
+        volume
+    })
+}
+
+// Status counts over all pairs, indexed like ALL_STATUSES, and per pair,
+// from the stored orders
+fn scan_order_counts() -> ([u64; ALL_STATUSES.len()], BTreeMap<(CurrencyPair, u8), u64>) {
+    let mut counts = [0u64; ALL_STATUSES.len()];
+    let mut counts_by_pair: BTreeMap<(CurrencyPair, u8), u64> = BTreeMap::new();
+    let mut count = |swap_order: SwapOrder| {
+        let index = status_index(&swap_order.status);
+        counts[index as usize] += 1;
+        *counts_by_pair.entry((pair_of(&swap_order), index)).or_default() += 1;
+    };
+    SWAP_ORDERS.with(|orders| orders.borrow().iter().for_each(|(_, swap_order)| count(swap_order)));
+    ARCHIVED_ORDERS.with(|archive| archive.borrow().iter().for_each(|(_, swap_order)| count(swap_order)));
+    (counts, counts_by_pair)
+}
+
+fn uncount(swap_order: &SwapOrder, status: &SwapStatus) {
+    let index = status_index(status);
+    ORDER_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let count = counts_borrowed.get(&index).unwrap_or(0);
+        counts_borrowed.insert(index, count.saturating_sub(1));
+    });
+    ORDER_COUNTS_BY_PAIR.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let key = (pair_of(swap_order), index);
+        let count = counts_borrowed.get(&key).unwrap_or(0);
+        counts_borrowed.insert(key, count.saturating_sub(1));
+    });
+}
+
+fn pair_of(swap_order: &SwapOrder) -> CurrencyPair {
+    CurrencyPair::new(&swap_order.from_currency, &swap_order.to_currency)
+}
+
+fn window_start(current_hour: u64) -> u64 {
+    (current_hour + 1).saturating_sub(ROLLING_VOLUME_HOURS)
+}
+
+fn status_index(status: &SwapStatus) -> u8 {
+    ALL_STATUSES
+        .iter()
+        .position(|candidate| candidate == status)
+        .expect("Every status is listed in ALL_STATUSES") as u8
+}
@@ -0,0 +1,147 @@
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::{book_side, stats, CurrencyPair, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// The most recent fill of orders selling from_currency for to_currency
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LastTrade {
+    from_amount: u128, // delivered by the order owner
+    to_amount: u128,   // paid by the executor
+    traded_at: u64,
+}
+
+impl Storable for LastTrade {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode LastTrade"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode LastTrade")
+    }
+}
+
+impl BoundedStorable for LastTrade {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static LAST_TRADES: RefCell<StableBTreeMap<CurrencyPair, LastTrade, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+}
+
+// Prices are in units of to_currency per unit of from_currency, like rates.
+// Sides and prices without orders or trades behind them are None.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Ticker {
+    from_currency: String,
+    to_currency: String,
+    best_bid: Option<f64>, // best price an order buying from_currency pays
+    best_ask: Option<f64>, // best price an order selling from_currency asks
+    mid: Option<f64>,
+    spread: Option<f64>, // best_ask - best_bid
+    last_price: Option<f64>,
+    last_traded_at: Option<u64>,
+    from_volume_24h: u128, // from_currency traded in either direction
+    to_volume_24h: u128,
+}
+
+// Reads the head of each side of the book and the pair's last trade, so the
+// cost doesn't grow with the number of open orders
+#[ic_cdk::query]
+fn get_ticker(from_currency: String, to_currency: String) -> Result<Ticker, Error> {
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+
+    // Asks sell from_currency, so their price is read as it is; bids sell
+    // to_currency and their price is inverted
+    let best_ask = best_order_price(&from_currency, &to_currency)
+        .map(|(from_amount, to_amount)| ratio(to_amount, from_amount));
+    let best_bid = best_order_price(&to_currency, &from_currency)
+        .map(|(from_amount, to_amount)| ratio(from_amount, to_amount));
+    let (mid, spread) = match (best_bid, best_ask) {
+        (Some(bid), Some(ask)) => (Some((bid + ask) / 2.0), Some(ask - bid)),
+        _ => (None, None),
+    };
+
+    let last_ask = last_trade(&from_currency, &to_currency)
+        .map(|trade| (trade.traded_at, ratio(trade.to_amount, trade.from_amount)));
+    let last_bid = last_trade(&to_currency, &from_currency)
+        .map(|trade| (trade.traded_at, ratio(trade.from_amount, trade.to_amount)));
+    let last = match (last_ask, last_bid) {
+        (Some(ask), Some(bid)) => Some(if ask.0 >= bid.0 { ask } else { bid }),
+        (ask, bid) => ask.or(bid),
+    };
+
+    let (ask_from_volume, ask_to_volume) = stats::volume_24h(&from_currency, &to_currency);
+    let (bid_from_volume, bid_to_volume) = stats::volume_24h(&to_currency, &from_currency);
+
+    Ok(Ticker {
+        best_bid,
+        best_ask,
+        mid,
+        spread,
+        last_price: last.map(|(_, price)| price),
+        last_traded_at: last.map(|(traded_at, _)| traded_at),
+        from_volume_24h: ask_from_volume.saturating_add(bid_to_volume),
+        to_volume_24h: ask_to_volume.saturating_add(bid_from_volume),
+        from_currency,
+        to_currency,
+    })
+}
+
+// Called by settle_fill with the amounts of every fill
+pub(crate) fn record_trade(from_currency: &str, to_currency: &str, from_amount: u128, to_amount: u128) {
+    let trade = LastTrade {
+        from_amount,
+        to_amount,
+        traded_at: time(),
+    };
+    LAST_TRADES.with(|trades| trades.borrow_mut().insert(CurrencyPair::new(from_currency, to_currency), trade));
+}
+
+fn last_trade(from_currency: &str, to_currency: &str) -> Option<LastTrade> {
+    LAST_TRADES.with(|trades| trades.borrow().get(&CurrencyPair::new(from_currency, to_currency)))
+}
+
+// (from_amount, to_amount) of the cheapest order selling `from_currency` for
+// `to_currency` that anyone may fill. OTC orders aren't on the public book.
+fn best_order_price(from_currency: &str, to_currency: &str) -> Option<(u128, u128)> {
+    book_side(from_currency, to_currency).next().map(|swap_order| (swap_order.from_amount, swap_order.to_amount))
+}
+
+fn ratio(numerator: u128, denominator: u128) -> f64 {
+    numerator as f64 / denominator as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{decoded_orders, list_asks};
+    use candid::Principal;
+
+    #[test]
+    fn the_best_price_leaves_otc_orders_out_and_reads_one_order() {
+        list_asks(1, 1..=5, Some(Principal::from_slice(&[40])));
+        list_asks(100, 30..=49, None);
+
+        let decoded_before = decoded_orders();
+        assert_eq!(best_order_price("EUR", "USD"), Some((40, 30)));
+        assert_eq!(decoded_orders() - decoded_before, 1);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::candles::stored_pair;
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::{CurrencyPair, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Trades kept per pair; each new one overwrites the oldest
+const TRADES_KEPT: u64 = 500;
+
+// Which side the executor of a fill took, as seen from from_currency
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+pub(crate) enum TradeSide {
+    Buy,  // the executor received from_currency
+    Sell, // the executor paid with from_currency
+}
+
+impl TradeSide {
+    fn flipped(self) -> TradeSide {
+        match self {
+            TradeSide::Buy => TradeSide::Sell,
+            TradeSide::Sell => TradeSide::Buy,
+        }
+    }
+}
+
+// A fill as the public feed shows it. Neither the principals nor the order id
+// are kept, since get_swap_order would lead from the id to the owner.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecentTrade {
+    price: f64,       // units of to_currency per unit of from_currency
+    size: u128,       // from_currency traded
+    quote_size: u128, // to_currency traded
+    side: TradeSide,
+    traded_at: u64,
+}
+
+impl RecentTrade {
+    // The same trade seen from the other currency
+    fn inverted(self) -> RecentTrade {
+        RecentTrade {
+            price: 1.0 / self.price,
+            size: self.quote_size,
+            quote_size: self.size,
+            side: self.side.flipped(),
+            traded_at: self.traded_at,
+        }
+    }
+}
+
+impl Storable for RecentTrade {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode RecentTrade"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode RecentTrade")
+    }
+}
+
+impl BoundedStorable for RecentTrade {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (pair, trade number % TRADES_KEPT) -> trade, pairs stored as candles are
+    // (see candles::stored_pair)
+    static RECENT_TRADES: RefCell<StableBTreeMap<(CurrencyPair, u64), RecentTrade, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+
+    // Trades recorded per pair so far, which places the next one in the ring
+    static TRADE_COUNTS: RefCell<StableBTreeMap<CurrencyPair, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+}
+
+// The pair's most recent trades in either direction, newest first
+#[ic_cdk::query]
+fn get_recent_trades(from_currency: String, to_currency: String, limit: u32) -> Result<Vec<RecentTrade>, Error> {
+    let from_currency = normalize_currency(&from_currency);
+    let to_currency = normalize_currency(&to_currency);
+    for currency in [&from_currency, &to_currency] {
+        if !is_known_currency(currency) {
+            return Err(Error::InvalidCurrency { provided: currency.clone() });
+        }
+    }
+
+    let (pair, inverted) = stored_pair(&from_currency, &to_currency);
+    let count = TRADE_COUNTS.with(|counts| counts.borrow().get(&pair)).unwrap_or(0);
+    let limit = (limit as u64).min(TRADES_KEPT).min(count);
+    Ok(RECENT_TRADES.with(|trades| {
+        let trades = trades.borrow();
+        (count - limit..count)
+            .rev()
+            .filter_map(|number| trades.get(&(pair.clone(), number % TRADES_KEPT)))
+            .map(|trade| if inverted { trade.inverted() } else { trade })
+            .collect()
+    }))
+}
+
+// Called by settle_fill with the amounts of every fill; the executor bought
+// the order's from_currency
+pub(crate) fn record_trade(from_currency: &str, to_currency: &str, from_amount: u128, to_amount: u128) {
+    let (pair, inverted) = stored_pair(from_currency, to_currency);
+    let trade = RecentTrade {
+        price: to_amount as f64 / from_amount as f64,
+        size: from_amount,
+        quote_size: to_amount,
+        side: TradeSide::Buy,
+        traded_at: time(),
+    };
+    let trade = if inverted { trade.inverted() } else { trade };
+
+    let number = TRADE_COUNTS.with(|counts| {
+        let mut counts_borrowed = counts.borrow_mut();
+        let number = counts_borrowed.get(&pair).unwrap_or(0);
+        counts_borrowed.insert(pair.clone(), number + 1);
+        number
+    });
+    RECENT_TRADES.with(|trades| trades.borrow_mut().insert((pair, number % TRADES_KEPT), trade));
+}
@@ -0,0 +1,267 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, StorablePrincipal, SwapOrder, SwapStatus, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Daily buckets summed for a profile, the current day included
+const PROFILE_WINDOW_DAYS: u64 = 7;
+
+const BPS_DENOMINATOR: u64 = 10_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+pub(crate) enum CancelRatioLimit {
+    Disabled,
+    Enabled {
+        max_cancel_ratio_bps: u16, // cancelled per 10,000 orders finished in the last 7 days
+        min_finished_orders: u64,  // the ratio isn't enforced below this many
+        block_secs: u64,           // how long order creation stays blocked
+    },
+}
+
+impl Storable for CancelRatioLimit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode CancelRatioLimit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode CancelRatioLimit")
+    }
+}
+
+impl BoundedStorable for CancelRatioLimit {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// How a principal's orders finished on one day
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug)]
+struct Outcomes {
+    executed: u64,
+    cancelled: u64, // by the owner; cancellations by the admin aren't counted
+    expired: u64,
+}
+
+impl Outcomes {
+    fn add(&mut self, other: &Outcomes) {
+        self.executed = self.executed.saturating_add(other.executed);
+        self.cancelled = self.cancelled.saturating_add(other.cancelled);
+        self.expired = self.expired.saturating_add(other.expired);
+    }
+
+    fn finished(&self) -> u64 {
+        self.executed.saturating_add(self.cancelled).saturating_add(self.expired)
+    }
+
+    // None before any order finished
+    fn cancel_ratio_bps(&self) -> Option<u16> {
+        let finished = self.finished();
+        (finished > 0).then(|| (self.cancelled.saturating_mul(BPS_DENOMINATOR) / finished) as u16)
+    }
+}
+
+impl Storable for Outcomes {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Outcomes"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Outcomes")
+    }
+}
+
+impl BoundedStorable for Outcomes {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TradingProfile {
+    executed_7d: u64,
+    cancelled_7d: u64,
+    expired_7d: u64,
+    cancel_ratio_bps: Option<u16>, // cancelled per 10,000 finished, None before any finished
+    blocked_until: Option<u64>,    // while order creation is blocked under the cancel ratio limit
+}
+
+thread_local! {
+    // (principal, days since epoch) -> how its orders finished that day.
+    // Buckets older than the window are pruned when another order finishes.
+    static OUTCOMES: RefCell<StableBTreeMap<(StorablePrincipal, u64), Outcomes, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106)))
+    ));
+
+    static CANCEL_RATIO_LIMIT: RefCell<Cell<CancelRatioLimit, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107))), CancelRatioLimit::Disabled)
+            .expect("Cannot create the cancel ratio limit")
+    );
+
+    // Principal -> (blocked until, cancel ratio in bps that caused the block)
+    static CREATION_BLOCKS: RefCell<StableBTreeMap<StorablePrincipal, (u64, u16), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108)))
+    ));
+}
+
+#[ic_cdk::query]
+fn get_user_trading_profile(principal: Principal) -> Result<TradingProfile, Error> {
+    require_admin()?;
+    let principal = StorablePrincipal::from(principal);
+    let outcomes = outcomes_in_window(&principal, time() / NANOS_PER_DAY);
+    Ok(TradingProfile {
+        executed_7d: outcomes.executed,
+        cancelled_7d: outcomes.cancelled,
+        expired_7d: outcomes.expired,
+        cancel_ratio_bps: outcomes.cancel_ratio_bps(),
+        blocked_until: active_block(&principal).map(|(until, _)| until),
+    })
+}
+
+#[ic_cdk::query]
+fn get_cancel_ratio_limit() -> CancelRatioLimit {
+    CANCEL_RATIO_LIMIT.with(|cell| *cell.borrow().get())
+}
+
+// Applies from the next cancellation on; blocks already in place run out as
+// they were set
+#[ic_cdk::update]
+fn set_cancel_ratio_limit(limit: CancelRatioLimit) -> Result<(), Error> {
+    require_admin()?;
+    if let CancelRatioLimit::Enabled {
+        max_cancel_ratio_bps,
+        block_secs,
+        ..
+    } = limit
+    {
+        if u64::from(max_cancel_ratio_bps) >= BPS_DENOMINATOR || block_secs == 0 {
+            return Err(Error::InvalidCancelRatioLimit);
+        }
+        block_secs.checked_mul(1_000_000_000).ok_or(Error::Overflow)?;
+    }
+    CANCEL_RATIO_LIMIT.with(|cell| cell.borrow_mut().set(limit))
+        .expect("Failed to store the cancel ratio limit");
+    Ok(())
+}
+
+// Lifts a block before it runs out. The principal's history is kept, so its
+// next cancellation can block it again.
+#[ic_cdk::update]
+fn clear_order_creation_block(principal: Principal) -> Result<(), Error> {
+    require_admin()?;
+    CREATION_BLOCKS.with(|blocks| blocks.borrow_mut().remove(&StorablePrincipal::from(principal)));
+    Ok(())
+}
+
+// Fails while the principal is blocked from placing orders
+pub(crate) fn check_not_blocked(principal: &StorablePrincipal) -> Result<(), Error> {
+    match active_block(principal) {
+        Some((until, cancel_ratio_bps)) => Err(Error::OrderCreationBlocked { until, cancel_ratio_bps }),
+        None => Ok(()),
+    }
+}
+
+// Called by store_order with the status before the write. Counts the order
+// once it is executed, cancelled by its owner or expired, and checks the
+// owner against the cancel ratio limit after a cancellation.
+pub(crate) fn record_outcome(previous: Option<&SwapStatus>, swap_order: &SwapOrder) {
+    if previous == Some(&swap_order.status) {
+        return;
+    }
+    let outcome = match swap_order.status {
+        SwapStatus::Executed => Outcomes {
+            executed: 1,
+            ..Outcomes::default()
+        },
+        SwapStatus::Cancelled if swap_order.admin_cancel_reason.is_none() => Outcomes {
+            cancelled: 1,
+            ..Outcomes::default()
+        },
+        SwapStatus::Expired => Outcomes {
+            expired: 1,
+            ..Outcomes::default()
+        },
+        _ => return,
+    };
+
+    let principal = StorablePrincipal::from(swap_order.owner);
+    let today = time() / NANOS_PER_DAY;
+    OUTCOMES.with(|outcomes| {
+        let mut outcomes_borrowed = outcomes.borrow_mut();
+        let expired: Vec<(StorablePrincipal, u64)> = outcomes_borrowed
+            .range((principal.clone(), 0)..(principal.clone(), window_start(today)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            outcomes_borrowed.remove(&key);
+        }
+
+        let key = (principal.clone(), today);
+        let mut bucket = outcomes_borrowed.get(&key).unwrap_or_default();
+        bucket.add(&outcome);
+        outcomes_borrowed.insert(key, bucket);
+    });
+
+    if outcome.cancelled > 0 {
+        enforce_cancel_ratio(&principal, today);
+    }
+}
+
+fn enforce_cancel_ratio(principal: &StorablePrincipal, today: u64) {
+    let CancelRatioLimit::Enabled {
+        max_cancel_ratio_bps,
+        min_finished_orders,
+        block_secs,
+    } = get_cancel_ratio_limit()
+    else {
+        return;
+    };
+    let outcomes = outcomes_in_window(principal, today);
+    let Some(cancel_ratio_bps) = outcomes.cancel_ratio_bps() else {
+        return;
+    };
+    if outcomes.finished() < min_finished_orders || cancel_ratio_bps <= max_cancel_ratio_bps {
+        return;
+    }
+
+    let until = time().saturating_add(block_secs.saturating_mul(1_000_000_000));
+    CREATION_BLOCKS.with(|blocks| {
+        let mut blocks_borrowed = blocks.borrow_mut();
+        // A longer block already in place stands
+        if blocks_borrowed.get(principal).is_none_or(|(blocked_until, _)| blocked_until < until) {
+            blocks_borrowed.insert(principal.clone(), (until, cancel_ratio_bps));
+        }
+    });
+}
+
+// The principal's block while it is in force
+fn active_block(principal: &StorablePrincipal) -> Option<(u64, u16)> {
+    CREATION_BLOCKS
+        .with(|blocks| blocks.borrow().get(principal))
+        .filter(|(until, _)| *until > time())
+}
+
+fn outcomes_in_window(principal: &StorablePrincipal, today: u64) -> Outcomes {
+    OUTCOMES.with(|outcomes| {
+        let mut total = Outcomes::default();
+        for (_, bucket) in outcomes
+            .borrow()
+            .range((principal.clone(), window_start(today))..=(principal.clone(), today))
+        {
+            total.add(&bucket);
+        }
+        total
+    })
+}
+
+fn window_start(today: u64) -> u64 {
+    today.saturating_sub(PROFILE_WINDOW_DAYS - 1)
+}
@@ -0,0 +1,259 @@
+use crate::admin::require_admin;
+use crate::{Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::caller;
+#[cfg(test)]
+use crate::admin::tests::time;
+#[cfg(not(test))]
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum TransactionKind {
+    Deposit,
+    Transfer,
+    Escrow, // funds locked when an order is created
+    Fill,   // one leg of an order execution
+    Fee,    // trading fee paid to the fee account on a fill
+    Rebate, // maker rebate paid by the fee account on a fill
+    Refund, // escrow returned when an order is cancelled, expires or shrinks
+    Withdrawal,
+    FeeWithdrawal,    // collected fees moved out of the fee account by the admin
+    RecurringOrder,   // order placed by a recurring schedule; its escrow and fills are logged as usual
+    Adjustment,       // balance corrected by the admin, see list_adjustments
+    OrderDeposit,     // creation deposit locked when an order rests
+    DepositRefund,    // creation deposit returned when the order executes or is cancelled
+    DepositForfeited, // creation deposit of an order that expired unfilled, paid to the fee account
+    LiquidityAdded,   // deposited into a pool, one per currency
+    LiquidityRemoved, // paid out of a pool for burned LP shares, one per currency
+    PoolSwap,         // one leg of a swap_via_pool
+    ReferralReward,   // share of a taker fee paid by the fee account to the taker's referrer
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct Transaction {
+    id: u64,
+    kind: TransactionKind,
+    from: Option<Principal>, // account debited, None when funds enter the canister
+    to: Option<Principal>,   // account credited, None when funds leave the canister
+    currency: String,
+    amount: u128,
+    order_id: Option<u64>,
+    timestamp: u64,
+    ledger_block_index: Option<u64>, // block on the currency's ledger that moved the funds in or out
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Transaction"))
+    }
+
+    // Entries logged while amounts were u64 are widened on the way out
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Transaction::try_from_bytes(&bytes).expect("Failed to decode Transaction")
+    }
+}
+
+impl Transaction {
+    // from_bytes without trapping, for scan_for_corrupt_records
+    pub(crate) fn try_from_bytes(bytes: &[u8]) -> Result<Transaction, candid::Error> {
+        Decode!(bytes, Self).or_else(|_| Decode!(bytes, NarrowTransaction).map(Transaction::from))
+    }
+}
+
+impl BoundedStorable for Transaction {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Entries of the log as stored under the old bound, which the Adjustment kind
+// outgrew; drained into TRANSACTIONS on upgrade
+#[derive(Clone)]
+struct LegacyTransaction(Transaction);
+
+impl Storable for LegacyTransaction {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        LegacyTransaction(Transaction::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for LegacyTransaction {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Transaction as stored while amounts were u64
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NarrowTransaction {
+    id: u64,
+    kind: TransactionKind,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    currency: String,
+    amount: u64,
+    order_id: Option<u64>,
+    timestamp: u64,
+    ledger_block_index: Option<u64>,
+}
+
+impl From<NarrowTransaction> for Transaction {
+    fn from(narrow: NarrowTransaction) -> Self {
+        Transaction {
+            id: narrow.id,
+            kind: narrow.kind,
+            from: narrow.from,
+            to: narrow.to,
+            currency: narrow.currency,
+            amount: narrow.amount.into(),
+            order_id: narrow.order_id,
+            timestamp: narrow.timestamp,
+            ledger_block_index: narrow.ledger_block_index,
+        }
+    }
+}
+
+// Upper bound on transactions returned by a single page
+const MAX_TRANSACTIONS_PAGE_SIZE: u64 = 100;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TransactionsPage {
+    transactions: Vec<Transaction>,
+    total: u64, // number of matching transactions across all pages
+}
+
+thread_local! {
+    static LEGACY_TRANSACTIONS: RefCell<StableBTreeMap<u64, LegacyTransaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static TRANSACTIONS: RefCell<StableBTreeMap<u64, Transaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+}
+
+// Reloads the log from stable memory after quarantine_corrupt_record removed
+// an entry through a map of its own
+pub(crate) fn reload_transactions() {
+    TRANSACTIONS.with(|transactions| {
+        *transactions.borrow_mut() = StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))))
+    });
+}
+
+// Moves the log out of its old memory, keeping the ids
+pub(crate) fn migrate_legacy_transactions() {
+    let legacy: Vec<(u64, LegacyTransaction)> =
+        LEGACY_TRANSACTIONS.with(|transactions| transactions.borrow().iter().collect());
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions_borrowed = transactions.borrow_mut();
+        for (id, transaction) in &legacy {
+            transactions_borrowed.insert(*id, transaction.0.clone());
+        }
+    });
+    LEGACY_TRANSACTIONS.with(|transactions| {
+        let mut transactions_borrowed = transactions.borrow_mut();
+        for (id, _) in &legacy {
+            transactions_borrowed.remove(id);
+        }
+    });
+}
+
+// Appends an entry to the log and returns its id. Ids are sequential, the next
+// one follows the highest id stored.
+pub(crate) fn record_transaction(
+    kind: TransactionKind,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    currency: &str,
+    amount: u128,
+    order_id: Option<u64>,
+) -> u64 {
+    append_transaction(kind, from, to, currency, amount, order_id, None)
+}
+
+// Same as record_transaction for funds that crossed a ledger, keeping the
+// ledger's block index so the log can be reconciled against it
+pub(crate) fn record_ledger_transaction(
+    kind: TransactionKind,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    currency: &str,
+    amount: u128,
+    ledger_block_index: u64,
+) -> u64 {
+    append_transaction(kind, from, to, currency, amount, None, Some(ledger_block_index))
+}
+
+fn append_transaction(
+    kind: TransactionKind,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    currency: &str,
+    amount: u128,
+    order_id: Option<u64>,
+    ledger_block_index: Option<u64>,
+) -> u64 {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions_borrowed = transactions.borrow_mut();
+        let id = transactions_borrowed.last_key_value().map_or(1, |(last_id, _)| last_id + 1);
+        let transaction = Transaction {
+            id,
+            kind,
+            from,
+            to,
+            currency: currency.to_string(),
+            amount,
+            order_id,
+            timestamp: time(),
+            ledger_block_index,
+        };
+        transactions_borrowed.insert(id, transaction);
+        id
+    })
+}
+
+pub(crate) fn latest_transaction_id() -> u64 {
+    TRANSACTIONS.with(|transactions| transactions.borrow().last_key_value().map_or(0, |(last_id, _)| last_id))
+}
+
+#[ic_cdk::query]
+fn get_my_transactions(offset: u64, limit: u64) -> TransactionsPage {
+    transactions_involving(caller(), offset, limit)
+}
+
+#[ic_cdk::query]
+fn get_transactions_by_principal(principal: Principal, offset: u64, limit: u64) -> Result<TransactionsPage, Error> {
+    require_admin()?;
+    Ok(transactions_involving(principal, offset, limit))
+}
+
+// Transactions where the principal is on either side, newest first
+fn transactions_involving(principal: Principal, offset: u64, limit: u64) -> TransactionsPage {
+    let limit = limit.min(MAX_TRANSACTIONS_PAGE_SIZE) as usize;
+    let mut matching: Vec<Transaction> = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .filter(|transaction| transaction.from == Some(principal) || transaction.to == Some(principal))
+            .collect()
+    });
+
+    matching.reverse();
+    let total = matching.len() as u64;
+    let transactions = matching
+        .into_iter()
+        .skip(offset.min(total) as usize)
+        .take(limit)
+        .collect();
+
+    TransactionsPage { transactions, total }
+}
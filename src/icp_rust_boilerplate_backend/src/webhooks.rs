@@ -0,0 +1,474 @@
+use crate::admin::{self, require_admin};
+use crate::receipts::ExecutionReceipt;
+use crate::{events, rate_limit, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformContext,
+};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub(crate) const WEBHOOK_DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Events each webhook looks through per delivery, fills or not
+const EVENT_SCAN_LIMIT: usize = 500;
+
+// Fills sent in one POST at most
+const MAX_BATCH_SIZE: usize = 20;
+
+// Webhooks registered by one order owner; the admin's don't count
+const MAX_WEBHOOKS_PER_OWNER: usize = 3;
+
+// Webhooks registered in total
+const MAX_WEBHOOKS: u64 = 50;
+
+// Failed deliveries in a row after which a webhook stops until resumed
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+
+// Wait after the first failure, doubling with each one after it
+const RETRY_BACKOFF_NANOS: u64 = 30 * 1_000_000_000;
+
+const MAX_RETRY_BACKOFF_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+// How long a delivery run keeps others from starting. Released when the run
+// finishes; the timeout only matters if a run never gets its replies.
+const DELIVERY_LEASE_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+const MAX_URL_BYTES: usize = 256;
+
+const MIN_SECRET_BYTES: usize = 32;
+
+const MAX_SECRET_BYTES: usize = 64;
+
+// Receivers only need to acknowledge, so their reply is kept small
+const MAX_RESPONSE_BYTES: u64 = 1024;
+
+// Replicas on a standard application subnet, each of which makes the outcall
+const SUBNET_NODES: u128 = 13;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct WebhookConfig {
+    owner_webhooks_allowed: bool, // whether order owners may register webhooks for their own fills
+    max_cycles_per_batch: u128,   // a POST that would cost more is sent with fewer fills
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            owner_webhooks_allowed: false,
+            max_cycles_per_batch: 500_000_000,
+        }
+    }
+}
+
+impl Storable for WebhookConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode WebhookConfig"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode WebhookConfig")
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Webhook {
+    owner: Option<Principal>, // None for the admin's, which get every fill
+    url: String,
+    secret: Vec<u8>,
+    cursor: u64, // seq of the last event acknowledged, or skipped as not for this webhook
+    registered_at: u64,
+    consecutive_failures: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    paused: bool, // after MAX_CONSECUTIVE_FAILURES
+}
+
+impl Storable for Webhook {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode Webhook"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode Webhook")
+    }
+}
+
+impl BoundedStorable for Webhook {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static WEBHOOK_CONFIG: RefCell<Cell<WebhookConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85))), WebhookConfig::default())
+            .expect("Cannot create the webhook config")
+    );
+
+    // Keyed by id starting at 1
+    static WEBHOOKS: RefCell<StableBTreeMap<u64, Webhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+
+    // Heap only: a delivery run in flight when the canister upgrades is simply
+    // not acknowledged, and its fills are sent again
+    static DELIVERY_LEASE_UNTIL: RefCell<u64> = const { RefCell::new(0) };
+}
+
+// A webhook as listed, without its secret
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct WebhookInfo {
+    id: u64,
+    owner: Option<Principal>,
+    url: String,
+    cursor: u64,
+    registered_at: u64,
+    consecutive_failures: u32,
+    next_attempt_at: u64,
+    last_error: Option<String>,
+    paused: bool,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    webhook_id: u64,
+    cursor: u64, // acknowledge by answering 2xx; the next batch starts after this seq
+    fills: &'a [WebhookFill],
+}
+
+#[derive(Serialize, Clone)]
+struct WebhookFill {
+    seq: u64,
+    receipt: ExecutionReceipt,
+}
+
+#[ic_cdk::query]
+fn get_webhook_config() -> WebhookConfig {
+    WEBHOOK_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+fn set_webhook_config(config: WebhookConfig) -> Result<(), Error> {
+    require_admin()?;
+    if config.max_cycles_per_batch == 0 {
+        return Err(Error::InvalidWebhook);
+    }
+    WEBHOOK_CONFIG.with(|stored| stored.borrow_mut().set(config).expect("Failed to store the webhook config"));
+    Ok(())
+}
+
+// Registers an HTTPS endpoint that fills are POSTed to as JSON, signed with
+// an HMAC-SHA256 of the body under `secret` in the X-Webhook-Signature
+// header. The admin's webhooks get every fill; an order owner's, allowed
+// only while the config says so, get the fills of their own orders. Fills
+// before registration are not sent.
+#[ic_cdk::update]
+fn register_webhook(url: String, secret: Vec<u8>) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let url = url.trim().to_string();
+    if !url.starts_with("https://") || url.len() > MAX_URL_BYTES {
+        return Err(Error::InvalidWebhook);
+    }
+    if secret.len() < MIN_SECRET_BYTES || secret.len() > MAX_SECRET_BYTES {
+        return Err(Error::InvalidWebhook);
+    }
+
+    let owner = if admin::is_admin(&caller()) {
+        None
+    } else {
+        if !get_webhook_config().owner_webhooks_allowed {
+            return Err(Error::Unauthorized);
+        }
+        let registered = WEBHOOKS.with(|webhooks| {
+            webhooks.borrow().iter().filter(|(_, webhook)| webhook.owner == Some(caller())).count()
+        });
+        if registered >= MAX_WEBHOOKS_PER_OWNER {
+            return Err(Error::TooManyWebhooks { limit: MAX_WEBHOOKS_PER_OWNER as u64 });
+        }
+        Some(caller())
+    };
+    if WEBHOOKS.with(|webhooks| webhooks.borrow().len()) >= MAX_WEBHOOKS {
+        return Err(Error::TooManyWebhooks { limit: MAX_WEBHOOKS });
+    }
+
+    let now = time();
+    let webhook = Webhook {
+        owner,
+        url,
+        secret,
+        cursor: events::latest_seq(),
+        registered_at: now,
+        consecutive_failures: 0,
+        next_attempt_at: now,
+        last_error: None,
+        paused: false,
+    };
+    let id = WEBHOOKS.with(|webhooks| {
+        let mut webhooks_borrowed = webhooks.borrow_mut();
+        let id = webhooks_borrowed.last_key_value().map_or(1, |(last_id, _)| last_id + 1);
+        webhooks_borrowed.insert(id, webhook);
+        id
+    });
+    Ok(id)
+}
+
+// The admin can remove any webhook, an owner only theirs
+#[ic_cdk::update]
+fn remove_webhook(webhook_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    find_managed(webhook_id)?;
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().remove(&webhook_id));
+    Ok(())
+}
+
+// Starts delivering to a paused webhook again, from where it stopped
+#[ic_cdk::update]
+fn resume_webhook(webhook_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let mut webhook = find_managed(webhook_id)?;
+    webhook.paused = false;
+    webhook.consecutive_failures = 0;
+    webhook.next_attempt_at = time();
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().insert(webhook_id, webhook));
+    Ok(())
+}
+
+// Every webhook for the admin, the caller's own for anyone else
+#[ic_cdk::query]
+fn list_webhooks() -> Vec<WebhookInfo> {
+    let is_admin = admin::is_admin(&caller());
+    WEBHOOKS.with(|webhooks| {
+        webhooks
+            .borrow()
+            .iter()
+            .filter(|(_, webhook)| is_admin || webhook.owner == Some(caller()))
+            .map(|(id, webhook)| WebhookInfo {
+                id,
+                owner: webhook.owner,
+                url: webhook.url,
+                cursor: webhook.cursor,
+                registered_at: webhook.registered_at,
+                consecutive_failures: webhook.consecutive_failures,
+                next_attempt_at: webhook.next_attempt_at,
+                last_error: webhook.last_error,
+                paused: webhook.paused,
+            })
+            .collect()
+    })
+}
+
+// Every replica sees different headers, so only the status is kept for
+// consensus; the body carries nothing the canister reads
+#[ic_cdk::query]
+fn transform_webhook_response(
+    args: ic_cdk::api::management_canister::http_request::TransformArgs,
+) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Timer entry point
+pub(crate) fn deliver_webhooks() {
+    let now = time();
+    if DELIVERY_LEASE_UNTIL.with(|lease| *lease.borrow()) > now {
+        return;
+    }
+    DELIVERY_LEASE_UNTIL.with(|lease| *lease.borrow_mut() = now.saturating_add(DELIVERY_LEASE_NANOS));
+    ic_cdk::spawn(async {
+        let due: Vec<u64> = WEBHOOKS.with(|webhooks| {
+            webhooks
+                .borrow()
+                .iter()
+                .filter(|(_, webhook)| !webhook.paused && webhook.next_attempt_at <= time())
+                .map(|(id, _)| id)
+                .collect()
+        });
+        for webhook_id in due {
+            deliver(webhook_id).await;
+        }
+        DELIVERY_LEASE_UNTIL.with(|lease| *lease.borrow_mut() = 0);
+    });
+}
+
+// Sends the webhook's next batch, moving its cursor only once the endpoint
+// answers 2xx, so every fill is delivered at least once
+async fn deliver(webhook_id: u64) {
+    let Some(webhook) = WEBHOOKS.with(|webhooks| webhooks.borrow().get(&webhook_id)) else {
+        return;
+    };
+    let (executions, last_scanned) = events::executions_after(webhook.cursor, EVENT_SCAN_LIMIT);
+    let Some(last_scanned) = last_scanned else {
+        return;
+    };
+    let fills: Vec<WebhookFill> = executions
+        .into_iter()
+        .filter(|(_, receipt)| webhook.owner.is_none_or(|owner| receipt.owner == owner))
+        .map(|(seq, receipt)| WebhookFill { seq, receipt })
+        .collect();
+    if fills.is_empty() {
+        advance(webhook_id, last_scanned);
+        return;
+    }
+
+    // Fewer fills when the batch is full or the POST would cost too much; the
+    // cursor then stops at the last fill sent
+    let max_cycles = get_webhook_config().max_cycles_per_batch;
+    let mut batch_size = fills.len().min(MAX_BATCH_SIZE);
+    let (request, cycles, cursor) = loop {
+        let batch = &fills[..batch_size];
+        let cursor = if batch_size == fills.len() { last_scanned } else { batch[batch_size - 1].seq };
+        let request = build_request(webhook_id, &webhook, batch, cursor);
+        let cycles = outcall_cost(&request);
+        if cycles <= max_cycles {
+            break (request, cycles, cursor);
+        }
+        if batch_size == 1 {
+            record_failure(webhook_id, format!("A single fill costs {} cycles, over the cap", cycles));
+            return;
+        }
+        batch_size /= 2;
+    };
+
+    match http_request(request, cycles).await {
+        Ok((response,)) if is_success(&response) => advance(webhook_id, cursor),
+        Ok((response,)) => record_failure(webhook_id, format!("Endpoint answered {}", response.status)),
+        Err((code, message)) => record_failure(webhook_id, format!("Outcall rejected ({:?}): {}", code, message)),
+    }
+}
+
+fn build_request(webhook_id: u64, webhook: &Webhook, fills: &[WebhookFill], cursor: u64) -> CanisterHttpRequestArgument {
+    let body = serde_json::to_vec(&WebhookPayload {
+        webhook_id,
+        cursor,
+        fills,
+    })
+    .expect("Failed to encode the webhook payload");
+    let signature = hmac_sha256(&webhook.secret, &body);
+    CanisterHttpRequestArgument {
+        url: webhook.url.clone(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            HttpHeader {
+                name: "X-Webhook-Signature".to_string(),
+                value: format!("sha256={}", to_hex(&signature)),
+            },
+            // Each replica sends its own copy, and retries resend the batch
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: format!("{}-{}-{}", webhook_id, webhook.cursor, cursor),
+            },
+        ],
+        body: Some(body),
+        transform: Some(TransformContext::from_name("transform_webhook_response".to_string(), Vec::new())),
+    }
+}
+
+// The documented HTTPS outcall fee for a request of this size on a 13 node
+// subnet, with the largest reply MAX_RESPONSE_BYTES allows
+fn outcall_cost(request: &CanisterHttpRequestArgument) -> u128 {
+    let request_bytes = request.url.len()
+        + request.body.as_ref().map_or(0, |body| body.len())
+        + request.headers.iter().map(|header| header.name.len() + header.value.len()).sum::<usize>();
+    let base = (3_000_000 + 60_000 * SUBNET_NODES) * SUBNET_NODES;
+    base + 400 * SUBNET_NODES * request_bytes as u128 + 800 * SUBNET_NODES * MAX_RESPONSE_BYTES as u128
+}
+
+fn is_success(response: &HttpResponse) -> bool {
+    response.status >= 200u64 && response.status < 300u64
+}
+
+fn advance(webhook_id: u64, cursor: u64) {
+    update(webhook_id, |webhook| {
+        webhook.cursor = cursor;
+        webhook.consecutive_failures = 0;
+        webhook.last_error = None;
+    });
+}
+
+fn record_failure(webhook_id: u64, error: String) {
+    update(webhook_id, |webhook| {
+        webhook.consecutive_failures += 1;
+        let backoff = RETRY_BACKOFF_NANOS
+            .saturating_mul(1u64 << (webhook.consecutive_failures - 1).min(16))
+            .min(MAX_RETRY_BACKOFF_NANOS);
+        webhook.next_attempt_at = time().saturating_add(backoff);
+        webhook.paused = webhook.consecutive_failures >= MAX_CONSECUTIVE_FAILURES;
+        webhook.last_error = Some(error.chars().take(200).collect());
+    });
+}
+
+// Reads the webhook again, since it may have been removed or resumed while
+// the outcall was in flight
+fn update(webhook_id: u64, change: impl FnOnce(&mut Webhook)) {
+    WEBHOOKS.with(|webhooks| {
+        let mut webhooks_borrowed = webhooks.borrow_mut();
+        if let Some(mut webhook) = webhooks_borrowed.get(&webhook_id) {
+            change(&mut webhook);
+            webhooks_borrowed.insert(webhook_id, webhook);
+        }
+    });
+}
+
+fn find_managed(webhook_id: u64) -> Result<Webhook, Error> {
+    let webhook = WEBHOOKS
+        .with(|webhooks| webhooks.borrow().get(&webhook_id))
+        .ok_or(Error::WebhookNotFound)?;
+    if !admin::is_admin(&caller()) && webhook.owner != Some(caller()) {
+        return Err(Error::WebhookNotFound);
+    }
+    Ok(webhook)
+}
+
+// RFC 2104 over SHA-256, whose block is 64 bytes; secrets are at most that
+// long, so they are never hashed first
+fn hmac_sha256(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut key = [0u8; 64];
+    key[..secret.len()].copy_from_slice(secret);
+    let mut inner = Sha256::new();
+    inner.update(key.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(key.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymous_webhook_changes_are_rejected() {
+        assert_eq!(register_webhook("https://example.com/fills".to_string(), vec![1; 32]), Err(Error::AnonymousNotAllowed));
+        assert_eq!(remove_webhook(1), Err(Error::AnonymousNotAllowed));
+        assert_eq!(resume_webhook(1), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn signatures_match_the_rfc_4231_vectors() {
+        let signature = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+
+        assert_eq!(to_hex(&signature), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+}
@@ -0,0 +1,207 @@
+use crate::currencies::{is_known_currency, normalize_currency};
+use crate::ledgers::{check_account, Account};
+use crate::{admin, blacklist, rate_limit, Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::{Decode, Encode};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// How long a new destination waits before withdrawals can use it, so a
+// stolen key can't add one and drain the account straight away
+const DESTINATION_TIMELOCK_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+const MAX_DESTINATIONS_PER_OWNER: usize = 20;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredDestination {
+    currency: String,
+    account: Account,
+    added_at: u64,
+    active_at: u64,
+}
+
+impl StoredDestination {
+    fn matches(&self, currency: &str, account: &Account) -> bool {
+        self.currency == currency && self.account.same_as(account)
+    }
+}
+
+impl Storable for StoredDestination {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode StoredDestination"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode StoredDestination")
+    }
+}
+
+impl BoundedStorable for StoredDestination {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // (owner, destination id) -> destination, ids counting up per owner
+    static WITHDRAWAL_DESTINATIONS: RefCell<StableBTreeMap<(StorablePrincipal, u64), StoredDestination, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76)))
+    ));
+
+    // Owner -> when the restriction ends for an owner who removed their last
+    // destination
+    static RESTRICTION_LIFTS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
+    ));
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum DestinationStatus {
+    Pending, // still in its timelock
+    Active,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct WithdrawalDestination {
+    id: u64,
+    currency: String,
+    account: Account,
+    added_at: u64,
+    active_at: u64,
+    status: DestinationStatus,
+}
+
+// Registers an account withdraw_to_ledger may send `currency` to, usable once
+// the timelock has passed. The first destination an owner registers turns the
+// restriction on: from then on withdrawals to anything else fail, including
+// currencies without a destination of their own.
+#[ic_cdk::update]
+fn add_withdrawal_destination(currency: String, account: Account) -> Result<u64, Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    blacklist::require_not_blacklisted()?;
+    let currency = normalize_currency(&currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+    check_account(&account)?;
+
+    let owner = StorablePrincipal::from(caller());
+    let existing = destinations_of(&owner);
+    if existing.iter().any(|(_, destination)| destination.matches(&currency, &account)) {
+        return Err(Error::DestinationAlreadyRegistered);
+    }
+    if existing.len() >= MAX_DESTINATIONS_PER_OWNER {
+        return Err(Error::TooManyWithdrawalDestinations { limit: MAX_DESTINATIONS_PER_OWNER as u64 });
+    }
+
+    let now = time();
+    let destination = StoredDestination {
+        currency,
+        account,
+        added_at: now,
+        active_at: now.saturating_add(DESTINATION_TIMELOCK_NANOS),
+    };
+    let id = existing.last().map_or(1, |(last_id, _)| last_id + 1);
+    RESTRICTION_LIFTS.with(|lifts| lifts.borrow_mut().remove(&owner));
+    WITHDRAWAL_DESTINATIONS.with(|destinations| destinations.borrow_mut().insert((owner, id), destination));
+    Ok(id)
+}
+
+// Takes effect immediately. Removing the last destination only lifts the
+// restriction after the timelock, the same wait as adding one, so a stolen key
+// can't clear the list and withdraw anywhere.
+#[ic_cdk::update]
+fn remove_withdrawal_destination(destination_id: u64) -> Result<(), Error> {
+    admin::require_authenticated()?;
+    rate_limit::check_call_rate()?;
+    let owner = StorablePrincipal::from(caller());
+    WITHDRAWAL_DESTINATIONS
+        .with(|destinations| destinations.borrow_mut().remove(&(owner.clone(), destination_id)))
+        .ok_or(Error::DestinationNotFound)?;
+    if destinations_of(&owner).is_empty() {
+        let lift_at = time().saturating_add(DESTINATION_TIMELOCK_NANOS);
+        RESTRICTION_LIFTS.with(|lifts| lifts.borrow_mut().insert(owner, lift_at));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_my_withdrawal_destinations() -> Vec<WithdrawalDestination> {
+    let now = time();
+    destinations_of(&StorablePrincipal::from(caller()))
+        .into_iter()
+        .map(|(id, destination)| WithdrawalDestination {
+            id,
+            status: if destination.active_at <= now {
+                DestinationStatus::Active
+            } else {
+                DestinationStatus::Pending
+            },
+            currency: destination.currency,
+            account: destination.account,
+            added_at: destination.added_at,
+            active_at: destination.active_at,
+        })
+        .collect()
+}
+
+// Called by withdraw_to_ledger. Owners who never registered a destination,
+// or removed the last one more than the timelock ago, can withdraw anywhere.
+pub(crate) fn check_destination(owner: &StorablePrincipal, currency: &str, account: &Account) -> Result<(), Error> {
+    let destinations = destinations_of(owner);
+    if destinations.is_empty() {
+        return match RESTRICTION_LIFTS.with(|lifts| lifts.borrow().get(owner)) {
+            Some(lift_at) if lift_at > time() => Err(Error::DestinationNotRegistered),
+            _ => Ok(()),
+        };
+    }
+    let Some((_, destination)) = destinations
+        .into_iter()
+        .find(|(_, destination)| destination.matches(currency, account))
+    else {
+        return Err(Error::DestinationNotRegistered);
+    };
+    if destination.active_at > time() {
+        return Err(Error::DestinationNotYetActive { active_at: destination.active_at });
+    }
+    Ok(())
+}
+
+fn destinations_of(owner: &StorablePrincipal) -> Vec<(u64, StoredDestination)> {
+    WITHDRAWAL_DESTINATIONS.with(|destinations| {
+        destinations
+            .borrow()
+            .range((owner.clone(), 0)..=(owner.clone(), u64::MAX))
+            .map(|((_, id), destination)| (id, destination))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledgers::tests::account;
+    use candid::Principal;
+
+    #[test]
+    fn anonymous_destination_changes_are_rejected() {
+        assert_eq!(add_withdrawal_destination("USD".to_string(), account(1)), Err(Error::AnonymousNotAllowed));
+        assert_eq!(remove_withdrawal_destination(1), Err(Error::AnonymousNotAllowed));
+    }
+
+    #[test]
+    fn only_registered_destinations_are_allowed_once_there_are_any() {
+        let owner = StorablePrincipal::from(Principal::from_slice(&[87]));
+        assert_eq!(check_destination(&owner, "USD", &account(2)), Ok(()));
+
+        let destination = StoredDestination { currency: "USD".to_string(), account: account(2), added_at: 0, active_at: 0 };
+        WITHDRAWAL_DESTINATIONS.with(|destinations| destinations.borrow_mut().insert((owner.clone(), 1), destination));
+
+        assert_eq!(check_destination(&owner, "USD", &account(3)), Err(Error::DestinationNotRegistered));
+        assert_eq!(check_destination(&owner, "EUR", &account(2)), Err(Error::DestinationNotRegistered));
+    }
+}
@@ -0,0 +1,209 @@
+use crate::admin::require_admin;
+use crate::currencies::{is_known_currency, normalize_currency, CurrencySymbol};
+use crate::{Error, Memory, StorablePrincipal, MEMORY_MANAGER};
+use candid::Principal;
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const WITHDRAWAL_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+type WithdrawalKey = ((StorablePrincipal, CurrencySymbol), u64);
+
+thread_local! {
+    // Limits and withdrawals as stored while amounts were u64. The maps hold
+    // fixed-size values, so the wider ones live in new memories and these are
+    // drained into them once by the upgrade migration.
+    static LEGACY_WITHDRAWAL_LIMITS: RefCell<StableBTreeMap<CurrencySymbol, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static LEGACY_RECENT_WITHDRAWALS: RefCell<StableBTreeMap<WithdrawalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // Currency -> most that one principal may withdraw in any 24 hour window.
+    // Currencies without an entry are unlimited.
+    static WITHDRAWAL_LIMITS: RefCell<StableBTreeMap<CurrencySymbol, u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    // ((principal, currency), time) -> amount withdrawn at that time. Entries
+    // older than the window are pruned whenever the principal withdraws again.
+    static RECENT_WITHDRAWALS: RefCell<StableBTreeMap<WithdrawalKey, u128, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    // Accounts the admin has exempted from every withdrawal limit
+    static WITHDRAWAL_EXEMPTIONS: RefCell<StableBTreeMap<StorablePrincipal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct WithdrawalAllowance {
+    limit: Option<u128>,     // None when the currency is unlimited or the caller is exempt
+    used: u128,              // withdrawn in the last 24 hours
+    remaining: Option<u128>, // None when there is no limit
+}
+
+// Sets the daily cap for a currency; None removes it
+#[ic_cdk::update]
+fn set_withdrawal_limit(currency: String, limit: Option<u128>) -> Result<(), Error> {
+    require_admin()?;
+    let currency = normalize_currency(&currency);
+    if !is_known_currency(&currency) {
+        return Err(Error::InvalidCurrency { provided: currency });
+    }
+
+    WITHDRAWAL_LIMITS.with(|limits| {
+        let mut limits_borrowed = limits.borrow_mut();
+        match limit {
+            Some(limit) => limits_borrowed.insert(CurrencySymbol(currency), limit),
+            None => limits_borrowed.remove(&CurrencySymbol(currency)),
+        };
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_withdrawal_exemption(principal: Principal, exempt: bool) -> Result<(), Error> {
+    require_admin()?;
+
+    WITHDRAWAL_EXEMPTIONS.with(|exemptions| {
+        let mut exemptions_borrowed = exemptions.borrow_mut();
+        if exempt {
+            exemptions_borrowed.insert(StorablePrincipal::from(principal), ());
+        } else {
+            exemptions_borrowed.remove(&StorablePrincipal::from(principal));
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_my_withdrawal_allowance(currency: String) -> WithdrawalAllowance {
+    let principal = StorablePrincipal::from(caller());
+    let currency = CurrencySymbol(normalize_currency(&currency));
+    let used = withdrawn_in_window(&principal, &currency, time());
+    let limit = effective_limit(&principal, &currency);
+
+    WithdrawalAllowance {
+        limit,
+        used,
+        remaining: limit.map(|limit| limit.saturating_sub(used)),
+    }
+}
+
+// Fails with the headroom left if withdrawing `amount` now would exceed the cap
+pub(crate) fn check_withdrawal_limit(principal: &StorablePrincipal, currency: &str, amount: u128) -> Result<(), Error> {
+    let currency = CurrencySymbol(currency.to_string());
+    let limit = match effective_limit(principal, &currency) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    let remaining = limit.saturating_sub(withdrawn_in_window(principal, &currency, time()));
+    if amount > remaining {
+        return Err(Error::WithdrawalLimitExceeded { remaining });
+    }
+    Ok(())
+}
+
+// Counts a completed withdrawal against the window and drops entries that
+// have aged out of it
+pub(crate) fn record_withdrawal(principal: &StorablePrincipal, currency: &str, amount: u128) {
+    let key_prefix = (principal.clone(), CurrencySymbol(currency.to_string()));
+    let now = time();
+
+    RECENT_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals_borrowed = withdrawals.borrow_mut();
+        let expired: Vec<WithdrawalKey> = withdrawals_borrowed
+            .range((key_prefix.clone(), 0)..(key_prefix.clone(), window_start(now)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            withdrawals_borrowed.remove(&key);
+        }
+
+        // Withdrawals in the same round share a timestamp and one entry
+        let key = (key_prefix, now);
+        let total = withdrawals_borrowed.get(&key).unwrap_or(0).saturating_add(amount);
+        withdrawals_borrowed.insert(key, total);
+    });
+}
+
+// Takes a withdrawal recorded at `recorded_at` back out of the window, for
+// withdrawals that were counted up front and then failed
+pub(crate) fn release_withdrawal(principal: &StorablePrincipal, currency: &str, recorded_at: u64, amount: u128) {
+    let key = ((principal.clone(), CurrencySymbol(currency.to_string())), recorded_at);
+
+    RECENT_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals_borrowed = withdrawals.borrow_mut();
+        match withdrawals_borrowed.get(&key).map(|total| total.saturating_sub(amount)) {
+            Some(0) => withdrawals_borrowed.remove(&key),
+            Some(total) => withdrawals_borrowed.insert(key, total),
+            None => None,
+        };
+    });
+}
+
+// Moves the u64 limits and recent withdrawals into the u128 maps
+pub(crate) fn migrate_legacy_withdrawals() {
+    let legacy_limits: Vec<(CurrencySymbol, u64)> =
+        LEGACY_WITHDRAWAL_LIMITS.with(|limits| limits.borrow().iter().collect());
+    WITHDRAWAL_LIMITS.with(|limits| {
+        let mut limits_borrowed = limits.borrow_mut();
+        for (currency, limit) in &legacy_limits {
+            limits_borrowed.insert(currency.clone(), (*limit).into());
+        }
+    });
+    LEGACY_WITHDRAWAL_LIMITS.with(|limits| {
+        let mut limits_borrowed = limits.borrow_mut();
+        for (currency, _) in &legacy_limits {
+            limits_borrowed.remove(currency);
+        }
+    });
+
+    let legacy_withdrawals: Vec<(WithdrawalKey, u64)> =
+        LEGACY_RECENT_WITHDRAWALS.with(|withdrawals| withdrawals.borrow().iter().collect());
+    RECENT_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals_borrowed = withdrawals.borrow_mut();
+        for (key, amount) in &legacy_withdrawals {
+            withdrawals_borrowed.insert(key.clone(), (*amount).into());
+        }
+    });
+    LEGACY_RECENT_WITHDRAWALS.with(|withdrawals| {
+        let mut withdrawals_borrowed = withdrawals.borrow_mut();
+        for (key, _) in &legacy_withdrawals {
+            withdrawals_borrowed.remove(key);
+        }
+    });
+}
+
+fn effective_limit(principal: &StorablePrincipal, currency: &CurrencySymbol) -> Option<u128> {
+    if WITHDRAWAL_EXEMPTIONS.with(|exemptions| exemptions.borrow().contains_key(principal)) {
+        return None;
+    }
+    WITHDRAWAL_LIMITS.with(|limits| limits.borrow().get(currency))
+}
+
+fn withdrawn_in_window(principal: &StorablePrincipal, currency: &CurrencySymbol, now: u64) -> u128 {
+    let key_prefix = (principal.clone(), currency.clone());
+    RECENT_WITHDRAWALS.with(|withdrawals| {
+        withdrawals
+            .borrow()
+            .range((key_prefix.clone(), window_start(now))..=(key_prefix, u64::MAX))
+            .fold(0u128, |total, (_, amount)| total.saturating_add(amount))
+    })
+}
+
+fn window_start(now: u64) -> u64 {
+    now.saturating_sub(WITHDRAWAL_WINDOW_NANOS) + 1
+}